@@ -0,0 +1,638 @@
+//! `From`/`TryFrom` conversions between messages with the same fields, declared with
+//! [`ServiceGenerator::convert`](crate::ServiceGenerator::convert).
+//!
+//! Two messages have the same fields if every field of one has a field of the same name in the
+//! other, with the same label and type. Fields of message or enum types match fields of the same
+//! type, or of a type declared as converting to it. Conversions of enums check that the value is
+//! one the target enum declares, so messages with fields converted that way (directly or through
+//! their message fields) get a `TryFrom` conversion failing with `prost::UnknownEnumValue`;
+//! others get a `From` conversion. Enums convert by number, so values both enums declare must
+//! have the same name in both.
+//!
+//! Recursive message fields, which `prost-build` boxes, are converted through their boxes; fields
+//! boxed with `prost_build::Config::boxed` aren't supported.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+use heck::{ToSnakeCase, ToUpperCamelCase};
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::{DescriptorProto, EnumDescriptorProto, FieldDescriptorProto, FileDescriptorSet};
+
+use crate::validate::rust_field_ident;
+
+/// The name of the Rust variant of an enum value, which `prost-build` strips of the enum's name,
+/// e.g. `Red` for `COLOR_RED` in `Color`.
+fn variant_name(e: &EnumDescriptorProto, value: &str) -> String {
+    let (enum_name, name) = (e.name().to_upper_camel_case(), value.to_upper_camel_case());
+    match name.strip_prefix(&enum_name) {
+        Some(stripped) if stripped.starts_with(char::is_uppercase) => stripped.to_string(),
+        _ => name,
+    }
+}
+
+/// A message or enum type in a set of file descriptors.
+enum Definition<'a> {
+    Message(&'a DescriptorProto),
+    Enum(&'a EnumDescriptorProto),
+}
+
+/// A type by fully-qualified name (e.g. `.service.haberdash.v1.Hat`), with the file and package
+/// declaring it.
+struct TypeRef<'a> {
+    file: &'a str,
+    package: &'a str,
+    definition: Definition<'a>,
+}
+
+/// All messages and enums in the descriptor set (including nested ones) by fully-qualified name.
+fn types(fds: &FileDescriptorSet) -> HashMap<String, TypeRef<'_>> {
+    fn add<'a>(
+        file: &'a str,
+        package: &'a str,
+        prefix: &str,
+        message: &'a DescriptorProto,
+        out: &mut HashMap<String, TypeRef<'a>>,
+    ) {
+        let name = format!("{prefix}.{}", message.name());
+        for nested in &message.nested_type {
+            add(file, package, &name, nested, out);
+        }
+        for e in &message.enum_type {
+            let definition = Definition::Enum(e);
+            out.insert(
+                format!("{name}.{}", e.name()),
+                TypeRef {
+                    file,
+                    package,
+                    definition,
+                },
+            );
+        }
+        let definition = Definition::Message(message);
+        out.insert(
+            name,
+            TypeRef {
+                file,
+                package,
+                definition,
+            },
+        );
+    }
+    let mut out = HashMap::new();
+    for file_descriptor in &fds.file {
+        let (file, package) = (file_descriptor.name(), file_descriptor.package());
+        let prefix = match package {
+            "" => String::new(),
+            package => format!(".{package}"),
+        };
+        for message in &file_descriptor.message_type {
+            add(file, package, &prefix, message, &mut out);
+        }
+        for e in &file_descriptor.enum_type {
+            let definition = Definition::Enum(e);
+            out.insert(
+                format!("{prefix}.{}", e.name()),
+                TypeRef {
+                    file,
+                    package,
+                    definition,
+                },
+            );
+        }
+    }
+    out
+}
+
+/// A field of the source and target messages of a conversion, with the source type of the field
+/// if it is converted.
+type Field<'a> = (
+    &'a FieldDescriptorProto,
+    &'a FieldDescriptorProto,
+    Option<&'a str>,
+);
+
+/// The declared conversions, and the messages and enums they convert between.
+pub(crate) struct Conversions<'a> {
+    types: HashMap<String, TypeRef<'a>>,
+    // Target type by source type, both fully-qualified.
+    pairs: HashMap<String, String>,
+    fallible: HashMap<String, bool>,
+}
+
+impl<'a> Conversions<'a> {
+    /// Check the declared conversions (`from`, `to`) against the descriptors, panicking on types
+    /// that don't exist or whose fields don't match.
+    pub(crate) fn new(fds: &'a FileDescriptorSet, pairs: &[(String, String)]) -> Self {
+        let types = types(fds);
+        let mut map = HashMap::new();
+        for (from, to) in pairs {
+            for name in [from, to] {
+                if !types.contains_key(name) {
+                    panic!("conversion between {from} and {to}: there is no type {name}");
+                }
+            }
+            let kinds = (&types[from].definition, &types[to].definition);
+            if !matches!(
+                kinds,
+                (Definition::Message(_), Definition::Message(_))
+                    | (Definition::Enum(_), Definition::Enum(_))
+            ) {
+                panic!("conversion between {from} and {to}: both must be messages, or enums");
+            }
+            map.insert(from.clone(), to.clone());
+        }
+        let mut conversions = Self {
+            types,
+            pairs: map,
+            fallible: HashMap::new(),
+        };
+        for (from, to) in pairs {
+            match conversions.types[from].definition {
+                Definition::Message(_) => conversions.check_fields(from, to),
+                Definition::Enum(_) => conversions.check_values(from, to),
+            }
+        }
+        for (from, _) in pairs {
+            let fallible = conversions.is_fallible(from, &mut HashSet::new());
+            conversions.fallible.insert(from.clone(), fallible);
+        }
+        conversions
+    }
+
+    fn message(&self, name: &str) -> &'a DescriptorProto {
+        match self.types[name].definition {
+            Definition::Message(message) => message,
+            Definition::Enum(_) => unreachable!("{name} is an enum"),
+        }
+    }
+
+    fn enumeration(&self, name: &str) -> &'a EnumDescriptorProto {
+        match self.types[name].definition {
+            Definition::Enum(e) => e,
+            Definition::Message(_) => unreachable!("{name} is a message"),
+        }
+    }
+
+    /// Check that the values both enums declare, by number or by name, are the same, as the
+    /// conversion keeps the numbers.
+    fn check_values(&self, from: &str, to: &str) {
+        let (from_enum, to_enum) = (self.enumeration(from), self.enumeration(to));
+        for from_value in &from_enum.value {
+            let name = variant_name(from_enum, from_value.name());
+            let number = from_value.number();
+            let same_name = to_enum
+                .value
+                .iter()
+                .find(|v| variant_name(to_enum, v.name()) == name);
+            let matches = match same_name {
+                Some(to_value) => to_value.number() == number,
+                None => !to_enum.value.iter().any(|v| v.number() == number),
+            };
+            if !matches {
+                panic!(
+                    "conversion from {from} to {to}: value {} = {number} has a different name or \
+                     number in {to}",
+                    from_value.name()
+                );
+            }
+        }
+    }
+
+    fn check_fields(&self, from: &str, to: &str) {
+        let (from_msg, to_msg) = (self.message(from), self.message(to));
+        let names = |m: &DescriptorProto| -> HashSet<String> {
+            m.field.iter().map(|f| f.name().to_string()).collect()
+        };
+        if names(from_msg) != names(to_msg) {
+            panic!("conversion from {from} to {to}: the messages have different fields");
+        }
+        for to_field in &to_msg.field {
+            let from_field = from_msg
+                .field
+                .iter()
+                .find(|f| f.name() == to_field.name())
+                .expect("the messages have the same field names");
+            for field in [from_field, to_field] {
+                if field.oneof_index.is_some() && !field.proto3_optional() {
+                    panic!(
+                        "conversion from {from} to {to}: oneof fields (like `{}`) are not \
+                         supported",
+                        field.name()
+                    );
+                }
+            }
+            if !self.fields_match(from_field, to_field) {
+                panic!(
+                    "conversion from {from} to {to}: field `{}` has a different type",
+                    to_field.name()
+                );
+            }
+        }
+    }
+
+    /// Whether a value of the type `from` can be converted to one of the type `to`.
+    fn types_match(&self, from: &str, to: &str) -> bool {
+        from == to || self.pairs.get(from).map(String::as_str) == Some(to)
+    }
+
+    fn fields_match(&self, from: &FieldDescriptorProto, to: &FieldDescriptorProto) -> bool {
+        if from.label() != to.label()
+            || from.proto3_optional() != to.proto3_optional()
+            || from.r#type() != to.r#type()
+        {
+            return false;
+        }
+        match (self.map_entry(from), self.map_entry(to)) {
+            // Map entries are messages of their own, so compare their keys and values, which
+            // aren't converted.
+            (Some((from_key, from_value)), Some((to_key, to_value))) => {
+                from_key.r#type() == to_key.r#type()
+                    && from_value.r#type() == to_value.r#type()
+                    && from_value.type_name == to_value.type_name
+            }
+            (None, None) => match (&from.type_name, &to.type_name) {
+                (Some(from), Some(to)) => self.types_match(from, to),
+                (None, None) => true,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// The key and value fields of a map field's entry message.
+    fn map_entry(
+        &self,
+        field: &FieldDescriptorProto,
+    ) -> Option<(&'a FieldDescriptorProto, &'a FieldDescriptorProto)> {
+        let entry = field.type_name.as_ref().and_then(|t| self.types.get(t))?;
+        let Definition::Message(entry) = entry.definition else {
+            return None;
+        };
+        if !entry.options.as_ref().map_or(false, |o| o.map_entry()) {
+            return None;
+        }
+        Some((&entry.field[0], &entry.field[1]))
+    }
+
+    /// The fields of the conversion from the message `from` to `to`: the field of each message,
+    /// and the type of the fields that are converted (the source type, fully-qualified).
+    fn fields(&self, from: &str, to: &str) -> Vec<Field<'a>> {
+        let (from_msg, to_msg) = (self.message(from), self.message(to));
+        to_msg
+            .field
+            .iter()
+            .map(|to_field| {
+                let from_field = from_msg
+                    .field
+                    .iter()
+                    .find(|f| f.name() == to_field.name())
+                    .expect("the messages have the same field names");
+                // Map fields are moved as they are: their keys and values have the same types.
+                let converted = from_field.type_name.as_deref().filter(|_| {
+                    from_field.type_name != to_field.type_name
+                        && self.map_entry(from_field).is_none()
+                });
+                (from_field, to_field, converted)
+            })
+            .collect()
+    }
+
+    /// Whether `prost-build` boxes `field` of the message `message`, which it does for singular
+    /// message fields whose type contains the message (through singular message fields), so
+    /// that recursive messages have a size.
+    fn is_boxed(&self, message: &str, field: &FieldDescriptorProto) -> bool {
+        if field.label() == Label::Repeated || field.r#type() != Type::Message {
+            return false;
+        }
+        let mut seen = HashSet::new();
+        let mut stack = vec![field.type_name()];
+        while let Some(name) = stack.pop() {
+            if name == message {
+                return true;
+            }
+            if !seen.insert(name) {
+                continue;
+            }
+            if let Some(TypeRef {
+                definition: Definition::Message(nested),
+                ..
+            }) = self.types.get(name)
+            {
+                stack.extend(
+                    nested
+                        .field
+                        .iter()
+                        .filter(|f| f.label() != Label::Repeated && f.r#type() == Type::Message)
+                        .map(|f| f.type_name()),
+                );
+            }
+        }
+        false
+    }
+
+    /// Whether converting the type `from` can fail: converting enums checks the value, and
+    /// converting a message can fail if converting one of its fields can.
+    fn is_fallible(&self, from: &str, seen: &mut HashSet<String>) -> bool {
+        if !seen.insert(from.to_string()) {
+            return false;
+        }
+        match self.types[from].definition {
+            Definition::Enum(_) => true,
+            Definition::Message(_) => self
+                .fields(from, &self.pairs[from])
+                .into_iter()
+                .filter_map(|(_, _, converted)| converted)
+                .any(|converted| self.is_fallible(converted, seen)),
+        }
+    }
+
+    /// Write the conversions to the types declared in `file`.
+    pub(crate) fn generate(&self, file: &str, buf: &mut String) {
+        let mut pairs: Vec<_> = self
+            .pairs
+            .iter()
+            .filter(|(_, to)| self.types[*to].file == file)
+            .collect();
+        pairs.sort();
+        for (from, to) in pairs {
+            let package = self.types[to].package;
+            let from_type = self.rust_type(package, from);
+            let to_type = self.rust_type(package, to);
+            match self.types[from].definition {
+                Definition::Enum(_) => {
+                    writeln!(buf, "impl TryFrom<{from_type}> for {to_type} {{").unwrap();
+                    writeln!(buf, "    type Error = ::prost::UnknownEnumValue;").unwrap();
+                    writeln!(
+                        buf,
+                        "    fn try_from(value: {from_type}) -> Result<Self, Self::Error> {{"
+                    )
+                    .unwrap();
+                    writeln!(buf, "        Self::try_from(value as i32)").unwrap();
+                    writeln!(buf, "    }}").unwrap();
+                    writeln!(buf, "}}").unwrap();
+                }
+                Definition::Message(_) if self.fallible[from] => {
+                    writeln!(buf, "impl TryFrom<{from_type}> for {to_type} {{").unwrap();
+                    writeln!(buf, "    type Error = ::prost::UnknownEnumValue;").unwrap();
+                    writeln!(
+                        buf,
+                        "    fn try_from(value: {from_type}) -> Result<Self, Self::Error> {{"
+                    )
+                    .unwrap();
+                    writeln!(buf, "        Ok(Self {{").unwrap();
+                    self.write_fields(package, from, to, buf);
+                    writeln!(buf, "        }})").unwrap();
+                    writeln!(buf, "    }}").unwrap();
+                    writeln!(buf, "}}").unwrap();
+                }
+                Definition::Message(_) => {
+                    writeln!(buf, "impl From<{from_type}> for {to_type} {{").unwrap();
+                    writeln!(buf, "    fn from(value: {from_type}) -> Self {{").unwrap();
+                    writeln!(buf, "        Self {{").unwrap();
+                    self.write_fields(package, from, to, buf);
+                    writeln!(buf, "        }}").unwrap();
+                    writeln!(buf, "    }}").unwrap();
+                    writeln!(buf, "}}").unwrap();
+                }
+            }
+        }
+    }
+
+    fn write_fields(&self, package: &str, from: &str, to: &str, buf: &mut String) {
+        for (from_field, field, converted) in self.fields(from, to) {
+            let ident = rust_field_ident(field.name());
+            let value = format!("value.{ident}");
+            // Recursive message fields are `Option<Box<_>>`s.
+            let (from_boxed, to_boxed) =
+                (self.is_boxed(from, from_field), self.is_boxed(to, field));
+            let expr = match converted {
+                None => match (from_boxed, to_boxed) {
+                    (true, false) => format!("{value}.map(|v| *v)"),
+                    (false, true) => format!("{value}.map(::prost::alloc::boxed::Box::new)"),
+                    _ => value,
+                },
+                Some(from) => {
+                    let repeated = field.label() == Label::Repeated;
+                    let optional = field.proto3_optional() || field.r#type() == Type::Message;
+                    let fallible = self.fallible[from];
+                    let arg = if from_boxed { "(*v)" } else { "v" };
+                    let convert = match self.types[from].definition {
+                        // Enum fields are `i32`s.
+                        Definition::Enum(_) if !repeated && !optional => {
+                            let to = self.rust_type(package, &self.pairs[from]);
+                            writeln!(buf, "            {ident}: {to}::try_from({value})?.into(),")
+                                .unwrap();
+                            continue;
+                        }
+                        Definition::Enum(_) => format!(
+                            "|v| {}::try_from(v).map(i32::from)",
+                            self.rust_type(package, &self.pairs[from])
+                        ),
+                        Definition::Message(_) if fallible && to_boxed => {
+                            format!("|v| {arg}.try_into().map(::prost::alloc::boxed::Box::new)")
+                        }
+                        Definition::Message(_) if to_boxed => {
+                            format!("|v| ::prost::alloc::boxed::Box::new({arg}.into())")
+                        }
+                        Definition::Message(_) if from_boxed && fallible => {
+                            "|v| (*v).try_into()".to_string()
+                        }
+                        Definition::Message(_) if from_boxed => "|v| (*v).into()".to_string(),
+                        Definition::Message(_) if fallible => "TryInto::try_into".to_string(),
+                        Definition::Message(_) => "Into::into".to_string(),
+                    };
+                    match (repeated, fallible) {
+                        (true, false) => format!("{value}.into_iter().map({convert}).collect()"),
+                        (true, true) => {
+                            format!("{value}.into_iter().map({convert}).collect::<Result<_, _>>()?")
+                        }
+                        (false, false) => format!("{value}.map({convert})"),
+                        (false, true) => format!("{value}.map({convert}).transpose()?"),
+                    }
+                }
+            };
+            writeln!(buf, "            {ident}: {expr},").unwrap();
+        }
+    }
+
+    /// The path of the type `name` in the module generated for `package`, e.g.
+    /// `super::v1::Hat`, the way `prost-build` refers to types of other packages.
+    fn rust_type(&self, package: &str, name: &str) -> String {
+        let type_package = self.types[name].package;
+        let local: Vec<&str> = package.split('.').filter(|s| !s.is_empty()).collect();
+        let target: Vec<&str> = type_package.split('.').filter(|s| !s.is_empty()).collect();
+        let common = local
+            .iter()
+            .zip(&target)
+            .take_while(|(a, b)| a == b)
+            .count();
+        let mut path: Vec<String> = vec!["super".to_string(); local.len() - common];
+        path.extend(target[common..].iter().map(|s| s.to_snake_case()));
+        let relative = match type_package {
+            "" => name.trim_start_matches('.'),
+            _ => &name[type_package.len() + 2..],
+        };
+        let mut segments: Vec<&str> = relative.split('.').collect();
+        let last = segments.pop().expect("type names aren't empty");
+        path.extend(segments.iter().map(|s| s.to_snake_case()));
+        path.push(last.to_upper_camel_case());
+        path.join("::")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost_types::field_descriptor_proto::Type;
+    use prost_types::{DescriptorProto, EnumDescriptorProto, FileDescriptorSet};
+
+    use super::Conversions;
+    use crate::test::*;
+
+    /// A `Hat` in `package`, which may contain another hat, and its `Part`s and `Color`.
+    fn hat(package: &str) -> DescriptorProto {
+        let name = |name: &str| format!(".{package}.{name}");
+        message(
+            "Hat",
+            vec![
+                field("name", 1, Type::String),
+                typed_field("color", 2, Type::Enum, &name("Color")),
+                repeated(typed_field("parts", 3, Type::Message, &name("Part"))),
+                typed_field("inner", 4, Type::Message, &name("Hat")),
+            ],
+        )
+    }
+
+    fn part() -> DescriptorProto {
+        message("Part", vec![field("name", 1, Type::String)])
+    }
+
+    fn color(values: &[(&str, i32)]) -> EnumDescriptorProto {
+        enumeration("Color", values)
+    }
+
+    const COLORS: &[(&str, i32)] = &[("COLOR_RED", 0), ("COLOR_BLUE", 1)];
+
+    fn fds(storefront: Vec<DescriptorProto>, colors: &[(&str, i32)]) -> FileDescriptorSet {
+        FileDescriptorSet {
+            file: vec![
+                file(
+                    "inventory.v1",
+                    vec![hat("inventory.v1"), part()],
+                    vec![color(COLORS)],
+                ),
+                file("storefront.v1", storefront, vec![color(colors)]),
+            ],
+        }
+    }
+
+    fn generate(fds: &FileDescriptorSet, types: &[&str]) -> String {
+        let pairs: Vec<_> = types
+            .iter()
+            .map(|t| (format!(".inventory.v1.{t}"), format!(".storefront.v1.{t}")))
+            .collect();
+        let mut buf = String::new();
+        Conversions::new(fds, &pairs).generate("storefront/v1.proto", &mut buf);
+        buf
+    }
+
+    #[test]
+    fn test_conversions() {
+        // The storefront has a color more, and enums prost strips of the same prefix match.
+        let colors = [("RED", 0), ("BLUE", 1), ("GREEN", 2)];
+        let fds = fds(vec![hat("storefront.v1"), part()], &colors);
+        let code = generate(&fds, &["Hat", "Part", "Color"]);
+
+        assert!(code.contains(
+            "impl TryFrom<super::super::inventory::v1::Color> for Color {\n    \
+             type Error = ::prost::UnknownEnumValue;"
+        ));
+        assert!(code.contains("impl From<super::super::inventory::v1::Part> for Part {"));
+        // Hats have an enum field, which can fail to convert.
+        assert!(code.contains(
+            "impl TryFrom<super::super::inventory::v1::Hat> for Hat {\n    \
+             type Error = ::prost::UnknownEnumValue;"
+        ));
+        for field in [
+            "name: value.name,",
+            "color: Color::try_from(value.color)?.into(),",
+            "parts: value.parts.into_iter().map(Into::into).collect(),",
+            // Recursive fields are boxed.
+            "inner: value.inner.map(|v| (*v).try_into().map(::prost::alloc::boxed::Box::new))\
+             .transpose()?,",
+        ] {
+            assert!(code.contains(field), "no {field} in:\n{code}");
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "there is no type .storefront.v1.Hat")]
+    fn test_missing_type() {
+        generate(&fds(vec![part()], COLORS), &["Hat"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "both must be messages, or enums")]
+    fn test_message_to_enum() {
+        let fds = fds(vec![hat("storefront.v1"), part()], COLORS);
+        let pairs = [(
+            ".inventory.v1.Part".to_string(),
+            ".storefront.v1.Color".to_string(),
+        )];
+        Conversions::new(&fds, &pairs);
+    }
+
+    #[test]
+    #[should_panic(expected = "the messages have different fields")]
+    fn test_different_fields() {
+        let mut hat = hat("storefront.v1");
+        hat.field.pop();
+        generate(&fds(vec![hat, part()], COLORS), &["Hat", "Part", "Color"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "field `name` has a different type")]
+    fn test_different_field_type() {
+        let part = message("Part", vec![field("name", 1, Type::Bytes)]);
+        generate(&fds(vec![hat("storefront.v1"), part], COLORS), &["Part"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "field `parts` has a different type")]
+    fn test_unconverted_field_type() {
+        // Parts of different packages only match if they convert.
+        generate(
+            &fds(vec![hat("storefront.v1"), part()], COLORS),
+            &["Hat", "Color"],
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "oneof fields (like `name`) are not supported")]
+    fn test_oneof() {
+        let part = |package: &str| {
+            let mut part = part();
+            part.field[0].oneof_index = Some(0);
+            let part = with_oneof(part, "label");
+            file(package, vec![part], vec![])
+        };
+        let fds = FileDescriptorSet {
+            file: vec![part("inventory.v1"), part("storefront.v1")],
+        };
+        generate(&fds, &["Part"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "value COLOR_BLUE = 1 has a different name or number")]
+    fn test_enum_value_names() {
+        let colors = [("COLOR_RED", 0), ("COLOR_GREEN", 1)];
+        generate(&fds(vec![part()], &colors), &["Color"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "value COLOR_BLUE = 1 has a different name or number")]
+    fn test_enum_value_numbers() {
+        let colors = [("COLOR_RED", 0), ("COLOR_BLUE", 2)];
+        generate(&fds(vec![part()], &colors), &["Color"]);
+    }
+}
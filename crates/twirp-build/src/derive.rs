@@ -0,0 +1,247 @@
+//! Extra `#[derive(...)]` attributes on generated messages.
+
+use std::collections::{HashMap, HashSet};
+
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::{DescriptorProto, FieldDescriptorProto, FileDescriptorSet};
+
+/// Derive `traits` on every message matching `path`, and on the enums of its oneofs, leaving out
+/// the traits a message can't implement.
+pub(crate) fn derive(
+    config: &mut prost_build::Config,
+    fds: &FileDescriptorSet,
+    path: &str,
+    traits: &[&str],
+) {
+    let messages = messages(fds);
+    let mut names: Vec<&String> = messages.keys().filter(|n| matches(path, n)).collect();
+    names.sort();
+    for name in names {
+        let message = messages[name];
+        let fields: Vec<&FieldDescriptorProto> = message.field.iter().collect();
+        let derived = supported(&messages, path, &fields, traits);
+        if !derived.is_empty() {
+            config.message_attribute(name, format!("#[derive({})]", derived.join(", ")));
+        }
+        // The fields of a oneof are the variants of an enum of their own, which needs the traits
+        // too.
+        for (index, oneof) in message.oneof_decl.iter().enumerate() {
+            let fields: Vec<&FieldDescriptorProto> = message
+                .field
+                .iter()
+                .filter(|f| f.oneof_index == Some(index as i32) && !f.proto3_optional())
+                .collect();
+            // The oneofs of proto3 `optional` fields aren't generated.
+            if fields.is_empty() {
+                continue;
+            }
+            let derived = supported(&messages, path, &fields, traits);
+            if !derived.is_empty() {
+                config.enum_attribute(
+                    format!("{name}.{}", oneof.name()),
+                    format!("#[derive({})]", derived.join(", ")),
+                );
+            }
+        }
+    }
+}
+
+/// The `traits` a type with `fields` can derive. `Eq` needs every field to implement it, and
+/// `Hash` too: `f32`/`f64` are only `PartialEq`, `HashMap` isn't `Hash`, and messages that don't
+/// match `path` get neither.
+fn supported<'t>(
+    messages: &HashMap<String, &DescriptorProto>,
+    path: &str,
+    fields: &[&FieldDescriptorProto],
+    traits: &[&'t str],
+) -> Vec<&'t str> {
+    let is_map_entry = |type_name: &str| {
+        messages.get(type_name).map_or(false, |m| {
+            m.options.as_ref().map_or(false, |o| o.map_entry())
+        })
+    };
+    let has_float = reaches(messages, fields, &|f| {
+        matches!(f.r#type(), Type::Float | Type::Double)
+    });
+    let has_map = reaches(messages, fields, &|f| {
+        f.label() == Label::Repeated && is_map_entry(f.type_name())
+    });
+    let has_underived = reaches(messages, fields, &|f| {
+        f.r#type() == Type::Message
+            && messages.contains_key(f.type_name())
+            && !is_map_entry(f.type_name())
+            && !matches(path, f.type_name())
+    });
+    traits
+        .iter()
+        .copied()
+        .filter(|t| match *t {
+            "Eq" => !has_float && !has_underived,
+            "Hash" => !has_float && !has_map && !has_underived,
+            _ => true,
+        })
+        .collect()
+}
+
+/// All messages in the descriptor set (including nested ones) by fully-qualified name, e.g.
+/// `.service.haberdash.v1.MakeHatRequest`.
+fn messages(fds: &FileDescriptorSet) -> HashMap<String, &DescriptorProto> {
+    fn add<'a>(
+        prefix: &str,
+        messages: &'a [DescriptorProto],
+        out: &mut HashMap<String, &'a DescriptorProto>,
+    ) {
+        for m in messages {
+            let name = format!("{prefix}.{}", m.name());
+            add(&name, &m.nested_type, out);
+            out.insert(name, m);
+        }
+    }
+    let mut out = HashMap::new();
+    for file in &fds.file {
+        let prefix = match file.package() {
+            "" => String::new(),
+            package => format!(".{package}"),
+        };
+        add(&prefix, &file.message_type, &mut out);
+    }
+    out
+}
+
+/// Matches a message name against a `prost_build` style path: `.` matches everything, otherwise
+/// the path must name the message or one of its enclosing packages/messages.
+fn matches(path: &str, name: &str) -> bool {
+    let path = path.trim_end_matches('.');
+    path.is_empty()
+        || name == path
+        || name
+            .strip_prefix(path)
+            .map_or(false, |rest| rest.starts_with('.'))
+}
+
+/// Whether `pred` holds for any of `fields`, or any field of the messages they refer to.
+fn reaches(
+    messages: &HashMap<String, &DescriptorProto>,
+    fields: &[&FieldDescriptorProto],
+    pred: &dyn Fn(&FieldDescriptorProto) -> bool,
+) -> bool {
+    let mut seen = HashSet::new();
+    let mut stack = fields.to_vec();
+    while let Some(field) = stack.pop() {
+        if pred(field) {
+            return true;
+        }
+        let Some(type_name) = &field.type_name else {
+            continue;
+        };
+        if !seen.insert(type_name) {
+            continue;
+        }
+        if let Some(message) = messages.get(type_name) {
+            stack.extend(&message.field);
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use prost_types::field_descriptor_proto::Type;
+    use prost_types::FileDescriptorSet;
+
+    use crate::test::*;
+
+    /// A `Tag` with a oneof of a string or number, a `Hat` with a oneof holding a `double`, and a
+    /// `Crate` of either.
+    fn fds() -> FileDescriptorSet {
+        let tag = with_oneof(
+            message(
+                "Tag",
+                vec![
+                    in_oneof(field("text", 1, Type::String), 0),
+                    in_oneof(field("number", 2, Type::Int64), 0),
+                ],
+            ),
+            "value",
+        );
+        let hat = with_oneof(
+            message(
+                "Hat",
+                vec![
+                    field("name", 1, Type::String),
+                    in_oneof(field("inches", 2, Type::Int32), 0),
+                    in_oneof(field("ratio", 3, Type::Double), 0),
+                ],
+            ),
+            "size",
+        );
+        let krate = with_oneof(
+            message(
+                "Crate",
+                vec![
+                    field("label", 1, Type::String),
+                    in_oneof(typed_field("tag", 2, Type::Message, ".shop.Tag"), 0),
+                    in_oneof(typed_field("hat", 3, Type::Message, ".shop.Hat"), 0),
+                ],
+            ),
+            "item",
+        );
+        let shelf = with_oneof(
+            message(
+                "Shelf",
+                vec![in_oneof(
+                    typed_field("tag", 1, Type::Message, ".shop.Tag"),
+                    0,
+                )],
+            ),
+            "front",
+        );
+        FileDescriptorSet {
+            file: vec![file("shop", vec![tag, hat, krate, shelf], vec![])],
+        }
+    }
+
+    fn derived<'a>(code: &'a str, item: &str) -> Vec<&'a str> {
+        attributes(code, item)
+            .into_iter()
+            .filter(|a| !a.contains("prost::"))
+            .collect()
+    }
+
+    #[test]
+    fn test_derive_oneofs() {
+        let fds = fds();
+        let mut config = prost_build::Config::new();
+        super::derive(&mut config, &fds, ".", &["Eq", "Hash"]);
+        let code = &generate(config, &fds)["shop"];
+
+        assert_eq!(derived(code, "pub struct Tag"), ["#[derive(Eq, Hash)]"]);
+        assert_eq!(derived(code, "pub enum Value"), ["#[derive(Eq, Hash)]"]);
+        // The double is in the oneof, and so in the message holding it.
+        assert!(derived(code, "pub struct Hat").is_empty());
+        assert!(derived(code, "pub enum Size").is_empty());
+        // And in the messages and oneofs containing that.
+        assert!(derived(code, "pub struct Crate").is_empty());
+        assert!(derived(code, "pub enum Item").is_empty());
+        assert_eq!(derived(code, "pub struct Shelf"), ["#[derive(Eq, Hash)]"]);
+        assert_eq!(derived(code, "pub enum Front"), ["#[derive(Eq, Hash)]"]);
+    }
+
+    #[test]
+    fn test_derive_path() {
+        let fds = fds();
+        let mut config = prost_build::Config::new();
+        super::derive(
+            &mut config,
+            &fds,
+            ".shop.Shelf",
+            &["Eq", "Hash", "PartialOrd"],
+        );
+        let code = &generate(config, &fds)["shop"];
+
+        // `Tag` doesn't match the path, so can't be part of an `Eq` message.
+        assert!(derived(code, "pub struct Tag").is_empty());
+        assert_eq!(derived(code, "pub struct Shelf"), ["#[derive(PartialOrd)]"]);
+        assert_eq!(derived(code, "pub enum Front"), ["#[derive(PartialOrd)]"]);
+    }
+}
@@ -0,0 +1,80 @@
+//! Code generation for constants naming error metadata keys.
+//!
+//! Keys are declared with an `@error_meta:` annotation in the comments of a service or method:
+//!
+//! ```proto
+//! service HaberdasherAPI {
+//!   // @error_meta: argument, retry_after
+//!   rpc MakeHat(MakeHatRequest) returns (MakeHatResponse);
+//! }
+//! ```
+
+use std::fmt::Write;
+
+use heck::ToShoutySnakeCase;
+
+const ANNOTATION: &str = "@error_meta:";
+
+/// The error metadata keys declared in `comments`.
+pub(crate) fn keys(comments: &prost_build::Comments) -> Vec<String> {
+    comments
+        .leading
+        .iter()
+        .chain(&comments.trailing)
+        .flat_map(|comment| comment.lines())
+        .filter_map(|line| line.trim().strip_prefix(ANNOTATION))
+        .flat_map(|keys| keys.split(','))
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Writes the constant for `key`, e.g. `pub const ERR_META_ARGUMENT: &str = "argument";`, or
+/// returns an error if `key` has no characters usable in a constant name (e.g. `--`).
+pub(crate) fn generate(key: &str, buf: &mut String) -> Result<(), String> {
+    let name = key.to_shouty_snake_case();
+    if name.is_empty() {
+        return Err(format!(
+            "error metadata key `{key}` has no characters usable in a constant name"
+        ));
+    }
+    writeln!(buf, "/// The `{key}` error metadata key.").unwrap();
+    writeln!(buf, "pub const ERR_META_{name}: &str = {key:?};").unwrap();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys() {
+        let comments = prost_build::Comments {
+            leading: vec![" Makes a hat.\n @error_meta: argument, retry-after,\n".to_string()],
+            trailing: vec![" @error_meta: size".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(keys(&comments), ["argument", "retry-after", "size"]);
+    }
+
+    #[test]
+    fn test_generate() {
+        let mut buf = String::new();
+        generate("retry-after", &mut buf).unwrap();
+        assert_eq!(
+            buf,
+            "/// The `retry-after` error metadata key.\n\
+             pub const ERR_META_RETRY_AFTER: &str = \"retry-after\";\n"
+        );
+
+        for key in ["", "--", "?!"] {
+            let mut buf = String::new();
+            assert_eq!(
+                generate(key, &mut buf).unwrap_err(),
+                format!("error metadata key `{key}` has no characters usable in a constant name")
+            );
+            assert!(buf.is_empty());
+        }
+    }
+}
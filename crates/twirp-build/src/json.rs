@@ -0,0 +1,288 @@
+//! Field attributes making the serde representation of generated messages follow the protobuf
+//! JSON mapping.
+//!
+//! Fields can be given a JSON key other than their name with a `@json_name:` annotation in their
+//! comments, for peers that expect one:
+//!
+//! ```proto
+//! message Hat {
+//!   // @json_name: colour
+//!   string color = 1;
+//! }
+//! ```
+//!
+//! Fields also accept their lowerCamelCase names, and annotated fields their proto names, when
+//! deserialized.
+
+use std::collections::HashMap;
+
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::{DescriptorProto, FieldDescriptorProto, FileDescriptorProto, FileDescriptorSet};
+
+const ANNOTATION: &str = "@json_name:";
+
+// Field numbers from descriptor.proto, used to build source code info paths.
+const FILE_MESSAGE_TYPE: i32 = 4;
+const MESSAGE_FIELD: i32 = 2;
+const MESSAGE_NESTED_TYPE: i32 = 3;
+
+pub(crate) fn configure(config: &mut prost_build::Config, fds: &FileDescriptorSet) {
+    let json_names = json_names(fds);
+    for file in &fds.file {
+        let prefix = match file.package() {
+            "" => String::new(),
+            package => format!(".{package}"),
+        };
+        for message in &file.message_type {
+            configure_message(config, &prefix, message, &json_names);
+        }
+    }
+}
+
+/// The JSON keys declared with `@json_name:` annotations, by fully qualified message name (e.g.
+/// `.service.haberdash.v1.Hat`) and field name.
+pub(crate) fn json_names(fds: &FileDescriptorSet) -> HashMap<(String, String), String> {
+    fn add(
+        file: &FileDescriptorProto,
+        prefix: &str,
+        message: &DescriptorProto,
+        path: &[i32],
+        out: &mut HashMap<(String, String), String>,
+    ) {
+        let name = format!("{prefix}.{}", message.name());
+        for (idx, nested) in message.nested_type.iter().enumerate() {
+            let path = [path, &[MESSAGE_NESTED_TYPE, idx as i32]].concat();
+            add(file, &name, nested, &path, out);
+        }
+        for (idx, field) in message.field.iter().enumerate() {
+            let path = [path, &[MESSAGE_FIELD, idx as i32]].concat();
+            if let Some(json_name) = annotation(file, &path) {
+                out.insert((name.clone(), field.name().to_string()), json_name);
+            }
+        }
+    }
+
+    let mut out = HashMap::new();
+    for file in &fds.file {
+        let prefix = match file.package() {
+            "" => String::new(),
+            package => format!(".{package}"),
+        };
+        for (idx, message) in file.message_type.iter().enumerate() {
+            add(
+                file,
+                &prefix,
+                message,
+                &[FILE_MESSAGE_TYPE, idx as i32],
+                &mut out,
+            );
+        }
+    }
+    out
+}
+
+/// The JSON key declared in the comments at `path`, if any.
+fn annotation(file: &FileDescriptorProto, path: &[i32]) -> Option<String> {
+    let location = file
+        .source_code_info
+        .as_ref()?
+        .location
+        .iter()
+        .find(|l| l.path == path)?;
+    let json_name = location
+        .leading_comments()
+        .lines()
+        .chain(location.trailing_comments().lines())
+        .find_map(|line| line.trim().strip_prefix(ANNOTATION))?
+        .trim();
+    if json_name.is_empty() {
+        panic!("`{ANNOTATION}` needs a JSON key");
+    }
+    Some(json_name.to_string())
+}
+
+/// The lowerCamelCase name the protobuf JSON mapping gives `field`, which `protoc` fills in as its
+/// `json_name`; derived from its name the same way if that isn't set.
+fn camel_case_name(field: &FieldDescriptorProto) -> String {
+    if let Some(json_name) = &field.json_name {
+        return json_name.clone();
+    }
+    let mut out = String::with_capacity(field.name().len());
+    let mut capitalize = false;
+    for c in field.name().chars() {
+        if c == '_' {
+            capitalize = true;
+        } else if capitalize {
+            out.push(c.to_ascii_uppercase());
+            capitalize = false;
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn configure_message(
+    config: &mut prost_build::Config,
+    prefix: &str,
+    message: &DescriptorProto,
+    json_names: &HashMap<(String, String), String>,
+) {
+    let name = format!("{prefix}.{}", message.name());
+    for nested in &message.nested_type {
+        // Map entries aren't generated as structs.
+        if !nested.options.as_ref().map_or(false, |o| o.map_entry()) {
+            configure_message(config, &name, nested, json_names);
+        }
+    }
+    for field in &message.field {
+        // prost-build generates the fields of a oneof as the variants of an enum, whose
+        // attributes it looks up at the path of the oneof.
+        let path = match field.oneof_index {
+            Some(idx) if !field.proto3_optional() => {
+                let oneof = message.oneof_decl[idx as usize].name();
+                format!("{name}.{oneof}.{}", field.name())
+            }
+            _ => format!("{name}.{}", field.name()),
+        };
+        let json_name = json_names.get(&(name.clone(), field.name().to_string()));
+        if let Some(json_name) = json_name {
+            config.field_attribute(&path, format!("#[serde(rename = {json_name:?})]"));
+        }
+        // The mapping has parsers accept both the proto name and the lowerCamelCase name.
+        let key = json_name.map_or(field.name(), String::as_str);
+        let camel_name = camel_case_name(field);
+        let mut aliases = vec![field.name(), camel_name.as_str()];
+        aliases.dedup();
+        for alias in aliases.into_iter().filter(|&alias| alias != key) {
+            config.field_attribute(&path, format!("#[serde(alias = {alias:?})]"));
+        }
+        let module = match field.r#type() {
+            Type::Bytes => "bytes",
+            Type::Float | Type::Double => "float",
+            Type::Int64 | Type::Uint64 | Type::Sint64 | Type::Fixed64 | Type::Sfixed64 => "int64",
+            _ => continue,
+        };
+        let with = if field.label() == Label::Repeated {
+            format!("repeated_{module}")
+        } else if field.proto3_optional() {
+            format!("option_{module}")
+        } else {
+            module.to_string()
+        };
+        config.field_attribute(
+            &path,
+            format!("#[serde(with = \"::twirp::details::json::{with}\")]"),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost_types::field_descriptor_proto::Type;
+    use prost_types::FileDescriptorSet;
+
+    use crate::test::*;
+
+    #[test]
+    fn test_json_names() {
+        let hat = with_oneof(
+            message(
+                "Hat",
+                vec![
+                    field("color", 1, Type::String),
+                    in_oneof(field("inches", 2, Type::Int32), 0),
+                    in_oneof(field("centimeters", 3, Type::Int64), 0),
+                    field("weight", 4, Type::Int64),
+                ],
+            ),
+            "size",
+        );
+        let file = file("hats.v1", vec![hat], vec![]);
+        let file = with_comment(file, &[4, 0, 2, 0], " @json_name: colour\n");
+        let file = with_comment(file, &[4, 0, 2, 1], " @json_name: in\n");
+        let fds = FileDescriptorSet { file: vec![file] };
+
+        let mut config = prost_build::Config::new();
+        crate::protobuf_json(&mut config, &fds);
+        let code = &generate(config, &fds)["hats.v1"];
+
+        assert!(attributes(code, "pub color:").contains(&r#"#[serde(rename = "colour")]"#));
+        assert!(attributes(code, "pub weight:")
+            .contains(&r#"#[serde(with = "::twirp::details::json::int64")]"#));
+        // The fields of oneofs are the variants of an enum.
+        assert!(attributes(code, "Inches(").contains(&r#"#[serde(rename = "in")]"#));
+        assert!(attributes(code, "Centimeters(")
+            .contains(&r#"#[serde(with = "::twirp::details::json::int64")]"#));
+        assert!(!attributes(code, "Centimeters(")
+            .iter()
+            .any(|a| a.contains("rename")));
+    }
+
+    /// What `prost-build` generates for the `Hat` of `test_aliases`, as far as serde is concerned.
+    #[derive(Debug, PartialEq, serde::Deserialize)]
+    struct Hat {
+        #[serde(alias = "hatSize")]
+        #[serde(with = "::twirp::details::json::int64")]
+        hat_size: i64,
+        #[serde(rename = "brim")]
+        #[serde(alias = "brim_width")]
+        #[serde(alias = "brimWidth")]
+        brim_width: i32,
+    }
+
+    #[test]
+    fn test_aliases() {
+        let hat = message(
+            "Hat",
+            vec![
+                field("hat_size", 1, Type::Int64),
+                field("brim_width", 2, Type::Int32),
+            ],
+        );
+        let file = file("hats.v1", vec![hat], vec![]);
+        let file = with_comment(file, &[4, 0, 2, 1], " @json_name: brim\n");
+        let fds = FileDescriptorSet { file: vec![file] };
+
+        let mut config = prost_build::Config::new();
+        crate::protobuf_json(&mut config, &fds);
+        let code = &generate(config, &fds)["hats.v1"];
+        let serde_attributes = |item| -> Vec<&str> {
+            attributes(code, item)
+                .into_iter()
+                .filter(|a| a.starts_with("#[serde("))
+                .collect()
+        };
+        assert_eq!(
+            serde_attributes("pub hat_size:"),
+            [
+                r#"#[serde(alias = "hatSize")]"#,
+                r#"#[serde(with = "::twirp::details::json::int64")]"#,
+            ]
+        );
+        assert_eq!(
+            serde_attributes("pub brim_width:"),
+            [
+                r#"#[serde(rename = "brim")]"#,
+                r#"#[serde(alias = "brim_width")]"#,
+                r#"#[serde(alias = "brimWidth")]"#,
+            ]
+        );
+
+        let expected = Hat {
+            hat_size: 7,
+            brim_width: 2,
+        };
+        for json in [
+            r#"{"hatSize": "7", "brimWidth": 2}"#,
+            r#"{"hat_size": "7", "brim_width": 2}"#,
+            r#"{"hatSize": "7", "brim": 2}"#,
+        ] {
+            assert_eq!(
+                serde_json::from_str::<Hat>(json).unwrap(),
+                expected,
+                "{json}"
+            );
+        }
+    }
+}
@@ -1,4 +1,17 @@
+use std::collections::HashSet;
 use std::fmt::Write;
+use std::path::PathBuf;
+
+use prost_types::FileDescriptorSet;
+
+mod convert;
+mod derive;
+mod error_meta;
+mod json;
+mod openapi;
+#[cfg(test)]
+mod test;
+mod validate;
 
 /// Generates twirp services for protobuf rpc service definitions.
 ///
@@ -8,27 +21,298 @@ use std::fmt::Write;
 /// Add a call to `.service_generator(twirp_build::service_generator())` in
 /// main() of `build.rs`.
 pub fn service_generator() -> Box<ServiceGenerator> {
-    Box::new(ServiceGenerator {})
+    Box::new(ServiceGenerator::new())
+}
+
+/// Derive additional traits on the messages generated by `config`, e.g. `Eq` and `Hash` for
+/// messages used as map keys.
+///
+/// `path` selects messages the same way as `prost_build::Config::message_attribute`: `"."` for all
+/// messages, a package like `".service.haberdash.v1"`, or a single message like
+/// `".service.haberdash.v1.MakeHatRequest"`. Call this once per set of traits; `fds` are the
+/// descriptors returned by `prost_build::Config::load_fds`.
+///
+/// The traits are also derived on the enums generated for the messages' oneofs. `Eq` is left off
+/// messages and oneofs that (directly or through their message fields) contain `float` or `double`
+/// fields or messages outside `path`, and `Hash` is also left off those containing maps, since the
+/// generated Rust types can't implement those traits. Other traits are derived as given.
+///
+/// ```no_run
+/// let mut config = prost_build::Config::new();
+/// let fds = config.load_fds(&["service.proto"], &["."]).unwrap();
+/// twirp_build::derive(&mut config, &fds, ".", &["Eq", "Hash"]);
+/// ```
+pub fn derive(
+    config: &mut prost_build::Config,
+    fds: &FileDescriptorSet,
+    path: &str,
+    traits: &[&str],
+) {
+    derive::derive(config, fds, path, traits)
+}
+
+/// Make the JSON encoding of the messages generated by `config` follow the [protobuf JSON
+/// mapping], so JSON requests and responses interoperate with other twirp implementations.
+///
+/// This assumes messages derive `serde::Serialize` and `serde::Deserialize` (e.g. with
+/// `config.type_attribute(".", "#[derive(serde::Serialize, serde::Deserialize)]")`), and adjusts
+/// fields whose default serde representation differs from the mapping:
+///
+/// - `bytes` fields are (de)serialized as base64 strings.
+/// - `float` and `double` fields represent `NaN` and infinities as the strings `"NaN"`,
+///   `"Infinity"` and `"-Infinity"`, which aren't valid JSON numbers.
+/// - 64-bit integer fields are (de)serialized as strings, to avoid losing precision in JSON
+///   parsers that use doubles for all numbers.
+///
+/// Fields keep their proto names as JSON keys, unless given another with a `@json_name:`
+/// annotation in their comments (for peers that don't follow the mapping):
+///
+/// ```proto
+/// message Hat {
+///   // @json_name: colour
+///   string color = 1;
+/// }
+/// ```
+///
+/// As the mapping requires, parsing also accepts the lowerCamelCase names of fields (e.g.
+/// `hatSize` for `hat_size`), and the proto names of fields given another key.
+///
+/// Map values of these types are not supported. The generated attributes refer to helpers in
+/// `twirp::details::json`, which need `twirp`'s `json` feature (on by default).
+///
+/// `fds` are the descriptors returned by `prost_build::Config::load_fds`.
+///
+/// [protobuf JSON mapping]: https://protobuf.dev/programming-guides/proto3/#json
+pub fn protobuf_json(config: &mut prost_build::Config, fds: &FileDescriptorSet) {
+    json::configure(config, fds)
+}
+
+/// A `prost_build::ServiceGenerator` for twirp services.
+///
+/// Use [`service_generator`] for the defaults, or build one with `ServiceGenerator::new()` to
+/// customize the generated code.
+#[derive(Debug, Default)]
+pub struct ServiceGenerator {
+    descriptors: Option<FileDescriptorSet>,
+    validated_services: HashSet<String>,
+    validated_response_services: HashSet<String>,
+    // Proto types of the messages that a `validate()` method has been generated for.
+    validated_messages: HashSet<String>,
+    error_meta_constants: bool,
+    // Packages and keys that an error metadata constant has been generated for.
+    error_meta_keys: HashSet<(String, String)>,
+    openapi_dir: Option<PathBuf>,
+    client_structs: bool,
+    // Fully-qualified source and target types of the conversions to generate.
+    conversions: Vec<(String, String)>,
+    // Files finalized so far, to tell which file of the descriptor set is being generated.
+    finalized_files: usize,
 }
 
-pub struct ServiceGenerator;
+impl ServiceGenerator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Provide the descriptors of the protos being compiled, as returned by
+    /// `prost_build::Config::load_fds`. Features that inspect message definitions (like request
+    /// validation) need these.
+    pub fn file_descriptor_set(mut self, descriptors: FileDescriptorSet) -> Self {
+        self.descriptors = Some(descriptors);
+        self
+    }
+
+    /// Have the generated router for `service` (e.g. `service.haberdash.v1.HaberdasherAPI`) call
+    /// `validate()` on each request before invoking the handler. Requests failing validation get
+    /// an `invalid_argument` error.
+    ///
+    /// A `validate()` method is generated for every request message with fields annotated with
+    /// `@validate:` rules in their comments (this requires
+    /// [`file_descriptor_set`](Self::file_descriptor_set)). The supported rules are `required`,
+    /// `min=N`, `max=N` (numbers), `min_len=N` and `max_len=N` (strings, bytes, and repeated
+    /// fields):
+    ///
+    /// ```proto
+    /// message MakeHatRequest {
+    ///   // @validate: min=1, max=64
+    ///   int32 inches = 1;
+    /// }
+    /// ```
+    pub fn validate_requests(mut self, service: impl Into<String>) -> Self {
+        self.validated_services.insert(service.into());
+        self
+    }
+
+    /// Have the generated client for `service` check each response with its `validate()` method
+    /// (see [`validate_requests`](Self::validate_requests) for the rules), failing with
+    /// `twirp::ClientError::InvalidResponse` if it breaks one. This catches servers sending
+    /// responses without the fields clients rely on. Off by default, as messages can't tell a
+    /// field that wasn't set from one set to its default value.
+    ///
+    /// A `validate()` method is generated for every response message with fields annotated with
+    /// `@validate:` rules (this requires [`file_descriptor_set`](Self::file_descriptor_set)).
+    pub fn validate_responses(mut self, service: impl Into<String>) -> Self {
+        self.validated_response_services.insert(service.into());
+        self
+    }
+
+    /// Generate a constant for every error metadata key declared with an `@error_meta:`
+    /// annotation in the comments of a service or its methods, so servers setting the metadata
+    /// and clients reading it agree on the keys:
+    ///
+    /// ```proto
+    /// service HaberdasherAPI {
+    ///   // @error_meta: argument
+    ///   rpc MakeHat(MakeHatRequest) returns (MakeHatResponse);
+    /// }
+    /// ```
+    ///
+    /// generates `pub const ERR_META_ARGUMENT: &str = "argument";` in the package's module. Off
+    /// by default. A key with no characters usable in a constant name (e.g. `--`) generates a
+    /// `compile_error!` saying so instead.
+    pub fn error_meta_constants(mut self, enabled: bool) -> Self {
+        self.error_meta_constants = enabled;
+        self
+    }
+
+    /// Write an [OpenAPI](https://spec.openapis.org/oas/v3.0.3) spec describing the JSON
+    /// endpoints of every service to `dir`, as `<package>.<Service>.openapi.json` (e.g.
+    /// `service.haberdash.v1.HaberdasherAPI.openapi.json`), for generating clients in other
+    /// languages. This requires [`file_descriptor_set`](Self::file_descriptor_set).
+    ///
+    /// Paths are relative to the twirp prefix the services are served under (e.g.
+    /// `/service.haberdash.v1.HaberdasherAPI/MakeHat`), which belongs in the spec's `servers`. The
+    /// schemas describe the JSON of messages deriving `serde::Serialize` and `serde::Deserialize`
+    /// with [`protobuf_json`] configured; other customizations of their serde representation aren't
+    /// reflected.
+    pub fn openapi(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.openapi_dir = Some(dir.into());
+        self
+    }
+
+    /// Also generate a client struct for every service, e.g. `HaberdasherApiHttpClient`, which
+    /// wraps a `twirp::Client` and has only that service's methods. Unlike the `...Client` traits
+    /// implemented on `twirp::Client`, which put the methods of every service on one type, these
+    /// don't collide when services share method names. The structs implement the traits too. Off
+    /// by default.
+    pub fn client_structs(mut self, enabled: bool) -> Self {
+        self.client_structs = enabled;
+        self
+    }
+
+    /// Generate a conversion from the message `from` to the message `to` (fully-qualified, e.g.
+    /// `inventory.v1.Hat` and `storefront.v1.Hat`), usually of different packages, in the module
+    /// of `to`'s package. This requires [`file_descriptor_set`](Self::file_descriptor_set), and
+    /// both packages must be compiled together.
+    ///
+    /// The messages must have the same fields: fields of the same names, labels and types, except
+    /// that message and enum fields can also have types declared as converting to one another
+    /// with this method. Oneof fields and map values of different types aren't supported. The
+    /// build panics if the messages don't match, so conversions never drop data silently.
+    ///
+    /// The conversion is a `From` impl, or a `TryFrom` impl failing with
+    /// `prost::UnknownEnumValue` if the message has (directly or in its message fields) enum
+    /// fields converted to another enum, whose values are checked. Conversions between enums
+    /// declared with this method are `TryFrom` impls too, matching values by number.
+    ///
+    /// ```no_run
+    /// let generator = twirp_build::ServiceGenerator::new()
+    ///     .convert("inventory.v1.Hat", "storefront.v1.Hat")
+    ///     .convert("inventory.v1.Color", "storefront.v1.Color");
+    /// ```
+    pub fn convert(mut self, from: impl Into<String>, to: impl Into<String>) -> Self {
+        let qualify = |name: String| format!(".{}", name.trim_start_matches('.'));
+        self.conversions
+            .push((qualify(from.into()), qualify(to.into())));
+        self
+    }
+}
 
 impl prost_build::ServiceGenerator for ServiceGenerator {
     fn generate(&mut self, service: prost_build::Service, buf: &mut String) {
+        if let Some(dir) = &self.openapi_dir {
+            let descriptors = self
+                .descriptors
+                .as_ref()
+                .expect("generating OpenAPI specs requires a file_descriptor_set");
+            openapi::generate(&service, descriptors, dir);
+        }
+
         let service_name = service.name;
         let service_fqn = format!("{}.{}", service.package, service.proto_name);
         writeln!(buf).unwrap();
 
+        let validate_requests = self.validated_services.contains(&service_fqn);
+        let validate_responses = self.validated_response_services.contains(&service_fqn);
+
+        // generate `validate()` for request (and checked response) messages that declare rules
+        if let Some(descriptors) = &self.descriptors {
+            let requests = service
+                .methods
+                .iter()
+                .map(|m| (&m.input_type, &m.input_proto_type));
+            let responses = service
+                .methods
+                .iter()
+                .filter(|_| validate_responses)
+                .map(|m| (&m.output_type, &m.output_proto_type));
+            for (rust_type, proto_type) in requests.chain(responses) {
+                if self.validated_messages.contains(proto_type) {
+                    continue;
+                }
+                let Some(msg) = validate::find_message(descriptors, &service.package, proto_type)
+                else {
+                    continue;
+                };
+                if validate::generate(rust_type, &msg, buf) {
+                    self.validated_messages.insert(proto_type.clone());
+                }
+            }
+        }
+
         writeln!(buf, "pub use twirp;").unwrap();
         writeln!(buf).unwrap();
         writeln!(buf, "pub const SERVICE_FQN: &str = \"/{service_fqn}\";").unwrap();
 
+        // generate constants for the declared error metadata keys, once per package
+        if self.error_meta_constants {
+            let comments = std::iter::once(&service.comments)
+                .chain(service.methods.iter().map(|m| &m.comments));
+            for key in comments.flat_map(error_meta::keys) {
+                if self
+                    .error_meta_keys
+                    .insert((service.package.clone(), key.clone()))
+                {
+                    if let Err(msg) = error_meta::generate(&key, buf) {
+                        writeln!(buf, "compile_error!({msg:?});").unwrap();
+                    }
+                }
+            }
+        }
+        for m in &service.methods {
+            writeln!(
+                buf,
+                "/// The path of the `{}` method, relative to the twirp prefix.",
+                m.proto_name
+            )
+            .unwrap();
+            writeln!(
+                buf,
+                "pub const {}: &str = \"{service_fqn}/{}\";",
+                method_path_const(&m.name),
+                m.proto_name
+            )
+            .unwrap();
+        }
+
         //
         // generate the twirp server
         //
+        doc_comments(&service.comments, 0, buf);
         writeln!(buf, "#[twirp::async_trait::async_trait]").unwrap();
         writeln!(buf, "pub trait {} {{", service_name).unwrap();
         for m in &service.methods {
+            doc_comments(&m.comments, 1, buf);
             writeln!(
                 buf,
                 "    async fn {}(&self, ctx: twirp::Context, req: {}) -> Result<{}, twirp::TwirpErrorResponse>;",
@@ -62,25 +346,30 @@ impl prost_build::ServiceGenerator for ServiceGenerator {
 where
     T: {service_name} + Clone + Send + Sync + 'static,
 {{
-    twirp::details::TwirpRouterBuilder::new(api)"#,
+    router_with_headers(api, twirp::axum::http::HeaderMap::new())
+}}
+
+/// Like `router`, adding `headers` to every response of the service (unless a response already
+/// has them), e.g. a header naming the service's version.
+pub fn router_with_headers<T>(api: T, headers: twirp::axum::http::HeaderMap) -> twirp::Router
+where
+    T: {service_name} + Clone + Send + Sync + 'static,
+{{
+    twirp::routes!(api, headers = headers, {{"#,
         )
         .unwrap();
         for m in &service.methods {
-            let uri = &m.proto_name;
-            let req_type = &m.input_type;
-            let rust_method_name = &m.name;
-            writeln!(
-                buf,
-                r#"        .route("/{uri}", |api: T, ctx: twirp::Context, req: {req_type}| async move {{
-            api.{rust_method_name}(ctx, req).await
-        }})"#,
-            )
-            .unwrap();
+            let check =
+                if validate_requests && self.validated_messages.contains(&m.input_proto_type) {
+                    "#[validate] "
+                } else {
+                    ""
+                };
+            writeln!(buf, r#"        {check}"{}" => {},"#, m.proto_name, m.name).unwrap();
         }
         writeln!(
             buf,
-            r#"
-        .build()
+            r#"    }})
 }}"#
         )
         .unwrap();
@@ -89,6 +378,7 @@ where
         // generate the twirp client
         //
         writeln!(buf).unwrap();
+        doc_comments(&service.comments, 0, buf);
         writeln!(buf, "#[twirp::async_trait::async_trait]").unwrap();
         writeln!(
             buf,
@@ -97,6 +387,7 @@ where
         .unwrap();
         for m in &service.methods {
             // Define: <METHOD>
+            doc_comments(&m.comments, 1, buf);
             writeln!(
                 buf,
                 "    async fn {}(&self, req: {}) -> Result<{}, twirp::ClientError>;",
@@ -106,6 +397,20 @@ where
         }
         writeln!(buf, "}}").unwrap();
 
+        // The call making the request for `m` with the `twirp::Client` `client`.
+        let request_call = |client: &str, m: &prost_build::Method| {
+            let request =
+                if validate_responses && self.validated_messages.contains(&m.output_proto_type) {
+                    "request_validated"
+                } else {
+                    "request"
+                };
+            format!(
+                "{client}.{request}({}, req).await",
+                method_path_const(&m.name)
+            )
+        };
+
         // Implement the rpc traits for: `twirp::client::Client`
         writeln!(buf, "#[twirp::async_trait::async_trait]").unwrap();
         writeln!(
@@ -121,14 +426,127 @@ where
                 m.name, m.input_type, m.output_type,
             )
             .unwrap();
+            writeln!(buf, "    {}", request_call("self", m)).unwrap();
+            writeln!(buf, "    }}").unwrap();
+        }
+        writeln!(buf, "}}").unwrap();
+
+        // The trait is object safe, so clients can be swapped for mocks as a
+        // `Box<dyn ...Client>`; these let the boxed (or shared) clients be used wherever a client
+        // is expected too.
+        for pointer in ["Box", "std::sync::Arc"] {
+            writeln!(buf, "#[twirp::async_trait::async_trait]").unwrap();
             writeln!(
                 buf,
-                r#"    self.request("{}/{}", req).await"#,
-                service_fqn, m.proto_name
+                "impl<T> {service_name}Client for {pointer}<T> where T: {service_name}Client + ?Sized {{",
             )
             .unwrap();
+            for m in &service.methods {
+                writeln!(
+                    buf,
+                    "    async fn {}(&self, req: {}) -> Result<{}, twirp::ClientError> {{",
+                    m.name, m.input_type, m.output_type,
+                )
+                .unwrap();
+                writeln!(buf, "        (**self).{}(req).await", m.name).unwrap();
+                writeln!(buf, "    }}").unwrap();
+            }
+            writeln!(buf, "}}").unwrap();
+        }
+
+        // A client for just this service, when asked for
+        if self.client_structs {
+            let client_struct = format!("{service_name}HttpClient");
+            writeln!(
+                buf,
+                "/// A client for the `{service_fqn}` service, making requests with a `twirp::Client`."
+            )
+            .unwrap();
+            writeln!(buf, "#[derive(Clone, Debug)]").unwrap();
+            writeln!(buf, "pub struct {client_struct} {{").unwrap();
+            writeln!(buf, "    client: twirp::client::Client,").unwrap();
+            writeln!(buf, "}}").unwrap();
+            writeln!(buf, "impl {client_struct} {{").unwrap();
+            writeln!(
+                buf,
+                "    pub fn new(client: twirp::client::Client) -> Self {{"
+            )
+            .unwrap();
+            writeln!(buf, "        Self {{ client }}").unwrap();
             writeln!(buf, "    }}").unwrap();
+            writeln!(buf, "    /// The client making the requests.").unwrap();
+            writeln!(buf, "    pub fn client(&self) -> &twirp::client::Client {{").unwrap();
+            writeln!(buf, "        &self.client").unwrap();
+            writeln!(buf, "    }}").unwrap();
+            for m in &service.methods {
+                doc_comments(&m.comments, 1, buf);
+                writeln!(
+                    buf,
+                    "    pub async fn {}(&self, req: {}) -> Result<{}, twirp::ClientError> {{",
+                    m.name, m.input_type, m.output_type,
+                )
+                .unwrap();
+                writeln!(buf, "        {}", request_call("self.client", m)).unwrap();
+                writeln!(buf, "    }}").unwrap();
+            }
+            writeln!(buf, "}}").unwrap();
+            writeln!(
+                buf,
+                "impl From<twirp::client::Client> for {client_struct} {{"
+            )
+            .unwrap();
+            writeln!(buf, "    fn from(client: twirp::client::Client) -> Self {{").unwrap();
+            writeln!(buf, "        Self::new(client)").unwrap();
+            writeln!(buf, "    }}").unwrap();
+            writeln!(buf, "}}").unwrap();
+            writeln!(buf, "#[twirp::async_trait::async_trait]").unwrap();
+            writeln!(buf, "impl {service_name}Client for {client_struct} {{").unwrap();
+            for m in &service.methods {
+                writeln!(
+                    buf,
+                    "    async fn {}(&self, req: {}) -> Result<{}, twirp::ClientError> {{",
+                    m.name, m.input_type, m.output_type,
+                )
+                .unwrap();
+                writeln!(buf, "        {client_struct}::{}(self, req).await", m.name).unwrap();
+                writeln!(buf, "    }}").unwrap();
+            }
+            writeln!(buf, "}}").unwrap();
         }
-        writeln!(buf, "}}").unwrap();
     }
+
+    fn finalize(&mut self, buf: &mut String) {
+        // prost-build generates (and finalizes) the files in the order of the descriptor set.
+        let index = self.finalized_files;
+        self.finalized_files += 1;
+        if self.conversions.is_empty() {
+            return;
+        }
+        let descriptors = self
+            .descriptors
+            .as_ref()
+            .expect("generating conversions requires a file_descriptor_set");
+        if let Some(file) = descriptors.file.get(index) {
+            convert::Conversions::new(descriptors, &self.conversions).generate(file.name(), buf);
+        }
+    }
+}
+
+/// Write the comments on a service or method in the proto file as doc comments (`prost_build`
+/// already does this for messages and their fields). Detached comments, which aren't about the
+/// item, are left out.
+fn doc_comments(comments: &prost_build::Comments, indent_level: u8, buf: &mut String) {
+    let comments = prost_build::Comments {
+        leading_detached: vec![],
+        ..comments.clone()
+    };
+    comments.append_with_indent(indent_level, buf);
+}
+
+/// The name of the constant holding a method's path, e.g. `MAKE_HAT_METHOD` for `make_hat`.
+fn method_path_const(rust_method_name: &str) -> String {
+    format!(
+        "{}_METHOD",
+        rust_method_name.trim_start_matches("r#").to_uppercase()
+    )
 }
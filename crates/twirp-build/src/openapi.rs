@@ -0,0 +1,526 @@
+//! Generation of OpenAPI specs for the JSON encoding of services.
+//!
+//! The schemas describe the JSON that `twirp` servers read and write for messages generated with
+//! `#[derive(serde::Serialize, serde::Deserialize)]` and [`crate::protobuf_json`]: fields keep
+//! their proto names (or the key in their `@json_name:` annotation), 64-bit integers and `bytes`
+//! are strings, enums are their numbers, and a `oneof` is an object with a single key, the name of
+//! the variant that is set.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use heck::ToUpperCamelCase;
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::{DescriptorProto, FieldDescriptorProto, FileDescriptorSet};
+use serde_json::{json, Map, Value};
+
+/// What the schemas are generated from.
+struct Definitions<'a> {
+    messages: HashMap<String, &'a DescriptorProto>,
+    json_names: HashMap<(String, String), String>,
+}
+
+impl Definitions<'_> {
+    /// The JSON key of `field` of the message `proto_type`.
+    fn json_name(&self, proto_type: &str, field: &FieldDescriptorProto) -> Option<&str> {
+        let key = (proto_type.to_string(), field.name().to_string());
+        self.json_names.get(&key).map(String::as_str)
+    }
+}
+
+/// Every message in `fds`, by fully qualified proto type (e.g. `.service.haberdash.v1.Hat`).
+fn messages(fds: &FileDescriptorSet) -> HashMap<String, &DescriptorProto> {
+    fn add<'a>(
+        prefix: &str,
+        message: &'a DescriptorProto,
+        out: &mut HashMap<String, &'a DescriptorProto>,
+    ) {
+        let name = format!("{prefix}.{}", message.name());
+        for nested in &message.nested_type {
+            add(&name, nested, out);
+        }
+        out.insert(name, message);
+    }
+
+    let mut out = HashMap::new();
+    for file in &fds.file {
+        let prefix = match file.package() {
+            "" => String::new(),
+            package => format!(".{package}"),
+        };
+        for message in &file.message_type {
+            add(&prefix, message, &mut out);
+        }
+    }
+    out
+}
+
+/// Writes the OpenAPI spec for `service` to `dir`, as `<package>.<Service>.openapi.json`.
+pub(crate) fn generate(service: &prost_build::Service, fds: &FileDescriptorSet, dir: &Path) {
+    let spec = spec(service, fds);
+    std::fs::create_dir_all(dir).expect("failed to create the OpenAPI output directory");
+    let path = dir.join(format!(
+        "{}.{}.openapi.json",
+        service.package, service.proto_name
+    ));
+    let json = serde_json::to_string_pretty(&spec).expect("failed to serialize OpenAPI spec");
+    std::fs::write(&path, json + "\n")
+        .unwrap_or_else(|e| panic!("failed to write {}: {e}", path.display()));
+}
+
+/// The OpenAPI spec for `service`.
+fn spec(service: &prost_build::Service, fds: &FileDescriptorSet) -> Value {
+    let service_fqn = format!("{}.{}", service.package, service.proto_name);
+    let defs = Definitions {
+        messages: messages(fds),
+        json_names: crate::json::json_names(fds),
+    };
+    let mut schemas = BTreeMap::new();
+
+    let mut paths = Map::new();
+    for m in &service.methods {
+        let request = schema_ref(&m.input_proto_type, &defs, &mut schemas);
+        let response = schema_ref(&m.output_proto_type, &defs, &mut schemas);
+        let mut operation = json!({
+            "operationId": format!("{}_{}", service.proto_name, m.proto_name),
+            "requestBody": {
+                "required": true,
+                "content": { "application/json": { "schema": request } },
+            },
+            "responses": {
+                "200": {
+                    "description": "Success",
+                    "content": { "application/json": { "schema": response } },
+                },
+                "default": {
+                    "description": "A twirp error",
+                    "content": {
+                        "application/json": {
+                            "schema": { "$ref": "#/components/schemas/twirp.Error" },
+                        },
+                    },
+                },
+            },
+        });
+        let description = comment(&m.comments);
+        if !description.is_empty() {
+            operation["description"] = Value::String(description);
+        }
+        paths.insert(
+            format!("/{service_fqn}/{}", m.proto_name),
+            json!({ "post": operation }),
+        );
+    }
+
+    schemas.insert(
+        "twirp.Error".to_string(),
+        json!({
+            "type": "object",
+            "required": ["code", "msg"],
+            "properties": {
+                "code": { "type": "string" },
+                "msg": { "type": "string" },
+                "meta": { "type": "object", "additionalProperties": { "type": "string" } },
+            },
+        }),
+    );
+
+    let mut info = json!({ "title": service_fqn, "version": "1.0.0" });
+    let description = comment(&service.comments);
+    if !description.is_empty() {
+        info["description"] = Value::String(description);
+    }
+    json!({
+        "openapi": "3.0.3",
+        "info": info,
+        "paths": paths,
+        "components": { "schemas": schemas },
+    })
+}
+
+/// The leading comment of a service or method, without its annotations.
+fn comment(comments: &prost_build::Comments) -> String {
+    comments
+        .leading
+        .iter()
+        .flat_map(|c| c.lines())
+        .map(str::trim)
+        .filter(|line| !line.starts_with('@'))
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+/// A schema referring to the message `proto_type`, adding its schema (and those of the messages it
+/// contains) to `schemas`.
+fn schema_ref(
+    proto_type: &str,
+    defs: &Definitions<'_>,
+    schemas: &mut BTreeMap<String, Value>,
+) -> Value {
+    // Generated with `prost-wkt-types`, which uses the JSON mapping's representations.
+    match proto_type {
+        ".google.protobuf.Timestamp" => return json!({ "type": "string", "format": "date-time" }),
+        ".google.protobuf.Duration" => return json!({ "type": "string" }),
+        _ => {}
+    }
+    let name = proto_type.trim_start_matches('.').to_string();
+    let reference = json!({ "$ref": format!("#/components/schemas/{name}") });
+    if schemas.contains_key(&name) {
+        return reference;
+    }
+    let message = defs
+        .messages
+        .get(proto_type)
+        .unwrap_or_else(|| panic!("message {proto_type} not found in the descriptors"));
+    // Placeholder, so recursive messages refer to themselves rather than recursing forever.
+    schemas.insert(name.clone(), Value::Null);
+
+    let mut properties = Map::new();
+    let mut oneofs: BTreeMap<i32, Vec<Value>> = BTreeMap::new();
+    for field in &message.field {
+        let schema = field_schema(field, defs, schemas);
+        let json_name = defs.json_name(proto_type, field);
+        match field.oneof_index {
+            Some(idx) if !field.proto3_optional() => {
+                let variant =
+                    json_name.map_or_else(|| field.name().to_upper_camel_case(), str::to_string);
+                oneofs.entry(idx).or_default().push(json!({
+                    "type": "object",
+                    "required": [variant],
+                    "properties": { variant: schema },
+                }));
+            }
+            _ => {
+                let name = json_name.unwrap_or(field.name());
+                properties.insert(name.to_string(), schema);
+            }
+        }
+    }
+    for (idx, variants) in oneofs {
+        let name = message.oneof_decl[idx as usize].name().to_string();
+        properties.insert(name, json!({ "oneOf": variants, "nullable": true }));
+    }
+
+    schemas.insert(name, json!({ "type": "object", "properties": properties }));
+    reference
+}
+
+fn field_schema(
+    field: &FieldDescriptorProto,
+    defs: &Definitions<'_>,
+    schemas: &mut BTreeMap<String, Value>,
+) -> Value {
+    if field.label() == Label::Repeated {
+        // Maps are repeated fields of generated `...Entry` messages.
+        let entry = defs
+            .messages
+            .get(field.type_name())
+            .filter(|m| m.options.as_ref().map_or(false, |o| o.map_entry()));
+        if let Some(entry) = entry {
+            return json!({
+                "type": "object",
+                "additionalProperties": field_schema(&entry.field[1], defs, schemas),
+            });
+        }
+        return json!({
+            "type": "array",
+            "items": scalar_schema(field, defs, schemas),
+        });
+    }
+    let mut schema = scalar_schema(field, defs, schemas);
+    if field.proto3_optional() || field.r#type() == Type::Message {
+        schema = match schema {
+            Value::Object(mut schema) if !schema.contains_key("$ref") => {
+                schema.insert("nullable".to_string(), Value::Bool(true));
+                Value::Object(schema)
+            }
+            // `$ref` can't have siblings in OpenAPI 3.0.
+            schema => json!({ "allOf": [schema], "nullable": true }),
+        };
+    }
+    schema
+}
+
+fn scalar_schema(
+    field: &FieldDescriptorProto,
+    defs: &Definitions<'_>,
+    schemas: &mut BTreeMap<String, Value>,
+) -> Value {
+    match field.r#type() {
+        Type::Message | Type::Group => schema_ref(field.type_name(), defs, schemas),
+        Type::String => json!({ "type": "string" }),
+        Type::Bytes => json!({ "type": "string", "format": "byte" }),
+        Type::Bool => json!({ "type": "boolean" }),
+        Type::Float => json!({ "type": "number", "format": "float" }),
+        Type::Double => json!({ "type": "number", "format": "double" }),
+        Type::Int64 | Type::Sint64 | Type::Sfixed64 => {
+            json!({ "type": "string", "format": "int64" })
+        }
+        Type::Uint64 | Type::Fixed64 => json!({ "type": "string", "format": "uint64" }),
+        Type::Int32 | Type::Sint32 | Type::Sfixed32 | Type::Enum => {
+            json!({ "type": "integer", "format": "int32" })
+        }
+        Type::Uint32 | Type::Fixed32 => {
+            json!({ "type": "integer", "format": "int64", "minimum": 0 })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use prost_types::field_descriptor_proto::Type;
+    use prost_types::FileDescriptorSet;
+    use serde_json::{json, Value};
+
+    use crate::test::*;
+
+    /// A `Hat` with a field of each kind the specs describe differently.
+    fn fds() -> FileDescriptorSet {
+        let hat = with_oneof(
+            message(
+                "Hat",
+                vec![
+                    field("name", 1, Type::String),
+                    field("size", 2, Type::Int64),
+                    typed_field("color", 3, Type::Enum, ".shop.Color"),
+                    field("ratio", 4, Type::Double),
+                    field("logo", 5, Type::Bytes),
+                    repeated(field("sizes", 6, Type::Int64)),
+                    typed_field("inner", 7, Type::Message, ".shop.Hat"),
+                    in_oneof(field("inches", 8, Type::Int32), 0),
+                    in_oneof(field("fit_name", 9, Type::String), 0),
+                ],
+            ),
+            "fit",
+        );
+        let hat = with_map(
+            hat,
+            "shop",
+            "stock",
+            10,
+            Type::String,
+            field("", 0, Type::Uint32),
+        );
+        let hat = with_optional(hat, field("label", 11, Type::String));
+        let file = file("shop", vec![hat], vec![enumeration("Color", &[("RED", 0)])]);
+        let file = with_comment(file, &[4, 0, 2, 8], " @json_name: byName\n");
+        FileDescriptorSet { file: vec![file] }
+    }
+
+    fn service() -> prost_build::Service {
+        prost_build::Service {
+            name: "Shop".to_string(),
+            proto_name: "Shop".to_string(),
+            package: "shop".to_string(),
+            comments: Default::default(),
+            methods: vec![prost_build::Method {
+                name: "make_hat".to_string(),
+                proto_name: "MakeHat".to_string(),
+                comments: Default::default(),
+                input_type: "Hat".to_string(),
+                output_type: "Hat".to_string(),
+                input_proto_type: ".shop.Hat".to_string(),
+                output_proto_type: ".shop.Hat".to_string(),
+                options: Default::default(),
+                client_streaming: false,
+                server_streaming: false,
+            }],
+            options: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_spec() {
+        let spec = super::spec(&service(), &fds());
+        let golden: Value =
+            serde_json::from_str(include_str!("../testdata/shop.Shop.openapi.json")).unwrap();
+        assert_eq!(
+            spec,
+            golden,
+            "{}",
+            serde_json::to_string_pretty(&spec).unwrap()
+        );
+    }
+
+    /// What `prost-build` generates for [`fds`] with [`crate::protobuf_json`], as far as serde is
+    /// concerned. `test_serde_output` checks that the attributes match.
+    #[derive(Default, serde::Serialize)]
+    struct Hat {
+        name: String,
+        #[serde(with = "::twirp::details::json::int64")]
+        size: i64,
+        color: i32,
+        #[serde(with = "::twirp::details::json::float")]
+        ratio: f64,
+        #[serde(with = "::twirp::details::json::bytes")]
+        logo: Vec<u8>,
+        #[serde(with = "::twirp::details::json::repeated_int64")]
+        sizes: Vec<i64>,
+        inner: Option<Box<Hat>>,
+        stock: HashMap<String, u32>,
+        label: Option<String>,
+        fit: Option<Fit>,
+    }
+
+    #[derive(serde::Serialize)]
+    enum Fit {
+        Inches(i32),
+        #[serde(rename = "byName")]
+        #[serde(alias = "fit_name")]
+        #[serde(alias = "fitName")]
+        FitName(String),
+    }
+
+    #[test]
+    fn test_serde_output() {
+        let fds = fds();
+        let mut config = prost_build::Config::new();
+        crate::protobuf_json(&mut config, &fds);
+        let code = &generate(config, &fds)["shop"];
+        let serde_attributes = |item| -> Vec<&str> {
+            attributes(code, item)
+                .into_iter()
+                .filter(|a| a.starts_with("#[serde("))
+                .collect()
+        };
+        let with = |module| format!("#[serde(with = \"::twirp::details::json::{module}\")]");
+        assert_eq!(serde_attributes("pub size:"), [with("int64")]);
+        assert_eq!(serde_attributes("pub ratio:"), [with("float")]);
+        assert_eq!(serde_attributes("pub logo:"), [with("bytes")]);
+        assert_eq!(serde_attributes("pub sizes:"), [with("repeated_int64")]);
+        assert_eq!(
+            serde_attributes("FitName("),
+            [
+                r#"#[serde(rename = "byName")]"#,
+                r#"#[serde(alias = "fit_name")]"#,
+                r#"#[serde(alias = "fitName")]"#,
+            ]
+        );
+        for item in [
+            "pub name:",
+            "pub color:",
+            "pub inner:",
+            "pub stock:",
+            "pub label:",
+            "pub fit:",
+            "Inches(",
+        ] {
+            assert!(serde_attributes(item).is_empty(), "{item}");
+        }
+
+        let spec = super::spec(&service(), &fds);
+        let schema = &spec["paths"]["/shop.Shop/MakeHat"]["post"]["requestBody"]["content"]
+            ["application/json"]["schema"];
+        let hats = [
+            Hat::default(),
+            Hat {
+                name: "fedora".to_string(),
+                size: i64::MAX,
+                color: 1,
+                ratio: 1.5,
+                logo: b"logo".to_vec(),
+                sizes: vec![1, -2],
+                inner: Some(Box::new(Hat {
+                    fit: Some(Fit::FitName("snug".to_string())),
+                    ..Hat::default()
+                })),
+                stock: HashMap::from([("blue".to_string(), 3)]),
+                label: Some("new".to_string()),
+                fit: Some(Fit::Inches(7)),
+            },
+        ];
+        for hat in hats {
+            let value = serde_json::to_value(&hat).unwrap();
+            if let Err(err) = conforms(&spec, schema, &value) {
+                panic!("{value} doesn't match the spec: {err}");
+            }
+        }
+        // And the other way around.
+        assert!(conforms(&spec, schema, &json!({ "fit": { "Ratio": 1 } })).is_err());
+        assert!(conforms(&spec, schema, &json!({ "size": 1 })).is_err());
+    }
+
+    /// Checks `value` against `schema`, for the parts of OpenAPI the specs use. Objects must have
+    /// exactly the properties of their schema, since serde writes every field of a message.
+    fn conforms(spec: &Value, schema: &Value, value: &Value) -> Result<(), String> {
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            let name = reference.trim_start_matches("#/components/schemas/");
+            return conforms(spec, &spec["components"]["schemas"][name], value);
+        }
+        if value.is_null() {
+            return match schema.get("nullable") {
+                Some(Value::Bool(true)) => Ok(()),
+                _ => Err(format!("null for {schema}")),
+            };
+        }
+        if let Some(all) = schema.get("allOf").and_then(Value::as_array) {
+            return all.iter().try_for_each(|s| conforms(spec, s, value));
+        }
+        if let Some(one) = schema.get("oneOf").and_then(Value::as_array) {
+            return match one
+                .iter()
+                .filter(|s| conforms(spec, s, value).is_ok())
+                .count()
+            {
+                1 => Ok(()),
+                n => Err(format!("{value} matches {n} of {schema}")),
+            };
+        }
+        let format = schema.get("format").and_then(Value::as_str);
+        let ok = match (schema["type"].as_str(), value) {
+            (Some("object"), Value::Object(object)) => {
+                if let Some(properties) = schema.get("properties").and_then(Value::as_object) {
+                    if object.len() != properties.len() {
+                        return Err(format!("{value} doesn't have the properties of {schema}"));
+                    }
+                    for (key, value) in object {
+                        let schema = properties
+                            .get(key)
+                            .ok_or_else(|| format!("{key} isn't in {schema}"))?;
+                        conforms(spec, schema, value)?;
+                    }
+                }
+                if let Some(required) = schema.get("required").and_then(Value::as_array) {
+                    if let Some(key) = required
+                        .iter()
+                        .find(|k| !object.contains_key(k.as_str().unwrap()))
+                    {
+                        return Err(format!("{value} doesn't have {key}"));
+                    }
+                }
+                if let Some(values) = schema.get("additionalProperties") {
+                    for value in object.values() {
+                        conforms(spec, values, value)?;
+                    }
+                }
+                true
+            }
+            (Some("array"), Value::Array(items)) => {
+                return items
+                    .iter()
+                    .try_for_each(|v| conforms(spec, &schema["items"], v));
+            }
+            (Some("string"), Value::String(s)) => match format {
+                Some("int64") => s.parse::<i64>().is_ok(),
+                Some("uint64") => s.parse::<u64>().is_ok(),
+                _ => true,
+            },
+            (Some("integer"), Value::Number(n)) => match format {
+                Some("int32") => n.as_i64().map_or(false, |n| i32::try_from(n).is_ok()),
+                _ => n.is_i64() || n.is_u64(),
+            },
+            (Some("number"), Value::Number(_)) => true,
+            (Some("boolean"), Value::Bool(_)) => true,
+            _ => false,
+        };
+        if ok {
+            Ok(())
+        } else {
+            Err(format!("{value} isn't a {schema}"))
+        }
+    }
+}
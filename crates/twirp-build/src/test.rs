@@ -0,0 +1,214 @@
+//! Descriptors for the tests, built by hand as they would be by `prost_build::Config::load_fds`,
+//! and the code `prost-build` generates for them.
+
+use std::collections::HashMap;
+
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::source_code_info::Location;
+use prost_types::{
+    DescriptorProto, EnumDescriptorProto, EnumValueDescriptorProto, FieldDescriptorProto,
+    FileDescriptorProto, FileDescriptorSet, MessageOptions, OneofDescriptorProto, SourceCodeInfo,
+};
+
+/// A singular field of a scalar type.
+pub(crate) fn field(name: &str, number: i32, r#type: Type) -> FieldDescriptorProto {
+    FieldDescriptorProto {
+        name: Some(name.to_string()),
+        number: Some(number),
+        label: Some(Label::Optional as i32),
+        r#type: Some(r#type as i32),
+        ..Default::default()
+    }
+}
+
+/// A singular field of the message or enum `type_name` (fully-qualified, e.g. `.pkg.Hat`).
+pub(crate) fn typed_field(
+    name: &str,
+    number: i32,
+    r#type: Type,
+    type_name: &str,
+) -> FieldDescriptorProto {
+    FieldDescriptorProto {
+        type_name: Some(type_name.to_string()),
+        ..field(name, number, r#type)
+    }
+}
+
+pub(crate) fn repeated(field: FieldDescriptorProto) -> FieldDescriptorProto {
+    FieldDescriptorProto {
+        label: Some(Label::Repeated as i32),
+        ..field
+    }
+}
+
+/// `field` as a member of the message's oneof number `index`.
+pub(crate) fn in_oneof(field: FieldDescriptorProto, index: i32) -> FieldDescriptorProto {
+    FieldDescriptorProto {
+        oneof_index: Some(index),
+        ..field
+    }
+}
+
+pub(crate) fn message(name: &str, fields: Vec<FieldDescriptorProto>) -> DescriptorProto {
+    DescriptorProto {
+        name: Some(name.to_string()),
+        field: fields,
+        ..Default::default()
+    }
+}
+
+/// `message`, with a oneof named `name` (the fields are added with [`in_oneof`]).
+pub(crate) fn with_oneof(mut message: DescriptorProto, name: &str) -> DescriptorProto {
+    message.oneof_decl.push(OneofDescriptorProto {
+        name: Some(name.to_string()),
+        options: None,
+    });
+    message
+}
+
+/// `message`, with a map field `name` from `key` to `value` (whose name and number are replaced),
+/// declared in `package`.
+pub(crate) fn with_map(
+    mut message: DescriptorProto,
+    package: &str,
+    name: &str,
+    number: i32,
+    key: Type,
+    value: FieldDescriptorProto,
+) -> DescriptorProto {
+    let entry = format!("{}Entry", heck::ToUpperCamelCase::to_upper_camel_case(name));
+    message.nested_type.push(DescriptorProto {
+        name: Some(entry.clone()),
+        field: vec![
+            field("key", 1, key),
+            FieldDescriptorProto {
+                name: Some("value".to_string()),
+                number: Some(2),
+                ..value
+            },
+        ],
+        options: Some(MessageOptions {
+            map_entry: Some(true),
+            ..Default::default()
+        }),
+        ..Default::default()
+    });
+    let type_name = format!(".{package}.{}.{entry}", message.name());
+    message.field.push(repeated(typed_field(
+        name,
+        number,
+        Type::Message,
+        &type_name,
+    )));
+    message
+}
+
+/// `message`, with a proto3 `optional` field, which is in a oneof of its own.
+pub(crate) fn with_optional(
+    mut message: DescriptorProto,
+    field: FieldDescriptorProto,
+) -> DescriptorProto {
+    let index = message.oneof_decl.len() as i32;
+    message.oneof_decl.push(OneofDescriptorProto {
+        name: Some(format!("_{}", field.name())),
+        options: None,
+    });
+    message.field.push(FieldDescriptorProto {
+        proto3_optional: Some(true),
+        ..in_oneof(field, index)
+    });
+    message
+}
+
+pub(crate) fn enumeration(name: &str, values: &[(&str, i32)]) -> EnumDescriptorProto {
+    EnumDescriptorProto {
+        name: Some(name.to_string()),
+        value: values
+            .iter()
+            .map(|(name, number)| EnumValueDescriptorProto {
+                name: Some(name.to_string()),
+                number: Some(*number),
+                options: None,
+            })
+            .collect(),
+        ..Default::default()
+    }
+}
+
+pub(crate) fn file(
+    package: &str,
+    messages: Vec<DescriptorProto>,
+    enums: Vec<EnumDescriptorProto>,
+) -> FileDescriptorProto {
+    FileDescriptorProto {
+        name: Some(format!("{}.proto", package.replace('.', "/"))),
+        package: Some(package.to_string()),
+        syntax: Some("proto3".to_string()),
+        message_type: messages,
+        enum_type: enums,
+        ..Default::default()
+    }
+}
+
+/// `file`, with `comment` as the leading comment of the item at `path` (see
+/// `SourceCodeInfo.Location.path` in descriptor.proto).
+pub(crate) fn with_comment(
+    mut file: FileDescriptorProto,
+    path: &[i32],
+    comment: &str,
+) -> FileDescriptorProto {
+    file.source_code_info
+        .get_or_insert_with(SourceCodeInfo::default)
+        .location
+        .push(Location {
+            path: path.to_vec(),
+            leading_comments: Some(comment.to_string()),
+            ..Default::default()
+        });
+    file
+}
+
+/// The code `config` generates for `fds`, by package.
+///
+/// The descriptors' comments are left out: `prost-build` expects the source code info of every
+/// item once a file has some, while the tests only add comments to the items they annotate.
+pub(crate) fn generate(
+    mut config: prost_build::Config,
+    fds: &FileDescriptorSet,
+) -> HashMap<String, String> {
+    let requests = fds
+        .file
+        .iter()
+        .map(|file| {
+            let module = prost_build::Module::from_protobuf_package_name(file.package());
+            let file = FileDescriptorProto {
+                source_code_info: None,
+                ..file.clone()
+            };
+            (module, file)
+        })
+        .collect();
+    config
+        .generate(requests)
+        .expect("generating code for the descriptors")
+        .into_iter()
+        .map(|(module, code)| (module.parts().collect::<Vec<_>>().join("."), code))
+        .collect()
+}
+
+/// The attributes of the first item (e.g. a field or a variant) in `code` whose line starts with
+/// `item`, e.g. `pub color:`.
+pub(crate) fn attributes<'a>(code: &'a str, item: &str) -> Vec<&'a str> {
+    let lines: Vec<&str> = code.lines().map(str::trim).collect();
+    let Some(idx) = lines.iter().position(|line| line.starts_with(item)) else {
+        panic!("no {item} in:\n{code}");
+    };
+    let mut attributes: Vec<&str> = lines[..idx]
+        .iter()
+        .rev()
+        .take_while(|line| line.starts_with("#["))
+        .copied()
+        .collect();
+    attributes.reverse();
+    attributes
+}
@@ -0,0 +1,297 @@
+//! Code generation for request validation rules.
+//!
+//! Rules are declared with an `@validate:` annotation in the comments of a message field:
+//!
+//! ```proto
+//! message MakeHatRequest {
+//!   // @validate: required, min=1, max=64
+//!   int32 inches = 1;
+//! }
+//! ```
+//!
+//! Protobuf custom options can't be used for this because `prost` drops unknown extension fields
+//! when decoding descriptors, so the rules live in the comments (which are part of the
+//! descriptor's source code info).
+
+use std::fmt::Write;
+
+use heck::ToSnakeCase;
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::{DescriptorProto, FieldDescriptorProto, FileDescriptorProto, FileDescriptorSet};
+
+const ANNOTATION: &str = "@validate:";
+
+// Field numbers from descriptor.proto, used to build source code info paths.
+const FILE_MESSAGE_TYPE: i32 = 4;
+const MESSAGE_FIELD: i32 = 2;
+const MESSAGE_NESTED_TYPE: i32 = 3;
+
+#[derive(Debug)]
+enum Rule {
+    Required,
+    Min(String),
+    Max(String),
+    MinLen(usize),
+    MaxLen(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Repeated,
+    Message,
+    Str,
+    Bytes,
+    Bool,
+    Int,
+    Float,
+}
+
+/// A message definition located in a set of file descriptors.
+pub(crate) struct MessageRef<'a> {
+    file: &'a FileDescriptorProto,
+    message: &'a DescriptorProto,
+    path: Vec<i32>,
+}
+
+/// Find the message named by `proto_type` (e.g. `.service.haberdash.v1.MakeHatRequest`) if it is
+/// defined in `package`.
+pub(crate) fn find_message<'a>(
+    fds: &'a FileDescriptorSet,
+    package: &str,
+    proto_type: &str,
+) -> Option<MessageRef<'a>> {
+    let name = proto_type.strip_prefix(&format!(".{package}."))?;
+    let mut segments = name.split('.');
+    let first = segments.next()?;
+    let nested: Vec<&str> = segments.collect();
+    fds.file
+        .iter()
+        .filter(|f| f.package() == package)
+        .find_map(|file| {
+            let (idx, mut message) = file
+                .message_type
+                .iter()
+                .enumerate()
+                .find(|(_, m)| m.name() == first)?;
+            let mut path = vec![FILE_MESSAGE_TYPE, idx as i32];
+            for segment in &nested {
+                let (idx, inner) = message
+                    .nested_type
+                    .iter()
+                    .enumerate()
+                    .find(|(_, m)| m.name() == *segment)?;
+                path.extend([MESSAGE_NESTED_TYPE, idx as i32]);
+                message = inner;
+            }
+            Some(MessageRef {
+                file,
+                message,
+                path,
+            })
+        })
+}
+
+/// Writes an inherent `validate()` method for `rust_type` checking the rules declared on the
+/// message's fields. Returns `false` (and writes nothing) if the message has no rules.
+pub(crate) fn generate(rust_type: &str, msg: &MessageRef<'_>, buf: &mut String) -> bool {
+    let mut checks = String::new();
+    for (idx, field) in msg.message.field.iter().enumerate() {
+        let mut path = msg.path.clone();
+        path.extend([MESSAGE_FIELD, idx as i32]);
+        let rules = field_rules(msg.file, &path);
+        if rules.is_empty() {
+            continue;
+        }
+        if field.oneof_index.is_some() && !field.proto3_optional() {
+            panic!(
+                "{}.{}: validation rules are not supported on oneof fields",
+                msg.message.name(),
+                field.name()
+            );
+        }
+        for rule in &rules {
+            write_check(msg.message.name(), field, rule, &mut checks);
+        }
+    }
+    if checks.is_empty() {
+        return false;
+    }
+
+    writeln!(buf, "impl {rust_type} {{").unwrap();
+    writeln!(
+        buf,
+        "    /// Checks the `@validate` rules declared on the fields of this message."
+    )
+    .unwrap();
+    writeln!(
+        buf,
+        "    pub fn validate(&self) -> Result<(), twirp::TwirpErrorResponse> {{"
+    )
+    .unwrap();
+    buf.push_str(&checks);
+    writeln!(buf, "        Ok(())").unwrap();
+    writeln!(buf, "    }}").unwrap();
+    writeln!(buf, "}}").unwrap();
+    writeln!(buf, "impl twirp::details::Validate for {rust_type} {{").unwrap();
+    writeln!(
+        buf,
+        "    fn validate(&self) -> Result<(), twirp::TwirpErrorResponse> {{"
+    )
+    .unwrap();
+    writeln!(buf, "        {rust_type}::validate(self)").unwrap();
+    writeln!(buf, "    }}").unwrap();
+    writeln!(buf, "}}").unwrap();
+    true
+}
+
+fn field_rules(file: &FileDescriptorProto, path: &[i32]) -> Vec<Rule> {
+    let Some(location) = file
+        .source_code_info
+        .as_ref()
+        .and_then(|info| info.location.iter().find(|l| l.path == path))
+    else {
+        return vec![];
+    };
+    location
+        .leading_comments()
+        .lines()
+        .chain(location.trailing_comments().lines())
+        .filter_map(|line| line.trim().strip_prefix(ANNOTATION))
+        .flat_map(|rules| rules.split(','))
+        .map(str::trim)
+        .filter(|rule| !rule.is_empty())
+        .map(parse_rule)
+        .collect()
+}
+
+fn parse_rule(rule: &str) -> Rule {
+    let (name, value) = match rule.split_once('=') {
+        Some((name, value)) => (name.trim(), Some(value.trim())),
+        None => (rule, None),
+    };
+    let len = |value: Option<&str>| -> usize {
+        value
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_else(|| panic!("validation rule `{rule}` needs a non-negative integer"))
+    };
+    let number = |value: Option<&str>| -> String {
+        match value {
+            Some(v) if v.parse::<f64>().is_ok() => v.to_string(),
+            _ => panic!("validation rule `{rule}` needs a number"),
+        }
+    };
+    match name {
+        "required" => Rule::Required,
+        "min" => Rule::Min(number(value)),
+        "max" => Rule::Max(number(value)),
+        "min_len" => Rule::MinLen(len(value)),
+        "max_len" => Rule::MaxLen(len(value)),
+        _ => panic!("unknown validation rule `{rule}`"),
+    }
+}
+
+fn kind(field: &FieldDescriptorProto) -> Kind {
+    if field.label() == Label::Repeated {
+        return Kind::Repeated;
+    }
+    match field.r#type() {
+        Type::Message | Type::Group => Kind::Message,
+        Type::String => Kind::Str,
+        Type::Bytes => Kind::Bytes,
+        Type::Bool => Kind::Bool,
+        Type::Float | Type::Double => Kind::Float,
+        _ => Kind::Int,
+    }
+}
+
+fn write_check(message: &str, field: &FieldDescriptorProto, rule: &Rule, buf: &mut String) {
+    let name = field.name();
+    let ident = format!("self.{}", rust_field_ident(name));
+    let kind = kind(field);
+    let optional = field.proto3_optional();
+    let unsupported =
+        || -> ! { panic!("{message}.{name}: `{rule:?}` is not valid for this field") };
+
+    let number = |value: &str| -> String {
+        match kind {
+            // Float literals need a decimal point to compare against `f32`/`f64` fields.
+            Kind::Float => format!("{:?}", value.parse::<f64>().unwrap_or_default()),
+            Kind::Int if value.parse::<i128>().is_ok() => value.to_string(),
+            _ => unsupported(),
+        }
+    };
+    let compare = |op: &str, value: &str| -> String {
+        if optional {
+            format!("matches!({ident}, Some(v) if v {op} {value})")
+        } else {
+            format!("{ident} {op} {value}")
+        }
+    };
+
+    let (condition, msg) = match rule {
+        Rule::Required => {
+            let condition = match kind {
+                _ if optional => format!("{ident}.is_none()"),
+                Kind::Message => format!("{ident}.is_none()"),
+                Kind::Repeated | Kind::Str | Kind::Bytes => format!("{ident}.is_empty()"),
+                Kind::Bool => format!("!{ident}"),
+                Kind::Int => format!("{ident} == 0"),
+                Kind::Float => format!("{ident} == 0.0"),
+            };
+            (condition, "is required".to_string())
+        }
+        Rule::Min(value) => (
+            compare("<", &number(value)),
+            format!("must be at least {value}"),
+        ),
+        Rule::Max(value) => (
+            compare(">", &number(value)),
+            format!("must be at most {value}"),
+        ),
+        Rule::MinLen(len) | Rule::MaxLen(len) => {
+            let length = match kind {
+                _ if optional => unsupported(),
+                Kind::Str => format!("{ident}.chars().count()"),
+                Kind::Repeated | Kind::Bytes => format!("{ident}.len()"),
+                _ => unsupported(),
+            };
+            match rule {
+                Rule::MinLen(1) if kind != Kind::Str => (
+                    format!("{ident}.is_empty()"),
+                    "must not be empty".to_string(),
+                ),
+                Rule::MinLen(_) => (
+                    format!("{length} < {len}"),
+                    format!("must have a length of at least {len}"),
+                ),
+                _ => (
+                    format!("{length} > {len}"),
+                    format!("must have a length of at most {len}"),
+                ),
+            }
+        }
+    };
+
+    writeln!(buf, "        if {condition} {{").unwrap();
+    writeln!(
+        buf,
+        "            return Err(twirp::details::invalid_field(\"{name}\", \"{msg}\"));"
+    )
+    .unwrap();
+    writeln!(buf, "        }}").unwrap();
+}
+
+/// The Rust identifier `prost-build` generates for a proto field name.
+pub(crate) fn rust_field_ident(name: &str) -> String {
+    let ident = name.to_snake_case();
+    match ident.as_str() {
+        "as" | "break" | "const" | "continue" | "else" | "enum" | "false" | "fn" | "for" | "if"
+        | "impl" | "in" | "let" | "loop" | "match" | "mod" | "move" | "mut" | "pub" | "ref"
+        | "return" | "static" | "struct" | "trait" | "true" | "type" | "unsafe" | "use"
+        | "where" | "while" | "dyn" | "abstract" | "become" | "box" | "do" | "final" | "macro"
+        | "override" | "priv" | "typeof" | "unsized" | "virtual" | "yield" | "async" | "await"
+        | "try" => format!("r#{ident}"),
+        "_" | "super" | "self" | "Self" | "extern" | "crate" => format!("{ident}_"),
+        _ => ident,
+    }
+}
@@ -0,0 +1,20 @@
+//! Decodes arbitrary request bodies as protobuf or JSON, as the server does.
+//!
+//! Run with `cargo +nightly fuzz run decode_request` from `crates/twirp`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use twirp::test::PingRequest;
+
+fuzz_target!(|data: &[u8]| {
+    // The first byte picks the encoding, the rest is the body.
+    let Some((&encoding, body)) = data.split_first() else {
+        return;
+    };
+    let content_type = match encoding % 2 {
+        0 => "application/protobuf",
+        _ => "application/json",
+    };
+    let _ = twirp::server::decode_request::<PingRequest>(content_type, body);
+});
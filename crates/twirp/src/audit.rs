@@ -0,0 +1,294 @@
+//! Copying request bodies to an audit sink, for services that must keep a record of every call
+//! they receive (see [`Options::audit_sink`](crate::server::Options::audit_sink)).
+//!
+//! Unlike logs, audit records carry the whole request body. The server reads the body, hands a
+//! copy to a buffer, and goes on to handle the request; a background task passes the records in
+//! the buffer to the sink in the order the requests were read. What happens when the sink falls
+//! behind and the buffer fills up is up to the [`AuditOverflow`] policy.
+//!
+//! A record is made as soon as the body is read, before anything else is checked: requests whose
+//! HMAC signature turns out to be wrong (see
+//! [`Options::verify_hmac`](crate::server::Options::verify_hmac)) and dry runs (see
+//! [`Options::allow_dry_run`](crate::server::Options::allow_dry_run)) are recorded like any
+//! other. The values of headers carrying credentials are left out (see [`REDACTED_HEADERS`]).
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use http::header::{HeaderName, HeaderValue, AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION};
+use http::HeaderMap;
+use tokio::sync::mpsc;
+
+use crate::MethodPath;
+
+/// The default size of the buffer of records waiting for the sink.
+pub const DEFAULT_AUDIT_BUFFER: usize = 1024;
+
+/// The headers whose values are replaced with [`REDACTED`] in audit records, as they carry
+/// credentials.
+pub const REDACTED_HEADERS: [HeaderName; 3] = [AUTHORIZATION, PROXY_AUTHORIZATION, COOKIE];
+
+/// The value of the [`REDACTED_HEADERS`] in audit records.
+pub const REDACTED: &str = "redacted";
+
+/// A request body, and what it was sent with.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct AuditRecord {
+    /// The method called, if the request's path names one.
+    pub method: Option<MethodPath>,
+    /// The ID of the request (see [`crate::request_id`]).
+    pub request_id: String,
+    /// The headers of the request, with a single [`REDACTED`] value for each of the
+    /// [`REDACTED_HEADERS`] it has.
+    pub headers: HeaderMap,
+    /// The body of the request, as received.
+    pub body: bytes::Bytes,
+    /// When the body was read.
+    pub received: SystemTime,
+}
+
+/// A copy of `headers` for a record, with the values of the [`REDACTED_HEADERS`] replaced.
+pub(crate) fn redact(headers: &HeaderMap) -> HeaderMap {
+    let mut headers = headers.clone();
+    for name in &REDACTED_HEADERS {
+        if headers.contains_key(name) {
+            headers.insert(name, HeaderValue::from_static(REDACTED));
+        }
+    }
+    headers
+}
+
+/// Where audit records go, e.g. a channel, a file, or a remote store.
+///
+/// Records are passed to the sink one at a time, each after the sink is done with the one before.
+#[async_trait]
+pub trait AuditSink: Send + Sync + 'static {
+    async fn record(&self, record: AuditRecord);
+}
+
+/// Sends records on the channel, waiting for room in it. Records are dropped once the receiver is.
+#[async_trait]
+impl AuditSink for mpsc::Sender<AuditRecord> {
+    async fn record(&self, record: AuditRecord) {
+        let _ = self.send(record).await;
+    }
+}
+
+/// What to do with a record when the buffer in front of the sink is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AuditOverflow {
+    /// Drop the record and log a warning (with `tracing`), so a slow sink never holds up
+    /// requests. The default.
+    #[default]
+    Drop,
+    /// Wait for room in the buffer before handling the request, so no record is lost but a slow
+    /// sink slows down the service.
+    Wait,
+}
+
+/// The buffer in front of a sink, and the task draining it, which is started with the first
+/// record (when there is sure to be a runtime to start it on).
+pub(crate) struct Auditor {
+    tx: mpsc::Sender<AuditRecord>,
+    drain: Mutex<Option<Drain>>,
+    overflow: AuditOverflow,
+    dropped: AtomicU64,
+}
+
+impl Auditor {
+    pub(crate) fn new(sink: impl AuditSink, buffer: usize, overflow: AuditOverflow) -> Self {
+        let (tx, rx) = mpsc::channel(buffer.max(1));
+        Self {
+            tx,
+            drain: Mutex::new(Some(Drain {
+                rx,
+                sink: Box::new(sink),
+            })),
+            overflow,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) async fn send(&self, record: AuditRecord) {
+        if let Some(drain) = self.drain.lock().expect("mutex poisoned").take() {
+            tokio::spawn(drain.run());
+        }
+        match self.overflow {
+            AuditOverflow::Drop => {
+                if let Err(mpsc::error::TrySendError::Full(record)) = self.tx.try_send(record) {
+                    let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                    tracing::warn!(
+                        method = record.method.map(|m| m.to_string()).unwrap_or_default(),
+                        request_id = record.request_id,
+                        dropped,
+                        "audit sink is falling behind, dropping record"
+                    );
+                }
+            }
+            AuditOverflow::Wait => {
+                let _ = self.tx.send(record).await;
+            }
+        }
+    }
+}
+
+/// The receiving end of the buffer, and the sink records go to.
+struct Drain {
+    rx: mpsc::Receiver<AuditRecord>,
+    sink: Box<dyn AuditSink>,
+}
+
+impl Drain {
+    /// Pass records to the sink until the buffer is closed, when the server's options are dropped.
+    async fn run(mut self) {
+        while let Some(record) = self.rx.recv().await {
+            self.sink.record(record).await;
+        }
+    }
+}
+
+impl std::fmt::Debug for Auditor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Auditor")
+            .field("overflow", &self.overflow)
+            .field("dropped", &self.dropped)
+            .finish_non_exhaustive()
+    }
+}
+
+// The tests call the service with JSON requests.
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::body::Body;
+    use http::Request;
+    use tokio::sync::Semaphore;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::server::Options;
+    use crate::test::*;
+
+    fn ping(name: &str) -> Request<Body> {
+        Request::post("/twirp/test.TestAPI/Ping")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header("x-request-id", name)
+            .body(Body::from(format!(r#"{{"name":"{name}"}}"#)))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_audit_sink() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let app = test_api_router().layer(Options::new().audit_sink(tx));
+        for name in ["alice", "bob"] {
+            let resp = app.clone().oneshot(ping(name)).await.unwrap();
+            assert!(resp.status().is_success(), "{resp:?}");
+        }
+
+        for name in ["alice", "bob"] {
+            let record = rx.recv().await.unwrap();
+            assert_eq!(record.method.unwrap().to_string(), "test.TestAPI/Ping");
+            assert_eq!(record.request_id, name);
+            assert_eq!(record.headers["content-type"], "application/json");
+            assert_eq!(record.body, format!(r#"{{"name":"{name}"}}"#));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_audit_redacted_headers() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let app = test_api_router().layer(Options::new().audit_sink(tx));
+        let mut req = ping("alice");
+        let headers = req.headers_mut();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer secret"));
+        headers.append(COOKIE, HeaderValue::from_static("session=secret"));
+        headers.append(COOKIE, HeaderValue::from_static("theme=dark"));
+        let resp = app.oneshot(req).await.unwrap();
+        assert!(resp.status().is_success(), "{resp:?}");
+
+        let record = rx.recv().await.unwrap();
+        let values = |name| record.headers.get_all(name).iter().collect::<Vec<_>>();
+        assert_eq!(values(AUTHORIZATION), [REDACTED]);
+        assert_eq!(values(COOKIE), [REDACTED]);
+        assert_eq!(record.headers["x-request-id"], "alice");
+    }
+
+    /// Records the request IDs of records, once a permit lets it.
+    #[derive(Clone)]
+    struct Gated {
+        permits: Arc<Semaphore>,
+        ids: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl AuditSink for Gated {
+        async fn record(&self, record: AuditRecord) {
+            self.permits.acquire().await.unwrap().forget();
+            self.ids.lock().unwrap().push(record.request_id);
+        }
+    }
+
+    impl Gated {
+        fn new() -> Self {
+            Self {
+                permits: Arc::new(Semaphore::new(0)),
+                ids: Default::default(),
+            }
+        }
+
+        async fn wait_for(&self, n: usize) -> Vec<String> {
+            loop {
+                let ids = self.ids.lock().unwrap().clone();
+                if ids.len() >= n {
+                    return ids;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_audit_overflow_drop() {
+        let sink = Gated::new();
+        let app = test_api_router().layer(Options::new().audit_sink_with(
+            sink.clone(),
+            1,
+            AuditOverflow::Drop,
+        ));
+        // The sink is stuck, and the first record fills the buffer.
+        for name in ["a", "b", "c"] {
+            let resp = app.clone().oneshot(ping(name)).await.unwrap();
+            assert!(resp.status().is_success(), "{resp:?}");
+        }
+
+        sink.permits.add_permits(10);
+        assert_eq!(sink.wait_for(1).await, ["a"]);
+        app.oneshot(ping("d")).await.unwrap();
+        assert_eq!(sink.wait_for(2).await, ["a", "d"]);
+    }
+
+    #[tokio::test]
+    async fn test_audit_overflow_wait() {
+        let sink = Gated::new();
+        let app = test_api_router().layer(Options::new().audit_sink_with(
+            sink.clone(),
+            1,
+            AuditOverflow::Wait,
+        ));
+        app.clone().oneshot(ping("a")).await.unwrap();
+        // The sink is stuck with the first record, and the second fills the buffer.
+        app.clone().oneshot(ping("b")).await.unwrap();
+        let third = tokio::spawn(app.oneshot(ping("c")));
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        assert!(!third.is_finished());
+
+        sink.permits.add_permits(10);
+        assert!(third.await.unwrap().unwrap().status().is_success());
+        assert_eq!(sink.wait_for(3).await, ["a", "b", "c"]);
+    }
+}
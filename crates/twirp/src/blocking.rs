@@ -0,0 +1,182 @@
+//! A blocking twirp client, for CLI tools and scripts that don't otherwise need an async runtime.
+//!
+//! [`Client`] wraps the async [`crate::Client`], and so shares its configuration, URL joining and
+//! encoding, running each call to completion on a runtime of its own:
+//!
+//! ```no_run
+//! # fn run() -> twirp::Result<()> {
+//! # #[derive(Clone, PartialEq, prost::Message, serde::Serialize, serde::Deserialize)]
+//! # struct MakeHatRequest { #[prost(int32, tag = "1")] inches: i32 }
+//! # #[derive(Clone, PartialEq, prost::Message, serde::Serialize, serde::Deserialize)]
+//! # struct Hat { #[prost(string, tag = "1")] name: String }
+//! let base_url = twirp::url::Url::parse("http://localhost:3000/twirp/").unwrap();
+//! let client = twirp::blocking::Client::from_base_url(base_url)?;
+//! let hat: Hat = client.request(
+//!     "example.haberdash.v1.Haberdasher/MakeHat",
+//!     MakeHatRequest { inches: 7 },
+//! )?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Like `reqwest::blocking`, the client must not be used from within an async runtime: calls panic
+//! there. Use the async client instead.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use url::Url;
+
+use crate::client::{ClientError, Connectivity, Encoding, RequestStats, Result};
+use crate::{ClientBuilder, JsonDeserialize, JsonSerialize};
+
+/// A twirp client whose calls block until they complete. See the [module docs](self).
+///
+/// Clones share the runtime and the connection pool.
+#[derive(Clone, Debug)]
+pub struct Client {
+    inner: crate::Client,
+    runtime: Arc<tokio::runtime::Runtime>,
+}
+
+impl Client {
+    /// A blocking client making its calls with `client`.
+    pub fn new(client: crate::Client) -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(ClientError::Runtime)?;
+        Ok(Self {
+            inner: client,
+            runtime: Arc::new(runtime),
+        })
+    }
+
+    /// A blocking client with the default configuration of [`crate::Client::from_base_url`].
+    pub fn from_base_url(base_url: Url) -> Result<Self> {
+        Self::new(crate::Client::from_base_url(base_url)?)
+    }
+
+    /// A blocking client built from `builder`, e.g. with retries or middleware.
+    pub fn from_builder(builder: ClientBuilder) -> Result<Self> {
+        Self::new(builder.build()?)
+    }
+
+    /// The async client the calls are made with.
+    pub fn async_client(&self) -> &crate::Client {
+        &self.inner
+    }
+
+    pub fn base_url(&self) -> &Url {
+        self.inner.base_url()
+    }
+
+    /// See [`crate::Client::with_host`].
+    pub fn with_host(&self, host: &str) -> Self {
+        Self {
+            inner: self.inner.with_host(host),
+            runtime: self.runtime.clone(),
+        }
+    }
+
+    /// See [`crate::Client::with_encoding`].
+    pub fn with_encoding(&self, encoding: Encoding) -> Self {
+        Self {
+            inner: self.inner.with_encoding(encoding),
+            runtime: self.runtime.clone(),
+        }
+    }
+
+    /// Make a twirp request. See [`crate::Client::request`].
+    pub fn request<I, O>(&self, path: &str, body: I) -> Result<O>
+    where
+        I: prost::Message + JsonSerialize,
+        O: prost::Message + JsonDeserialize + Default,
+    {
+        self.runtime.block_on(self.inner.request(path, body))
+    }
+
+    /// See [`crate::Client::request_validated`].
+    pub fn request_validated<I, O>(&self, path: &str, body: I) -> Result<O>
+    where
+        I: prost::Message + JsonSerialize,
+        O: prost::Message + JsonDeserialize + Default + crate::details::Validate,
+    {
+        self.runtime
+            .block_on(self.inner.request_validated(path, body))
+    }
+
+    /// See [`crate::Client::request_with_meta`].
+    pub fn request_with_meta<I, O>(
+        &self,
+        path: &str,
+        body: I,
+    ) -> Result<(O, HashMap<String, String>)>
+    where
+        I: prost::Message + JsonSerialize,
+        O: prost::Message + JsonDeserialize + Default,
+    {
+        self.runtime
+            .block_on(self.inner.request_with_meta(path, body))
+    }
+
+    /// See [`crate::Client::check_connectivity`].
+    pub fn check_connectivity(&self, path: &str) -> Result<Connectivity> {
+        self.runtime.block_on(self.inner.check_connectivity(path))
+    }
+
+    /// See [`crate::Client::server_twirp_version`].
+    pub fn server_twirp_version(&self) -> Option<String> {
+        self.inner.server_twirp_version()
+    }
+
+    /// See [`crate::Client::request_stats`].
+    pub fn request_stats(&self) -> RequestStats {
+        self.inner.request_stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::*;
+
+    #[test]
+    fn test_blocking_client() {
+        // The server runs on a runtime of its own, on another thread.
+        let (addr_tx, addr_rx) = std::sync::mpsc::channel();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async move {
+                let server = crate::testing::TestServer::start(test_api_router()).await;
+                addr_tx.send(server.base_url()).unwrap();
+                let _ = stopped.await;
+                server.shutdown().await;
+            });
+        });
+
+        let client = Client::from_base_url(addr_rx.recv().unwrap()).unwrap();
+        let req = PingRequest {
+            name: "hi".to_string(),
+        };
+        let resp: PingResponse = client.request("test.TestAPI/Ping", req.clone()).unwrap();
+        assert_eq!(resp.name, "hi");
+        let resp: PingResponse = client
+            .with_encoding(Encoding::Json)
+            .request("test.TestAPI/Ping", req.clone())
+            .unwrap();
+        assert_eq!(resp.name, "hi");
+        let err = client
+            .request::<_, PingResponse>("test.TestAPI/Boom", req)
+            .unwrap_err();
+        assert!(matches!(err, ClientError::TwirpError(_)), "{err:?}");
+        assert_eq!(client.request_stats().requests, 3);
+
+        stop.send(()).unwrap();
+        server.join().unwrap();
+    }
+}
@@ -1,24 +1,40 @@
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::vec;
 
 use async_trait::async_trait;
-use reqwest::header::{InvalidHeaderValue, CONTENT_TYPE};
+use reqwest::header::{
+    HeaderMap, HeaderValue, InvalidHeaderValue, AUTHORIZATION, CONTENT_TYPE, ETAG, IF_NONE_MATCH,
+    LOCATION, RETRY_AFTER, USER_AGENT,
+};
+#[cfg(feature = "gzip")]
+use reqwest::header::{ACCEPT_ENCODING, CONTENT_ENCODING};
 use reqwest::StatusCode;
 use thiserror::Error;
 use url::Url;
 
-use crate::headers::{CONTENT_TYPE_JSON, CONTENT_TYPE_PROTOBUF};
-use crate::{serialize_proto_message, GenericError, TwirpErrorResponse};
+use crate::headers::{
+    CLIENT_LANGUAGE_HEADER, CONTENT_TYPE_JSON, CONTENT_TYPE_PROTOBUF, META_HEADER_PREFIX,
+    REQUEST_ID_HEADER, REQUEST_TIMEOUT_HEADER, TWIRP_VERSION_HEADER,
+};
+use crate::request_id::RequestIdGenerator;
+use crate::{GenericError, JsonDeserialize, JsonSerialize, TwirpErrorResponse};
 
 #[derive(Debug, Error)]
 #[non_exhaustive]
 pub enum ClientError {
     #[error(transparent)]
     InvalidHeader(#[from] InvalidHeaderValue),
-    #[error("base_url must end in /, but got: {0}")]
+    #[error("base_url must be a URL that paths can be appended to, but got: {0}")]
     InvalidBaseUrl(Url),
+    #[error("base_url must be an http or https URL, but got scheme {scheme:?} in: {url}")]
+    UnsupportedScheme { scheme: String, url: Url },
     #[error(transparent)]
     InvalidUrl(#[from] url::ParseError),
+    #[error("invalid environment variable {name}: {msg}")]
+    InvalidEnv { name: String, msg: String },
     #[error(
         "http error, status code: {status}, msg:{msg} for path:{path} and content-type:{content_type}"
     )]
@@ -28,37 +44,207 @@ pub enum ClientError {
         path: String,
         content_type: String,
     },
+    /// A JSON response (or error response) couldn't be decoded.
     #[error(transparent)]
     JsonDecodeError(#[from] serde_json::Error),
+    /// The request couldn't be encoded as JSON.
+    #[error("failed to encode request as JSON: {0}")]
+    JsonEncodeError(serde_json::Error),
     #[error("malformed response: {0}")]
     MalformedResponse(String),
-    #[error(transparent)]
+    /// A response body was larger than [`ClientBuilder::max_response_size`].
+    #[error("response larger than {limit} bytes")]
+    ResponseTooLarge { limit: usize },
+    /// A compressed response body grew larger than the limit of
+    /// [`ClientBuilder::max_decompressed_size`] when decompressed.
+    #[cfg(feature = "gzip")]
+    #[error("response larger than {limit} bytes when decompressed")]
+    DecompressedResponseTooLarge { limit: usize },
+    /// A protobuf response couldn't be decoded, which usually means the client and server
+    /// disagree on the response message's definition.
+    #[error("failed to decode protobuf response: {0}")]
     ProtoDecodeError(#[from] prost::DecodeError),
     #[error(transparent)]
     ReqwestError(#[from] reqwest::Error),
+    /// The server sent an error. Its `meta` also has the response metadata the handler set with
+    /// [`Context::set_meta`](crate::Context::set_meta), for keys the error's own metadata doesn't
+    /// have.
     #[error("twirp error: {0:?}")]
     TwirpError(TwirpErrorResponse),
+    /// A response failed its validation rules (see [`Client::request_validated`]). The error is
+    /// the one the response's `validate()` method returned.
+    #[error("invalid response: {}", .0.msg)]
+    InvalidResponse(TwirpErrorResponse),
 
     /// A generic error that can be used by custom middleware.
     #[error(transparent)]
     MiddlewareError(#[from] GenericError),
+
+    /// Connecting or talking to the server over the named pipe of
+    /// [`ClientBuilder::named_pipe`] failed.
+    #[cfg(windows)]
+    #[error("named pipe error: {0}")]
+    NamedPipe(GenericError),
+
+    /// The runtime of a [`blocking::Client`](crate::blocking::Client) couldn't be started.
+    #[cfg(feature = "blocking")]
+    #[error("failed to start the runtime of the blocking client: {0}")]
+    Runtime(std::io::Error),
 }
 
 pub type Result<T, E = ClientError> = std::result::Result<T, E>;
 
 pub struct ClientBuilder {
     base_url: Url,
-    http_client: reqwest::Client,
+    // `None` to build one with the connection settings below.
+    http_client: Option<reqwest::Client>,
+    http2_keep_alive: (Duration, Duration),
+    pool_idle_timeout: Option<Duration>,
+    tcp_nodelay: bool,
+    follow_redirects: bool,
     middleware: Vec<Box<dyn Middleware>>,
+    response_cache: Option<ResponseCache>,
+    max_response_size: Option<usize>,
+    #[cfg(feature = "gzip")]
+    gzip_responses: bool,
+    #[cfg(feature = "gzip")]
+    max_decompressed_size: Option<usize>,
+    user_agent: Option<String>,
+    #[cfg(feature = "json")]
+    json_fallback: bool,
+    url_rewriter: Option<Box<UrlRewriter>>,
+    on_call: Option<Box<CallObserver>>,
+    retries: Option<(u32, Duration)>,
+    max_retry_after: Duration,
+    retry_deadline: Option<Duration>,
+    request_timeout: Option<Duration>,
+    hedge_policy: Option<HedgePolicy>,
+    request_id_generator: RequestIdGenerator,
+    #[cfg(feature = "hmac")]
+    hmac_signer: Option<crate::signing::HmacSigner>,
+    #[cfg(windows)]
+    named_pipe: Option<String>,
 }
 
+type UrlRewriter = dyn Fn(&str, &Url) -> Url + Send + Sync;
+
+type CallObserver = dyn Fn(&CallMetrics) + Send + Sync;
+
+/// The `User-Agent` clients send unless configured otherwise.
+pub const DEFAULT_USER_AGENT: &str = concat!("twirp-rs/", env!("CARGO_PKG_VERSION"));
+
+/// The [`CLIENT_LANGUAGE_HEADER`] clients send with every request.
+pub const CLIENT_LANGUAGE: &str = concat!("rust/", env!("CARGO_PKG_VERSION"));
+
+/// How many times larger than [`ClientBuilder::max_response_size`] a compressed response may grow
+/// when decompressed, unless [`ClientBuilder::max_decompressed_size`] says otherwise.
+#[cfg(feature = "gzip")]
+pub const DEFAULT_DECOMPRESSION_RATIO: usize = 10;
+
+/// The limit on the size of decompressed responses of clients without a
+/// [`ClientBuilder::max_response_size`] or [`ClientBuilder::max_decompressed_size`].
+#[cfg(feature = "gzip")]
+pub const DEFAULT_MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+
+/// The defaults for [`ClientBuilder::http2_keep_alive`].
+pub const DEFAULT_HTTP2_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(30);
+pub const DEFAULT_HTTP2_KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The default for [`ClientBuilder::pool_idle_timeout`].
+pub const DEFAULT_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// The default for [`ClientBuilder::max_retry_after`].
+pub const DEFAULT_MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
 impl ClientBuilder {
+    /// A builder for a client that sends requests with `http_client`, which keeps its own
+    /// connection settings.
     pub fn new(base_url: Url, http_client: reqwest::Client) -> Self {
+        Self {
+            http_client: Some(http_client),
+            ..Self::from_base_url(base_url)
+        }
+    }
+
+    /// A builder for a client with its own `reqwest::Client`, configured with the connection
+    /// settings of this builder.
+    pub fn from_base_url(base_url: Url) -> Self {
         Self {
             base_url,
             middleware: vec![],
-            http_client,
+            http_client: None,
+            http2_keep_alive: (
+                DEFAULT_HTTP2_KEEP_ALIVE_INTERVAL,
+                DEFAULT_HTTP2_KEEP_ALIVE_TIMEOUT,
+            ),
+            pool_idle_timeout: Some(DEFAULT_POOL_IDLE_TIMEOUT),
+            tcp_nodelay: true,
+            follow_redirects: false,
+            response_cache: None,
+            max_response_size: None,
+            #[cfg(feature = "gzip")]
+            gzip_responses: false,
+            #[cfg(feature = "gzip")]
+            max_decompressed_size: None,
+            user_agent: None,
+            #[cfg(feature = "json")]
+            json_fallback: false,
+            url_rewriter: None,
+            on_call: None,
+            retries: None,
+            max_retry_after: DEFAULT_MAX_RETRY_AFTER,
+            retry_deadline: None,
+            request_timeout: None,
+            hedge_policy: None,
+            request_id_generator: crate::request_id::default_generator(),
+            #[cfg(feature = "hmac")]
+            hmac_signer: None,
+            #[cfg(windows)]
+            named_pipe: None,
+        }
+    }
+
+    /// A builder configured from environment variables starting with `prefix`, with the defaults
+    /// of [`from_base_url`](Self::from_base_url) otherwise:
+    ///
+    /// - `{prefix}_URL` (required): the base URL, e.g. `http://localhost:3000/twirp/`.
+    /// - `{prefix}_TIMEOUT_MS`: the [`request_timeout`](Self::request_timeout) in milliseconds.
+    /// - `{prefix}_TOKEN`: a token sent as `Authorization: Bearer <token>` on every request.
+    ///
+    /// Errors name the variable that is missing or invalid.
+    pub fn from_env(prefix: &str) -> Result<Self> {
+        Self::from_env_with(prefix, |name| std::env::var(name).ok())
+    }
+
+    /// Like [`from_env`](Self::from_env), getting the value of each variable from `lookup`
+    /// instead of the process's environment, e.g. from a config file or, in tests, a map.
+    pub fn from_env_with(prefix: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let var = |suffix: &str| {
+            let name = format!("{prefix}_{suffix}");
+            let value = lookup(&name);
+            (name, value)
+        };
+        let invalid = |name: String, msg: String| ClientError::InvalidEnv { name, msg };
+
+        let mut builder = match var("URL") {
+            (name, Some(url)) => {
+                Self::from_base_url(Url::parse(&url).map_err(|e| invalid(name, e.to_string()))?)
+            }
+            (name, None) => return Err(invalid(name, "not set".to_string())),
+        };
+        if let (name, Some(timeout)) = var("TIMEOUT_MS") {
+            let ms = timeout
+                .parse()
+                .map_err(|e: std::num::ParseIntError| invalid(name, e.to_string()))?;
+            builder = builder.request_timeout(Duration::from_millis(ms));
+        }
+        if let (name, Some(token)) = var("TOKEN") {
+            let mut value = HeaderValue::try_from(format!("Bearer {token}"))
+                .map_err(|e| invalid(name, e.to_string()))?;
+            value.set_sensitive(true);
+            builder = builder.with(BearerToken(value));
         }
+        Ok(builder)
     }
 
     /// Add middleware to the client that will be called on each request.
@@ -74,11 +260,342 @@ impl ClientBuilder {
             base_url: self.base_url,
             http_client: self.http_client,
             middleware: mw,
+            ..self
+        }
+    }
+
+    /// Send an HTTP/2 PING frame every `interval`, also while no requests are in flight, and
+    /// close the connection if the server doesn't acknowledge one within `timeout`. This keeps
+    /// NATs and proxies from silently dropping pooled connections. Defaults to
+    /// [`DEFAULT_HTTP2_KEEP_ALIVE_INTERVAL`] and [`DEFAULT_HTTP2_KEEP_ALIVE_TIMEOUT`].
+    ///
+    /// Like the other connection settings, this only applies to builders made with
+    /// [`from_base_url`](Self::from_base_url).
+    pub fn http2_keep_alive(self, interval: Duration, timeout: Duration) -> Self {
+        Self {
+            http2_keep_alive: (interval, timeout),
+            ..self
+        }
+    }
+
+    /// Close pooled connections that have been idle for `timeout` (`None` to keep them open).
+    /// Defaults to [`DEFAULT_POOL_IDLE_TIMEOUT`].
+    pub fn pool_idle_timeout(self, timeout: Option<Duration>) -> Self {
+        Self {
+            pool_idle_timeout: timeout,
+            ..self
+        }
+    }
+
+    /// Whether to disable Nagle's algorithm (set `TCP_NODELAY`) on connections, so small requests
+    /// are sent right away instead of being held back to coalesce with more data. On by default,
+    /// as twirp calls are a request followed by a wait for the response, where any delay adds to
+    /// the latency of every call. Turning it off can make better use of the network for clients
+    /// streaming many small writes, at the cost of latency.
+    pub fn tcp_nodelay(self, enabled: bool) -> Self {
+        Self {
+            tcp_nodelay: enabled,
+            ..self
+        }
+    }
+
+    /// Send requests over the Windows named pipe `pipe_name` (e.g. `\\.\pipe\my-service`), to
+    /// a server started with [`serve_named_pipe`](crate::server::serve_named_pipe), instead of
+    /// over TCP. The base URL still names the server: its path prefixes the method paths, and its
+    /// host is sent in the `Host` header.
+    ///
+    /// Every request is sent over HTTP/1.1 on a connection of its own, after all the middleware,
+    /// and its response is read in full before the client sees it. The `http_client` and the
+    /// other connection settings of the builder aren't used.
+    #[cfg(windows)]
+    pub fn named_pipe(self, pipe_name: impl Into<String>) -> Self {
+        Self {
+            named_pipe: Some(pipe_name.into()),
+            ..self
+        }
+    }
+
+    /// Whether to follow redirects (up to 10 in a row). Off by default, unlike `reqwest`'s own
+    /// default, since a twirp server answering with a redirect (say, to a login page) is
+    /// misconfigured, and following it would send the call somewhere it wasn't meant to go. The
+    /// redirect fails the request with a [`ClientError::HttpError`] instead, with the redirect's
+    /// status and location.
+    ///
+    /// Like the connection settings, this only applies to builders made with
+    /// [`from_base_url`](Self::from_base_url).
+    pub fn follow_redirects(self, enabled: bool) -> Self {
+        Self {
+            follow_redirects: enabled,
+            ..self
+        }
+    }
+
+    /// Cache responses that come with an `ETag` header, and revalidate them with `If-None-Match`
+    /// when the same method is called again with the same request. A `304 Not Modified` response
+    /// then returns the cached response without transferring it again.
+    ///
+    /// At most `capacity` responses are kept, each for at most `ttl` after it was received (after
+    /// which the next call makes an unconditional request).
+    pub fn response_cache(self, capacity: usize, ttl: Duration) -> Self {
+        Self {
+            response_cache: Some(ResponseCache::new(capacity, ttl)),
+            ..self
+        }
+    }
+
+    /// Fail calls whose response body is larger than `bytes` with
+    /// [`ClientError::ResponseTooLarge`], without reading more of it than that. No limit by
+    /// default.
+    ///
+    /// The limit applies to the body as sent. Decompressed responses have a limit of their own
+    /// (see `max_decompressed_size`, with the `gzip` feature).
+    pub fn max_response_size(self, bytes: usize) -> Self {
+        Self {
+            max_response_size: Some(bytes),
+            ..self
+        }
+    }
+
+    /// Ask servers to compress responses with gzip (in the `Accept-Encoding` header of requests),
+    /// and decompress the ones that are, including the bodies of [`Client::request_raw`]. Off by default, and only available with the `gzip` feature.
+    ///
+    /// Responses are decompressed as they are read, and fail with
+    /// [`ClientError::DecompressedResponseTooLarge`] as soon as they grow larger than
+    /// [`max_decompressed_size`](Self::max_decompressed_size), so a small response from a
+    /// malicious server can't expand into one that exhausts the client's memory.
+    #[cfg(feature = "gzip")]
+    pub fn gzip_responses(self, enabled: bool) -> Self {
+        Self {
+            gzip_responses: enabled,
+            ..self
+        }
+    }
+
+    /// Fail calls whose compressed response grows larger than `bytes` when decompressed with
+    /// [`ClientError::DecompressedResponseTooLarge`]. Defaults to [`DEFAULT_DECOMPRESSION_RATIO`]
+    /// times the [`max_response_size`](Self::max_response_size), or to
+    /// [`DEFAULT_MAX_DECOMPRESSED_SIZE`] without one. Only available with the `gzip` feature.
+    #[cfg(feature = "gzip")]
+    pub fn max_decompressed_size(self, bytes: usize) -> Self {
+        Self {
+            max_decompressed_size: Some(bytes),
+            ..self
+        }
+    }
+
+    /// Send `user_agent` as the `User-Agent` of every request, instead of
+    /// [`DEFAULT_USER_AGENT`] (`twirp-rs/<version>`).
+    pub fn user_agent(self, user_agent: impl Into<String>) -> Self {
+        Self {
+            user_agent: Some(user_agent.into()),
+            ..self
+        }
+    }
+
+    /// Retry requests once with JSON if the server rejects protobuf with a `415 Unsupported Media
+    /// Type` response or a `bad_route` error, as servers that only support JSON do. This eases
+    /// migrations where clients are updated before servers. Each fallback is logged as a warning
+    /// (with `tracing`). Off by default, and only available with the `json` feature.
+    #[cfg(feature = "json")]
+    pub fn json_fallback(self, enabled: bool) -> Self {
+        Self {
+            json_fallback: enabled,
+            ..self
+        }
+    }
+
+    /// Fail requests that take longer than `timeout` (each attempt, with
+    /// [`retries`](Self::retries)), and send the timeout to the server in the
+    /// [`REQUEST_TIMEOUT_HEADER`] header so handlers know when to give up (see
+    /// [`Context::deadline`](crate::Context::deadline)).
+    pub fn request_timeout(self, timeout: Duration) -> Self {
+        Self {
+            request_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Retry requests failing with a [retryable](TwirpErrorResponse::is_retryable) error up to
+    /// `max_retries` times, waiting `backoff` before the first retry and twice as long before
+    /// each one after that. When the error response has a `Retry-After` header (in seconds or as
+    /// an HTTP date), the client waits as long as it says instead, up to
+    /// [`max_retry_after`](Self::max_retry_after). No retries by default.
+    pub fn retries(self, max_retries: u32, backoff: Duration) -> Self {
+        Self {
+            retries: Some((max_retries, backoff)),
+            ..self
+        }
+    }
+
+    /// Wait at most `max` before a retry when the server's `Retry-After` asks for longer, so a
+    /// misbehaving server can't stall calls indefinitely. Defaults to [`DEFAULT_MAX_RETRY_AFTER`].
+    pub fn max_retry_after(self, max: Duration) -> Self {
+        Self {
+            max_retry_after: max,
+            ..self
+        }
+    }
+
+    /// Stop retrying calls `deadline` after they started: waits before retries are cut short to
+    /// end by then, and once it has passed, the last error is returned. No deadline by default.
+    pub fn retry_deadline(self, deadline: Duration) -> Self {
+        Self {
+            retry_deadline: Some(deadline),
+            ..self
         }
     }
 
+    /// Hedge requests to the methods `policy` allows: see [`HedgePolicy`]. No hedging by default.
+    pub fn hedge(self, policy: HedgePolicy) -> Self {
+        Self {
+            hedge_policy: Some(policy),
+            ..self
+        }
+    }
+
+    /// Generate the ID sent with every call in the [`REQUEST_ID_HEADER`] header with `generate`,
+    /// instead of [`uuid_v4`](crate::request_id::uuid_v4). Retries and hedged requests of a call
+    /// send the same ID.
+    pub fn request_id_generator<F>(self, generate: F) -> Self
+    where
+        F: Fn() -> String + Send + Sync + 'static,
+    {
+        Self {
+            request_id_generator: Arc::new(generate),
+            ..self
+        }
+    }
+
+    /// Call `rewrite` with the method path (e.g. `service.haberdash.v1.HaberdasherAPI/MakeHat`) and
+    /// URL of every request, and send the request to the URL it returns instead, e.g. to route
+    /// some methods to a canary deployment.
+    pub fn url_rewriter<F>(self, rewrite: F) -> Self
+    where
+        F: Fn(&str, &Url) -> Url + Send + Sync + 'static,
+    {
+        Self {
+            url_rewriter: Some(Box::new(rewrite)),
+            ..self
+        }
+    }
+
+    /// Call `observe` with the [`CallMetrics`] of every response the client gets for a call made
+    /// with [`Client::request`] (or another method decoding the response), e.g. to record latency
+    /// and bandwidth per method. Retries and hedged requests are reported separately.
+    pub fn on_call<F>(self, observe: F) -> Self
+    where
+        F: Fn(&CallMetrics) + Send + Sync + 'static,
+    {
+        Self {
+            on_call: Some(Box::new(observe)),
+            ..self
+        }
+    }
+
+    /// Sign every request body with HMAC-SHA256 under `key`, sending the signature in the
+    /// `header_name` header (see [`twirp::signing`](crate::signing) for its format). The signer
+    /// runs after all other middleware, so it signs the request that is actually sent.
+    ///
+    /// Use [`with`](Self::with) and an [`HmacSigner`](crate::signing::HmacSigner) to also sign the
+    /// method and a timestamp.
+    #[cfg(feature = "hmac")]
+    pub fn hmac_signer(
+        self,
+        key: impl Into<Vec<u8>>,
+        header_name: reqwest::header::HeaderName,
+    ) -> Self {
+        Self {
+            hmac_signer: Some(crate::signing::HmacSigner::new(key, header_name)),
+            ..self
+        }
+    }
+
+    /// Build the client. Method paths are appended to the path of the base URL, which is taken to
+    /// end in `/` whether or not it does, so `http://localhost/twirp` and `http://localhost/twirp/`
+    /// are the same base URL.
     pub fn build(self) -> Result<Client> {
-        Client::new(self.base_url, self.http_client, self.middleware)
+        let mut base_url = self.base_url;
+        if base_url.cannot_be_a_base() {
+            return Err(ClientError::InvalidBaseUrl(base_url));
+        }
+        if !matches!(base_url.scheme(), "http" | "https") {
+            return Err(ClientError::UnsupportedScheme {
+                scheme: base_url.scheme().to_string(),
+                url: base_url,
+            });
+        }
+        if !base_url.path().ends_with('/') {
+            // Otherwise joining a method path would replace the last segment.
+            let path = format!("{}/", base_url.path());
+            base_url.set_path(&path);
+        }
+        #[allow(unused_mut)]
+        let mut middlewares = self.middleware;
+        #[cfg(feature = "hmac")]
+        if let Some(signer) = self.hmac_signer {
+            middlewares.push(Box::new(signer));
+        }
+        #[cfg(windows)]
+        if let Some(pipe_name) = self.named_pipe {
+            middlewares.push(Box::new(crate::named_pipe::NamedPipeTransport::new(
+                pipe_name,
+            )));
+        }
+        let user_agent = match self.user_agent {
+            Some(user_agent) => HeaderValue::try_from(user_agent)?,
+            None => HeaderValue::from_static(DEFAULT_USER_AGENT),
+        };
+        let http_client = match self.http_client {
+            Some(http_client) => http_client,
+            None => {
+                let (interval, timeout) = self.http2_keep_alive;
+                reqwest::Client::builder()
+                    .http2_keep_alive_interval(interval)
+                    .http2_keep_alive_timeout(timeout)
+                    .http2_keep_alive_while_idle(true)
+                    .pool_idle_timeout(self.pool_idle_timeout)
+                    .tcp_nodelay(self.tcp_nodelay)
+                    .redirect(if self.follow_redirects {
+                        reqwest::redirect::Policy::limited(10)
+                    } else {
+                        reqwest::redirect::Policy::none()
+                    })
+                    .build()?
+            }
+        };
+        Ok(Client {
+            http_client,
+            inner: Arc::new(ClientRef {
+                base_url,
+                middlewares,
+                response_cache: self.response_cache,
+                max_response_size: self.max_response_size,
+                #[cfg(feature = "gzip")]
+                gzip_responses: self.gzip_responses,
+                #[cfg(feature = "gzip")]
+                max_decompressed_size: self.max_decompressed_size.unwrap_or_else(|| {
+                    self.max_response_size
+                        .map_or(DEFAULT_MAX_DECOMPRESSED_SIZE, |size| {
+                            size.saturating_mul(DEFAULT_DECOMPRESSION_RATIO)
+                        })
+                }),
+                user_agent,
+                #[cfg(feature = "json")]
+                json_fallback: self.json_fallback,
+                url_rewriter: self.url_rewriter,
+                on_call: self.on_call,
+                retries: self.retries,
+                max_retry_after: self.max_retry_after,
+                retry_deadline: self.retry_deadline,
+                request_timeout: self.request_timeout,
+                hedge_policy: self.hedge_policy,
+                request_id_generator: self.request_id_generator,
+                request_counters: RequestCounters::default(),
+                server_twirp_version: Mutex::new(None),
+            }),
+            host: None,
+            encoding: Encoding::Protobuf,
+        })
     }
 }
 
@@ -92,11 +609,31 @@ pub struct Client {
     http_client: reqwest::Client,
     inner: Arc<ClientRef>,
     host: Option<String>,
+    encoding: Encoding,
 }
 
 struct ClientRef {
     base_url: Url,
     middlewares: Vec<Box<dyn Middleware>>,
+    response_cache: Option<ResponseCache>,
+    max_response_size: Option<usize>,
+    #[cfg(feature = "gzip")]
+    gzip_responses: bool,
+    #[cfg(feature = "gzip")]
+    max_decompressed_size: usize,
+    user_agent: HeaderValue,
+    #[cfg(feature = "json")]
+    json_fallback: bool,
+    url_rewriter: Option<Box<UrlRewriter>>,
+    on_call: Option<Box<CallObserver>>,
+    retries: Option<(u32, Duration)>,
+    max_retry_after: Duration,
+    retry_deadline: Option<Duration>,
+    request_timeout: Option<Duration>,
+    hedge_policy: Option<HedgePolicy>,
+    request_id_generator: RequestIdGenerator,
+    request_counters: RequestCounters,
+    server_twirp_version: Mutex<Option<String>>,
 }
 
 impl std::fmt::Debug for Client {
@@ -119,26 +656,32 @@ impl Client {
         http_client: reqwest::Client,
         middlewares: Vec<Box<dyn Middleware>>,
     ) -> Result<Self> {
-        if base_url.path().ends_with('/') {
-            Ok(Client {
-                http_client,
-                inner: Arc::new(ClientRef {
-                    base_url,
-                    middlewares,
-                }),
-                host: None,
-            })
-        } else {
-            Err(ClientError::InvalidBaseUrl(base_url))
+        ClientBuilder {
+            middleware: middlewares,
+            ..ClientBuilder::new(base_url, http_client)
         }
+        .build()
     }
 
-    /// Creates a `twirp::Client` with the default `reqwest::ClientBuilder`.
+    /// Creates a `twirp::Client` with the default connection settings of
+    /// [`ClientBuilder::from_base_url`].
     ///
     /// The underlying `reqwest::Client` holds a connection pool internally, so it is advised that
     /// you create one and **reuse** it.
     pub fn from_base_url(base_url: Url) -> Result<Self> {
-        Self::new(base_url, reqwest::Client::new(), vec![])
+        ClientBuilder::from_base_url(base_url).build()
+    }
+
+    /// Creates a `twirp::Client` configured from environment variables starting with `prefix`
+    /// (see [`ClientBuilder::from_env`]).
+    pub fn from_env(prefix: &str) -> Result<Self> {
+        ClientBuilder::from_env(prefix)?.build()
+    }
+
+    /// Like [`from_env`](Self::from_env), getting the value of each variable from `lookup`
+    /// instead of the process's environment (see [`ClientBuilder::from_env_with`]).
+    pub fn from_env_with(prefix: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        ClientBuilder::from_env_with(prefix, lookup)?.build()
     }
 
     pub fn base_url(&self) -> &Url {
@@ -152,136 +695,1533 @@ impl Client {
             http_client: self.http_client.clone(),
             inner: self.inner.clone(),
             host: Some(host.to_string()),
+            encoding: self.encoding,
+        }
+    }
+
+    /// Creates a new `twirp::Client` with the same configuration as the current one, but that
+    /// encodes requests (and so gets responses) with `encoding`, e.g. JSON to make calls easier
+    /// to inspect while debugging. The response cache only stores protobuf responses.
+    pub fn with_encoding(&self, encoding: Encoding) -> Self {
+        Self {
+            encoding,
+            ..self.clone()
         }
     }
 
     /// Make an HTTP twirp request.
+    ///
+    /// Dropping the returned future cancels the request: the in-flight HTTP request is aborted
+    /// rather than left to complete in the background, so the server can notice the client went
+    /// away (see [`Context::cancellation_token`](crate::Context::cancellation_token)).
     pub async fn request<I, O>(&self, path: &str, body: I) -> Result<O>
     where
-        I: prost::Message,
-        O: prost::Message + Default,
+        I: prost::Message + JsonSerialize,
+        O: prost::Message + JsonDeserialize + Default,
     {
-        let mut url = self.inner.base_url.join(path)?;
-        if let Some(host) = &self.host {
-            url.set_host(Some(host))?
+        Ok(self.request_with_meta(path, body).await?.0)
+    }
+
+    /// Make an HTTP twirp request, and check the response with its generated `validate()` method,
+    /// failing with [`ClientError::InvalidResponse`] if it breaks a rule. Generated clients call
+    /// this for services configured with `twirp_build::ServiceGenerator::validate_responses`.
+    pub async fn request_validated<I, O>(&self, path: &str, body: I) -> Result<O>
+    where
+        I: prost::Message + JsonSerialize,
+        O: prost::Message + JsonDeserialize + Default + crate::details::Validate,
+    {
+        let resp: O = self.request(path, body).await?;
+        resp.validate().map_err(ClientError::InvalidResponse)?;
+        Ok(resp)
+    }
+
+    /// Make an HTTP twirp request, also returning the response metadata the handler set with
+    /// [`Context::set_meta`](crate::Context::set_meta). Errors carry it in their `meta` (see
+    /// [`ClientError::TwirpError`]).
+    pub async fn request_with_meta<I, O>(
+        &self,
+        path: &str,
+        body: I,
+    ) -> Result<(O, HashMap<String, String>)>
+    where
+        I: prost::Message + JsonSerialize,
+        O: prost::Message + JsonDeserialize + Default,
+    {
+        let request_id = (self.inner.request_id_generator)();
+        match self.send(path, &body, self.encoding, &request_id).await {
+            #[cfg(feature = "json")]
+            Err(err)
+                if self.encoding == Encoding::Protobuf
+                    && self.inner.json_fallback
+                    && rejects_encoding(&err) =>
+            {
+                tracing::warn!(path, error = %err, "protobuf request rejected, retrying with JSON");
+                self.send(path, &body, Encoding::Json, &request_id).await
+            }
+            res => res,
+        }
+    }
+
+    async fn send<I, O>(
+        &self,
+        path: &str,
+        body: &I,
+        encoding: Encoding,
+        request_id: &str,
+    ) -> Result<(O, HashMap<String, String>)>
+    where
+        I: prost::Message + JsonSerialize,
+        O: prost::Message + JsonDeserialize + Default,
+    {
+        let Some((max_retries, backoff)) = self.inner.retries else {
+            return self
+                .send_hedged(path, body, encoding, request_id, &mut None)
+                .await;
+        };
+        let deadline = self
+            .inner
+            .retry_deadline
+            .map(|deadline| Instant::now() + deadline);
+        let mut retries = 0;
+        loop {
+            let mut retry_after = None;
+            match self
+                .send_hedged(path, body, encoding, request_id, &mut retry_after)
+                .await
+            {
+                Err(ClientError::TwirpError(err))
+                    if err.is_retryable() && retries < max_retries =>
+                {
+                    let mut delay = match retry_after {
+                        Some(delay) => delay.min(self.inner.max_retry_after),
+                        None => backoff.saturating_mul(2u32.saturating_pow(retries)),
+                    };
+                    if let Some(deadline) = deadline {
+                        let remaining = deadline.saturating_duration_since(Instant::now());
+                        if remaining.is_zero() {
+                            return Err(ClientError::TwirpError(err));
+                        }
+                        delay = delay.min(remaining);
+                    }
+                    tracing::debug!(path, error = ?err, ?delay, "retrying twirp request");
+                    tokio::time::sleep(delay).await;
+                    retries += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Make an attempt at a request, sending a second, identical request if the hedge policy
+    /// applies and the first hasn't completed after its delay. Whichever completes first wins,
+    /// and the other is cancelled.
+    async fn send_hedged<I, O>(
+        &self,
+        path: &str,
+        body: &I,
+        encoding: Encoding,
+        request_id: &str,
+        retry_after: &mut Option<Duration>,
+    ) -> Result<(O, HashMap<String, String>)>
+    where
+        I: prost::Message + JsonSerialize,
+        O: prost::Message + JsonDeserialize + Default,
+    {
+        let delay = match &self.inner.hedge_policy {
+            Some(policy) if policy.methods.contains(path) => policy.delay,
+            _ => {
+                return self
+                    .send_once(path, body, encoding, request_id, retry_after)
+                    .await
+            }
+        };
+        let (mut first_retry_after, mut second_retry_after) = (None, None);
+        let (res, first_won) = {
+            let first = self.send_once(path, body, encoding, request_id, &mut first_retry_after);
+            let second = async {
+                tokio::time::sleep(delay).await;
+                tracing::debug!(path, ?delay, "hedging twirp request");
+                self.send_once(path, body, encoding, request_id, &mut second_retry_after)
+                    .await
+            };
+            futures::pin_mut!(first, second);
+            // Dropping the loser at the end of this block aborts its HTTP request.
+            match futures::future::select(first, second).await {
+                futures::future::Either::Left((res, _)) => (res, true),
+                futures::future::Either::Right((res, _)) => (res, false),
+            }
+        };
+        *retry_after = if first_won {
+            first_retry_after
+        } else {
+            second_retry_after
         };
+        res
+    }
+
+    /// Make a single attempt at a request, setting `retry_after` to the delay the server asked
+    /// for with a `Retry-After` header if it fails.
+    async fn send_once<I, O>(
+        &self,
+        path: &str,
+        body: &I,
+        encoding: Encoding,
+        request_id: &str,
+        retry_after: &mut Option<Duration>,
+    ) -> Result<(O, HashMap<String, String>)>
+    where
+        I: prost::Message + JsonSerialize,
+        O: prost::Message + JsonDeserialize + Default,
+    {
+        let url = self.url(path)?;
         let path = url.path().to_string();
-        let req = self
-            .http_client
-            .post(url)
-            .header(CONTENT_TYPE, CONTENT_TYPE_PROTOBUF)
-            .body(serialize_proto_message(body))
-            .build()?;
+        let body = encoding.encode(body)?;
+        let cache_key = self
+            .inner
+            .response_cache
+            .as_ref()
+            .map(|_| (url.to_string(), body.clone()));
+        let cached = match (&self.inner.response_cache, &cache_key) {
+            (Some(cache), Some(key)) => cache.get(key),
+            _ => None,
+        };
+        let request_bytes = body.len();
+        let mut req = self.post(url, body, encoding, request_id);
+        if let Some((etag, _)) = &cached {
+            req = req.header(IF_NONE_MATCH, etag.clone());
+        }
+        #[cfg(feature = "gzip")]
+        if self.inner.gzip_responses {
+            req = req.header(ACCEPT_ENCODING, "gzip");
+        }
+        let req = req.build()?;
 
-        // Create and execute the middleware handlers
-        let next = Next::new(&self.http_client, &self.inner.middlewares);
-        let resp = next.run(req).await?;
+        let start = Instant::now();
+        let resp = self.execute(req).await?;
 
         // These have to be extracted because reading the body consumes `Response`.
         let status = resp.status();
         let content_type = resp.headers().get(CONTENT_TYPE).cloned();
 
-        // TODO: Include more info in the error cases: request path, content-type, etc.
+        let meta = response_meta(resp.headers());
+        let report = |response_bytes: usize, compressed_response_bytes: usize| {
+            if let Some(observe) = &self.inner.on_call {
+                observe(&CallMetrics {
+                    path: path.clone(),
+                    status,
+                    latency: start.elapsed(),
+                    request_bytes,
+                    response_bytes,
+                    compressed_response_bytes,
+                });
+            }
+        };
+
+        if let (StatusCode::NOT_MODIFIED, Some((_, body))) = (status, cached) {
+            report(0, 0);
+            return Ok((O::decode(&body[..])?, meta));
+        }
+
         match (status, content_type) {
             (status, Some(ct)) if status.is_success() && ct.as_bytes() == CONTENT_TYPE_PROTOBUF => {
-                O::decode(resp.bytes().await?).map_err(|e| e.into())
+                let etag = resp.headers().get(ETAG).cloned();
+                let (body, received) = self.read_body(resp).await?;
+                report(body.len(), received);
+                let res = O::decode(&body[..])?;
+                if let (Some(cache), Some(key), Some(etag)) =
+                    (&self.inner.response_cache, cache_key, etag)
+                {
+                    cache.insert(key, etag, body.to_vec());
+                }
+                Ok((res, meta))
             }
-            (status, Some(ct))
-                if (status.is_client_error() || status.is_server_error())
-                    && ct.as_bytes() == CONTENT_TYPE_JSON =>
-            {
-                Err(ClientError::TwirpError(serde_json::from_slice(
-                    &resp.bytes().await?,
-                )?))
+            #[cfg(feature = "json")]
+            (status, Some(ct)) if status.is_success() && ct.as_bytes() == CONTENT_TYPE_JSON => {
+                let (body, received) = self.read_body(resp).await?;
+                report(body.len(), received);
+                // Some servers send responses without fields as an empty body rather than `{}`.
+                let body = if body.is_empty() { &b"{}"[..] } else { &body };
+                Ok((serde_json::from_slice(body)?, meta))
+            }
+            _ => {
+                // Error bodies are read (and their size is known) while decoding the error.
+                let response_bytes = resp.content_length().unwrap_or_default();
+                let response_bytes = usize::try_from(response_bytes).unwrap_or(usize::MAX);
+                report(response_bytes, response_bytes);
+                *retry_after = resp.headers().get(RETRY_AFTER).and_then(parse_retry_after);
+                Err(error_from_response(resp, path).await)
             }
-            (status, ct) => Err(ClientError::HttpError {
-                status,
-                msg: "unknown error".to_string(),
-                path,
-                content_type: ct
-                    .map(|x| x.to_str().unwrap_or_default().to_string())
-                    .unwrap_or_default(),
-            }),
         }
     }
-}
 
-// This concept of reqwest middleware is taken pretty much directly from:
-// https://github.com/TrueLayer/reqwest-middleware, but simplified for the
-// specific needs of this twirp client.
-#[async_trait]
-pub trait Middleware: 'static + Send + Sync {
-    async fn handle(&self, mut req: reqwest::Request, next: Next<'_>) -> Result<reqwest::Response>;
-}
+    /// Send a request through the middleware.
+    async fn execute(&self, req: reqwest::Request) -> Result<reqwest::Response> {
+        let next = Next::new(
+            &self.http_client,
+            &self.inner.middlewares,
+            &self.inner.request_counters,
+        );
+        let resp = next.run(req).await;
+        let version = resp.as_ref().ok().and_then(|resp| {
+            let version = resp.headers().get(TWIRP_VERSION_HEADER)?;
+            Some(version.to_str().ok()?.to_string())
+        });
+        if let Some(version) = version {
+            *self
+                .inner
+                .server_twirp_version
+                .lock()
+                .expect("mutex poisoned") = Some(version);
+        }
+        resp
+    }
 
-#[async_trait]
-impl<F> Middleware for F
-where
-    F: Send
-        + Sync
-        + 'static
-        + for<'a> Fn(reqwest::Request, Next<'a>) -> BoxFuture<'a, Result<reqwest::Response>>,
-{
-    async fn handle(&self, req: reqwest::Request, next: Next<'_>) -> Result<reqwest::Response> {
-        (self)(req, next).await
+    /// The twirp protocol version the server sent in the
+    /// [`TWIRP_VERSION_HEADER`] header of the last response that had one (e.g. `v7`, see
+    /// [`TWIRP_VERSION`](crate::headers::TWIRP_VERSION)), for diagnosing fleets running mixed
+    /// versions. `None` until the client (or one of its clones) received such a response.
+    pub fn server_twirp_version(&self) -> Option<String> {
+        self.inner
+            .server_twirp_version
+            .lock()
+            .expect("mutex poisoned")
+            .clone()
     }
-}
 
-#[derive(Clone)]
-pub struct Next<'a> {
-    client: &'a reqwest::Client,
-    middlewares: &'a [Box<dyn Middleware>],
-}
+    /// Statistics about the HTTP requests this client (and its clones) sent. See
+    /// [`RequestStats`].
+    pub fn request_stats(&self) -> RequestStats {
+        self.inner.request_counters.stats()
+    }
 
-pub type BoxFuture<'a, T> = std::pin::Pin<Box<dyn std::future::Future<Output = T> + Send + 'a>>;
+    /// Read the body of a response, checking it against the size limits and decompressing it if it
+    /// is compressed. Returns the body and the number of bytes received for it.
+    async fn read_body(&self, mut resp: reqwest::Response) -> Result<(bytes::Bytes, usize)> {
+        let max_size = self.inner.max_response_size;
+        if let (Some(limit), Some(len)) = (max_size, resp.content_length()) {
+            if len > limit as u64 {
+                return Err(ClientError::ResponseTooLarge { limit });
+            }
+        }
+        #[cfg(feature = "gzip")]
+        let gzip = is_gzip(resp.headers());
+        #[cfg(not(feature = "gzip"))]
+        let gzip = false;
+        if max_size.is_none() && !gzip {
+            let body = resp.bytes().await?;
+            let received = body.len();
+            return Ok((body, received));
+        }
 
-impl<'a> Next<'a> {
-    pub(crate) fn new(client: &'a reqwest::Client, middlewares: &'a [Box<dyn Middleware>]) -> Self {
-        Next {
-            client,
-            middlewares,
+        #[cfg(feature = "gzip")]
+        if gzip {
+            let (buf, received) = self.read_gzip_body(resp).await?;
+            return Ok((buf.into(), received));
+        }
+        let mut buf = vec![];
+        let mut read = 0;
+        while let Some(chunk) = resp.chunk().await? {
+            read += chunk.len();
+            if let Some(limit) = max_size.filter(|&limit| read > limit) {
+                return Err(ClientError::ResponseTooLarge { limit });
+            }
+            buf.extend_from_slice(&chunk);
         }
+        Ok((buf.into(), read))
     }
 
-    pub fn run(mut self, req: reqwest::Request) -> BoxFuture<'a, Result<reqwest::Response>> {
-        if let Some((current, rest)) = self.middlewares.split_first() {
-            self.middlewares = rest;
-            Box::pin(current.handle(req, self))
-        } else {
-            Box::pin(async move { self.client.execute(req).await.map_err(ClientError::from) })
+    /// Decompress a gzip response body as it arrives, stopping as soon as either the
+    /// compressed or the decompressed body is over its limit. Returns the decompressed body and
+    /// the size of the compressed one.
+    #[cfg(feature = "gzip")]
+    async fn read_gzip_body(&self, mut resp: reqwest::Response) -> Result<(Vec<u8>, usize)> {
+        use std::io::Write;
+
+        let limit = self.inner.max_decompressed_size;
+        let mut decoder = flate2::write::GzDecoder::new(LimitedWriter { buf: vec![], limit });
+        let decompress_error = |err| decompress_error(err, limit);
+        let mut read = 0;
+        while let Some(chunk) = resp.chunk().await? {
+            read += chunk.len();
+            if let Some(limit) = self.inner.max_response_size.filter(|&limit| read > limit) {
+                return Err(ClientError::ResponseTooLarge { limit });
+            }
+            decoder.write_all(&chunk).map_err(decompress_error)?;
         }
+        Ok((decoder.finish().map_err(decompress_error)?.buf, read))
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use reqwest::{Request, Response};
 
-    use crate::test::*;
+    /// Check the body of a response to [`Client::request_raw`] against the size limits as it is
+    /// read, decompressing it if it is compressed.
+    fn limit_raw_body(&self, resp: reqwest::Response) -> Result<reqwest::Response> {
+        use futures::StreamExt;
 
-    use super::*;
+        let max_size = self.inner.max_response_size;
+        if let (Some(limit), Some(len)) = (max_size, resp.content_length()) {
+            if len > limit as u64 {
+                return Err(ClientError::ResponseTooLarge { limit });
+            }
+        }
+        #[cfg(feature = "gzip")]
+        let gzip = is_gzip(resp.headers());
+        #[cfg(not(feature = "gzip"))]
+        let gzip = false;
+        if max_size.is_none() && !gzip {
+            return Ok(resp);
+        }
 
-    struct AssertRouting {
-        expected_url: &'static str,
+        let (parts, body) = http::Response::<reqwest::Body>::from(resp).into_parts();
+        let mut read = 0;
+        let chunks = http_body_util::BodyDataStream::new(body).map(move |chunk| {
+            let chunk = chunk?;
+            read += chunk.len();
+            match max_size.filter(|&limit| read > limit) {
+                Some(limit) => Err(ClientError::ResponseTooLarge { limit }),
+                None => Ok(chunk),
+            }
+        });
+        #[cfg(feature = "gzip")]
+        if gzip {
+            // The body is no longer the one these describe.
+            let mut parts = parts;
+            parts.headers.remove(CONTENT_ENCODING);
+            parts.headers.remove(reqwest::header::CONTENT_LENGTH);
+            let chunks = gunzip(chunks, self.inner.max_decompressed_size);
+            return Ok(raw_response(parts, chunks));
+        }
+        Ok(raw_response(parts, chunks))
     }
 
-    #[async_trait]
-    impl Middleware for AssertRouting {
-        async fn handle(&self, req: Request, next: Next<'_>) -> Result<Response> {
-            assert_eq!(self.expected_url, &req.url().to_string());
+    /// Make an HTTP twirp request, returning the successful response without reading its body.
+    ///
+    /// This lets large responses be processed as they arrive (e.g. with
+    /// `reqwest::Response::chunk`) rather than decoded into a message all at once. Error responses
+    /// are decoded as with [`Client::request`]. The response cache is not used.
+    ///
+    /// The body is checked against the size limits and decompressed as it is read, so reading it
+    /// fails once it is over [`ClientBuilder::max_response_size`] (or, decompressed, the
+    /// `max_decompressed_size`), with the [`ClientError`] among the sources of the `reqwest::Error`.
+    pub async fn request_raw<I>(&self, path: &str, body: I) -> Result<reqwest::Response>
+    where
+        I: prost::Message + JsonSerialize,
+    {
+        let url = self.url(path)?;
+        let path = url.path().to_string();
+        #[allow(unused_mut)]
+        let mut req = self.post(
+            url,
+            self.encoding.encode(&body)?,
+            self.encoding,
+            &(self.inner.request_id_generator)(),
+        );
+        #[cfg(feature = "gzip")]
+        if self.inner.gzip_responses {
+            req = req.header(ACCEPT_ENCODING, "gzip");
+        }
+        let req = req.build()?;
+        let resp = self.execute(req).await?;
+
+        let is_encoded = resp
+            .headers()
+            .get(CONTENT_TYPE)
+            .map_or(false, |ct| ct.as_bytes() == self.encoding.content_type());
+        if resp.status().is_success() && is_encoded {
+            self.limit_raw_body(resp)
+        } else {
+            Err(error_from_response(resp, path).await)
+        }
+    }
+
+    /// Check that the server is reachable and serving, e.g. in a readiness probe or before
+    /// starting work, by calling the method at `path` with an empty request and timing the round
+    /// trip. Use a method without side effects that accepts an empty request, like a health check
+    /// taking `google.protobuf.Empty`.
+    ///
+    /// Returns an error only if no twirp response came back (e.g. the server couldn't be reached).
+    /// Error responses are part of the [`Connectivity`]: a server that isn't
+    /// [ready](crate::server::Options::readiness) responds with `unavailable`.
+    pub async fn check_connectivity(&self, path: &str) -> Result<Connectivity> {
+        let start = Instant::now();
+        // `()` is the empty message, and only encodes as such with protobuf.
+        let res: Result<()> = self
+            .with_encoding(Encoding::Protobuf)
+            .request(path, ())
+            .await;
+        let latency = start.elapsed();
+        match res {
+            Ok(()) => Ok(Connectivity {
+                latency,
+                error: None,
+            }),
+            Err(ClientError::TwirpError(err)) => Ok(Connectivity {
+                latency,
+                error: Some(err),
+            }),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn post(
+        &self,
+        url: Url,
+        body: Vec<u8>,
+        encoding: Encoding,
+        request_id: &str,
+    ) -> reqwest::RequestBuilder {
+        let mut req = self
+            .http_client
+            .post(url)
+            .header(CONTENT_TYPE, encoding.content_type())
+            .header(USER_AGENT, self.inner.user_agent.clone())
+            .header(CLIENT_LANGUAGE_HEADER, CLIENT_LANGUAGE)
+            .header(REQUEST_ID_HEADER, request_id)
+            .body(body);
+        if let Some(timeout) = self.inner.request_timeout {
+            req = req
+                .timeout(timeout)
+                .header(REQUEST_TIMEOUT_HEADER, timeout.as_millis().to_string());
+        }
+        req
+    }
+
+    /// The URL of the method at `path`, which is always appended to the base URL, even if it
+    /// starts with `/`.
+    fn url(&self, path: &str) -> Result<Url> {
+        let mut url = self.inner.base_url.join(path.trim_start_matches('/'))?;
+        if let Some(host) = &self.host {
+            url.set_host(Some(host))?
+        };
+        if let Some(rewrite) = &self.inner.url_rewriter {
+            url = rewrite(path, &url);
+        }
+        Ok(url)
+    }
+}
+
+/// Which requests a [`Client`] hedges, and when: see [`ClientBuilder::hedge`].
+///
+/// A hedged request that hasn't completed after `delay` is sent again, with the same body and
+/// headers (middleware such as authentication runs for both), and the client takes whichever
+/// response arrives first, cancelling the other. This trims tail latency at the cost of extra
+/// load, so `delay` is typically around the method's 95th-percentile latency.
+///
+/// The server may handle both requests, so only methods marked [`idempotent`](Self::idempotent)
+/// are hedged.
+#[derive(Clone, Debug)]
+pub struct HedgePolicy {
+    delay: Duration,
+    methods: std::collections::HashSet<String>,
+}
+
+impl HedgePolicy {
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            methods: Default::default(),
+        }
+    }
+
+    /// Allow hedging requests to `path`, the method path passed to [`Client::request`] (e.g.
+    /// `example.Service/GetThing`, as generated clients do).
+    pub fn idempotent(mut self, path: impl Into<String>) -> Self {
+        self.methods.insert(path.into());
+        self
+    }
+}
+
+/// How a [`Client`] encodes requests. See [`Client::with_encoding`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// `application/protobuf`, the default.
+    #[default]
+    Protobuf,
+    /// `application/json`. Requires the `json` feature.
+    #[cfg(feature = "json")]
+    Json,
+}
+
+impl Encoding {
+    fn content_type(self) -> &'static [u8] {
+        match self {
+            Encoding::Protobuf => CONTENT_TYPE_PROTOBUF,
+            #[cfg(feature = "json")]
+            Encoding::Json => CONTENT_TYPE_JSON,
+        }
+    }
+
+    fn encode<I>(self, body: &I) -> Result<Vec<u8>>
+    where
+        I: prost::Message + JsonSerialize,
+    {
+        match self {
+            Encoding::Protobuf => Ok(body.encode_to_vec()),
+            #[cfg(feature = "json")]
+            Encoding::Json => serde_json::to_vec(body).map_err(ClientError::JsonEncodeError),
+        }
+    }
+}
+
+/// Whether the server rejected a request because of its encoding, which JSON-only servers do with
+/// a `415 Unsupported Media Type` or a `bad_route` error.
+#[cfg(feature = "json")]
+fn rejects_encoding(err: &ClientError) -> bool {
+    match err {
+        ClientError::HttpError { status, .. } => *status == StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        ClientError::TwirpError(err) => err.code == crate::TwirpErrorCode::BadRoute,
+        _ => false,
+    }
+}
+
+/// The error for a response that isn't a successful response.
+async fn error_from_response(resp: reqwest::Response, path: String) -> ClientError {
+    let status = resp.status();
+    let content_type = resp.headers().get(CONTENT_TYPE).cloned();
+
+    // TODO: Include more info in the error cases: request path, content-type, etc.
+    match content_type {
+        Some(ct)
+            if (status.is_client_error() || status.is_server_error())
+                && ct.as_bytes() == CONTENT_TYPE_JSON =>
+        {
+            let meta = response_meta(resp.headers());
+            match resp.bytes().await {
+                Ok(body) => match serde_json::from_slice::<TwirpErrorResponse>(&body) {
+                    Ok(mut err) => {
+                        for (key, value) in meta {
+                            err.meta.entry(key).or_insert(value);
+                        }
+                        ClientError::TwirpError(err)
+                    }
+                    Err(e) => e.into(),
+                },
+                Err(e) => e.into(),
+            }
+        }
+        ct => ClientError::HttpError {
+            status,
+            msg: match resp.headers().get(LOCATION) {
+                Some(location) if status.is_redirection() => {
+                    format!("redirect to {}", location.to_str().unwrap_or_default())
+                }
+                _ => "unknown error".to_string(),
+            },
+            path,
+            content_type: ct
+                .map(|x| x.to_str().unwrap_or_default().to_string())
+                .unwrap_or_default(),
+        },
+    }
+}
+
+/// The delay in a `Retry-After` header, which is either a number of seconds or an HTTP date.
+fn parse_retry_after(value: &HeaderValue) -> Option<Duration> {
+    let value = value.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(std::time::SystemTime::now())
+            .unwrap_or_default(),
+    )
+}
+
+/// Collects the `Twirp-Meta-*` response headers, keyed by the rest of their (lowercase) name.
+fn response_meta(headers: &HeaderMap) -> HashMap<String, String> {
+    headers
+        .iter()
+        .filter_map(|(name, value)| {
+            let key = name.as_str().strip_prefix(META_HEADER_PREFIX)?;
+            Some((key.to_string(), value.to_str().ok()?.to_string()))
+        })
+        .collect()
+}
+
+/// A response to [`Client::request_raw`] with its body replaced by `chunks`.
+fn raw_response<S>(parts: http::response::Parts, chunks: S) -> reqwest::Response
+where
+    S: futures::Stream<Item = Result<bytes::Bytes>> + Send + Sync + 'static,
+{
+    use futures::TryStreamExt;
+
+    let body = http_body_util::StreamBody::new(chunks.map_ok(hyper::body::Frame::data));
+    http::Response::from_parts(parts, reqwest::Body::wrap(body)).into()
+}
+
+/// Decompress a gzip body as its `chunks` arrive, failing once the decompressed body is larger
+/// than `limit`.
+#[cfg(feature = "gzip")]
+fn gunzip<S>(chunks: S, limit: usize) -> impl futures::Stream<Item = Result<bytes::Bytes>>
+where
+    S: futures::Stream<Item = Result<bytes::Bytes>> + Unpin,
+{
+    use futures::TryStreamExt;
+    use std::io::Write;
+
+    let decoder = flate2::write::GzDecoder::new(LimitedWriter {
+        buf: Vec::new(),
+        limit,
+    });
+    futures::stream::try_unfold(
+        (chunks, Some(decoder)),
+        move |(mut chunks, decoder)| async move {
+            let Some(mut decoder) = decoder else {
+                return Ok(None);
+            };
+            while let Some(chunk) = chunks.try_next().await? {
+                decoder
+                    .write_all(&chunk)
+                    .map_err(|err| decompress_error(err, limit))?;
+                let writer = decoder.get_mut();
+                if !writer.buf.is_empty() {
+                    // The limit is on the whole body, so what's left of it shrinks with each chunk.
+                    writer.limit -= writer.buf.len();
+                    let out = std::mem::take(&mut writer.buf);
+                    return Ok(Some((out.into(), (chunks, Some(decoder)))));
+                }
+            }
+            let rest = decoder
+                .finish()
+                .map_err(|err| decompress_error(err, limit))?
+                .buf;
+            Ok(Some((rest.into(), (chunks, None))))
+        },
+    )
+}
+
+#[cfg(feature = "gzip")]
+fn decompress_error(err: std::io::Error, limit: usize) -> ClientError {
+    match err.kind() {
+        std::io::ErrorKind::WriteZero => ClientError::DecompressedResponseTooLarge { limit },
+        _ => ClientError::MalformedResponse(format!("invalid gzip body: {err}")),
+    }
+}
+
+/// Whether a response body is compressed with gzip, by its `Content-Encoding` header.
+#[cfg(feature = "gzip")]
+fn is_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| v.trim().eq_ignore_ascii_case("gzip"))
+}
+
+/// Collects the output of a decompressor, refusing to write more than `limit` bytes (with a
+/// `WriteZero` error).
+#[cfg(feature = "gzip")]
+struct LimitedWriter {
+    buf: Vec<u8>,
+    limit: usize,
+}
+
+#[cfg(feature = "gzip")]
+impl std::io::Write for LimitedWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        if self.buf.len() + data.len() > self.limit {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The result of [`Client::check_connectivity`].
+#[derive(Debug)]
+pub struct Connectivity {
+    /// How long the call took, from sending the request to decoding the response.
+    pub latency: Duration,
+    /// The error the server responded with, if it did.
+    pub error: Option<TwirpErrorResponse>,
+}
+
+impl Connectivity {
+    /// Whether the call succeeded.
+    pub fn is_serving(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// A response to a call, as reported to the observer set with [`ClientBuilder::on_call`].
+///
+/// The client doesn't compress requests, so `request_bytes` is the size of the encoded message.
+/// Responses may be compressed (see `ClientBuilder::gzip_responses`, with the `gzip` feature):
+/// `compressed_response_bytes` is the size of the body as received and `response_bytes` its size
+/// once decompressed, and the two are the same for uncompressed responses.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CallMetrics {
+    /// The path of the request, e.g. `/twirp/example.haberdash.v1.Haberdasher/MakeHat`.
+    pub path: String,
+    /// The status of the response.
+    pub status: StatusCode,
+    /// How long it took from sending the request to receiving the response (and, for successful
+    /// responses, its body).
+    pub latency: Duration,
+    /// The size of the request body in bytes.
+    pub request_bytes: usize,
+    /// The size of the response body in bytes (after decompressing it, if the client did): 0 for
+    /// a `304 Not Modified` response served from the
+    /// [response cache](ClientBuilder::response_cache), and its `Content-Length` for error
+    /// responses.
+    pub response_bytes: usize,
+    /// The size of the response body in bytes as received, before decompressing it.
+    pub compressed_response_bytes: usize,
+}
+
+/// Statistics about the HTTP requests a [`Client`] sent with its `reqwest::Client`, from
+/// [`Client::request_stats`]. Retries and hedged requests count as separate requests, while calls
+/// that a [`Middleware`] answered without sending them (see [`Next::respond`]) don't count.
+///
+/// These count requests, not connections: `reqwest` doesn't tell when it opens or reuses one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RequestStats {
+    /// Requests sent and still waiting for their response headers.
+    pub in_flight: usize,
+    /// The most requests that were in flight at once.
+    pub peak_in_flight: usize,
+    /// Requests sent, including those in flight.
+    pub requests: u64,
+    /// Requests that failed without a response, e.g. because a connection couldn't be made.
+    pub failed: u64,
+    /// The total time requests spent waiting for their response headers, including the time
+    /// spent connecting but not the time spent in middleware.
+    pub wait_time: Duration,
+}
+
+#[derive(Default)]
+pub(crate) struct RequestCounters {
+    in_flight: AtomicUsize,
+    peak_in_flight: AtomicUsize,
+    requests: AtomicU64,
+    failed: AtomicU64,
+    wait_nanos: AtomicU64,
+}
+
+impl RequestCounters {
+    /// Count a new request, returning a guard that marks it as no longer in flight when dropped.
+    fn start(&self) -> InFlight<'_> {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let in_flight = self.in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak_in_flight.fetch_max(in_flight, Ordering::Relaxed);
+        InFlight(&self.in_flight)
+    }
+
+    fn finish(&self, waited: Duration, ok: bool) {
+        let nanos = u64::try_from(waited.as_nanos()).unwrap_or(u64::MAX);
+        self.wait_nanos.fetch_add(nanos, Ordering::Relaxed);
+        if !ok {
+            self.failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn stats(&self) -> RequestStats {
+        RequestStats {
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            peak_in_flight: self.peak_in_flight.load(Ordering::Relaxed),
+            requests: self.requests.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            wait_time: Duration::from_nanos(self.wait_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// A request in flight, including one whose future was dropped (e.g. the loser of a hedge).
+struct InFlight<'a>(&'a AtomicUsize);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+type CacheKey = (String, Vec<u8>);
+
+/// Responses cached by URL and serialized request, with their `ETag`.
+struct ResponseCache {
+    capacity: usize,
+    ttl: Duration,
+    entries: Mutex<HashMap<CacheKey, CacheEntry>>,
+}
+
+struct CacheEntry {
+    etag: HeaderValue,
+    body: Vec<u8>,
+    received: Instant,
+}
+
+impl ResponseCache {
+    fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<(HeaderValue, Vec<u8>)> {
+        let mut entries = self.entries.lock().expect("mutex poisoned");
+        match entries.get(key) {
+            Some(entry) if entry.received.elapsed() < self.ttl => {
+                Some((entry.etag.clone(), entry.body.clone()))
+            }
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, key: CacheKey, etag: HeaderValue, body: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().expect("mutex poisoned");
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            entries.retain(|_, entry| entry.received.elapsed() < self.ttl);
+        }
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            // Evict the oldest response.
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.received)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        let received = Instant::now();
+        entries.insert(
+            key,
+            CacheEntry {
+                etag,
+                body,
+                received,
+            },
+        );
+    }
+}
+
+// This concept of reqwest middleware is taken pretty much directly from:
+// https://github.com/TrueLayer/reqwest-middleware, but simplified for the
+// specific needs of this twirp client.
+#[async_trait]
+pub trait Middleware: 'static + Send + Sync {
+    async fn handle(&self, mut req: reqwest::Request, next: Next<'_>) -> Result<reqwest::Response>;
+}
+
+#[async_trait]
+impl<F> Middleware for F
+where
+    F: Send
+        + Sync
+        + 'static
+        + for<'a> Fn(reqwest::Request, Next<'a>) -> BoxFuture<'a, Result<reqwest::Response>>,
+{
+    async fn handle(&self, req: reqwest::Request, next: Next<'_>) -> Result<reqwest::Response> {
+        (self)(req, next).await
+    }
+}
+
+#[derive(Clone)]
+pub struct Next<'a> {
+    client: &'a reqwest::Client,
+    middlewares: &'a [Box<dyn Middleware>],
+    counters: &'a RequestCounters,
+}
+
+pub type BoxFuture<'a, T> = std::pin::Pin<Box<dyn std::future::Future<Output = T> + Send + 'a>>;
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(
+        client: &'a reqwest::Client,
+        middlewares: &'a [Box<dyn Middleware>],
+        counters: &'a RequestCounters,
+    ) -> Self {
+        Next {
+            client,
+            middlewares,
+            counters,
+        }
+    }
+
+    pub fn run(mut self, req: reqwest::Request) -> BoxFuture<'a, Result<reqwest::Response>> {
+        if let Some((current, rest)) = self.middlewares.split_first() {
+            self.middlewares = rest;
+            Box::pin(current.handle(req, self))
+        } else {
+            Box::pin(async move {
+                // Only requests that get this far are sent, and counted in the `RequestStats`.
+                let _in_flight = self.counters.start();
+                let start = Instant::now();
+                let resp = self.client.execute(req).await;
+                self.counters.finish(start.elapsed(), resp.is_ok());
+                resp.map_err(ClientError::from)
+            })
+        }
+    }
+
+    /// Respond to `req` with `message` instead of running the rest of the middleware and sending
+    /// it, e.g. to serve calls from a local cache or canned responses in an offline mode. The
+    /// message is encoded as the request is (see [`Encoding`]), so the client decodes it as it
+    /// would the server's response.
+    ///
+    /// ```
+    /// use twirp::{Middleware, Next};
+    /// use twirp::async_trait::async_trait;
+    /// use twirp::reqwest::{Request, Response};
+    ///
+    /// /// Answers every call with an empty message, which most responses decode from.
+    /// struct Offline;
+    ///
+    /// #[async_trait]
+    /// impl Middleware for Offline {
+    ///     async fn handle(&self, req: Request, next: Next<'_>) -> twirp::Result<Response> {
+    ///         next.respond(&req, &())
+    ///     }
+    /// }
+    /// ```
+    ///
+    /// To fail the call instead, return an error such as [`ClientError::TwirpError`].
+    pub fn respond<M>(self, req: &reqwest::Request, message: &M) -> Result<reqwest::Response>
+    where
+        M: prost::Message + JsonSerialize,
+    {
+        let encoding = match req.headers().get(CONTENT_TYPE) {
+            #[cfg(feature = "json")]
+            Some(ct) if ct.as_bytes() == CONTENT_TYPE_JSON => Encoding::Json,
+            _ => Encoding::Protobuf,
+        };
+        let resp = http::Response::builder()
+            .header(CONTENT_TYPE, encoding.content_type())
+            .body(encoding.encode(message)?)
+            .expect("always a valid response");
+        Ok(resp.into())
+    }
+}
+
+/// Sends its `Authorization` header with every request that doesn't have one, for
+/// [`ClientBuilder::from_env`].
+struct BearerToken(HeaderValue);
+
+#[async_trait]
+impl Middleware for BearerToken {
+    async fn handle(&self, mut req: reqwest::Request, next: Next<'_>) -> Result<reqwest::Response> {
+        req.headers_mut()
+            .entry(AUTHORIZATION)
+            .or_insert_with(|| self.0.clone());
+        next.run(req).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+    use reqwest::{Request, Response};
+
+    use crate::serialize_proto_message;
+    use crate::test::*;
+
+    use super::*;
+
+    struct AssertRouting {
+        expected_url: &'static str,
+    }
+
+    #[async_trait]
+    impl Middleware for AssertRouting {
+        async fn handle(&self, req: Request, next: Next<'_>) -> Result<Response> {
+            assert_eq!(self.expected_url, &req.url().to_string());
             next.run(req).await
         }
     }
 
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn test_named_pipe() {
+        let pipe_name = format!(r"\\.\pipe\twirp-test-{}", std::process::id());
+        let server = tokio::spawn({
+            let pipe_name = pipe_name.clone();
+            async move {
+                let config = crate::server::ServeConfig::new();
+                crate::server::serve_named_pipe(&pipe_name, test_api_router(), config).await
+            }
+        });
+        let client = ClientBuilder::from_base_url(Url::parse("http://localhost/twirp/").unwrap())
+            .named_pipe(pipe_name)
+            .build()
+            .unwrap();
+        let req = PingRequest {
+            name: "hi".to_string(),
+        };
+        // The pipe doesn't exist until the server runs.
+        let mut attempts = 0;
+        let resp = loop {
+            match client.ping(req.clone()).await {
+                Err(ClientError::NamedPipe(_)) if attempts < 100 => {
+                    attempts += 1;
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                res => break res.unwrap(),
+            }
+        };
+        assert_eq!(resp.name, "hi");
+
+        server.abort();
+    }
+
     #[tokio::test]
     async fn test_base_url() {
-        let url = Url::parse("http://localhost:3001/twirp/").unwrap();
-        assert!(Client::from_base_url(url).is_ok());
-        let url = Url::parse("http://localhost:3001/twirp").unwrap();
+        for (base_url, path, expected) in [
+            (
+                "http://localhost:3001/twirp/",
+                "test.TestAPI/Ping",
+                "/twirp/test.TestAPI/Ping",
+            ),
+            (
+                "http://localhost:3001/twirp",
+                "test.TestAPI/Ping",
+                "/twirp/test.TestAPI/Ping",
+            ),
+            (
+                "http://localhost:3001/twirp/",
+                "/test.TestAPI/Ping",
+                "/twirp/test.TestAPI/Ping",
+            ),
+            (
+                "http://localhost:3001/twirp",
+                "/test.TestAPI/Ping",
+                "/twirp/test.TestAPI/Ping",
+            ),
+            (
+                "http://localhost:3001/a/b/twirp",
+                "test.TestAPI/Ping",
+                "/a/b/twirp/test.TestAPI/Ping",
+            ),
+            (
+                "http://localhost:3001",
+                "test.TestAPI/Ping",
+                "/test.TestAPI/Ping",
+            ),
+            (
+                "http://localhost:3001/",
+                "/test.TestAPI/Ping",
+                "/test.TestAPI/Ping",
+            ),
+        ] {
+            let client = Client::from_base_url(Url::parse(base_url).unwrap()).unwrap();
+            let url = client.url(path).unwrap();
+            assert_eq!(url.path(), expected, "{base_url} + {path}");
+        }
+
+        let url = Url::parse("mailto:twirp@localhost").unwrap();
         assert_eq!(
             Client::from_base_url(url).unwrap_err().to_string(),
-            "base_url must end in /, but got: http://localhost:3001/twirp",
+            "base_url must be a URL that paths can be appended to, but got: mailto:twirp@localhost",
+        );
+
+        let url = Url::parse("htttp://localhost:3001/twirp/").unwrap();
+        let err = Client::from_base_url(url).unwrap_err();
+        assert!(
+            matches!(&err, ClientError::UnsupportedScheme { scheme, .. } if scheme == "htttp"),
+            "{err:?}"
+        );
+        assert_eq!(
+            err.to_string(),
+            "base_url must be an http or https URL, but got scheme \"htttp\" in: htttp://localhost:3001/twirp/",
+        );
+        let url = Url::parse("https://localhost:3001/twirp/").unwrap();
+        assert!(Client::from_base_url(url).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_from_env_settings() {
+        let app = axum::Router::new()
+            .route(
+                "/old/test.TestAPI/Ping",
+                axum::routing::post(|| async {
+                    (
+                        StatusCode::MOVED_PERMANENTLY,
+                        [(LOCATION, "/twirp/test.TestAPI/Ping")],
+                    )
+                }),
+            )
+            .route(
+                "/twirp/test.TestAPI/Ping",
+                axum::routing::post(|headers: axum::http::HeaderMap| async move {
+                    let header = |name| headers[name].to_str().unwrap().to_string();
+                    let name = format!(
+                        "{} {}",
+                        header(REQUEST_TIMEOUT_HEADER),
+                        header("authorization")
+                    );
+                    (
+                        [(CONTENT_TYPE, "application/protobuf")],
+                        PingResponse { name }.encode_to_vec(),
+                    )
+                }),
+            );
+        let server = crate::testing::TestServer::start(app).await;
+        let from_env = |prefix: &str| {
+            let url = format!("http://{}/{prefix}/", server.addr());
+            let vars = HashMap::from([
+                ("TWIRP_URL".to_string(), url),
+                ("TWIRP_TIMEOUT_MS".to_string(), "500".to_string()),
+                ("TWIRP_TOKEN".to_string(), "secret".to_string()),
+            ]);
+            Client::from_env_with("TWIRP", |name| vars.get(name).cloned()).unwrap()
+        };
+        let ping = || PingRequest {
+            name: "hi".to_string(),
+        };
+
+        // the timeout is sent to the server, with the token
+        let resp = from_env("twirp").ping(ping()).await.unwrap();
+        assert_eq!(resp.name, "500 Bearer secret");
+
+        // redirects aren't followed
+        let err = from_env("old").ping(ping()).await.unwrap_err();
+        assert!(
+            matches!(&err, ClientError::HttpError { status, .. }
+                if *status == StatusCode::MOVED_PERMANENTLY),
+            "{err:?}"
+        );
+
+        server.shutdown().await;
+    }
+
+    #[test]
+    fn test_from_env() {
+        let from_env = |prefix: &str, vars: &[(&str, &str)]| {
+            let vars: HashMap<String, String> = vars
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect();
+            Client::from_env_with(prefix, |name| vars.get(name).cloned())
+        };
+        let url = ("TWIRP_URL", "http://localhost:3001/twirp/");
+
+        let client = from_env(
+            "TWIRP",
+            &[url, ("TWIRP_TIMEOUT_MS", "500"), ("TWIRP_TOKEN", "secret")],
+        )
+        .unwrap();
+        assert_eq!(client.base_url().as_str(), "http://localhost:3001/twirp/");
+
+        for (vars, msg) in [
+            (&[][..], "invalid environment variable TWIRP_URL: not set"),
+            (
+                &[("TWIRP_URL", "not a url")][..],
+                "invalid environment variable TWIRP_URL: relative URL without a base",
+            ),
+            (
+                &[url, ("TWIRP_TIMEOUT_MS", "soon")][..],
+                "invalid environment variable TWIRP_TIMEOUT_MS: invalid digit found in string",
+            ),
+            (
+                &[url, ("TWIRP_TOKEN", "new\nline")][..],
+                "invalid environment variable TWIRP_TOKEN: failed to parse header value",
+            ),
+        ] {
+            assert_eq!(from_env("TWIRP", vars).unwrap_err().to_string(), msg);
+        }
+    }
+
+    struct AssertUserAgent(&'static str);
+
+    #[async_trait]
+    impl Middleware for AssertUserAgent {
+        async fn handle(&self, req: Request, next: Next<'_>) -> Result<Response> {
+            assert_eq!(req.headers()[USER_AGENT], self.0);
+            assert_eq!(req.headers()[CLIENT_LANGUAGE_HEADER], CLIENT_LANGUAGE);
+            next.run(req).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_user_agent() {
+        let base_url = Url::parse("http://localhost:3001/twirp/").unwrap();
+        assert!(DEFAULT_USER_AGENT.starts_with("twirp-rs/0."));
+        assert!(CLIENT_LANGUAGE.starts_with("rust/0."));
+        let ping = || PingRequest {
+            name: "hi".to_string(),
+        };
+
+        let client = ClientBuilder::new(base_url.clone(), reqwest::Client::new())
+            .with(AssertUserAgent(DEFAULT_USER_AGENT))
+            .build()
+            .unwrap();
+        assert!(client.ping(ping()).await.is_err()); // expected connection refused error.
+
+        let client = ClientBuilder::new(base_url.clone(), reqwest::Client::new())
+            .user_agent("haberdasher-cli/1.2")
+            .with(AssertUserAgent("haberdasher-cli/1.2"))
+            .build()
+            .unwrap();
+        assert!(client.ping(ping()).await.is_err());
+
+        assert!(matches!(
+            ClientBuilder::new(base_url, reqwest::Client::new())
+                .user_agent("bad\nagent")
+                .build(),
+            Err(ClientError::InvalidHeader(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_connection_settings() {
+        let server = crate::testing::TestServer::start(test_api_router()).await;
+        let base_url = server.base_url();
+
+        let client = ClientBuilder::from_base_url(base_url)
+            .http2_keep_alive(Duration::from_secs(5), Duration::from_secs(1))
+            .pool_idle_timeout(None)
+            .build()
+            .unwrap();
+        let resp = client
+            .ping(PingRequest {
+                name: "hi".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(resp.name, "hi");
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_url_rewriter() {
+        let base_url = Url::parse("http://localhost:3001/twirp/").unwrap();
+        let client = ClientBuilder::new(base_url, reqwest::Client::new())
+            .url_rewriter(|method, url| {
+                assert_eq!(method, "test.TestAPI/Ping");
+                let mut url = url.clone();
+                url.set_host(Some("canary.localhost")).unwrap();
+                url
+            })
+            .with(AssertRouting {
+                expected_url: "http://canary.localhost:3001/twirp/test.TestAPI/Ping",
+            })
+            .build()
+            .unwrap();
+        assert!(client
+            .ping(PingRequest {
+                name: "hi".to_string(),
+            })
+            .await
+            .is_err()); // expected connection refused error.
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let parse = |v: &str| parse_retry_after(&HeaderValue::from_str(v).unwrap());
+        assert_eq!(parse("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse("Wed, 21 Oct 2015 07:28:00 GMT"), Some(Duration::ZERO));
+        let later = std::time::SystemTime::now() + Duration::from_secs(3600);
+        let delay = parse(&httpdate::fmt_http_date(later)).unwrap();
+        assert!(delay > Duration::from_secs(3590) && delay <= Duration::from_secs(3600));
+        assert_eq!(parse("soon"), None);
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_limits() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use axum::response::IntoResponse;
+
+        // Always busy, asking for the retry in an hour.
+        let calls = Arc::new(AtomicUsize::new(0));
+        let c = calls.clone();
+        let app = axum::Router::new().route(
+            "/twirp/test.TestAPI/Ping",
+            axum::routing::post(move || async move {
+                c.fetch_add(1, Ordering::SeqCst);
+                let mut resp = crate::unavailable("busy").into_response();
+                resp.headers_mut()
+                    .insert(RETRY_AFTER, HeaderValue::from_static("3600"));
+                resp
+            }),
+        );
+        let server = crate::testing::TestServer::start(app).await;
+        let call = |client: Client| async move {
+            tokio::time::timeout(Duration::from_secs(5), client.ping(PingRequest::default()))
+                .await
+                .expect("the client waited as long as the server asked")
+        };
+
+        let client = ClientBuilder::from_base_url(server.base_url())
+            .retries(2, Duration::ZERO)
+            .max_retry_after(Duration::from_millis(10))
+            .build()
+            .unwrap();
+        crate::assert_twirp_error!(call(client).await, Unavailable);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // The deadline passes during the wait before the second retry.
+        calls.store(0, Ordering::SeqCst);
+        let client = ClientBuilder::from_base_url(server.base_url())
+            .retries(2, Duration::ZERO)
+            .retry_deadline(Duration::from_millis(50))
+            .build()
+            .unwrap();
+        crate::assert_twirp_error!(call(client).await, Unavailable);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_retries() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use axum::response::IntoResponse;
+
+        // Fails with `unavailable` until the third call, asking for the retry right away.
+        let calls = Arc::new(AtomicUsize::new(0));
+        let c = calls.clone();
+        let app = axum::Router::new().route(
+            "/twirp/test.TestAPI/Ping",
+            axum::routing::post(move || async move {
+                if c.fetch_add(1, Ordering::SeqCst) < 2 {
+                    let mut resp = crate::unavailable("busy").into_response();
+                    resp.headers_mut()
+                        .insert(RETRY_AFTER, HeaderValue::from_static("0"));
+                    return resp;
+                }
+                (
+                    [(CONTENT_TYPE, "application/protobuf")],
+                    serialize_proto_message(PingResponse {
+                        name: "hi".to_string(),
+                    }),
+                )
+                    .into_response()
+            }),
+        );
+        let server = crate::testing::TestServer::start(app).await;
+        let base_url = server.base_url();
+
+        // The backoff would time the test out if `Retry-After` weren't honored.
+        let client = ClientBuilder::from_base_url(base_url.clone())
+            .retries(2, Duration::from_secs(600))
+            .build()
+            .unwrap();
+        assert_eq!(
+            client.ping(PingRequest::default()).await.unwrap().name,
+            "hi"
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        calls.store(0, Ordering::SeqCst);
+        let client = ClientBuilder::from_base_url(base_url)
+            .retries(1, Duration::from_secs(600))
+            .build()
+            .unwrap();
+        crate::assert_twirp_error!(client.ping(PingRequest::default()).await, Unavailable);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_hedge() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // The first call hangs, and later ones respond right away.
+        let calls = Arc::new(AtomicUsize::new(0));
+        let c = calls.clone();
+        let app = axum::Router::new().route(
+            "/twirp/test.TestAPI/Ping",
+            axum::routing::post(move || async move {
+                let name = if c.fetch_add(1, Ordering::SeqCst) == 0 {
+                    tokio::time::sleep(Duration::from_secs(600)).await;
+                    "slow"
+                } else {
+                    "fast"
+                };
+                (
+                    [(CONTENT_TYPE, "application/protobuf")],
+                    serialize_proto_message(PingResponse {
+                        name: name.to_string(),
+                    }),
+                )
+            }),
+        );
+        let server = crate::testing::TestServer::start(app).await;
+        let base_url = server.base_url();
+
+        let client = ClientBuilder::from_base_url(base_url.clone())
+            .hedge(HedgePolicy::new(Duration::from_millis(50)).idempotent("test.TestAPI/Ping"))
+            .build()
+            .unwrap();
+        assert_eq!(
+            client.ping(PingRequest::default()).await.unwrap().name,
+            "fast"
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // Methods that aren't idempotent are never hedged.
+        calls.store(0, Ordering::SeqCst);
+        let client = ClientBuilder::from_base_url(base_url)
+            .hedge(HedgePolicy::new(Duration::from_millis(50)).idempotent("test.TestAPI/Boom"))
+            .build()
+            .unwrap();
+        let res = tokio::time::timeout(
+            Duration::from_millis(300),
+            client.ping(PingRequest::default()),
+        )
+        .await;
+        assert!(res.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        let app = axum::Router::new().nest(
+            "/twirp/test.TestAPI",
+            crate::details::TwirpRouterBuilder::new(())
+                .route(
+                    "/Ping",
+                    |_, ctx: crate::Context, req: PingRequest| async move {
+                        let remaining = ctx.remaining().unwrap();
+                        assert!(remaining <= Duration::from_secs(5), "{remaining:?}");
+                        if req.name == "slow" {
+                            tokio::time::sleep(Duration::from_secs(10)).await;
+                        }
+                        Ok(PingResponse { name: req.name })
+                    },
+                )
+                .build(),
+        );
+        let server = crate::testing::TestServer::start(app).await;
+
+        let base_url = server.base_url();
+        let client = ClientBuilder::from_base_url(base_url.clone())
+            .request_timeout(Duration::from_secs(5))
+            .build()
+            .unwrap();
+        let ping = |name: &str| PingRequest {
+            name: name.to_string(),
+        };
+        assert_eq!(client.ping(ping("hi")).await.unwrap().name, "hi");
+
+        let client = ClientBuilder::from_base_url(base_url)
+            .request_timeout(Duration::from_millis(50))
+            .build()
+            .unwrap();
+        let err = client.ping(ping("slow")).await.unwrap_err();
+        assert!(
+            matches!(&err, ClientError::ReqwestError(e) if e.is_timeout()),
+            "{err:?}"
         );
+
+        server.shutdown().await;
     }
 
     #[tokio::test]
@@ -302,6 +2242,796 @@ mod tests {
             .is_err()); // expected connection refused error.
     }
 
+    /// Answers calls to `Ping` itself, greeting the caller, and passes the rest on.
+    struct CannedPing;
+
+    #[async_trait]
+    impl Middleware for CannedPing {
+        async fn handle(&self, req: Request, next: Next<'_>) -> Result<Response> {
+            if req.url().path().ends_with("/Ping") {
+                let resp = PingResponse {
+                    name: "canned".to_string(),
+                };
+                return next.respond(&req, &resp);
+            }
+            next.run(req).await
+        }
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_middleware_respond() {
+        // Nothing listens on the port of a dropped listener, so only canned calls succeed.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let base_url = Url::parse(&format!("http://{addr}/twirp/")).unwrap();
+        let client = ClientBuilder::from_base_url(base_url)
+            .with(CannedPing)
+            .build()
+            .unwrap();
+
+        for client in [client.clone(), client.with_encoding(Encoding::Json)] {
+            let resp = client.ping(PingRequest::default()).await.unwrap();
+            assert_eq!(resp.name, "canned");
+        }
+        let err = client
+            .request::<_, PingResponse>("test.TestAPI/Boom", PingRequest::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ClientError::ReqwestError(_)), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn test_response_cache() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use axum::http::HeaderMap;
+        use axum::response::IntoResponse;
+
+        // A server that answers with an ETag, and with 304 when it is sent back.
+        let hits = Arc::new(AtomicUsize::new(0));
+        let not_modified = Arc::new(AtomicUsize::new(0));
+        let (h, n) = (hits.clone(), not_modified.clone());
+        let app = axum::Router::new().route(
+            "/twirp/test.TestAPI/Ping",
+            axum::routing::post(
+                move |headers: HeaderMap, body: axum::body::Bytes| async move {
+                    h.fetch_add(1, Ordering::SeqCst);
+                    let req = PingRequest::decode(body).unwrap();
+                    let etag = format!("\"{}\"", req.name);
+                    if headers.get(IF_NONE_MATCH).map(|v| v.as_bytes()) == Some(etag.as_bytes()) {
+                        n.fetch_add(1, Ordering::SeqCst);
+                        return StatusCode::NOT_MODIFIED.into_response();
+                    }
+                    let resp = serialize_proto_message(PingResponse { name: req.name });
+                    (
+                        [
+                            (CONTENT_TYPE, "application/protobuf"),
+                            (ETAG, etag.as_str()),
+                        ],
+                        resp,
+                    )
+                        .into_response()
+                },
+            ),
+        );
+        let server = crate::testing::TestServer::start(app).await;
+
+        let base_url = server.base_url();
+        let client = ClientBuilder::new(base_url, reqwest::Client::new())
+            .response_cache(1, Duration::from_secs(60))
+            .build()
+            .unwrap();
+        let ping = |name: &str| {
+            client.ping(PingRequest {
+                name: name.to_string(),
+            })
+        };
+
+        assert_eq!(ping("hi").await.unwrap().name, "hi");
+        assert_eq!(not_modified.load(Ordering::SeqCst), 0);
+        assert_eq!(ping("hi").await.unwrap().name, "hi");
+        assert_eq!(not_modified.load(Ordering::SeqCst), 1);
+
+        // a different request isn't served from the cache, and evicts "hi"
+        assert_eq!(ping("bye").await.unwrap().name, "bye");
+        assert_eq!(ping("hi").await.unwrap().name, "hi");
+        assert_eq!(not_modified.load(Ordering::SeqCst), 1);
+        assert_eq!(hits.load(Ordering::SeqCst), 4);
+
+        // expired responses aren't revalidated
+        let client = ClientBuilder::new(client.base_url().clone(), reqwest::Client::new())
+            .response_cache(10, Duration::ZERO)
+            .build()
+            .unwrap();
+        let req = || PingRequest {
+            name: "hi".to_string(),
+        };
+        client.ping(req()).await.unwrap();
+        client.ping(req()).await.unwrap();
+        assert_eq!(not_modified.load(Ordering::SeqCst), 1);
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_proto_decode_error() {
+        // A server whose responses aren't valid `PingResponse` messages.
+        let app = axum::Router::new().route(
+            "/twirp/test.TestAPI/Ping",
+            axum::routing::post(|| async {
+                ([(CONTENT_TYPE, "application/protobuf")], "\x12\x05hi")
+            }),
+        );
+        let server = crate::testing::TestServer::start(app).await;
+
+        let base_url = server.base_url();
+        let client = Client::from_base_url(base_url).unwrap();
+        let err = client.ping(PingRequest::default()).await.unwrap_err();
+        assert!(matches!(err, ClientError::ProtoDecodeError(_)), "{err:?}");
+        assert!(
+            err.to_string()
+                .starts_with("failed to decode protobuf response"),
+            "{err}"
+        );
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_request_id() {
+        let app = axum::Router::new().nest(
+            "/twirp/test.TestAPI",
+            crate::details::TwirpRouterBuilder::new(())
+                .route(
+                    "/Ping",
+                    |_, ctx: crate::Context, _: PingRequest| async move {
+                        let name = ctx.request_id().unwrap().to_string();
+                        Ok(PingResponse { name })
+                    },
+                )
+                .build(),
+        );
+        let server = crate::testing::TestServer::start(app).await;
+
+        let base_url = server.base_url();
+        let client = Client::from_base_url(base_url.clone()).unwrap();
+        let first = client.ping(PingRequest::default()).await.unwrap().name;
+        let second = client.ping(PingRequest::default()).await.unwrap().name;
+        assert_eq!(first.len(), 36);
+        assert_ne!(first, second);
+
+        let client = ClientBuilder::from_base_url(base_url)
+            .request_id_generator(|| "req-1".to_string())
+            .build()
+            .unwrap();
+        assert_eq!(
+            client.ping(PingRequest::default()).await.unwrap().name,
+            "req-1"
+        );
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_request_validated() {
+        impl crate::details::Validate for PingResponse {
+            fn validate(&self) -> std::result::Result<(), TwirpErrorResponse> {
+                if self.name.is_empty() {
+                    return Err(crate::details::invalid_field("name", "is required"));
+                }
+                Ok(())
+            }
+        }
+
+        let server = crate::testing::TestServer::start(test_api_router()).await;
+        let base_url = server.base_url();
+        let client = Client::from_base_url(base_url).unwrap();
+        let ping = |name: &str| PingRequest {
+            name: name.to_string(),
+        };
+
+        let resp: PingResponse = client
+            .request_validated("test.TestAPI/Ping", ping("hi"))
+            .await
+            .unwrap();
+        assert_eq!(resp.name, "hi");
+        let err = client
+            .request_validated::<_, PingResponse>("test.TestAPI/Ping", ping(""))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "invalid response: name is required");
+
+        server.shutdown().await;
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_check_connectivity() {
+        let ready = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let app = test_api_router().layer(crate::server::Options::new().readiness(ready.clone()));
+        let server = crate::testing::TestServer::start(app).await;
+        let base_url = server.base_url();
+        let client = Client::from_base_url(base_url)
+            .unwrap()
+            .with_encoding(Encoding::Json);
+
+        let check = client
+            .check_connectivity("test.TestAPI/Ping")
+            .await
+            .unwrap();
+        assert!(!check.is_serving());
+        assert_eq!(
+            check.error.unwrap().code,
+            crate::TwirpErrorCode::Unavailable
+        );
+
+        ready.store(true, std::sync::atomic::Ordering::Release);
+        let check = client
+            .check_connectivity("test.TestAPI/Ping")
+            .await
+            .unwrap();
+        assert!(check.is_serving(), "{check:?}");
+        assert!(check.latency > Duration::ZERO);
+
+        let check = client
+            .check_connectivity("test.TestAPI/Nope")
+            .await
+            .unwrap();
+        assert_eq!(check.error.unwrap().code, crate::TwirpErrorCode::BadRoute);
+
+        // nothing listens on the port of a stopped server
+        let base_url = server.base_url();
+        server.shutdown().await;
+        let client = Client::from_base_url(base_url).unwrap();
+        assert!(client
+            .check_connectivity("test.TestAPI/Ping")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_request_stats() {
+        let server = crate::testing::TestServer::start(test_api_router()).await;
+        let base_url = server.base_url();
+        let client = Client::from_base_url(base_url).unwrap();
+        assert_eq!(client.request_stats(), RequestStats::default());
+
+        let ping = |client: Client| async move {
+            client
+                .ping(PingRequest {
+                    name: "hi".to_string(),
+                })
+                .await
+        };
+        let (a, b) = tokio::join!(ping(client.clone()), ping(client.clone()));
+        a.unwrap();
+        b.unwrap();
+        let stats = client.request_stats();
+        assert_eq!(stats.in_flight, 0);
+        assert!((1..=2).contains(&stats.peak_in_flight), "{stats:?}");
+        assert_eq!(stats.requests, 2);
+        assert_eq!(stats.failed, 0);
+        assert!(stats.wait_time > Duration::ZERO);
+
+        // nothing listens on the port of a stopped server
+        let base_url = server.base_url();
+        server.shutdown().await;
+        let client = Client::from_base_url(base_url.clone()).unwrap();
+        ping(client.clone()).await.unwrap_err();
+        let stats = client.request_stats();
+        assert_eq!((stats.requests, stats.failed, stats.in_flight), (1, 1, 0));
+
+        // calls that middleware answers aren't sent
+        let client = ClientBuilder::from_base_url(base_url)
+            .with(CannedPing)
+            .build()
+            .unwrap();
+        assert_eq!(ping(client.clone()).await.unwrap().name, "canned");
+        assert_eq!(client.request_stats(), RequestStats::default());
+    }
+
+    #[tokio::test]
+    async fn test_max_response_size() {
+        let server = crate::testing::TestServer::start(test_api_router()).await;
+        let client = ClientBuilder::from_base_url(server.base_url())
+            .max_response_size(64)
+            .build()
+            .unwrap();
+        let resp = client
+            .ping(PingRequest {
+                name: "hi".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(resp.name, "hi");
+        let err = client
+            .ping(PingRequest {
+                name: "a".repeat(100),
+            })
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, ClientError::ResponseTooLarge { limit: 64 }),
+            "{err:?}"
+        );
+        server.shutdown().await;
+    }
+
+    #[cfg(feature = "gzip")]
+    #[tokio::test]
+    async fn test_gzip_responses() {
+        let app = test_api_router().layer(crate::server::Options::new().gzip_responses(0));
+        let server = crate::testing::TestServer::start(app).await;
+        let req = PingRequest {
+            name: "a".repeat(1000),
+        };
+
+        let client = ClientBuilder::from_base_url(server.base_url())
+            .gzip_responses(true)
+            .on_call(|metrics: &CallMetrics| {
+                assert!(metrics.response_bytes >= 1000, "{metrics:?}");
+                assert!(metrics.compressed_response_bytes < 100, "{metrics:?}");
+            })
+            .build()
+            .unwrap();
+        let resp = client.ping(req.clone()).await.unwrap();
+        assert_eq!(resp.name, req.name);
+        let mut raw = client
+            .request_raw("test.TestAPI/Ping", req.clone())
+            .await
+            .unwrap();
+        assert_eq!(raw.headers().get(CONTENT_ENCODING), None);
+        let mut body = vec![];
+        while let Some(chunk) = raw.chunk().await.unwrap() {
+            body.extend_from_slice(&chunk);
+        }
+        assert_eq!(PingResponse::decode(&body[..]).unwrap(), resp);
+
+        // The compressed response is small, but it decompresses to more than 10 times the limit.
+        let client = ClientBuilder::from_base_url(server.base_url())
+            .gzip_responses(true)
+            .max_response_size(64)
+            .build()
+            .unwrap();
+        let err = client.ping(req.clone()).await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                ClientError::DecompressedResponseTooLarge { limit: 640 }
+            ),
+            "{err:?}"
+        );
+        // Raw responses are limited as they are read.
+        let mut raw = client
+            .request_raw("test.TestAPI/Ping", req.clone())
+            .await
+            .unwrap();
+        let err = loop {
+            match raw.chunk().await {
+                Ok(Some(_)) => {}
+                Ok(None) => panic!("read the whole body"),
+                Err(err) => break err,
+            }
+        };
+        let mut source = std::error::Error::source(&err);
+        let source = loop {
+            match source {
+                Some(s) => match s.downcast_ref::<ClientError>() {
+                    Some(s) => break s,
+                    None => source = s.source(),
+                },
+                None => panic!("{err:?}"),
+            }
+        };
+        assert!(
+            matches!(
+                source,
+                ClientError::DecompressedResponseTooLarge { limit: 640 }
+            ),
+            "{err:?}"
+        );
+
+        let client = ClientBuilder::from_base_url(server.base_url())
+            .gzip_responses(true)
+            .max_response_size(64)
+            .max_decompressed_size(2000)
+            .build()
+            .unwrap();
+        let resp = client.ping(req.clone()).await.unwrap();
+        assert_eq!(resp.name, req.name);
+        server.shutdown().await;
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_on_call() {
+        use crate::server::BodySizes;
+
+        let server_sizes = Arc::new(Mutex::new(vec![]));
+        let app = test_api_router().layer(axum::middleware::map_response({
+            let server_sizes = server_sizes.clone();
+            move |resp: http::Response<axum::body::Body>| {
+                let sizes = resp.extensions().get::<BodySizes>().copied();
+                server_sizes.lock().unwrap().push(sizes);
+                async move { resp }
+            }
+        }));
+        let server = crate::testing::TestServer::start(app).await;
+        let calls = Arc::new(Mutex::new(vec![]));
+        let client = ClientBuilder::from_base_url(server.base_url())
+            .on_call({
+                let calls = calls.clone();
+                move |metrics: &CallMetrics| calls.lock().unwrap().push(metrics.clone())
+            })
+            .build()
+            .unwrap();
+
+        let req = PingRequest {
+            name: "hi".to_string(),
+        };
+        client.ping(req.clone()).await.unwrap();
+        let _: PingResponse = client
+            .with_encoding(Encoding::Json)
+            .request("test.TestAPI/Ping", req.clone())
+            .await
+            .unwrap();
+        let err = client
+            .request::<_, PingResponse>("test.TestAPI/Boom", req)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ClientError::TwirpError(_)), "{err:?}");
+
+        let calls = calls.lock().unwrap();
+        let server_sizes = server_sizes.lock().unwrap();
+        assert_eq!(calls.len(), 3, "{calls:?}");
+        assert_eq!(calls[0].path, "/twirp/test.TestAPI/Ping");
+        assert_eq!(calls[0].status, StatusCode::OK);
+        assert_eq!(calls[0].request_bytes, 4); // field 1, length 2, "hi"
+        assert_eq!(calls[1].request_bytes, r#"{"name":"hi"}"#.len());
+        assert_eq!(calls[2].path, "/twirp/test.TestAPI/Boom");
+        assert_eq!(calls[2].status, StatusCode::INTERNAL_SERVER_ERROR);
+        for (call, sizes) in calls.iter().zip(server_sizes.iter()) {
+            let sizes = sizes.unwrap();
+            assert_eq!(Some(call.request_bytes), sizes.request(), "{call:?}");
+            assert_eq!(Some(call.response_bytes), sizes.response(), "{call:?}");
+            assert_eq!(
+                call.compressed_response_bytes, call.response_bytes,
+                "{call:?}"
+            );
+            assert!(call.response_bytes > 0, "{call:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_twirp_version() {
+        let server = crate::testing::TestServer::start(test_api_router()).await;
+        let client = server.client();
+        assert_eq!(client.server_twirp_version(), None);
+
+        let req = PingRequest {
+            name: "hi".to_string(),
+        };
+        client.ping(req.clone()).await.unwrap();
+        assert_eq!(client.server_twirp_version().as_deref(), Some("v7"));
+
+        // error responses carry it too
+        let client = server.client();
+        let res: Result<PingResponse> = client.request("test.TestAPI/Missing", req).await;
+        assert!(res.is_err());
+        assert_eq!(client.server_twirp_version().as_deref(), Some("v7"));
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_request_with_meta() {
+        let app = axum::Router::new().nest(
+            "/twirp/test.TestAPI",
+            crate::details::TwirpRouterBuilder::new(())
+                .route(
+                    "/Ping",
+                    |_, ctx: crate::Context, req: PingRequest| async move {
+                        ctx.set_meta("Served-By", "replica-1");
+                        Ok(PingResponse { name: req.name })
+                    },
+                )
+                .route(
+                    "/Boom",
+                    |_, ctx: crate::Context, _: PingRequest| async move {
+                        ctx.set_meta("Served-By", "replica-1");
+                        ctx.set_meta("Zone", "response");
+                        Err::<PingResponse, _>(
+                            TwirpErrorResponse::builder(crate::TwirpErrorCode::Unavailable)
+                                .meta("zone", "error")
+                                .build(),
+                        )
+                    },
+                )
+                .build(),
+        );
+        let server = crate::testing::TestServer::start(app).await;
+
+        let base_url = server.base_url();
+        let client = Client::from_base_url(base_url).unwrap();
+        let (resp, meta): (PingResponse, _) = client
+            .request_with_meta(
+                "test.TestAPI/Ping",
+                PingRequest {
+                    name: "hi".to_string(),
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.name, "hi");
+        assert_eq!(
+            meta,
+            HashMap::from([("served-by".to_string(), "replica-1".to_string())])
+        );
+
+        // errors keep the response metadata next to their own
+        let err = client
+            .request_with_meta::<_, PingResponse>(
+                "test.TestAPI/Boom",
+                PingRequest {
+                    name: "hi".to_string(),
+                },
+            )
+            .await
+            .unwrap_err();
+        let ClientError::TwirpError(err) = err else {
+            panic!("unexpected error: {err:?}");
+        };
+        assert_eq!(err.code, crate::TwirpErrorCode::Unavailable);
+        assert_eq!(
+            err.meta,
+            HashMap::from([
+                ("served-by".to_string(), "replica-1".to_string()),
+                ("zone".to_string(), "error".to_string()),
+            ])
+        );
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_unknown_response_fields() {
+        // A newer server's version of `PingResponse`.
+        #[derive(Clone, PartialEq, prost::Message, serde::Serialize)]
+        struct PingResponseV2 {
+            #[prost(string, tag = "2")]
+            name: String,
+            #[prost(string, repeated, tag = "3")]
+            added_later: Vec<String>,
+        }
+
+        let app = axum::Router::new().nest(
+            "/twirp/test.TestAPI",
+            crate::details::TwirpRouterBuilder::new(())
+                .route(
+                    "/Ping",
+                    |_, _: crate::Context, req: PingRequest| async move {
+                        Ok(PingResponseV2 {
+                            name: req.name,
+                            added_later: vec!["new".to_string()],
+                        })
+                    },
+                )
+                .build(),
+        );
+        let server = crate::testing::TestServer::start(app).await;
+
+        let base_url = server.base_url();
+        let client = Client::from_base_url(base_url).unwrap();
+        let resp = client
+            .ping(PingRequest {
+                name: "hi".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(resp.name, "hi");
+
+        // and in JSON
+        let resp: PingResponse =
+            serde_json::from_str(r#"{"name":"hi","added_later":["new"]}"#).unwrap();
+        assert_eq!(resp.name, "hi");
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_follow_redirects() {
+        use axum::response::Redirect;
+
+        let app = axum::Router::new()
+            .route(
+                "/old/test.TestAPI/Ping",
+                axum::routing::post(|| async { Redirect::temporary("/twirp/test.TestAPI/Ping") }),
+            )
+            .merge(test_api_router());
+        let server = crate::testing::TestServer::start(app).await;
+        let base_url = Url::parse(&format!("http://{}/old/", server.addr())).unwrap();
+        let ping = || PingRequest {
+            name: "hi".to_string(),
+        };
+
+        let client = Client::from_base_url(base_url.clone()).unwrap();
+        let err = client.ping(ping()).await.unwrap_err();
+        assert!(
+            matches!(&err, ClientError::HttpError { status, msg, .. }
+                if *status == StatusCode::TEMPORARY_REDIRECT
+                    && msg == "redirect to /twirp/test.TestAPI/Ping"),
+            "{err:?}"
+        );
+
+        let client = ClientBuilder::from_base_url(base_url)
+            .follow_redirects(true)
+            .build()
+            .unwrap();
+        assert_eq!(client.ping(ping()).await.unwrap().name, "hi");
+
+        server.shutdown().await;
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_json_fallback() {
+        use axum::response::IntoResponse;
+
+        // A server that only speaks JSON.
+        let app = axum::Router::new().route(
+            "/twirp/test.TestAPI/Ping",
+            axum::routing::post(
+                |headers: axum::http::HeaderMap, body: axum::body::Bytes| async move {
+                    if headers[CONTENT_TYPE] != "application/json" {
+                        return StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response();
+                    }
+                    let req: PingRequest = serde_json::from_slice(&body).unwrap();
+                    axum::Json(PingResponse { name: req.name }).into_response()
+                },
+            ),
+        );
+        let server = crate::testing::TestServer::start(app).await;
+        let base_url = server.base_url();
+        let ping = || PingRequest {
+            name: "hi".to_string(),
+        };
+
+        let client = Client::from_base_url(base_url.clone()).unwrap();
+        let err = client.ping(ping()).await.unwrap_err();
+        assert!(
+            matches!(err, ClientError::HttpError { status, .. } if status == 415),
+            "{err:?}"
+        );
+
+        let client = ClientBuilder::new(base_url, reqwest::Client::new())
+            .json_fallback(true)
+            .build()
+            .unwrap();
+        assert_eq!(client.ping(ping()).await.unwrap().name, "hi");
+
+        server.shutdown().await;
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_empty_json_body() {
+        #[derive(Clone, PartialEq, prost::Message, serde::Serialize, serde::Deserialize)]
+        struct Empty {}
+
+        let app = axum::Router::new()
+            .nest(
+                "/twirp/test.TestAPI",
+                crate::details::TwirpRouterBuilder::new(())
+                    .route(
+                        "/Empty",
+                        |_, _: crate::Context, _: PingRequest| async move { Ok(Empty {}) },
+                    )
+                    .build(),
+            )
+            .layer(crate::server::Options::new().empty_json_body(true));
+        let server = crate::testing::TestServer::start(app).await;
+
+        let base_url = server.base_url();
+        let client = Client::from_base_url(base_url)
+            .unwrap()
+            .with_encoding(Encoding::Json);
+        let resp: Empty = client
+            .request("test.TestAPI/Empty", PingRequest::default())
+            .await
+            .unwrap();
+        assert_eq!(resp, Empty {});
+
+        server.shutdown().await;
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_with_encoding() {
+        struct AssertContentType(&'static str);
+
+        #[async_trait]
+        impl Middleware for AssertContentType {
+            async fn handle(&self, req: Request, next: Next<'_>) -> Result<Response> {
+                assert_eq!(req.headers()[CONTENT_TYPE], self.0);
+                let resp = next.run(req).await?;
+                assert_eq!(resp.headers()[CONTENT_TYPE], self.0);
+                Ok(resp)
+            }
+        }
+
+        let server = crate::testing::TestServer::start(test_api_router()).await;
+        let base_url = server.base_url();
+        let ping = || PingRequest {
+            name: "hi".to_string(),
+        };
+
+        let client = ClientBuilder::new(base_url.clone(), reqwest::Client::new())
+            .with(AssertContentType("application/json"))
+            .build()
+            .unwrap()
+            .with_encoding(Encoding::Json);
+        assert_eq!(client.ping(ping()).await.unwrap().name, "hi");
+
+        let client = ClientBuilder::new(base_url, reqwest::Client::new())
+            .with(AssertContentType("application/protobuf"))
+            .build()
+            .unwrap();
+        assert_eq!(client.ping(ping()).await.unwrap().name, "hi");
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_request_raw() {
+        let app = axum::Router::new().nest(
+            "/twirp/test.TestAPI",
+            crate::details::TwirpRouterBuilder::new(())
+                .route(
+                    "/Echo",
+                    |_, _: crate::Context, req: PingRequest| async move {
+                        if req.name.is_empty() {
+                            return Err(crate::invalid_argument("name is required"));
+                        }
+                        Ok(PingResponse {
+                            name: req.name.repeat(100_000),
+                        })
+                    },
+                )
+                .build(),
+        );
+        let server = crate::testing::TestServer::start(app).await;
+
+        let base_url = server.base_url();
+        let client = Client::from_base_url(base_url).unwrap();
+        let mut resp = client
+            .request_raw(
+                "test.TestAPI/Echo",
+                PingRequest {
+                    name: "hi".to_string(),
+                },
+            )
+            .await
+            .unwrap();
+        let mut body = vec![];
+        while let Some(chunk) = resp.chunk().await.unwrap() {
+            body.extend_from_slice(&chunk);
+        }
+        assert_eq!(PingResponse::decode(&body[..]).unwrap().name.len(), 200_000);
+
+        let err = client
+            .request_raw("test.TestAPI/Echo", PingRequest::default())
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, ClientError::TwirpError(e) if e.msg == "name is required"),
+            "{err:?}"
+        );
+
+        server.shutdown().await;
+    }
+
     #[tokio::test]
     #[ignore = "integration"]
     async fn test_standard_client() {
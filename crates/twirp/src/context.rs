@@ -1,6 +1,10 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use http::Extensions;
+use tokio::sync::Notify;
 
 /// Context allows passing information between twirp rpc handlers and http middleware by providing
 /// access to extensions on the `http::Request` and `http::Response`.
@@ -11,6 +15,8 @@ use http::Extensions;
 pub struct Context {
     extensions: Extensions,
     resp_extensions: Arc<Mutex<Extensions>>,
+    cancellation: CancellationToken,
+    deadline: Option<Instant>,
 }
 
 impl Context {
@@ -18,9 +24,21 @@ impl Context {
         Self {
             extensions,
             resp_extensions,
+            cancellation: CancellationToken::default(),
+            deadline: None,
         }
     }
 
+    pub(crate) fn with_cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.cancellation = cancellation;
+        self
+    }
+
+    pub(crate) fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
     /// Get a request extension.
     pub fn get<T>(&self) -> Option<&T>
     where
@@ -29,6 +47,33 @@ impl Context {
         self.extensions.get::<T>()
     }
 
+    /// The TLS parameters of the request's connection, if it was made over TLS and the server
+    /// recorded them (see [`TlsInfo`](crate::server::TlsInfo)).
+    pub fn tls_info(&self) -> Option<&crate::server::TlsInfo> {
+        self.extensions.get()
+    }
+
+    /// The client implementation the request came from, if it said (see
+    /// [`ClientLanguage`](crate::server::ClientLanguage)).
+    pub fn client_language(&self) -> Option<&str> {
+        self.extensions
+            .get::<crate::server::ClientLanguage>()
+            .map(|language| language.0.as_str())
+    }
+
+    /// The ID of the request: the one the client sent, or the one the server generated for it (see
+    /// [`crate::request_id`]).
+    pub fn request_id(&self) -> Option<&str> {
+        self.extensions
+            .get::<crate::request_id::RequestId>()
+            .map(|id| id.0.as_str())
+    }
+
+    /// The path of the method being called (see [`MethodPath`](crate::MethodPath)).
+    pub fn method_path(&self) -> Option<&crate::MethodPath> {
+        self.extensions.get()
+    }
+
     /// Insert a response extension.
     pub fn insert<T>(&self, val: T) -> Option<T>
     where
@@ -40,3 +85,174 @@ impl Context {
             .insert(val)
     }
 }
+
+impl Context {
+    /// Mark the response as cacheable: it gets an `ETag` computed from its body, and requests
+    /// sending a matching `If-None-Match` header get a `304 Not Modified` response without a body
+    /// instead. Only use this for methods without side effects.
+    pub fn set_cacheable(&self) {
+        self.insert(Cacheable);
+    }
+}
+
+impl Context {
+    /// Set response metadata, sent to the client in a `Twirp-Meta-{key}` header, whether the
+    /// handler succeeds or fails. Clients read it with
+    /// [`Client::request_with_meta`](crate::Client::request_with_meta).
+    ///
+    /// Keys are case-insensitive. Entries whose key isn't a valid header name, or whose value isn't
+    /// a valid header value, are left out of the response.
+    pub fn set_meta(&self, key: impl Into<String>, value: impl Into<String>) {
+        let mut exts = self.resp_extensions.lock().expect("mutex poisoned");
+        let meta = exts.get_or_insert_default::<ResponseMeta>();
+        meta.0.insert(key.into(), value.into());
+    }
+}
+
+impl Context {
+    /// Add a field to the request's logs, e.g. `ctx.record("user_id", id)`, for correlating them
+    /// with what the handler did. Fields are recorded in the `twirp_request` span the handler runs
+    /// in (as `fields`, once the handler completes) and in the line logged with
+    /// [`Options::access_log`](crate::server::Options::access_log). Recording a key again replaces
+    /// its value.
+    ///
+    /// Does nothing when `tracing` wouldn't log them, so handlers can record fields without the
+    /// cost of formatting them when logging is off.
+    pub fn record(&self, key: impl Into<String>, value: impl std::fmt::Display) {
+        if !tracing::enabled!(target: "twirp::server", tracing::Level::INFO) {
+            return;
+        }
+        let (key, value) = (key.into(), value.to_string());
+        let mut exts = self.resp_extensions.lock().expect("mutex poisoned");
+        let fields = &mut exts.get_or_insert_default::<LogFields>().0;
+        match fields.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, v)) => *v = value,
+            None => fields.push((key, value)),
+        }
+    }
+}
+
+/// Log fields recorded with [`Context::record`], in the order they were first recorded.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct LogFields(pub(crate) Vec<(String, String)>);
+
+impl std::fmt::Display for LogFields {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (key, value)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{key}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Response metadata set with [`Context::set_meta`].
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ResponseMeta(pub(crate) HashMap<String, String>);
+
+impl Context {
+    /// Set an HTTP/2 response trailer, sent after the response body, for peers that expect
+    /// trailing metadata (e.g. when bridging to gRPC-style systems). Like
+    /// [`set_meta`](Context::set_meta), it is sent whether the handler succeeds or fails.
+    ///
+    /// HTTP/1.1 responses don't carry trailers, so they are left out of those (as are entries
+    /// whose key isn't a valid header name, or whose value isn't a valid header value).
+    /// `twirp::Client` doesn't read trailers.
+    pub fn set_trailer(&self, key: impl Into<String>, value: impl Into<String>) {
+        let mut exts = self.resp_extensions.lock().expect("mutex poisoned");
+        let trailers = exts.get_or_insert_default::<ResponseTrailers>();
+        trailers.0.insert(key.into(), value.into());
+    }
+}
+
+/// Response trailers set with [`Context::set_trailer`].
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ResponseTrailers(pub(crate) HashMap<String, String>);
+
+/// A response extension marking the response as cacheable. See [`Context::set_cacheable`].
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Cacheable;
+
+impl Context {
+    /// A token that is cancelled if the client goes away before the handler completes, or when
+    /// the request's [deadline](Context::deadline) passes. Clone it into any work spawned by the
+    /// handler that should stop when the request is abandoned.
+    ///
+    /// Detection is best-effort: the server only notices once hyper sees the connection (or HTTP/2
+    /// stream) close, which some proxies delay or hide entirely. When that happens the handler
+    /// future itself is dropped, so the token matters only to work running outside of it.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation.clone()
+    }
+
+    /// Whether the request has been cancelled. See [`Context::cancellation_token`].
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation.is_cancelled()
+    }
+
+    /// Completes when the request is cancelled. See [`Context::cancellation_token`].
+    pub async fn cancelled(&self) {
+        self.cancellation.cancelled().await
+    }
+
+    /// When the client stops waiting for the response, if it sent a timeout (in the
+    /// [`REQUEST_TIMEOUT_HEADER`](crate::headers::REQUEST_TIMEOUT_HEADER) header, as clients
+    /// configured with [`ClientBuilder::request_timeout`](crate::ClientBuilder::request_timeout)
+    /// do), or the server has a timeout for the method (see
+    /// [`Options::default_timeout`](crate::server::Options::default_timeout)); the earlier of the
+    /// two when there are both. Handlers can give up on expensive work that can't finish in time,
+    /// and return a `deadline_exceeded` error instead.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// The time left until the [deadline](Context::deadline), which is zero once it has passed.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+}
+
+/// Signals that the request a [`Context`] belongs to has been cancelled.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    inner: Arc<CancellationInner>,
+}
+
+#[derive(Debug, Default)]
+struct CancellationInner {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel the token, waking everything waiting on [`CancellationToken::cancelled`].
+    pub fn cancel(&self) {
+        if !self.inner.cancelled.swap(true, Ordering::SeqCst) {
+            self.inner.notify.notify_waiters();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Completes when the token is cancelled.
+    pub async fn cancelled(&self) {
+        loop {
+            // Register for the notification before checking the flag so a concurrent `cancel`
+            // can't slip in between the two.
+            let notified = self.inner.notify.notified();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
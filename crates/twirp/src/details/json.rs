@@ -0,0 +1,508 @@
+//! Serde helpers implementing the [protobuf JSON mapping] for field types whose default serde
+//! representation differs from it. `twirp_build::protobuf_json` attaches these to generated
+//! message fields with `#[serde(with = "...")]`.
+//!
+//! [protobuf JSON mapping]: https://protobuf.dev/programming-guides/proto3/#json
+
+use base64::engine::general_purpose::{GeneralPurpose, STANDARD};
+use base64::engine::{DecodePaddingMode, GeneralPurposeConfig};
+use base64::{alphabet, Engine};
+use serde::de::{self, Deserialize, DeserializeOwned, Deserializer};
+use serde::ser::{Serialize, SerializeSeq, Serializer};
+
+// Decoders accept both the standard and URL-safe alphabets, padded or not, as the mapping
+// requires.
+const STANDARD_INDIFFERENT: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+const URL_SAFE_INDIFFERENT: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::URL_SAFE,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+fn decode_base64<E: de::Error>(s: &str) -> Result<Vec<u8>, E> {
+    STANDARD_INDIFFERENT
+        .decode(s)
+        .or_else(|_| URL_SAFE_INDIFFERENT.decode(s))
+        .map_err(|e| E::custom(format!("invalid base64: {e}")))
+}
+
+/// `bytes` fields, as base64 strings.
+pub mod bytes {
+    use super::*;
+
+    pub fn serialize<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: AsRef<[u8]>,
+        S: Serializer,
+    {
+        serializer.serialize_str(&STANDARD.encode(value.as_ref()))
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: From<Vec<u8>>,
+        D: Deserializer<'de>,
+    {
+        // `null` is the default value.
+        match Option::<String>::deserialize(deserializer)? {
+            Some(s) => decode_base64(&s).map(T::from),
+            None => Ok(T::from(Vec::new())),
+        }
+    }
+}
+
+/// `optional bytes` fields.
+pub mod option_bytes {
+    use super::*;
+
+    pub fn serialize<T, S>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: AsRef<[u8]>,
+        S: Serializer,
+    {
+        match value {
+            Some(value) => super::bytes::serialize(value, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+    where
+        T: From<Vec<u8>>,
+        D: Deserializer<'de>,
+    {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(s) => decode_base64(&s).map(|v| Some(T::from(v))),
+            None => Ok(None),
+        }
+    }
+}
+
+/// `repeated bytes` fields.
+pub mod repeated_bytes {
+    use super::*;
+
+    pub fn serialize<T, S>(value: &[T], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: AsRef<[u8]>,
+        S: Serializer,
+    {
+        let mut seq = serializer.serialize_seq(Some(value.len()))?;
+        for v in value {
+            seq.serialize_element(&STANDARD.encode(v.as_ref()))?;
+        }
+        seq.end()
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<Vec<T>, D::Error>
+    where
+        T: From<Vec<u8>>,
+        D: Deserializer<'de>,
+    {
+        Option::<Vec<String>>::deserialize(deserializer)?
+            .unwrap_or_default()
+            .iter()
+            .map(|s| decode_base64(s).map(T::from))
+            .collect()
+    }
+}
+
+/// `f32` and `f64`.
+pub trait Float: Copy + Default + Serialize + DeserializeOwned + std::str::FromStr {
+    fn is_nan(self) -> bool;
+    fn is_infinite(self) -> bool;
+    fn is_sign_negative(self) -> bool;
+    fn from_special(s: &str) -> Option<Self>;
+}
+
+macro_rules! impl_float {
+    ($($ty:ident),*) => {$(
+        impl Float for $ty {
+            fn is_nan(self) -> bool {
+                $ty::is_nan(self)
+            }
+            fn is_infinite(self) -> bool {
+                $ty::is_infinite(self)
+            }
+            fn is_sign_negative(self) -> bool {
+                $ty::is_sign_negative(self)
+            }
+            fn from_special(s: &str) -> Option<Self> {
+                match s {
+                    "NaN" => Some($ty::NAN),
+                    "Infinity" => Some($ty::INFINITY),
+                    "-Infinity" => Some($ty::NEG_INFINITY),
+                    _ => None,
+                }
+            }
+        }
+    )*};
+}
+impl_float!(f32, f64);
+
+// JSON has no representation for these values, so the mapping uses strings.
+fn serialize_float<T: Float, S: Serializer>(value: T, serializer: S) -> Result<S::Ok, S::Error> {
+    if value.is_nan() {
+        serializer.serialize_str("NaN")
+    } else if value.is_infinite() && value.is_sign_negative() {
+        serializer.serialize_str("-Infinity")
+    } else if value.is_infinite() {
+        serializer.serialize_str("Infinity")
+    } else {
+        value.serialize(serializer)
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum FloatValue<T> {
+    Number(T),
+    // The mapping also accepts numbers in strings.
+    String(String),
+}
+
+impl<T: Float> FloatValue<T> {
+    fn into_float<E: de::Error>(self) -> Result<T, E> {
+        match self {
+            FloatValue::Number(v) => Ok(v),
+            FloatValue::String(s) => T::from_special(&s)
+                .or_else(|| {
+                    // Rust also parses spellings like "inf", which the mapping doesn't allow.
+                    s.parse::<T>()
+                        .ok()
+                        .filter(|v| !v.is_nan() && !v.is_infinite())
+                })
+                .ok_or_else(|| E::custom(format!("invalid floating point value: {s:?}"))),
+        }
+    }
+}
+
+/// `float` and `double` fields, with `NaN` and infinities as the strings `"NaN"`, `"Infinity"`
+/// and `"-Infinity"`.
+pub mod float {
+    use super::*;
+
+    pub fn serialize<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: Float,
+        S: Serializer,
+    {
+        serialize_float(*value, serializer)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: Float,
+        D: Deserializer<'de>,
+    {
+        match Option::<FloatValue<T>>::deserialize(deserializer)? {
+            Some(v) => v.into_float(),
+            None => Ok(T::default()),
+        }
+    }
+}
+
+/// `optional float` and `optional double` fields.
+pub mod option_float {
+    use super::*;
+
+    pub fn serialize<T, S>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: Float,
+        S: Serializer,
+    {
+        match value {
+            Some(value) => serialize_float(*value, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+    where
+        T: Float,
+        D: Deserializer<'de>,
+    {
+        Option::<FloatValue<T>>::deserialize(deserializer)?
+            .map(FloatValue::into_float)
+            .transpose()
+    }
+}
+
+/// `repeated float` and `repeated double` fields.
+pub mod repeated_float {
+    use super::*;
+
+    struct Element<T>(T);
+
+    impl<T: Float> Serialize for Element<T> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serialize_float(self.0, serializer)
+        }
+    }
+
+    pub fn serialize<T, S>(value: &[T], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: Float,
+        S: Serializer,
+    {
+        let mut seq = serializer.serialize_seq(Some(value.len()))?;
+        for v in value {
+            seq.serialize_element(&Element(*v))?;
+        }
+        seq.end()
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<Vec<T>, D::Error>
+    where
+        T: Float,
+        D: Deserializer<'de>,
+    {
+        Option::<Vec<FloatValue<T>>>::deserialize(deserializer)?
+            .unwrap_or_default()
+            .into_iter()
+            .map(FloatValue::into_float)
+            .collect()
+    }
+}
+
+/// `i64` and `u64`.
+pub trait Int64: Copy + Default + std::fmt::Display + std::str::FromStr + DeserializeOwned {}
+
+impl Int64 for i64 {}
+impl Int64 for u64 {}
+
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum Int64Value<T> {
+    // Parsers are expected to accept numbers too.
+    Number(T),
+    String(String),
+}
+
+impl<T: Int64> Int64Value<T> {
+    fn into_int<E: de::Error>(self) -> Result<T, E> {
+        match self {
+            Int64Value::Number(v) => Ok(v),
+            Int64Value::String(s) => s
+                .parse()
+                .map_err(|_| E::custom(format!("invalid 64-bit integer: {s:?}"))),
+        }
+    }
+}
+
+/// 64-bit integer fields (`int64`, `uint64`, `sint64`, `fixed64` and `sfixed64`), as decimal
+/// strings, since JSON numbers usually can't represent them precisely.
+pub mod int64 {
+    use super::*;
+
+    pub fn serialize<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: Int64,
+        S: Serializer,
+    {
+        serializer.collect_str(value)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: Int64,
+        D: Deserializer<'de>,
+    {
+        match Option::<Int64Value<T>>::deserialize(deserializer)? {
+            Some(v) => v.into_int(),
+            None => Ok(T::default()),
+        }
+    }
+}
+
+/// Optional 64-bit integer fields.
+pub mod option_int64 {
+    use super::*;
+
+    pub fn serialize<T, S>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: Int64,
+        S: Serializer,
+    {
+        match value {
+            Some(value) => serializer.collect_str(value),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+    where
+        T: Int64,
+        D: Deserializer<'de>,
+    {
+        Option::<Int64Value<T>>::deserialize(deserializer)?
+            .map(Int64Value::into_int)
+            .transpose()
+    }
+}
+
+/// Repeated 64-bit integer fields.
+pub mod repeated_int64 {
+    use super::*;
+
+    pub fn serialize<T, S>(value: &[T], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: Int64,
+        S: Serializer,
+    {
+        serializer.collect_seq(value.iter().map(ToString::to_string))
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<Vec<T>, D::Error>
+    where
+        T: Int64,
+        D: Deserializer<'de>,
+    {
+        Option::<Vec<Int64Value<T>>>::deserialize(deserializer)?
+            .unwrap_or_default()
+            .into_iter()
+            .map(Int64Value::into_int)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+    #[serde(default)]
+    struct Blob {
+        #[serde(with = "super::bytes")]
+        data: Vec<u8>,
+        #[serde(with = "super::option_bytes")]
+        checksum: Option<Vec<u8>>,
+        #[serde(with = "super::repeated_bytes")]
+        chunks: Vec<Vec<u8>>,
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let blob = Blob {
+            data: b"\x00\xfftwirp".to_vec(),
+            checksum: Some(vec![0xfb, 0xef]),
+            chunks: vec![b"a".to_vec(), vec![]],
+        };
+        let json = serde_json::to_string(&blob).unwrap();
+        assert_eq!(
+            json,
+            r#"{"data":"AP90d2lycA==","checksum":"++8=","chunks":["YQ==",""]}"#
+        );
+        assert_eq!(serde_json::from_str::<Blob>(&json).unwrap(), blob);
+    }
+
+    #[test]
+    fn bytes_lenient_decoding() {
+        // URL-safe alphabet, missing padding, and nulls are all accepted.
+        let blob: Blob =
+            serde_json::from_str(r#"{"data":"AP90d2lycA","checksum":"--8","chunks":null}"#)
+                .unwrap();
+        assert_eq!(blob.data, b"\x00\xfftwirp");
+        assert_eq!(blob.checksum, Some(vec![0xfb, 0xef]));
+        assert!(blob.chunks.is_empty());
+
+        let blob: Blob = serde_json::from_str(r#"{"data":null}"#).unwrap();
+        assert_eq!(blob, Blob::default());
+        assert!(serde_json::from_str::<Blob>(r#"{"data":"not base64!"}"#).is_err());
+    }
+
+    #[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+    #[serde(default)]
+    struct Measurement {
+        #[serde(with = "super::float")]
+        value: f64,
+        #[serde(with = "super::option_float")]
+        limit: Option<f32>,
+        #[serde(with = "super::repeated_float")]
+        samples: Vec<f32>,
+    }
+
+    #[test]
+    fn float_round_trip() {
+        let m = Measurement {
+            value: f64::INFINITY,
+            limit: Some(f32::NEG_INFINITY),
+            samples: vec![0.1, 2.0],
+        };
+        let json = serde_json::to_string(&m).unwrap();
+        assert_eq!(
+            json,
+            r#"{"value":"Infinity","limit":"-Infinity","samples":[0.1,2.0]}"#
+        );
+        assert_eq!(serde_json::from_str::<Measurement>(&json).unwrap(), m);
+
+        let json = serde_json::to_string(&Measurement {
+            value: f64::NAN,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(json, r#"{"value":"NaN","limit":null,"samples":[]}"#);
+        let m: Measurement = serde_json::from_str(&json).unwrap();
+        assert!(m.value.is_nan());
+    }
+
+    #[test]
+    fn float_decoding() {
+        let m: Measurement =
+            serde_json::from_str(r#"{"value":"1.5","limit":null,"samples":["NaN",-3]}"#).unwrap();
+        assert_eq!(m.value, 1.5);
+        assert_eq!(m.limit, None);
+        assert_eq!(m.samples.len(), 2);
+        assert!(m.samples[0].is_nan());
+        assert_eq!(m.samples[1], -3.0);
+
+        assert!(serde_json::from_str::<Measurement>(r#"{"value":"inf"}"#).is_err());
+        assert!(serde_json::from_str::<Measurement>(r#"{"value":"nan"}"#).is_err());
+        assert!(serde_json::from_str::<Measurement>(r#"{"value":true}"#).is_err());
+    }
+
+    #[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+    #[serde(default)]
+    struct Counter {
+        #[serde(with = "super::int64")]
+        total: i64,
+        #[serde(with = "super::option_int64")]
+        max: Option<u64>,
+        #[serde(with = "super::repeated_int64")]
+        history: Vec<i64>,
+    }
+
+    #[test]
+    fn int64_round_trip() {
+        let c = Counter {
+            total: -9_007_199_254_740_993,
+            max: Some(u64::MAX),
+            history: vec![1, -2],
+        };
+        let json = serde_json::to_string(&c).unwrap();
+        assert_eq!(
+            json,
+            r#"{"total":"-9007199254740993","max":"18446744073709551615","history":["1","-2"]}"#
+        );
+        assert_eq!(serde_json::from_str::<Counter>(&json).unwrap(), c);
+    }
+
+    #[test]
+    fn int64_decoding() {
+        // Numbers and nulls are accepted too.
+        let c: Counter =
+            serde_json::from_str(r#"{"total":12,"max":null,"history":[3,"4"]}"#).unwrap();
+        assert_eq!(
+            c,
+            Counter {
+                total: 12,
+                max: None,
+                history: vec![3, 4],
+            }
+        );
+        assert!(serde_json::from_str::<Counter>(r#"{"max":"-1"}"#).is_err());
+        assert!(serde_json::from_str::<Counter>(r#"{"total":"1.5"}"#).is_err());
+    }
+}
@@ -0,0 +1,286 @@
+//! Undocumented features that are public for use in generated code (see `twirp-build`).
+
+use std::future::Future;
+use std::sync::Arc;
+
+use axum::extract::{Request, State};
+use axum::handler::Handler;
+use axum::Router;
+use tracing::instrument::Instrumented;
+
+use crate::operations::{self, OperationStore};
+use crate::{error, server, Context, JsonDeserialize, JsonSerialize, TwirpErrorResponse};
+
+#[cfg(feature = "json")]
+pub mod json;
+
+/// Builder object used by generated code to build a Twirp service.
+///
+/// The type `S` is something like `Arc<MyExampleApiServer>`, which can be cheaply cloned for each
+/// incoming request, providing access to the Rust value that actually implements the RPCs.
+pub struct TwirpRouterBuilder<S> {
+    service: S,
+    router: Router<S>,
+    has_fallback: bool,
+    default_headers: http::HeaderMap,
+}
+
+impl<S> TwirpRouterBuilder<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    pub fn new(service: S) -> Self {
+        TwirpRouterBuilder {
+            service,
+            router: Router::new(),
+            has_fallback: false,
+            default_headers: http::HeaderMap::new(),
+        }
+    }
+
+    /// Add a handler for an `rpc` to the router. The handler runs on the task serving the
+    /// connection, even with a `spawner` in the server's options (see [`Self::route_spawned`]).
+    ///
+    /// The closure calls the method, like
+    /// `|api: Arc<HaberdasherApiServer>, req: MakeHatRequest| async move { api.make_hat(req) }`.
+    pub fn route<F, Fut, Req, Res>(self, url: &str, f: F) -> Self
+    where
+        F: Fn(S, Context, Req) -> Fut + Clone + Sync + Send + 'static,
+        Fut: Future<Output = Result<Res, TwirpErrorResponse>> + Send,
+        Req: prost::Message + Default + JsonDeserialize,
+        Res: prost::Message + JsonSerialize,
+    {
+        self.post(url, server::Inline, f)
+    }
+
+    /// Like [`Self::route`], running the handler with the `spawner` in the server's options if
+    /// there is one (see [`server::Options::spawner`]), which needs a handler whose future and
+    /// response can move to another task. Generated code uses this.
+    pub fn route_spawned<F, Fut, Req, Res>(self, url: &str, f: F) -> Self
+    where
+        F: Fn(S, Context, Req) -> Fut + Clone + Sync + Send + 'static,
+        Fut: Future<Output = Result<Res, TwirpErrorResponse>> + Send + 'static,
+        Req: prost::Message + Default + JsonDeserialize,
+        Res: prost::Message + JsonSerialize + 'static,
+    {
+        self.post(url, server::Spawned, f)
+    }
+
+    /// Add a handler for an `rpc` that runs synchronously on tokio's blocking thread pool, for
+    /// CPU-heavy methods that would otherwise starve the async workers.
+    ///
+    /// At most `pool.size()` calls sharing `pool` run at once; further calls fail right away with
+    /// `resource_exhausted` rather than queue up.
+    pub fn route_blocking<F, Req, Res>(self, url: &str, pool: server::BlockingPool, f: F) -> Self
+    where
+        F: Fn(S, Context, Req) -> Result<Res, TwirpErrorResponse> + Clone + Sync + Send + 'static,
+        Req: prost::Message + Default + JsonDeserialize + 'static,
+        Res: prost::Message + JsonSerialize + 'static,
+    {
+        self.route(url, move |api: S, ctx: Context, req: Req| {
+            let (pool, f) = (pool.clone(), f.clone());
+            async move { pool.run(move || f(api, ctx, req)).await }
+        })
+    }
+
+    /// Add a handler for an `rpc` that reads the request body as it arrives rather than decoding it
+    /// up front, for uploads too large to buffer (see [`server::RequestStream`]). Only protobuf
+    /// requests are accepted; the response is encoded as for any other method.
+    ///
+    /// Request validation, and HMAC request signing (which needs the whole body before the
+    /// handler runs), aren't available for these methods.
+    pub fn route_streaming<F, Fut, Res>(self, url: &str, f: F) -> Self
+    where
+        F: Fn(S, Context, server::RequestStream) -> Fut + Clone + Sync + Send + 'static,
+        Fut: Future<Output = Result<Res, TwirpErrorResponse>> + Send,
+        Res: prost::Message + JsonSerialize,
+    {
+        self.post(url, server::Inline, f)
+    }
+
+    /// Add a handler for an `rpc` that gets the encoded request body and returns the encoded
+    /// response, e.g. to forward calls to another server without decoding them (see
+    /// [`server::RawBody`]). Errors are sent as for any other method.
+    ///
+    /// Request validation isn't available for these methods, and gRPC-Web requests are rejected.
+    pub fn route_raw<F, Fut>(self, url: &str, f: F) -> Self
+    where
+        F: Fn(S, Context, server::RawBody) -> Fut + Clone + Sync + Send + 'static,
+        Fut: Future<Output = Result<server::RawBody, TwirpErrorResponse>> + Send + 'static,
+    {
+        self.post(url, server::Spawned, f)
+    }
+
+    /// Add a handler for an `rpc` that clients can also call with a `Prefer: respond-async`
+    /// header, to have it run in the background and poll for its outcome with the status method
+    /// registered next to it at `url` + [`STATUS_METHOD_SUFFIX`](crate::operations::STATUS_METHOD_SUFFIX).
+    /// Operations are kept in `store`. See [`crate::operations`].
+    pub fn route_async<F, Fut, Req, Res>(
+        self,
+        url: &str,
+        store: Arc<dyn OperationStore>,
+        f: F,
+    ) -> Self
+    where
+        F: Fn(S, Context, Req) -> Fut + Clone + Sync + Send + 'static,
+        Fut: Future<Output = Result<Res, TwirpErrorResponse>> + Send + 'static,
+        Req: prost::Message + Default + JsonDeserialize + 'static,
+        Res: prost::Message + Default + JsonSerialize + Send + 'static,
+    {
+        let status_url = format!("{url}{}", operations::STATUS_METHOD_SUFFIX);
+        let status_store = store.clone();
+        TwirpRouterBuilder {
+            service: self.service,
+            has_fallback: self.has_fallback,
+            default_headers: self.default_headers,
+            router: self
+                .router
+                .route(
+                    url,
+                    axum::routing::post(move |State(api): State<S>, req: Request| async move {
+                        let resp = if operations::prefers_async(req.headers()) {
+                            let options = req
+                                .extensions()
+                                .get::<Arc<server::Options>>()
+                                .cloned()
+                                .unwrap_or_default();
+                            server::handle_request(
+                                api,
+                                req,
+                                server::Spawned,
+                                move |api, ctx, req: Req| {
+                                    operations::submit(store, options, api, ctx, req, f)
+                                },
+                            )
+                            .await
+                        } else {
+                            server::handle_request(
+                                api,
+                                req,
+                                server::Spawned,
+                                move |api, ctx, req: Req| async move {
+                                    f(api, ctx, req)
+                                        .await
+                                        .map(operations::OperationResponse::Done)
+                                },
+                            )
+                            .await
+                        };
+                        operations::finish_response(resp)
+                    })
+                    .fallback(server::method_not_allowed_handler),
+                )
+                .route(
+                    &status_url,
+                    axum::routing::post(move |State(api): State<S>, req: Request| async move {
+                        let resp = server::handle_request(
+                            api,
+                            req,
+                            server::Spawned,
+                            move |_, ctx, req| operations::status::<Res>(status_store, ctx, req),
+                        )
+                        .await;
+                        operations::finish_response(resp)
+                    })
+                    .fallback(server::method_not_allowed_handler),
+                ),
+        }
+    }
+
+    /// Route POST requests for `url` to `f`, run as `run` says.
+    fn post<F, Fut, Req, Res, R>(self, url: &str, run: R, f: F) -> Self
+    where
+        F: Fn(S, Context, Req) -> Fut + Clone + Sync + Send + 'static,
+        Fut: Future<Output = Result<Res, TwirpErrorResponse>> + Send,
+        Req: server::FromRequestBody,
+        Res: server::IntoResponseBody,
+        R: server::RunHandler<Instrumented<Fut>> + Clone + Send + Sync + 'static,
+    {
+        TwirpRouterBuilder {
+            service: self.service,
+            has_fallback: self.has_fallback,
+            default_headers: self.default_headers,
+            router: self.router.route(
+                url,
+                axum::routing::post(move |State(api): State<S>, req: Request| async move {
+                    server::handle_request(api, req, run, f).await
+                })
+                .fallback(server::method_not_allowed_handler),
+            ),
+        }
+    }
+
+    /// Handle requests for methods the service doesn't have with `handler` instead of
+    /// [`not_found_handler`](crate::server::not_found_handler). Any axum handler works; one
+    /// returning a `TwirpErrorResponse` keeps the responses Twirp compliant.
+    ///
+    /// The routers returned by generated `router()` functions (and `twirp::routes!`) have the
+    /// default fallback, which `axum::Router::fallback` replaces in the same way.
+    pub fn fallback<H, T>(self, handler: H) -> Self
+    where
+        H: Handler<T, S>,
+        T: 'static,
+    {
+        TwirpRouterBuilder {
+            service: self.service,
+            router: self.router.fallback(handler),
+            has_fallback: true,
+            default_headers: self.default_headers,
+        }
+    }
+
+    /// Add `headers` to every response of the service (errors and fallback responses included),
+    /// e.g. a header naming the service's version. All the values of a header are added, unless the
+    /// response already has that header, whose values are kept.
+    pub fn default_headers(self, headers: http::HeaderMap) -> Self {
+        TwirpRouterBuilder {
+            default_headers: headers,
+            ..self
+        }
+    }
+
+    /// Finish building the axum router.
+    pub fn build(self) -> axum::Router {
+        let router = if self.has_fallback {
+            self.router
+        } else {
+            self.router.fallback(crate::server::not_found_handler)
+        };
+        let router = router.with_state(self.service);
+        if self.default_headers.is_empty() {
+            return router;
+        }
+        let headers = self.default_headers;
+        router.layer(axum::middleware::map_response(
+            move |mut resp: axum::response::Response| {
+                let headers = headers.clone();
+                async move {
+                    for name in headers.keys() {
+                        if !resp.headers().contains_key(name) {
+                            for value in headers.get_all(name) {
+                                resp.headers_mut().append(name, value.clone());
+                            }
+                        }
+                    }
+                    resp
+                }
+            },
+        ))
+    }
+}
+
+/// Implemented by request messages with generated `validate()` methods, so `twirp::routes!` can
+/// call them without naming the request type.
+pub trait Validate {
+    fn validate(&self) -> Result<(), TwirpErrorResponse>;
+}
+
+/// The error returned by generated `validate()` methods when a field breaks one of its rules.
+///
+/// Like `twirp.InvalidArgumentError` in the Go implementation, the name of the field is included
+/// in the message and in the `argument` metadata.
+pub fn invalid_field(argument: &str, msg: &str) -> TwirpErrorResponse {
+    let mut err = error::invalid_argument(format!("{argument} {msg}"));
+    err.insert_meta("argument".to_string(), argument.to_string());
+    err
+}
@@ -6,7 +6,8 @@ use axum::body::Body;
 use axum::response::IntoResponse;
 use http::header::{self, HeaderMap, HeaderValue};
 use hyper::{Response, StatusCode};
-use serde::{Deserialize, Serialize, Serializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
 
 /// Alias for a generic error
 pub type GenericError = Box<dyn std::error::Error + Send + Sync>;
@@ -19,8 +20,7 @@ macro_rules! twirp_error_codes {
         )+
     ) => {
         /// A Twirp error code as defined by <https://twitchtv.github.io/twirp/docs/spec_v7.html>.
-        #[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
-        #[serde(field_identifier, rename_all = "snake_case")]
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
         #[non_exhaustive]
         pub enum TwirpErrorCode {
             $(
@@ -47,6 +47,20 @@ macro_rules! twirp_error_codes {
             }
         }
 
+        /// Parses the code's string in the wire format, e.g. `invalid_argument`.
+        impl std::str::FromStr for TwirpErrorCode {
+            type Err = UnknownErrorCode;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                match s {
+                    $(
+                        stringify!($phrase) => Ok(TwirpErrorCode::$konst),
+                    )+
+                    _ => Err(UnknownErrorCode(s.to_string())),
+                }
+            }
+        }
+
         $(
         pub fn $phrase<T: ToString>(msg: T) -> TwirpErrorResponse {
             TwirpErrorResponse {
@@ -129,6 +143,37 @@ twirp_error_codes! {
     (Dataloss, StatusCode::INTERNAL_SERVER_ERROR, dataloss);
 }
 
+impl TwirpErrorCode {
+    /// The code in title case, e.g. `Invalid Argument`, which error responses carry as the reason
+    /// phrase of their HTTP/1.1 status line (`HTTP/1.1 400 Invalid Argument`) so the code shows in
+    /// tools like curl. Clients must ignore reason phrases, so this doesn't change the meaning of
+    /// the status code. HTTP/2 responses have no reason phrase.
+    pub fn reason_phrase(&self) -> String {
+        self.twirp_code()
+            .split('_')
+            .map(|word| word[..1].to_uppercase() + &word[1..])
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// The error for a string that isn't a twirp error code (see [`TwirpErrorCode`]).
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+#[error("unknown twirp error code {0:?}")]
+pub struct UnknownErrorCode(pub String);
+
+/// Codes this crate doesn't know, e.g. ones added to the spec after it, deserialize as
+/// [`TwirpErrorCode::Unknown`] rather than failing, so clients still get the error's message.
+impl<'de> Deserialize<'de> for TwirpErrorCode {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let code = std::borrow::Cow::<str>::deserialize(deserializer)?;
+        Ok(code.parse().unwrap_or(TwirpErrorCode::Unknown))
+    }
+}
+
 impl Serialize for TwirpErrorCode {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -139,7 +184,7 @@ impl Serialize for TwirpErrorCode {
 }
 
 // Twirp error responses are always JSON
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct TwirpErrorResponse {
     pub code: TwirpErrorCode,
     pub msg: String,
@@ -149,9 +194,107 @@ pub struct TwirpErrorResponse {
 }
 
 impl TwirpErrorResponse {
+    /// Start building an error with `code`, an empty message and no metadata. This is handy for
+    /// errors with several metadata entries; the per-code constructors (like [`not_found`]) are
+    /// shorter otherwise. Both build the same errors:
+    ///
+    /// ```
+    /// use twirp::{Context, TwirpErrorCode, TwirpErrorResponse};
+    /// # #[derive(Clone, PartialEq, prost::Message, serde::Serialize, serde::Deserialize)]
+    /// # struct GetHatRequest { #[prost(string, tag = "1")] id: String }
+    /// # #[derive(Clone, PartialEq, prost::Message, serde::Serialize, serde::Deserialize)]
+    /// # struct Hat { #[prost(string, tag = "1")] id: String }
+    ///
+    /// async fn get_hat(_ctx: Context, req: GetHatRequest) -> Result<Hat, TwirpErrorResponse> {
+    ///     Err(TwirpErrorResponse::builder(TwirpErrorCode::NotFound)
+    ///         .msg(format!("no hat {}", req.id))
+    ///         .meta("hat_id", &req.id)
+    ///         .meta("retry_after_secs", 30)
+    ///         .build())
+    /// }
+    /// # let err = futures::executor::block_on(get_hat(
+    /// #     Context::default(),
+    /// #     GetHatRequest { id: "fedora".to_string() },
+    /// # ))
+    /// # .unwrap_err();
+    /// # let mut expected = twirp::not_found("no hat fedora");
+    /// # expected.insert_meta("hat_id".to_string(), "fedora".to_string());
+    /// # expected.insert_meta("retry_after_secs".to_string(), "30".to_string());
+    /// # assert_eq!(err, expected);
+    /// ```
+    pub fn builder(code: TwirpErrorCode) -> TwirpErrorBuilder {
+        TwirpErrorBuilder {
+            error: TwirpErrorResponse {
+                code,
+                msg: String::new(),
+                meta: Default::default(),
+            },
+        }
+    }
+
     pub fn insert_meta(&mut self, key: String, value: String) -> Option<String> {
         self.meta.insert(key, value)
     }
+
+    /// Whether the failed request is worth retrying (with a backoff).
+    ///
+    /// These codes describe transient conditions rather than a problem with the request itself:
+    /// `unavailable` (the service is temporarily down or overloaded), `deadline_exceeded` (the
+    /// request ran out of time and may succeed on another attempt), and `resource_exhausted` (a
+    /// rate limit or quota that frees up over time). Note that a `deadline_exceeded` request may
+    /// have partially or fully completed, so only retry operations that are idempotent.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self.code,
+            TwirpErrorCode::Unavailable
+                | TwirpErrorCode::DeadlineExceeded
+                | TwirpErrorCode::ResourceExhausted
+        )
+    }
+
+    /// The HTTP status code of the response for this error.
+    pub fn http_status_code(&self) -> StatusCode {
+        self.code.http_status_code()
+    }
+
+    /// The body of the response for this error: Twirp errors are always JSON, whatever the
+    /// encoding of the request. Together with [`http_status_code`](Self::http_status_code) and an
+    /// `application/json` content type, this is exactly the response twirp servers send.
+    pub fn to_json_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("JSON serialization of an error should not fail")
+    }
+}
+
+/// Builds a [`TwirpErrorResponse`]. See [`TwirpErrorResponse::builder`].
+#[derive(Clone, Debug)]
+pub struct TwirpErrorBuilder {
+    error: TwirpErrorResponse,
+}
+
+impl TwirpErrorBuilder {
+    /// The human-readable message of the error.
+    pub fn msg(self, msg: impl ToString) -> Self {
+        let mut error = self.error;
+        error.msg = msg.to_string();
+        Self { error }
+    }
+
+    /// Add a metadata entry, replacing any previous value for `key`.
+    pub fn meta(self, key: impl Into<String>, value: impl ToString) -> Self {
+        let mut error = self.error;
+        error.meta.insert(key.into(), value.to_string());
+        Self { error }
+    }
+
+    pub fn build(self) -> TwirpErrorResponse {
+        self.error
+    }
+}
+
+impl From<TwirpErrorBuilder> for TwirpErrorResponse {
+    fn from(builder: TwirpErrorBuilder) -> Self {
+        builder.build()
+    }
 }
 
 impl IntoResponse for TwirpErrorResponse {
@@ -161,11 +304,19 @@ impl IntoResponse for TwirpErrorResponse {
             header::CONTENT_TYPE,
             HeaderValue::from_static("application/json"),
         );
+        headers.insert(
+            crate::headers::TWIRP_VERSION_HEADER,
+            HeaderValue::from_static(crate::headers::TWIRP_VERSION),
+        );
 
-        let json =
-            serde_json::to_string(&self).expect("JSON serialization of an error should not fail");
-
-        (self.code.http_status_code(), headers, json).into_response()
+        let reason = hyper::ext::ReasonPhrase::try_from(self.code.reason_phrase())
+            .expect("reason phrases are letters and spaces");
+        let mut resp = (self.http_status_code(), headers, self.to_json_bytes()).into_response();
+        resp.extensions_mut().insert(reason);
+        // For middleware that wants the error rather than its encoding (see
+        // `server::Interceptor`).
+        resp.extensions_mut().insert(self);
+        resp
     }
 }
 
@@ -189,6 +340,25 @@ mod test {
         assert_code(TwirpErrorCode::Unavailable, "unavailable", 503);
     }
 
+    #[test]
+    fn test_reason_phrase() {
+        use axum::response::IntoResponse;
+
+        assert_eq!(
+            TwirpErrorCode::InvalidArgument.reason_phrase(),
+            "Invalid Argument"
+        );
+        assert_eq!(TwirpErrorCode::Internal.reason_phrase(), "Internal");
+        assert_eq!(
+            TwirpErrorCode::ResourceExhausted.reason_phrase(),
+            "Resource Exhausted"
+        );
+
+        let resp = crate::invalid_argument("nope").into_response();
+        let reason = resp.extensions().get::<hyper::ext::ReasonPhrase>().unwrap();
+        assert_eq!(reason.as_bytes(), b"Invalid Argument");
+    }
+
     fn assert_code(code: TwirpErrorCode, msg: &str, http: u16) {
         assert_eq!(
             code.http_status_code(),
@@ -206,6 +376,15 @@ mod test {
         );
     }
 
+    #[test]
+    fn twirp_error_retryable() {
+        assert!(crate::unavailable("down").is_retryable());
+        assert!(crate::deadline_exceeded("slow").is_retryable());
+        assert!(crate::resource_exhausted("busy").is_retryable());
+        assert!(!crate::internal("boom").is_retryable());
+        assert!(!crate::invalid_argument("inches").is_retryable());
+    }
+
     #[test]
     fn twirp_error_response_serialization() {
         let response = TwirpErrorResponse {
@@ -221,4 +400,78 @@ mod test {
         let result = serde_json::from_str(&result).unwrap();
         assert_eq!(response, result);
     }
+
+    #[test]
+    fn twirp_error_code_from_str() {
+        assert_eq!(
+            "invalid_argument".parse::<TwirpErrorCode>(),
+            Ok(TwirpErrorCode::InvalidArgument)
+        );
+        assert_eq!("dataloss".parse(), Ok(TwirpErrorCode::Dataloss));
+        let err = "InvalidArgument".parse::<TwirpErrorCode>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown twirp error code \"InvalidArgument\""
+        );
+    }
+
+    #[test]
+    fn twirp_error_unknown_code() {
+        let json = r#"{"code":"quota_exhausted_for_today","msg":"come back tomorrow"}"#;
+        let err: TwirpErrorResponse = serde_json::from_str(json).unwrap();
+        assert_eq!(err.code, TwirpErrorCode::Unknown);
+        assert_eq!(err.msg, "come back tomorrow");
+
+        // the code must still be a string
+        assert!(serde_json::from_str::<TwirpErrorResponse>(r#"{"code":7,"msg":""}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn twirp_error_wire_format() {
+        use axum::response::IntoResponse;
+        use http_body_util::BodyExt;
+
+        let mut err = crate::not_found("no hat");
+        err.insert_meta("size".to_string(), "7".to_string());
+        assert_eq!(err.http_status_code(), 404);
+        let bytes = err.to_json_bytes();
+        assert_eq!(
+            std::str::from_utf8(&bytes).unwrap(),
+            r#"{"code":"not_found","msg":"no hat","meta":{"size":"7"}}"#
+        );
+
+        let resp = err.into_response();
+        assert_eq!(resp.status(), 404);
+        assert_eq!(resp.headers()["content-type"], "application/json");
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, bytes);
+    }
+
+    #[test]
+    fn twirp_error_builder() {
+        let built = TwirpErrorResponse::builder(TwirpErrorCode::NotFound)
+            .msg("no hat")
+            .meta("size", 7)
+            .build();
+        let mut err = crate::not_found("no hat");
+        err.insert_meta("size".to_string(), "7".to_string());
+        assert_eq!(built, err);
+        assert_eq!(built.to_json_bytes(), err.to_json_bytes());
+
+        // without a message or metadata
+        let built: TwirpErrorResponse = TwirpErrorResponse::builder(TwirpErrorCode::Aborted).into();
+        assert_eq!(built, crate::aborted(""));
+        assert_eq!(
+            std::str::from_utf8(&built.to_json_bytes()).unwrap(),
+            r#"{"code":"aborted","msg":""}"#
+        );
+
+        // later entries replace earlier ones
+        let built = TwirpErrorResponse::builder(TwirpErrorCode::Internal)
+            .meta("attempt", 1)
+            .meta("attempt", 2)
+            .build();
+        assert_eq!(built.meta.len(), 1);
+        assert_eq!(built.meta["attempt"], "2");
+    }
 }
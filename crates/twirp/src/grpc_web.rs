@@ -0,0 +1,206 @@
+//! Support for serving Twirp services to [gRPC-Web] clients.
+//!
+//! With the `grpc-web` feature enabled, the handlers generated by `twirp-build` also accept unary
+//! gRPC-Web requests (content type `application/grpc-web` or `application/grpc-web+proto`) and
+//! answer them in kind, so one service implementation can serve both protocols. gRPC-Web clients
+//! address methods as `/<package>.<Service>/<Method>`, so nest the service router at its
+//! `SERVICE_FQN` without the `/twirp` prefix to serve them:
+//!
+//! ```
+//! use axum::Router;
+//!
+//! # fn build_app(service_fqn: &str, routes: fn() -> Router) -> Router {
+//! let app = Router::new()
+//!     .nest(&format!("/twirp{service_fqn}"), routes())
+//!     .nest(service_fqn, routes());
+//! # app }
+//! ```
+//!
+//! Only the binary (protobuf) encoding and unary calls are supported; `application/grpc-web-text`
+//! requests and streaming methods are not.
+//!
+//! [gRPC-Web]: https://github.com/grpc/grpc/blob/master/doc/PROTOCOL-WEB.md
+
+use axum::body::Body;
+use bytes::{BufMut, BytesMut};
+use hyper::{header, Response, StatusCode};
+
+use crate::{GenericError, TwirpErrorCode, TwirpErrorResponse};
+
+pub(crate) const CONTENT_TYPE_GRPC_WEB: &[u8] = b"application/grpc-web";
+pub(crate) const CONTENT_TYPE_GRPC_WEB_PROTO: &[u8] = CONTENT_TYPE_GRPC_WEB_PROTO_STR.as_bytes();
+const CONTENT_TYPE_GRPC_WEB_PROTO_STR: &str = "application/grpc-web+proto";
+
+const FRAME_HEADER_LEN: usize = 5;
+const FLAG_TRAILERS: u8 = 0x80;
+const FLAG_COMPRESSED: u8 = 0x01;
+
+/// The message in the single data frame of a unary request body.
+pub(crate) fn decode_request(body: &[u8]) -> Result<&[u8], GenericError> {
+    let Some((header, rest)) = body.split_first_chunk::<FRAME_HEADER_LEN>() else {
+        return Err("gRPC-Web request body is missing its frame header".into());
+    };
+    let [flags, len @ ..] = *header;
+    if flags & FLAG_COMPRESSED != 0 {
+        return Err("compressed gRPC-Web messages are not supported".into());
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if rest.len() != len {
+        return Err(format!(
+            "gRPC-Web frame length is {len} but the body has {} bytes",
+            rest.len()
+        )
+        .into());
+    }
+    Ok(rest)
+}
+
+/// A successful response: the message in a data frame, then an OK status in the trailers frame.
+pub(crate) fn response(message: &[u8]) -> Result<Response<Body>, GenericError> {
+    let mut body = BytesMut::new();
+    put_frame(&mut body, 0, message);
+    put_trailers(&mut body, 0, "");
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, CONTENT_TYPE_GRPC_WEB_PROTO)
+        .body(Body::from(body.freeze()))?)
+}
+
+/// A failed call. gRPC-Web reports errors in the trailers with an HTTP 200 status.
+pub(crate) fn error_response(err: TwirpErrorResponse) -> Response<Body> {
+    let mut body = BytesMut::new();
+    put_trailers(&mut body, grpc_status(err.code), &err.msg);
+    let mut resp = Response::new(Body::from(body.freeze()));
+    *resp.status_mut() = StatusCode::OK;
+    resp.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static(CONTENT_TYPE_GRPC_WEB_PROTO_STR),
+    );
+    resp.extensions_mut().insert(err);
+    resp
+}
+
+fn put_frame(buf: &mut BytesMut, flags: u8, data: &[u8]) {
+    buf.reserve(FRAME_HEADER_LEN + data.len());
+    buf.put_u8(flags);
+    buf.put_u32(data.len() as u32);
+    buf.put_slice(data);
+}
+
+fn put_trailers(buf: &mut BytesMut, status: u8, msg: &str) {
+    let mut trailers = format!("grpc-status:{status}\r\n");
+    if !msg.is_empty() {
+        trailers.push_str(&format!("grpc-message:{}\r\n", percent_encode(msg)));
+    }
+    put_frame(buf, FLAG_TRAILERS, trailers.as_bytes());
+}
+
+/// The gRPC status code corresponding to a Twirp error code. Twirp's codes were modeled after
+/// gRPC's, so most map one-to-one.
+fn grpc_status(code: TwirpErrorCode) -> u8 {
+    match code {
+        TwirpErrorCode::Canceled => 1,
+        TwirpErrorCode::Unknown => 2,
+        TwirpErrorCode::InvalidArgument | TwirpErrorCode::Malformed => 3,
+        TwirpErrorCode::DeadlineExceeded => 4,
+        TwirpErrorCode::NotFound => 5,
+        TwirpErrorCode::AlreadyExists => 6,
+        TwirpErrorCode::PermissionDenied => 7,
+        TwirpErrorCode::ResourceExhausted => 8,
+        TwirpErrorCode::FailedPrecondition => 9,
+        TwirpErrorCode::Aborted => 10,
+        TwirpErrorCode::OutOfRange => 11,
+        TwirpErrorCode::Unimplemented | TwirpErrorCode::BadRoute => 12,
+        TwirpErrorCode::Internal => 13,
+        TwirpErrorCode::Unavailable => 14,
+        TwirpErrorCode::Dataloss => 15,
+        TwirpErrorCode::Unauthenticated => 16,
+    }
+}
+
+/// Percent-encodes `grpc-message` values as required by the gRPC spec.
+fn percent_encode(msg: &str) -> String {
+    let mut out = String::with_capacity(msg.len());
+    for b in msg.bytes() {
+        if (0x20..=0x7e).contains(&b) && b != b'%' {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{b:02X}"));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use bytes::Bytes;
+    use http_body_util::BodyExt;
+    use hyper::Request;
+    use prost::Message;
+    use tower::Service;
+
+    use super::*;
+    use crate::test::*;
+
+    fn frame(flags: u8, data: &[u8]) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        put_frame(&mut buf, flags, data);
+        buf.to_vec()
+    }
+
+    async fn call(method: &str) -> (Response<Body>, Bytes) {
+        let mut router = test_api_router();
+        let msg = PingRequest {
+            name: "hi".to_string(),
+        };
+        let req = Request::post(format!("/twirp/test.TestAPI/{method}"))
+            .header(header::CONTENT_TYPE, "application/grpc-web+proto")
+            .body(Body::from(frame(0, &msg.encode_to_vec())))
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        let (parts, body) = resp.into_parts();
+        let body = body.collect().await.unwrap().to_bytes();
+        (Response::from_parts(parts, Body::empty()), body)
+    }
+
+    #[tokio::test]
+    async fn test_grpc_web_success() {
+        let (resp, body) = call("Ping").await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/grpc-web+proto"
+        );
+        let trailers = frame(FLAG_TRAILERS, b"grpc-status:0\r\n");
+        let (data, rest) = body.split_at(body.len() - trailers.len());
+        let data = decode_request(data).unwrap();
+        assert_eq!(PingResponse::decode(data).unwrap().name, "hi");
+        assert_eq!(rest, &trailers[..]);
+    }
+
+    #[tokio::test]
+    async fn test_grpc_web_error() {
+        let (resp, body) = call("Boom").await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            &body[..],
+            &frame(FLAG_TRAILERS, b"grpc-status:13\r\ngrpc-message:boom!\r\n")[..]
+        );
+    }
+
+    #[test]
+    fn test_decode_request() {
+        assert_eq!(decode_request(&frame(0, b"abc")).unwrap(), b"abc");
+        assert!(decode_request(b"\0\0").is_err());
+        assert!(decode_request(&frame(FLAG_COMPRESSED, b"abc")).is_err());
+        let mut truncated = frame(0, b"abc");
+        truncated.pop();
+        assert!(decode_request(&truncated).is_err());
+    }
+
+    #[test]
+    fn test_percent_encode() {
+        assert_eq!(percent_encode("no hats: 100%"), "no hats: 100%25");
+        assert_eq!(percent_encode("café\n"), "caf%C3%A9%0A");
+    }
+}
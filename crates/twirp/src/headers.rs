@@ -1,2 +1,30 @@
 pub(crate) const CONTENT_TYPE_PROTOBUF: &[u8] = b"application/protobuf";
 pub(crate) const CONTENT_TYPE_JSON: &[u8] = b"application/json";
+
+/// The prefix of the headers carrying response metadata (see
+/// [`Context::set_meta`](crate::Context::set_meta)). Header names are case-insensitive, and are
+/// always lowercase in `http`.
+pub const META_HEADER_PREFIX: &str = "twirp-meta-";
+
+/// The header clients send their timeout for a request in, as a whole number of milliseconds. The
+/// server makes the time it was received plus the timeout the request's deadline (see
+/// [`Context::deadline`](crate::Context::deadline)).
+pub const REQUEST_TIMEOUT_HEADER: &str = "request-timeout";
+
+/// The header clients identify their implementation in, as `<language>/<version>` (e.g.
+/// `rust/0.7.0`, which is what this crate's client sends). Servers make it available to handlers
+/// and middleware as a [`ClientLanguage`](crate::server::ClientLanguage), to segment traffic by
+/// client, e.g. while migrating a fleet between implementations.
+pub const CLIENT_LANGUAGE_HEADER: &str = "x-twirp-client-language";
+
+/// The header carrying the ID of a request (see [`crate::request_id`]).
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// The header servers send the version of the twirp protocol they implement in, on every response
+/// (successful or not). Clients record it, for diagnosing fleets running mixed versions (see
+/// [`Client::server_twirp_version`](crate::Client::server_twirp_version)).
+pub const TWIRP_VERSION_HEADER: &str = "twirp-version";
+
+/// The version of the [twirp protocol](https://twitchtv.github.io/twirp/docs/spec_v7.html) this
+/// crate implements, as sent in the [`TWIRP_VERSION_HEADER`] header.
+pub const TWIRP_VERSION: &str = "v7";
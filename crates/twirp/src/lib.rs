@@ -1,19 +1,45 @@
+pub mod audit;
 pub mod client;
 pub mod context;
 pub mod error;
 pub mod headers;
+pub mod method_path;
+pub mod operations;
+pub mod request_id;
 pub mod server;
 
+#[cfg(feature = "blocking")]
+pub mod blocking;
+
+#[cfg(feature = "grpc-web")]
+pub mod grpc_web;
+
+#[cfg(feature = "tracing-subscriber")]
+pub mod logging;
+
+#[cfg(feature = "hmac")]
+pub mod signing;
+
+#[cfg(windows)]
+mod named_pipe;
+
 #[cfg(any(test, feature = "test-support"))]
 pub mod test;
 
+#[cfg(any(test, feature = "test-support"))]
+pub mod testing;
+
 #[doc(hidden)]
 pub mod details;
 
-pub use client::{Client, ClientBuilder, ClientError, Middleware, Next, Result};
-pub use context::Context;
+pub use client::{
+    CallMetrics, Client, ClientBuilder, ClientError, Connectivity, Encoding, HedgePolicy,
+    Middleware, Next, RequestStats, Result,
+};
+pub use context::{CancellationToken, Context};
 pub use error::*; // many constructors like `invalid_argument()`
 pub use http::Extensions;
+pub use method_path::MethodPath;
 
 // Re-export this crate's dependencies that users are likely to code against. These can be used to
 // import the exact versions of these libraries `twirp` is built with -- useful if your project is
@@ -26,8 +52,35 @@ pub use url;
 
 /// Re-export of `axum::Router`, the type that encapsulates a server-side implementation of a Twirp
 /// service.
+///
+/// Services are composed with `Router::nest`, which strips the prefix before matching method
+/// paths, so the same service router can be mounted under several prefixes (e.g. one per API
+/// version: `/twirp/v1/my.Service` and `/twirp/v2/my.Service`). Nesting two routers at the same
+/// path panics.
 pub use axum::Router;
 
+/// The bound on request and response messages for the JSON encoding: `serde::Serialize` with the
+/// `json` feature (on by default), and nothing without it, so protobuf-only builds don't need
+/// serde derives on their messages.
+#[cfg(feature = "json")]
+pub trait JsonSerialize: serde::Serialize {}
+#[cfg(feature = "json")]
+impl<T: serde::Serialize> JsonSerialize for T {}
+#[cfg(not(feature = "json"))]
+pub trait JsonSerialize {}
+#[cfg(not(feature = "json"))]
+impl<T> JsonSerialize for T {}
+
+/// Like [`JsonSerialize`], for `serde::de::DeserializeOwned`.
+#[cfg(feature = "json")]
+pub trait JsonDeserialize: serde::de::DeserializeOwned {}
+#[cfg(feature = "json")]
+impl<T: serde::de::DeserializeOwned> JsonDeserialize for T {}
+#[cfg(not(feature = "json"))]
+pub trait JsonDeserialize {}
+#[cfg(not(feature = "json"))]
+impl<T> JsonDeserialize for T {}
+
 pub(crate) fn serialize_proto_message<T>(m: T) -> Vec<u8>
 where
     T: prost::Message,
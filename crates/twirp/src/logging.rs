@@ -0,0 +1,136 @@
+//! Printing the logs of twirp servers with [`tracing_subscriber`], for examples and small services
+//! that don't set up their own subscriber.
+//!
+//! Servers handle every request in a `twirp_request` span with the fields:
+//!
+//! - `method`: the method path, e.g. `example.haberdash.v1.Haberdasher/MakeHat`
+//! - `request_id`: the ID of the request (see [`crate::request_id`])
+//! - `code`: `ok`, or the twirp error code of the response, e.g. `not_found`
+//! - `elapsed_ms`: the time taken to handle the request and write the response
+//! - `fields`: the fields recorded by the handler with [`Context::record`](crate::Context::record)
+//!
+//! The subscriber set up here prefixes the events logged while handling a request with the
+//! method and request ID, and prints a line with all the fields when the request has been handled:
+//!
+//! ```text
+//! 2024-11-02T10:12:31.482Z  INFO twirp_request{method="example.haberdash.v1.Haberdasher/MakeHat" request_id="b3c1" code="ok" elapsed_ms=2}: twirp::server: close time.busy=1.2ms time.idle=0.8ms
+//! ```
+//!
+//! ```no_run
+//! # async fn run(app: twirp::Router) {
+//! twirp::logging::init();
+//! let listener = tokio::net::TcpListener::bind("127.0.0.1:3000").await.unwrap();
+//! twirp::axum::serve(listener, app).await.unwrap();
+//! # }
+//! ```
+
+use std::io::IsTerminal;
+
+use tracing::Subscriber;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+/// A [`tracing_subscriber::fmt`] layer that prints events to stdout, and a line with the method,
+/// error code and duration of every request when it has been handled.
+///
+/// Use this to add twirp's logs to a subscriber with other layers; [`init`] installs one that
+/// only prints them.
+pub fn layer<S>() -> impl Layer<S> + Send + Sync + 'static
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    layer_with_writer(std::io::stdout, std::io::stdout().is_terminal())
+}
+
+fn layer_with_writer<S, W>(writer: W, ansi: bool) -> impl Layer<S> + Send + Sync + 'static
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi)
+        .with_span_events(FmtSpan::CLOSE)
+}
+
+/// Install a global subscriber that prints events at the info level and above to stdout, with a
+/// line for every request handled by a twirp server (see the [module docs](self)).
+///
+/// # Panics
+///
+/// If a global subscriber has already been installed.
+pub fn init() {
+    tracing_subscriber::registry()
+        .with(layer().with_filter(LevelFilter::INFO))
+        .init();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::body::Body;
+    use http::Request;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test::*;
+
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_layer() {
+        let output = Output::default();
+        let writer = {
+            let output = output.clone();
+            move || output.clone()
+        };
+        let subscriber = tracing_subscriber::registry().with(
+            layer_with_writer(writer, false)
+                .with_filter(LevelFilter::INFO)
+                .boxed(),
+        );
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let router = test_api_router();
+        for method in ["Ping", "Boom"] {
+            let req = Request::post(format!("/twirp/test.TestAPI/{method}"))
+                .header("content-type", "application/json")
+                .header("x-request-id", "abc")
+                .body(Body::from(r#"{"name":"alice"}"#))
+                .unwrap();
+            router.clone().oneshot(req).await.unwrap();
+        }
+
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines.len(), 2, "{output}");
+        for (line, method, code) in [(lines[0], "Ping", "ok"), (lines[1], "Boom", "internal")] {
+            let span = format!(
+                r#"INFO twirp_request{{method="test.TestAPI/{method}" request_id="abc" code="{code}" elapsed_ms="#
+            );
+            assert!(line.contains(&span), "{line}");
+            assert!(
+                line.contains("}: twirp::server: close time.busy="),
+                "{line}"
+            );
+        }
+    }
+}
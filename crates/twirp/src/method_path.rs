@@ -0,0 +1,168 @@
+//! The paths naming twirp methods, like `example.haberdash.v1.Haberdasher/MakeHat`.
+
+use std::fmt;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+/// The path of a twirp method, split into its package, service, and method names.
+///
+/// Servers put the path of the method being called in the request extensions, where hooks like
+/// middleware find it (as do handlers, with [`Context::method_path`](crate::Context::method_path)),
+/// e.g. to apply per-package or per-service policies:
+///
+/// ```
+/// use twirp::MethodPath;
+///
+/// let path: MethodPath = "example.haberdash.v1.Haberdasher/MakeHat".parse().unwrap();
+/// assert_eq!(path.package(), "example.haberdash.v1");
+/// assert_eq!(path.service(), "Haberdasher");
+/// assert_eq!(path.method(), "MakeHat");
+/// assert_eq!(path.service_fqn(), "example.haberdash.v1.Haberdasher");
+/// assert_eq!(path.to_string(), "example.haberdash.v1.Haberdasher/MakeHat");
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MethodPath {
+    package: String,
+    service: String,
+    method: String,
+}
+
+/// The error for a string that isn't a method path (see [`MethodPath`]).
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+#[error("invalid method path {0:?}: expected `package.Service/Method`")]
+pub struct InvalidMethodPath(String);
+
+impl MethodPath {
+    /// The path of `method` of the service `service` in `package` (which is empty for services
+    /// declared without one).
+    pub fn new(
+        package: impl Into<String>,
+        service: impl Into<String>,
+        method: impl Into<String>,
+    ) -> Self {
+        Self {
+            package: package.into(),
+            service: service.into(),
+            method: method.into(),
+        }
+    }
+
+    /// The method path at the end of the path of a request URI, wherever the service is nested:
+    /// `/twirp/example.Haberdasher/MakeHat` is the `example.Haberdasher/MakeHat` method.
+    pub fn from_uri_path(path: &str) -> Result<Self, InvalidMethodPath> {
+        match path.rmatch_indices('/').nth(1) {
+            Some((i, _)) => path[i + 1..].parse(),
+            None => path.parse(),
+        }
+        .map_err(|_| InvalidMethodPath(path.to_string()))
+    }
+
+    pub fn package(&self) -> &str {
+        &self.package
+    }
+
+    pub fn service(&self) -> &str {
+        &self.service
+    }
+
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    /// The fully qualified name of the service, e.g. `example.haberdash.v1.Haberdasher`.
+    pub fn service_fqn(&self) -> String {
+        if self.package.is_empty() {
+            self.service.clone()
+        } else {
+            format!("{}.{}", self.package, self.service)
+        }
+    }
+}
+
+/// Parses `package.Service/Method`, with or without a leading `/`.
+impl FromStr for MethodPath {
+    type Err = InvalidMethodPath;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidMethodPath(s.to_string());
+        let (service_fqn, method) = s
+            .strip_prefix('/')
+            .unwrap_or(s)
+            .split_once('/')
+            .ok_or_else(invalid)?;
+        let is_name = |name: &str| {
+            !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        };
+        if !service_fqn.split('.').all(is_name) || !is_name(method) {
+            return Err(invalid());
+        }
+        let (package, service) = service_fqn.rsplit_once('.').unwrap_or(("", service_fqn));
+        Ok(Self::new(package, service, method))
+    }
+}
+
+impl fmt::Display for MethodPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.service_fqn(), self.method)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let path: MethodPath = "/example.v1.Haberdasher/MakeHat".parse().unwrap();
+        assert_eq!(
+            path,
+            MethodPath::new("example.v1", "Haberdasher", "MakeHat")
+        );
+        assert_eq!(path.to_string(), "example.v1.Haberdasher/MakeHat");
+
+        let path: MethodPath = "Haberdasher/MakeHat".parse().unwrap();
+        assert_eq!(path, MethodPath::new("", "Haberdasher", "MakeHat"));
+        assert_eq!(path.service_fqn(), "Haberdasher");
+        assert_eq!(path.to_string(), "Haberdasher/MakeHat");
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        for malformed in [
+            "",
+            "/",
+            "MakeHat",
+            "example.Haberdasher",
+            "example.Haberdasher/",
+            "/MakeHat",
+            "example./MakeHat",
+            ".Haberdasher/MakeHat",
+            "example..Haberdasher/MakeHat",
+            "example.Haberdasher/Make/Hat",
+            "twirp/example.Haberdasher/MakeHat",
+            "example.Haber dasher/MakeHat",
+        ] {
+            let err = malformed.parse::<MethodPath>().unwrap_err();
+            assert_eq!(err, InvalidMethodPath(malformed.to_string()));
+        }
+        assert_eq!(
+            InvalidMethodPath("MakeHat".to_string()).to_string(),
+            "invalid method path \"MakeHat\": expected `package.Service/Method`"
+        );
+    }
+
+    #[test]
+    fn test_from_uri_path() {
+        let expected = MethodPath::new("example", "Haberdasher", "MakeHat");
+        for path in [
+            "example.Haberdasher/MakeHat",
+            "/example.Haberdasher/MakeHat",
+            "/twirp/example.Haberdasher/MakeHat",
+            "/api/v1/twirp/example.Haberdasher/MakeHat",
+        ] {
+            assert_eq!(MethodPath::from_uri_path(path).unwrap(), expected, "{path}");
+        }
+        assert!(MethodPath::from_uri_path("/MakeHat").is_err());
+    }
+}
@@ -0,0 +1,77 @@
+//! The client side of [`serve_named_pipe`](crate::server::serve_named_pipe): a transport sending
+//! requests over a Windows named pipe, for
+//! [`ClientBuilder::named_pipe`](crate::ClientBuilder::named_pipe).
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use http::header;
+use hyper_util::rt::TokioIo;
+use tokio::net::windows::named_pipe::ClientOptions;
+
+use crate::client::{ClientError, Middleware, Next, Result};
+
+/// The error opening a pipe whose instances are all connected to other clients.
+const ERROR_PIPE_BUSY: i32 = 231;
+
+/// How long to wait before trying a busy pipe again.
+const BUSY_RETRY_DELAY: Duration = Duration::from_millis(20);
+
+/// Sends requests over HTTP/1.1 on a new connection to the pipe, instead of running the rest of
+/// the middleware. It has to be the last middleware.
+pub(crate) struct NamedPipeTransport {
+    name: String,
+}
+
+impl NamedPipeTransport {
+    pub(crate) fn new(name: String) -> Self {
+        Self { name }
+    }
+
+    async fn connect(&self) -> std::io::Result<tokio::net::windows::named_pipe::NamedPipeClient> {
+        loop {
+            match ClientOptions::new().open(&self.name) {
+                Err(err) if err.raw_os_error() == Some(ERROR_PIPE_BUSY) => {
+                    tokio::time::sleep(BUSY_RETRY_DELAY).await;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware for NamedPipeTransport {
+    async fn handle(&self, req: reqwest::Request, _: Next<'_>) -> Result<reqwest::Response> {
+        let mut req = http::Request::<reqwest::Body>::try_from(req)?;
+        // The pipe is the connection: send the path alone, with the URL's host in `Host`.
+        if let Some(authority) = req.uri().authority() {
+            let host = header::HeaderValue::from_str(authority.as_str())?;
+            req.headers_mut().insert(header::HOST, host);
+        }
+        if let Some(path) = req.uri().path_and_query().cloned() {
+            *req.uri_mut() = path.into();
+        }
+
+        let pipe = self.connect().await.map_err(pipe_error)?;
+        let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(pipe))
+            .await
+            .map_err(pipe_error)?;
+        tokio::spawn(async move {
+            if let Err(err) = conn.await {
+                tracing::debug!(error = %err, "error on named pipe connection");
+            }
+        });
+        let resp = sender.send_request(req).await.map_err(pipe_error)?;
+        let (parts, body) = resp.into_parts();
+        let body = http_body_util::BodyExt::collect(body)
+            .await
+            .map_err(pipe_error)?
+            .to_bytes();
+        Ok(http::Response::from_parts(parts, body).into())
+    }
+}
+
+fn pipe_error(err: impl std::error::Error + Send + Sync + 'static) -> ClientError {
+    ClientError::NamedPipe(Box::new(err))
+}
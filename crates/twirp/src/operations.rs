@@ -0,0 +1,503 @@
+//! Long-running methods that clients can submit and poll, following the `Prefer: respond-async`
+//! convention of [RFC 7240](https://www.rfc-editor.org/rfc/rfc7240#section-4.1). Entirely opt-in:
+//! only methods registered with `TwirpRouterBuilder::route_async` take part.
+//!
+//! A call to such a method without the preference is handled as usual. A call with a
+//! `Prefer: respond-async` header is answered right away with `202 Accepted`, a
+//! `Preference-Applied: respond-async` header and an [`Operation`] message naming the operation,
+//! while the handler runs in the background. The client then polls the companion status method,
+//! named after the method with [`STATUS_METHOD_SUFFIX`] (e.g. `MakeHatStatus` for `MakeHat`),
+//! sending it the `Operation`. The status method answers:
+//!
+//! - `202 Accepted` and the `Operation` again, while the handler is running;
+//! - `200 OK` and the method's response, once the handler succeeded;
+//! - the handler's error, if it failed;
+//! - a `not_found` error, for operations the store doesn't know (or no longer keeps).
+//!
+//! Operations are kept in an [`OperationStore`], e.g. an [`InMemoryOperationStore`], or one backed
+//! by a database for services with several replicas. Handlers running in the background have no
+//! deadline and aren't cancelled when the client goes away, and the metadata they set on the
+//! [`Context`] isn't sent. They run with the server's [`Options::spawner`] and count towards its
+//! [`Options::max_in_flight`] until they complete. gRPC-Web requests are always handled
+//! synchronously.
+//!
+//! ```
+//! # use std::sync::Arc;
+//! # use std::time::Duration;
+//! # use twirp::details::TwirpRouterBuilder;
+//! # use twirp::operations::InMemoryOperationStore;
+//! # #[derive(Clone, PartialEq, prost::Message, serde::Serialize, serde::Deserialize)]
+//! # struct ReportRequest {}
+//! # #[derive(Clone, PartialEq, prost::Message, serde::Serialize, serde::Deserialize)]
+//! # struct Report {}
+//! # async fn build_report(req: ReportRequest) -> Result<Report, twirp::TwirpErrorResponse> {
+//! #     Ok(Report {})
+//! # }
+//! let store = Arc::new(InMemoryOperationStore::new(Duration::from_secs(3600)));
+//! let router = TwirpRouterBuilder::new(())
+//!     .route_async("/BuildReport", store, |_, _, req: ReportRequest| build_report(req))
+//!     .build();
+//! let app = twirp::Router::new().nest("/twirp/example.ReportService", router);
+//! # let _: twirp::Router = app;
+//! ```
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use http::{header, HeaderValue, Response, StatusCode};
+
+use crate::server::{BodyFormat, InFlightPermit, IntoResponseBody, Options, ResponseBody};
+use crate::{error, Context, GenericError, TwirpErrorResponse};
+
+/// The suffix of the names of the companion status methods of methods registered with
+/// `TwirpRouterBuilder::route_async`.
+pub const STATUS_METHOD_SUFFIX: &str = "Status";
+
+/// A reference to an operation running in the background: the body of the `202 Accepted`
+/// response to a call with `Prefer: respond-async`, and the request of the status method.
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Operation {
+    #[prost(string, tag = "1")]
+    pub id: String,
+}
+
+/// Where an operation is at.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OperationState {
+    /// The handler is running.
+    Pending,
+    /// The handler succeeded with this response, encoded with protobuf.
+    Succeeded(bytes::Bytes),
+    /// The handler failed with this error.
+    Failed(TwirpErrorResponse),
+}
+
+/// Keeps the state of operations by their ID. Errors are sent to the clients calling the method or
+/// its status method.
+#[async_trait]
+pub trait OperationStore: Send + Sync + 'static {
+    /// Record the state of the operation `id`, which is [`OperationState::Pending`] when it is
+    /// submitted, and the outcome of the handler once it completes.
+    async fn put(&self, id: &str, state: OperationState) -> Result<(), TwirpErrorResponse>;
+
+    /// The state of the operation `id`, or `None` if there is no such operation.
+    async fn get(&self, id: &str) -> Result<Option<OperationState>, TwirpErrorResponse>;
+}
+
+/// An [`OperationStore`] keeping operations in memory, and only for a while, so they can't pile
+/// up. Operations don't survive a restart of the server, nor are they shared between replicas.
+#[derive(Debug)]
+pub struct InMemoryOperationStore {
+    ttl: Duration,
+    operations: Mutex<HashMap<String, (OperationState, Instant)>>,
+}
+
+impl InMemoryOperationStore {
+    /// A store forgetting operations `ttl` after they were last updated: finished ones `ttl` after
+    /// they finished, and pending ones `ttl` after they were submitted, so operations whose outcome
+    /// never gets recorded don't stay forever. Pick a `ttl` longer than handlers run, or clients
+    /// polling running operations get a `not_found` error.
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            operations: Mutex::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl OperationStore for InMemoryOperationStore {
+    async fn put(&self, id: &str, state: OperationState) -> Result<(), TwirpErrorResponse> {
+        let now = Instant::now();
+        let mut operations = self.operations.lock().expect("mutex poisoned");
+        operations.retain(|_, (_, updated)| now.duration_since(*updated) < self.ttl);
+        operations.insert(id.to_string(), (state, now));
+        Ok(())
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<OperationState>, TwirpErrorResponse> {
+        let operations = self.operations.lock().expect("mutex poisoned");
+        Ok(operations
+            .get(id)
+            .filter(|(_, updated)| updated.elapsed() < self.ttl)
+            .map(|(state, _)| state.clone()))
+    }
+}
+
+/// Whether a request asks to be answered before the handler completes, with a `Prefer` header
+/// including `respond-async`. gRPC-Web requests never are.
+pub(crate) fn prefers_async(headers: &header::HeaderMap) -> bool {
+    let grpc_web = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| v.starts_with("application/grpc-web"));
+    !grpc_web
+        && headers
+            .get_all("prefer")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .filter_map(|preference| preference.split(';').next())
+            .any(|preference| preference.trim().eq_ignore_ascii_case("respond-async"))
+}
+
+/// A response extension marking responses to be sent as `202 Accepted`.
+#[derive(Clone, Copy, Debug)]
+struct Accepted;
+
+/// Turn the response of a method registered with `route_async`, or of its status method, into a
+/// `202 Accepted` if the operation is still running.
+pub(crate) fn finish_response(mut resp: Response<axum::body::Body>) -> Response<axum::body::Body> {
+    if resp.status() == StatusCode::OK && resp.extensions().get::<Accepted>().is_some() {
+        *resp.status_mut() = StatusCode::ACCEPTED;
+        resp.headers_mut().insert(
+            "preference-applied",
+            HeaderValue::from_static("respond-async"),
+        );
+    }
+    resp
+}
+
+/// The response of a method registered with `route_async`, or of its status method.
+pub(crate) enum OperationResponse<T> {
+    Pending(Operation),
+    Done(T),
+}
+
+impl<T> IntoResponseBody for OperationResponse<T>
+where
+    T: IntoResponseBody,
+{
+    fn into_response_body(
+        self,
+        format: BodyFormat,
+        options: &Options,
+    ) -> Result<ResponseBody, GenericError> {
+        match self {
+            OperationResponse::Pending(operation) => operation.into_response_body(format, options),
+            OperationResponse::Done(resp) => resp.into_response_body(format, options),
+        }
+    }
+}
+
+/// Start an operation running `f` in the background, and answer with it.
+///
+/// The handler runs with the [spawner](Options::spawner), if any, and keeps the request's slot in
+/// [`Options::max_in_flight`] until it completes, so operations count towards the limit as long
+/// as they run. A handler that panics, or that the spawner drops, fails with `internal`.
+pub(crate) async fn submit<S, F, Fut, Req, Res>(
+    store: Arc<dyn OperationStore>,
+    options: Arc<Options>,
+    api: S,
+    ctx: Context,
+    req: Req,
+    f: F,
+) -> Result<OperationResponse<Res>, TwirpErrorResponse>
+where
+    S: Send + 'static,
+    F: FnOnce(S, Context, Req) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<Res, TwirpErrorResponse>> + Send + 'static,
+    Req: Send + 'static,
+    Res: prost::Message + 'static,
+{
+    let id = crate::request_id::uuid_v4();
+    store.put(&id, OperationState::Pending).await?;
+    ctx.insert(Accepted);
+    let in_flight = ctx.get::<InFlightPermit>().and_then(InFlightPermit::take);
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    options.spawn(Box::pin(async move {
+        let _ = tx.send(f(api, ctx, req).await);
+    }));
+    let operation = id.clone();
+    tokio::spawn(async move {
+        let state = match rx.await {
+            Ok(Ok(resp)) => OperationState::Succeeded(resp.encode_to_vec().into()),
+            Ok(Err(err)) => OperationState::Failed(err),
+            Err(_) => OperationState::Failed(error::internal("handler dropped before completing")),
+        };
+        drop(in_flight);
+        if let Err(err) = store.put(&operation, state).await {
+            tracing::error!(
+                operation,
+                error = %err.msg,
+                "failed to record the outcome of an operation"
+            );
+        }
+    });
+    Ok(OperationResponse::Pending(Operation { id }))
+}
+
+/// The status method: where `operation` is at.
+pub(crate) async fn status<Res>(
+    store: Arc<dyn OperationStore>,
+    ctx: Context,
+    operation: Operation,
+) -> Result<OperationResponse<Res>, TwirpErrorResponse>
+where
+    Res: prost::Message + Default,
+{
+    match store.get(&operation.id).await? {
+        None => Err(error::not_found(format!("no operation {:?}", operation.id))),
+        Some(OperationState::Pending) => {
+            ctx.insert(Accepted);
+            Ok(OperationResponse::Pending(operation))
+        }
+        Some(OperationState::Succeeded(body)) => Res::decode(body)
+            .map(OperationResponse::Done)
+            .map_err(|err| error::internal(format!("failed to decode operation result: {err}"))),
+        Some(OperationState::Failed(err)) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "json")]
+    use axum::body::Body;
+    #[cfg(feature = "json")]
+    use http::Request;
+
+    use super::*;
+
+    #[cfg(feature = "json")]
+    fn call(path: &str, prefer: Option<&str>, body: String) -> Request<Body> {
+        let mut req = Request::post(path).header(header::CONTENT_TYPE, "application/json");
+        if let Some(prefer) = prefer {
+            req = req.header("prefer", prefer);
+        }
+        req.body(Body::from(body)).unwrap()
+    }
+
+    #[cfg(feature = "json")]
+    async fn json_body<T: serde::de::DeserializeOwned>(resp: Response<Body>) -> T {
+        let body = http_body_util::BodyExt::collect(resp.into_body())
+            .await
+            .unwrap()
+            .to_bytes();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[test]
+    fn test_prefers_async() {
+        for (prefer, expected) in [
+            ("respond-async", true),
+            ("Respond-Async", true),
+            ("wait=10, respond-async", true),
+            ("respond-async; foo=bar", true),
+            ("return=minimal", false),
+            ("respond-asynchronously", false),
+        ] {
+            let mut headers = header::HeaderMap::new();
+            headers.insert("prefer", HeaderValue::from_static(prefer));
+            assert_eq!(prefers_async(&headers), expected, "{prefer}");
+        }
+        assert!(!prefers_async(&header::HeaderMap::new()));
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_route_async() {
+        use tokio::sync::Notify;
+        use tower::ServiceExt;
+
+        use crate::details::TwirpRouterBuilder;
+        use crate::test::*;
+
+        let release = Arc::new(Notify::new());
+        let store = Arc::new(InMemoryOperationStore::new(Duration::from_secs(60)));
+        let router = TwirpRouterBuilder::new(release.clone())
+            .route_async(
+                "/Ping",
+                store,
+                |release: Arc<Notify>, _: Context, req: PingRequest| async move {
+                    if req.name == "slow" {
+                        release.notified().await;
+                    }
+                    if req.name == "boom" {
+                        return Err(error::internal("boom!"));
+                    }
+                    Ok(PingResponse { name: req.name })
+                },
+            )
+            .build();
+        let ping = |prefer: Option<&'static str>, name: &str| {
+            router
+                .clone()
+                .oneshot(call("/Ping", prefer, format!(r#"{{"name":"{name}"}}"#)))
+        };
+        let status = |id: &str| {
+            router
+                .clone()
+                .oneshot(call("/PingStatus", None, format!(r#"{{"id":"{id}"}}"#)))
+        };
+
+        // without the preference, the method is called as usual
+        let resp = ping(None, "hi").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!resp.headers().contains_key("preference-applied"));
+        assert_eq!(json_body::<PingResponse>(resp).await.name, "hi");
+
+        let resp = ping(Some("respond-async"), "slow").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        assert_eq!(resp.headers()["preference-applied"], "respond-async");
+        let operation: Operation = json_body(resp).await;
+        let resp = status(&operation.id).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        assert_eq!(json_body::<Operation>(resp).await, operation);
+
+        release.notify_one();
+        let resp = loop {
+            let resp = status(&operation.id).await.unwrap();
+            if resp.status() != StatusCode::ACCEPTED {
+                break resp;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        };
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(json_body::<PingResponse>(resp).await.name, "slow");
+
+        let resp = ping(Some("respond-async"), "boom").await.unwrap();
+        let operation: Operation = json_body(resp).await;
+        let err = loop {
+            let resp = status(&operation.id).await.unwrap();
+            if resp.status() != StatusCode::ACCEPTED {
+                break read_err_body(resp.into_body()).await;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        };
+        assert_eq!(err, error::internal("boom!"));
+
+        let resp = status("nope").await.unwrap();
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err, error::not_found(r#"no operation "nope""#));
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_route_async_options() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use futures::future::BoxFuture;
+        use tokio::sync::Notify;
+        use tower::ServiceExt;
+
+        use crate::details::TwirpRouterBuilder;
+        use crate::server::Spawner;
+        use crate::test::*;
+
+        struct CountingSpawner(Arc<AtomicUsize>);
+
+        impl Spawner for CountingSpawner {
+            fn spawn(&self, future: BoxFuture<'static, ()>) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(future);
+            }
+        }
+
+        let spawned = Arc::new(AtomicUsize::new(0));
+        let release = Arc::new(Notify::new());
+        let store = Arc::new(InMemoryOperationStore::new(Duration::from_secs(60)));
+        let router = TwirpRouterBuilder::new(release.clone())
+            .route_async(
+                "/Ping",
+                store,
+                |release: Arc<Notify>, _: Context, req: PingRequest| async move {
+                    if req.name == "slow" {
+                        release.notified().await;
+                    }
+                    if req.name == "panic" {
+                        panic!("boom!");
+                    }
+                    Ok(PingResponse { name: req.name })
+                },
+            )
+            .build()
+            .layer(
+                Options::new()
+                    .max_in_flight(1)
+                    .spawner(CountingSpawner(spawned.clone())),
+            );
+        let ping = |prefer: Option<&'static str>, name: &str| {
+            router
+                .clone()
+                .oneshot(call("/Ping", prefer, format!(r#"{{"name":"{name}"}}"#)))
+        };
+        let wait = |id: String| {
+            let router = router.clone();
+            async move {
+                loop {
+                    let resp = router
+                        .clone()
+                        .oneshot(call("/PingStatus", None, format!(r#"{{"id":"{id}"}}"#)))
+                        .await
+                        .unwrap();
+                    // polls are shed too while the operation holds the only slot
+                    if resp.status() != StatusCode::ACCEPTED
+                        && resp.status() != StatusCode::SERVICE_UNAVAILABLE
+                    {
+                        break resp;
+                    }
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+            }
+        };
+
+        // the operation runs with the spawner, and keeps its slot until it completes
+        let resp = ping(Some("respond-async"), "slow").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        let operation: Operation = json_body(resp).await;
+        // once for the call submitting the operation, once for the handler in the background
+        assert_eq!(spawned.load(Ordering::SeqCst), 2);
+        let resp = ping(None, "hi").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            read_err_body(resp.into_body()).await,
+            error::unavailable("too many requests")
+        );
+        release.notify_one();
+        let resp = wait(operation.id).await;
+        assert_eq!(json_body::<PingResponse>(resp).await.name, "slow");
+        let resp = ping(None, "hi").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // a panicking handler fails the operation, and gives its slot back
+        let resp = ping(Some("respond-async"), "panic").await.unwrap();
+        let operation: Operation = json_body(resp).await;
+        let resp = wait(operation.id).await;
+        assert_eq!(
+            read_err_body(resp.into_body()).await,
+            error::internal("handler dropped before completing")
+        );
+        let resp = ping(None, "hi").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_store_ttl() {
+        let store = InMemoryOperationStore::new(Duration::from_millis(40));
+        store.put("a", OperationState::Pending).await.unwrap();
+        store
+            .put("b", OperationState::Succeeded(bytes::Bytes::new()))
+            .await
+            .unwrap();
+        store.put("c", OperationState::Pending).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(25)).await;
+        store
+            .put("c", OperationState::Succeeded(bytes::Bytes::new()))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(25)).await;
+        // pending operations expire too, counting from when they were submitted
+        assert_eq!(store.get("a").await.unwrap(), None);
+        assert_eq!(store.get("b").await.unwrap(), None);
+        assert_eq!(
+            store.get("c").await.unwrap(),
+            Some(OperationState::Succeeded(bytes::Bytes::new()))
+        );
+    }
+}
@@ -0,0 +1,62 @@
+//! Request IDs, for correlating the logs of clients and servers.
+//!
+//! Clients send an ID with every request in the [`REQUEST_ID_HEADER`] header, and servers assign
+//! one to requests that come without it. Both generate IDs with a [`RequestIdGenerator`], which is
+//! [`uuid_v4`] unless configured otherwise (see
+//! [`ClientBuilder::request_id_generator`](crate::ClientBuilder::request_id_generator) and
+//! [`Options::request_id_generator`](crate::server::Options::request_id_generator)), e.g. to use
+//! the ID format a tracing system expects.
+
+use std::fmt::Write;
+use std::sync::Arc;
+
+pub use crate::headers::REQUEST_ID_HEADER;
+
+/// Generates request IDs.
+pub type RequestIdGenerator = Arc<dyn Fn() -> String + Send + Sync>;
+
+/// The ID of the request being handled, either the one the client sent or one the server
+/// generated. Handlers read it with [`Context::request_id`](crate::Context::request_id), and
+/// middleware finds it in the extensions of the response.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+/// A random (version 4) UUID, e.g. `0b6f1e5c-3c4a-4f6e-9d2b-7a1c5e8f9d03`.
+pub fn uuid_v4() -> String {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).expect("the OS random number generator failed");
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = bytes.iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{b:02x}");
+        hex
+    });
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+pub(crate) fn default_generator() -> RequestIdGenerator {
+    Arc::new(uuid_v4)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uuid_v4() {
+        let id = uuid_v4();
+        assert_eq!(id.len(), 36);
+        let groups: Vec<_> = id.split('-').map(str::len).collect();
+        assert_eq!(groups, [8, 4, 4, 4, 12]);
+        assert_eq!(&id[14..15], "4");
+        assert!(matches!(&id[19..20], "8" | "9" | "a" | "b"), "{id}");
+        assert_ne!(id, uuid_v4());
+    }
+}
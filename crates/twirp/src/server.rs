@@ -3,318 +3,4798 @@
 //! There is not much to see in the documentation here. This API is meant to be used with
 //! `twirp-build`. See <https://github.com/github/twirp-rs#usage> for details and an example.
 
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 
+use arc_swap::ArcSwap;
 use axum::body::Body;
+use axum::middleware::AddExtension;
 use axum::response::IntoResponse;
+use axum::Extension;
+use futures::future::BoxFuture;
 use futures::Future;
 use http::Extensions;
 use http_body_util::BodyExt;
-use hyper::{header, Request, Response};
-use serde::de::DeserializeOwned;
-use serde::Serialize;
+use hyper::{header, Request, Response, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
 use tokio::time::{Duration, Instant};
+use tower::Layer;
+use tracing::instrument::{Instrument, Instrumented};
 
-use crate::headers::{CONTENT_TYPE_JSON, CONTENT_TYPE_PROTOBUF};
-use crate::{error, serialize_proto_message, Context, GenericError, TwirpErrorResponse};
+use crate::audit::{AuditOverflow, AuditRecord, AuditSink, Auditor, DEFAULT_AUDIT_BUFFER};
+use crate::context::{Cacheable, LogFields, ResponseMeta, ResponseTrailers};
+#[cfg(feature = "grpc-web")]
+use crate::grpc_web;
+use crate::headers::{
+    CLIENT_LANGUAGE_HEADER, CONTENT_TYPE_JSON, CONTENT_TYPE_PROTOBUF, META_HEADER_PREFIX,
+    REQUEST_ID_HEADER, REQUEST_TIMEOUT_HEADER, TWIRP_VERSION, TWIRP_VERSION_HEADER,
+};
+use crate::{
+    error, serialize_proto_message, CancellationToken, Context, Encoding, GenericError,
+    JsonDeserialize, JsonSerialize, MethodPath, TwirpErrorResponse,
+};
 
 // TODO: Properly implement JsonPb (de)serialization as it is slightly different
 // than standard JSON.
 #[derive(Debug, Clone, Copy, Default)]
-enum BodyFormat {
+pub(crate) enum BodyFormat {
     #[default]
     JsonPb,
     Pb,
+    #[cfg(feature = "grpc-web")]
+    GrpcWeb,
 }
 
 impl BodyFormat {
-    fn from_content_type(req: &Request<Body>) -> BodyFormat {
+    fn from_content_type_str(content_type: &str) -> Option<BodyFormat> {
+        // Parameters like `; charset=utf-8` don't change the format.
+        let media_type = content_type.split(';').next()?.trim();
+        match media_type.as_bytes() {
+            CONTENT_TYPE_PROTOBUF => Some(BodyFormat::Pb),
+            CONTENT_TYPE_JSON => Some(BodyFormat::JsonPb),
+            #[cfg(feature = "grpc-web")]
+            grpc_web::CONTENT_TYPE_GRPC_WEB | grpc_web::CONTENT_TYPE_GRPC_WEB_PROTO => {
+                Some(BodyFormat::GrpcWeb)
+            }
+            _ => None,
+        }
+    }
+
+    /// The format to write the response in, negotiated from the `Accept` header. Falls back to
+    /// `default` (the request's format) if there is no header or it lists no supported type.
+    fn from_accept(req: &Request<Body>, default: BodyFormat) -> BodyFormat {
+        #[cfg(feature = "grpc-web")]
+        if let BodyFormat::GrpcWeb = default {
+            // gRPC-Web clients can only read gRPC-Web responses.
+            return default;
+        }
         match req
             .headers()
-            .get(header::CONTENT_TYPE)
-            .map(|x| x.as_bytes())
+            .get(header::ACCEPT)
+            .and_then(|x| x.to_str().ok())
         {
-            Some(CONTENT_TYPE_PROTOBUF) => BodyFormat::Pb,
-            _ => BodyFormat::JsonPb,
+            Some(accept) => negotiate(accept, default),
+            None => default,
         }
     }
 }
 
-/// Entry point used in code generated by `twirp-build`.
-pub(crate) async fn handle_request<S, F, Fut, Req, Resp>(
-    service: S,
-    req: Request<Body>,
-    f: F,
-) -> Response<Body>
-where
-    F: FnOnce(S, Context, Req) -> Fut + Clone + Sync + Send + 'static,
-    Fut: Future<Output = Result<Resp, TwirpErrorResponse>> + Send,
-    Req: prost::Message + Default + serde::de::DeserializeOwned,
-    Resp: prost::Message + serde::Serialize,
-{
-    let mut timings = req
-        .extensions()
-        .get::<Timings>()
-        .copied()
-        .unwrap_or_else(|| Timings::new(Instant::now()));
-
-    let (req, exts, resp_fmt) = match parse_request(req, &mut timings).await {
-        Ok(pair) => pair,
-        Err(err) => {
-            // TODO: Capture original error in the response extensions. E.g.:
-            // resp_exts
-            //     .lock()
-            //     .expect("mutex poisoned")
-            //     .insert(RequestError(err));
-            let mut twirp_err = error::malformed("bad request");
-            twirp_err.insert_meta("error".to_string(), err.to_string());
-            return twirp_err.into_response();
-        }
-    };
-
-    let resp_exts = Arc::new(Mutex::new(Extensions::new()));
-    let ctx = Context::new(exts, resp_exts.clone());
-    let res = f(service, ctx, req).await;
-    timings.set_response_handled();
-
-    let mut resp = match write_response(res, resp_fmt) {
-        Ok(resp) => resp,
-        Err(err) => {
-            // TODO: Capture original error in the response extensions.
-            let mut twirp_err = error::unknown("error serializing response");
-            twirp_err.insert_meta("error".to_string(), err.to_string());
-            return twirp_err.into_response();
+/// Picks the supported media type in an `Accept` header with the highest q-value. Exact types win
+/// over wildcards with the same q-value, and earlier entries win ties.
+fn negotiate(accept: &str, default: BodyFormat) -> BodyFormat {
+    let mut best: Option<(f32, bool, BodyFormat)> = None;
+    for item in accept.split(',') {
+        let mut params = item.split(';');
+        let media_type = params.next().unwrap_or_default().trim().as_bytes();
+        let q = params
+            .find_map(|p| p.trim().strip_prefix("q="))
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        let (exact, format) = match media_type {
+            CONTENT_TYPE_PROTOBUF => (true, BodyFormat::Pb),
+            #[cfg(feature = "json")]
+            CONTENT_TYPE_JSON => (true, BodyFormat::JsonPb),
+            b"*/*" | b"application/*" => (false, default),
+            _ => continue,
+        };
+        let better = match best {
+            Some((best_q, best_exact, _)) => q > best_q || (q == best_q && exact && !best_exact),
+            None => true,
+        };
+        if q > 0.0 && better {
+            best = Some((q, exact, format));
         }
-    };
-    timings.set_response_written();
-
-    resp.extensions_mut()
-        .extend(resp_exts.lock().expect("mutex poisoned").clone());
-    resp.extensions_mut().insert(timings);
-    resp
-}
-
-async fn parse_request<T>(
-    req: Request<Body>,
-    timings: &mut Timings,
-) -> Result<(T, Extensions, BodyFormat), GenericError>
-where
-    T: prost::Message + Default + DeserializeOwned,
-{
-    let format = BodyFormat::from_content_type(&req);
-    let (parts, body) = req.into_parts();
-    let bytes = body.collect().await?.to_bytes();
-    timings.set_received();
-    let request = match format {
-        BodyFormat::Pb => T::decode(&bytes[..])?,
-        BodyFormat::JsonPb => serde_json::from_slice(&bytes)?,
-    };
-    timings.set_parsed();
-    Ok((request, parts.extensions, format))
-}
-
-fn write_response<T>(
-    response: Result<T, TwirpErrorResponse>,
-    response_format: BodyFormat,
-) -> Result<Response<Body>, GenericError>
-where
-    T: prost::Message + Serialize,
-{
-    let res = match response {
-        Ok(response) => match response_format {
-            BodyFormat::Pb => Response::builder()
-                .header(header::CONTENT_TYPE, CONTENT_TYPE_PROTOBUF)
-                .body(Body::from(serialize_proto_message(response)))?,
-            BodyFormat::JsonPb => {
-                let data = serde_json::to_string(&response)?;
-                Response::builder()
-                    .header(header::CONTENT_TYPE, CONTENT_TYPE_JSON)
-                    .body(Body::from(data))?
-            }
-        },
-        Err(err) => err.into_response(),
-    };
-    Ok(res)
+    }
+    best.map_or(default, |(_, _, format)| format)
 }
 
-/// Axum handler function that returns 404 Not Found with a Twirp JSON payload.
-///
-/// `axum::Router`'s default fallback handler returns a 404 Not Found with no body content.
-/// Use this fallback instead for full Twirp compliance.
+/// Server-wide options for Twirp services.
 ///
-/// # Usage
+/// Apply the options as a layer on the router that the services are nested in, and they will be
+/// consulted for every request to those services:
 ///
 /// ```
+/// use std::sync::atomic::AtomicBool;
+/// use std::sync::Arc;
+///
 /// use axum::Router;
 ///
-/// # fn build_app(twirp_routes: Router) -> Router {
-/// let app = Router::new()
-///     .nest("/twirp", twirp_routes)
-///     .fallback(twirp::server::not_found_handler);
-/// # app }
+/// # fn build_app(api_routes: Router) -> Router {
+/// let ready = Arc::new(AtomicBool::new(false));
+/// let twirp_routes = Router::new()
+///     .nest("/my.Service", api_routes)
+///     .layer(twirp::server::Options::new().readiness(ready));
+/// # twirp_routes }
 /// ```
-pub async fn not_found_handler() -> Response<Body> {
-    error::bad_route("not found").into_response()
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    ready: Option<Arc<AtomicBool>>,
+    #[cfg(feature = "json")]
+    max_json_depth: Option<usize>,
+    slow_request_threshold: Option<Duration>,
+    access_log: bool,
+    server_timing: bool,
+    default_content_type: Option<Encoding>,
+    #[cfg(feature = "json")]
+    empty_json_body: bool,
+    in_flight: Option<Arc<tokio::sync::Semaphore>>,
+    default_timeout: Option<Duration>,
+    method_timeouts: HashMap<String, Duration>,
+    request_id_generator: Option<GenerateRequestId>,
+    spawner: Option<HandlerSpawner>,
+    interceptors: Vec<HandlerInterceptor>,
+    required_headers: Vec<RequiredHeader>,
+    audit: Option<Arc<Auditor>>,
+    hide_internal_errors: bool,
+    allow_dry_run: bool,
+    #[cfg(feature = "gzip")]
+    gzip_min_size: Option<usize>,
+    #[cfg(feature = "hmac")]
+    hmac_verifier: Option<crate::signing::HmacVerifier>,
 }
 
-/// Contains timing information associated with a request.
-/// To access the timings in a given request, use the [extensions](Request::extensions)
-/// method and specialize to `Timings` appropriately.
-#[derive(Debug, Clone, Copy)]
-pub struct Timings {
-    // When the request started.
-    start: Instant,
-    // When the request was received (headers and body).
-    request_received: Option<Instant>,
-    // When the request body was parsed.
-    request_parsed: Option<Instant>,
-    // When the response handler returned.
-    response_handled: Option<Instant>,
-    // When the response was written.
-    response_written: Option<Instant>,
-}
+/// The message of the `internal` errors sent to clients with [`Options::hide_internal_errors`].
+pub const HIDDEN_INTERNAL_ERROR_MSG: &str = "internal error";
 
-impl Timings {
-    #[allow(clippy::new_without_default)]
-    pub fn new(start: Instant) -> Self {
-        Self {
-            start,
-            request_received: None,
-            request_parsed: None,
-            response_handled: None,
-            response_written: None,
-        }
+/// The message of the `internal` errors sent to clients when a handler's response can't be encoded.
+pub const ENCODE_ERROR_MSG: &str = "failed to encode response";
+
+/// The request header asking for a dry run of a method (see [`Options::allow_dry_run`]), and the
+/// response header marking the response to one.
+pub const DRY_RUN_HEADER: &str = "x-twirp-dry-run";
+
+/// The default for [`Options::max_json_depth`].
+#[cfg(feature = "json")]
+pub const DEFAULT_MAX_JSON_DEPTH: usize = 128;
+
+impl Options {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    fn set_received(&mut self) {
-        self.request_received = Some(Instant::now());
+    /// Respond to every request with `unavailable` until `ready` is set to `true`.
+    ///
+    /// Connections are still accepted while the service is not ready, which lets a load balancer
+    /// or Kubernetes readiness probe hold traffic back while dependencies start up (point your
+    /// health endpoint at the same flag).
+    pub fn readiness(mut self, ready: Arc<AtomicBool>) -> Self {
+        self.ready = Some(ready);
+        self
     }
 
-    fn set_parsed(&mut self) {
-        self.request_parsed = Some(Instant::now());
+    /// Reject JSON requests with arrays and objects nested deeper than `depth` as `malformed`,
+    /// before deserializing them. Defaults to [`DEFAULT_MAX_JSON_DEPTH`], which is also the most
+    /// `serde_json` accepts.
+    #[cfg(feature = "json")]
+    pub fn max_json_depth(mut self, depth: usize) -> Self {
+        self.max_json_depth = Some(depth);
+        self
     }
 
-    fn set_response_handled(&mut self) {
-        self.response_handled = Some(Instant::now());
+    /// Read requests without a `Content-Type` header as `encoding`, for hand-rolled clients that
+    /// leave it out. By default such requests are rejected as `malformed`.
+    pub fn default_content_type(mut self, encoding: Encoding) -> Self {
+        self.default_content_type = Some(encoding);
+        self
     }
 
-    fn set_response_written(&mut self) {
-        self.response_written = Some(Instant::now());
+    /// Log a warning (with `tracing`) for every request whose handler takes longer than
+    /// `threshold`, with the method path, the time the handler took, and the request's ID.
+    pub fn slow_request_log(mut self, threshold: Duration) -> Self {
+        self.slow_request_threshold = Some(threshold);
+        self
     }
 
-    pub fn received(&self) -> Option<Duration> {
-        self.request_received.map(|x| x - self.start)
+    /// Log a line (with `tracing`, at the info level) for every request that reaches a handler,
+    /// with the method path, the request's ID, the response status, the time taken, and the
+    /// fields the handler recorded with [`Context::record`]. Off by default.
+    pub fn access_log(mut self, enabled: bool) -> Self {
+        self.access_log = enabled;
+        self
     }
 
-    pub fn parsed(&self) -> Option<Duration> {
-        match (self.request_parsed, self.request_received) {
-            (Some(parsed), Some(received)) => Some(parsed - received),
-            _ => None,
-        }
+    /// Send the time the handler took in a [`Server-Timing`] header on every response of a
+    /// handler, as `handler;dur=<milliseconds>` (e.g. `handler;dur=12.345`), which browser
+    /// developer tools show alongside the request. Off by default, since it reveals how long
+    /// requests take to anyone calling the service.
+    ///
+    /// [`Server-Timing`]: https://www.w3.org/TR/server-timing/
+    pub fn server_timing(mut self, enabled: bool) -> Self {
+        self.server_timing = enabled;
+        self
     }
 
-    pub fn response_handled(&self) -> Option<Duration> {
-        match (self.response_handled, self.request_parsed) {
-            (Some(handled), Some(parsed)) => Some(handled - parsed),
-            _ => None,
+    /// Handle at most `n` requests at once, across all the services the options are applied to.
+    /// Further requests are shed right away with an `unavailable` error and a `Retry-After: 1`
+    /// header rather than left to queue up, which keeps latency bounded under overload. Operations
+    /// running in the background (see [`crate::operations`]) hold on to their request's slot until
+    /// they complete.
+    pub fn max_in_flight(mut self, n: usize) -> Self {
+        self.in_flight = Some(Arc::new(tokio::sync::Semaphore::new(n)));
+        self
+    }
+
+    /// Give every request a deadline `timeout` after it started (see
+    /// [`Context::deadline`](crate::Context::deadline)), unless its method has its own
+    /// [`method_timeout`](Self::method_timeout).
+    ///
+    /// When the client also sent a timeout, the tighter of the two wins, so a request never gets
+    /// more time than either side allows. Like the client's, the deadline cancels the request's
+    /// [cancellation token](crate::Context::cancellation_token) when it passes, and handlers decide
+    /// how to give up.
+    pub fn default_timeout(mut self, timeout: Duration) -> Self {
+        self.default_timeout = Some(timeout);
+        self
+    }
+
+    /// Give requests to `method` (e.g. `example.Service/GetThing`) a deadline `timeout` after they
+    /// started, instead of the [`default_timeout`](Self::default_timeout). As with that, a tighter
+    /// timeout sent by the client wins.
+    pub fn method_timeout(mut self, method: impl Into<String>, timeout: Duration) -> Self {
+        self.method_timeouts.insert(method.into(), timeout);
+        self
+    }
+
+    /// Generate the IDs of requests that come without a
+    /// [`REQUEST_ID_HEADER`](crate::headers::REQUEST_ID_HEADER) with `generate`, instead of
+    /// [`uuid_v4`](crate::request_id::uuid_v4).
+    pub fn request_id_generator<F>(mut self, generate: F) -> Self
+    where
+        F: Fn() -> String + Send + Sync + 'static,
+    {
+        self.request_id_generator = Some(GenerateRequestId(Arc::new(generate)));
+        self
+    }
+
+    /// Run handlers with `spawner`, e.g. on a dedicated runtime (a `tokio::runtime::Handle` is a
+    /// `Spawner`), rather than on the task serving the connection. The serving task waits for the
+    /// handler to complete; if the client goes away first, the handler keeps running, with its
+    /// [cancellation token](Context::cancellation_token) cancelled. Handlers that the spawner drops
+    /// without running them to completion fail with `internal`.
+    ///
+    /// This applies to the methods of generated routers, and to handlers added with
+    /// `TwirpRouterBuilder::route_spawned` (whose futures can move to another task); handlers added
+    /// with `route` or `route_streaming` always run inline.
+    pub fn spawner(mut self, spawner: impl Spawner) -> Self {
+        self.spawner = Some(HandlerSpawner(Arc::new(spawner)));
+        self
+    }
+
+    /// Run `interceptor`'s hooks around every call, e.g. for authentication, logging or metrics
+    /// that need the method called or the error sent (see [`Interceptor`]). Each call adds an
+    /// interceptor: their `before` hooks run in the order they were added, and their `after`
+    /// hooks in the reverse order.
+    pub fn interceptor(mut self, interceptor: impl Interceptor) -> Self {
+        self.interceptors
+            .push(HandlerInterceptor(Arc::new(interceptor)));
+        self
+    }
+
+    /// Copy the body of every request to `sink` once it has been read, along with the method, the
+    /// request's ID and its headers, less the values of those carrying credentials (see
+    /// [`crate::audit`]). Up to [`DEFAULT_AUDIT_BUFFER`] records wait for the sink, and records
+    /// that don't fit are dropped; see [`audit_sink_with`](Self::audit_sink_with) to change that.
+    ///
+    /// Bodies are recorded before the request's HMAC signature is checked, and for dry runs too.
+    /// The server doesn't read the bodies of streaming methods, so they aren't audited.
+    pub fn audit_sink(self, sink: impl AuditSink) -> Self {
+        self.audit_sink_with(sink, DEFAULT_AUDIT_BUFFER, AuditOverflow::Drop)
+    }
+
+    /// Like [`audit_sink`](Self::audit_sink), with up to `buffer` records waiting for the sink,
+    /// and `overflow` deciding what happens to a record when the buffer is full.
+    pub fn audit_sink_with(
+        mut self,
+        sink: impl AuditSink,
+        buffer: usize,
+        overflow: AuditOverflow,
+    ) -> Self {
+        self.audit = Some(Arc::new(Auditor::new(sink, buffer, overflow)));
+        self
+    }
+
+    /// Send `internal` errors returned by handlers (and by [`Interceptor::before`] hooks) to
+    /// clients with the message [`HIDDEN_INTERNAL_ERROR_MSG`] and no metadata, and log the
+    /// original error (with `tracing`, along with the method and the request's ID) instead. Off by
+    /// default, which sends errors as they are: messages of `internal` errors often come from the
+    /// underlying error (e.g. a database driver's), and can reveal details of the server's
+    /// internals to clients.
+    pub fn hide_internal_errors(mut self, enabled: bool) -> Self {
+        self.hide_internal_errors = enabled;
+        self
+    }
+
+    /// Let clients check that a method is routed and that their request decodes without running
+    /// the handler, e.g. for smoke tests after a deploy, by sending a [`DRY_RUN_HEADER`] header with
+    /// the value `true`. Off by default, when the header is ignored (and the handler runs).
+    ///
+    /// A dry run goes through everything a call would up to the handler: routing, the checks of
+    /// these options, reading the body and decoding the request (except for streaming methods,
+    /// whose handlers read the body). Errors are sent as usual. If all goes well, the response is
+    /// `200 OK` with a `X-Twirp-Dry-Run: true` header and an empty message as its body: no bytes
+    /// for protobuf, `{}` for JSON, and a message frame with no bytes followed by an `OK` status for
+    /// gRPC-Web.
+    pub fn allow_dry_run(mut self, enabled: bool) -> Self {
+        self.allow_dry_run = enabled;
+        self
+    }
+
+    /// Compress the responses of handlers with gzip when the client accepts it (in its
+    /// `Accept-Encoding` header) and the body is at least `min_size` bytes, as smaller bodies
+    /// rarely shrink enough to pay for the work. Off by default, and only available with the `gzip`
+    /// feature.
+    ///
+    /// Every response of a handler then has a `Vary: Accept-Encoding` header, compressed or not,
+    /// so shared caches don't serve a compressed response to a client that can't read it. The
+    /// `ETag` of a compressed [cacheable](crate::Context::set_cacheable) response becomes weak
+    /// (`W/"..."`), as the compressed bytes aren't those it was computed from.
+    #[cfg(feature = "gzip")]
+    pub fn gzip_responses(mut self, min_size: usize) -> Self {
+        self.gzip_min_size = Some(min_size);
+        self
+    }
+
+    /// Send JSON responses that would be `{}` (those of messages without fields, like
+    /// `google.protobuf.Empty`) with an empty body instead, for clients that expect one. Twirp
+    /// clients (including this crate's) accept both. Off by default, as the spec calls for `{}`.
+    #[cfg(feature = "json")]
+    pub fn empty_json_body(mut self, enabled: bool) -> Self {
+        self.empty_json_body = enabled;
+        self
+    }
+
+    /// Reject requests without a `name` header, or whose value `check` returns `false` for, with
+    /// an `invalid_argument` error, e.g. to make every method require an API version or tenant
+    /// header. Requests are checked before their body is read. Each call adds a requirement.
+    ///
+    /// ```
+    /// use twirp::server::Options;
+    ///
+    /// let options = Options::new().require_header("x-api-version", |v| v == "2024-01-01");
+    /// ```
+    ///
+    /// Requirements are checked once a request reaches the service, so middleware like
+    /// authentication (see [`Pipeline`]) still sees every request first.
+    ///
+    /// # Panics
+    ///
+    /// If `name` isn't a valid header name.
+    pub fn require_header<F>(self, name: impl AsRef<str>, check: F) -> Self
+    where
+        F: Fn(&header::HeaderValue) -> bool + Send + Sync + 'static,
+    {
+        self.require_header_with_code(name, crate::TwirpErrorCode::InvalidArgument, check)
+    }
+
+    /// Like [`require_header`](Self::require_header), but rejecting requests with `code`, e.g.
+    /// `failed_precondition` for an unsupported API version.
+    ///
+    /// # Panics
+    ///
+    /// If `name` isn't a valid header name.
+    pub fn require_header_with_code<F>(
+        mut self,
+        name: impl AsRef<str>,
+        code: crate::TwirpErrorCode,
+        check: F,
+    ) -> Self
+    where
+        F: Fn(&header::HeaderValue) -> bool + Send + Sync + 'static,
+    {
+        let name = header::HeaderName::try_from(name.as_ref()).expect("invalid header name");
+        self.required_headers.push(RequiredHeader {
+            name,
+            code,
+            check: Arc::new(check),
+        });
+        self
+    }
+
+    /// Reject requests without a valid HMAC signature (see [`crate::signing`]) as
+    /// `unauthenticated`, before decoding them.
+    #[cfg(feature = "hmac")]
+    pub fn verify_hmac(mut self, verifier: crate::signing::HmacVerifier) -> Self {
+        self.hmac_verifier = Some(verifier);
+        self
+    }
+
+    /// The time a request to `method` gets: the tightest of the timeout the client sent and the
+    /// server's timeout for the method.
+    fn timeout(&self, method: &str, client_timeout: Option<Duration>) -> Option<Duration> {
+        let server_timeout = self
+            .method_timeouts
+            .get(method)
+            .copied()
+            .or(self.default_timeout);
+        match (client_timeout, server_timeout) {
+            (Some(client), Some(server)) => Some(client.min(server)),
+            (client, server) => client.or(server),
         }
     }
 
-    pub fn response_written(&self) -> Option<Duration> {
-        match (self.response_written, self.response_handled) {
-            (Some(written), Some(handled)) => Some(written - handled),
-            (Some(written), None) => {
-                if let Some(parsed) = self.request_parsed {
-                    Some(written - parsed)
-                } else {
-                    self.request_received.map(|received| written - received)
-                }
+    fn is_ready(&self) -> bool {
+        self.ready
+            .as_ref()
+            .map_or(true, |ready| ready.load(Ordering::Acquire))
+    }
+}
+
+/// A [`RequestIdGenerator`](crate::request_id::RequestIdGenerator) that `Options` can derive
+/// `Debug` with.
+#[derive(Clone)]
+struct GenerateRequestId(crate::request_id::RequestIdGenerator);
+
+impl Debug for GenerateRequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("GenerateRequestId")
+    }
+}
+
+impl Options {
+    /// Run `future` with the [`spawner`](Self::spawner), or on the tokio runtime without one.
+    pub(crate) fn spawn(&self, future: BoxFuture<'static, ()>) {
+        match &self.spawner {
+            Some(HandlerSpawner(spawner)) => spawner.spawn(future),
+            None => {
+                tokio::spawn(future);
             }
-            _ => None,
         }
     }
+}
 
-    /// The total duration since the request started.
-    pub fn total_duration(&self) -> Duration {
-        self.start.elapsed()
+/// Runs handler futures to completion, for [`Options::spawner`].
+pub trait Spawner: Send + Sync + 'static {
+    fn spawn(&self, future: BoxFuture<'static, ()>);
+}
+
+impl Spawner for tokio::runtime::Handle {
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        tokio::runtime::Handle::spawn(self, future);
     }
 }
 
-#[cfg(test)]
-mod tests {
+/// How [`handle_request`] runs a handler: [`Inline`] on the task serving the connection, or
+/// [`Spawned`] with the [`Options::spawner`] if there is one, which needs handlers whose futures
+/// can move to another task.
+pub(crate) trait RunHandler<Fut: Future> {
+    /// Hand `handler` to the spawner, returning where its output is sent, or give it back to be
+    /// awaited inline.
+    fn spawn(
+        self,
+        options: &Options,
+        handler: Fut,
+    ) -> Result<tokio::sync::oneshot::Receiver<Fut::Output>, Fut>;
+}
 
-    use super::*;
-    use crate::test::*;
+/// Runs handlers on the task serving the connection, whatever the options.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Inline;
 
-    use axum::middleware::{self, Next};
-    use tower::Service;
+impl<Fut: Future> RunHandler<Fut> for Inline {
+    fn spawn(
+        self,
+        _: &Options,
+        handler: Fut,
+    ) -> Result<tokio::sync::oneshot::Receiver<Fut::Output>, Fut> {
+        Err(handler)
+    }
+}
 
-    fn timings() -> Timings {
-        Timings::new(Instant::now())
+/// Runs handlers with the [`Options::spawner`], or inline without one.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Spawned;
+
+impl<Fut> RunHandler<Fut> for Spawned
+where
+    Fut: Future + Send + 'static,
+    Fut::Output: Send + 'static,
+{
+    fn spawn(
+        self,
+        options: &Options,
+        handler: Fut,
+    ) -> Result<tokio::sync::oneshot::Receiver<Fut::Output>, Fut> {
+        let Some(HandlerSpawner(spawner)) = &options.spawner else {
+            return Err(handler);
+        };
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        spawner.spawn(Box::pin(async move {
+            let _ = tx.send(handler.await);
+        }));
+        Ok(rx)
     }
+}
 
-    #[tokio::test]
-    async fn test_bad_route() {
-        let mut router = test_api_router();
-        let req = Request::get("/nothing")
-            .extension(timings())
-            .body(Body::empty())
-            .unwrap();
+/// A header requests must have, from [`Options::require_header`].
+#[derive(Clone)]
+struct RequiredHeader {
+    name: header::HeaderName,
+    code: crate::TwirpErrorCode,
+    check: Arc<dyn Fn(&header::HeaderValue) -> bool + Send + Sync>,
+}
 
-        let resp = router.call(req).await.unwrap();
-        let data = read_err_body(resp.into_body()).await;
-        assert_eq!(data, error::bad_route("not found"));
+impl RequiredHeader {
+    fn check(&self, headers: &header::HeaderMap) -> Result<(), TwirpErrorResponse> {
+        let msg = match headers.get(&self.name) {
+            Some(value) if (self.check)(value) => return Ok(()),
+            Some(_) => format!("invalid {} header", self.name),
+            None => format!("missing required header {}", self.name),
+        };
+        Err(TwirpErrorResponse {
+            code: self.code,
+            msg,
+            meta: HashMap::new(),
+        })
     }
+}
 
-    #[tokio::test]
-    async fn test_ping_success() {
-        let mut router = test_api_router();
-        let resp = router.call(gen_ping_request("hi")).await.unwrap();
-        assert!(resp.status().is_success(), "{:?}", resp);
-        let data: PingResponse = read_json_body(resp.into_body()).await;
-        assert_eq!(&data.name, "hi");
+impl Debug for RequiredHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RequiredHeader")
+            .field("name", &self.name)
+            .field("code", &self.code)
+            .finish_non_exhaustive()
     }
+}
 
-    #[tokio::test]
-    async fn test_ping_invalid_request() {
-        let mut router = test_api_router();
-        let req = Request::post("/twirp/test.TestAPI/Ping")
-            .extension(timings())
-            .body(Body::empty()) // not a valid request
-            .unwrap();
-        let resp = router.call(req).await.unwrap();
-        assert!(resp.status().is_client_error(), "{:?}", resp);
-        let data = read_err_body(resp.into_body()).await;
+/// The slot a request takes in [`Options::max_in_flight`], held until the response is sent, or
+/// handed over to the work the request started in the background (see [`crate::operations`]).
+#[derive(Clone, Debug)]
+pub(crate) struct InFlightPermit(Arc<Mutex<Option<tokio::sync::OwnedSemaphorePermit>>>);
 
-        // TODO: I think malformed should return some info about what was wrong
-        // with the request, but we don't want to leak server errors that have
-        // other details.
-        let mut expected = error::malformed("bad request");
-        expected.insert_meta(
-            "error".to_string(),
-            "EOF while parsing a value at line 1 column 0".to_string(),
-        );
-        assert_eq!(data, expected);
+impl InFlightPermit {
+    /// Take the permit, which is `None` when the options don't limit requests in flight.
+    pub(crate) fn take(&self) -> Option<tokio::sync::OwnedSemaphorePermit> {
+        self.0.lock().expect("mutex poisoned").take()
     }
+}
 
-    #[tokio::test]
-    async fn test_boom() {
-        let mut router = test_api_router();
-        let req = serde_json::to_string(&PingRequest {
-            name: "hi".to_string(),
-        })
-        .unwrap();
-        let req = Request::post("/twirp/test.TestAPI/Boom")
-            .extension(timings())
-            .body(Body::from(req))
-            .unwrap();
-        let resp = router.call(req).await.unwrap();
-        assert!(resp.status().is_server_error(), "{:?}", resp);
-        let data = read_err_body(resp.into_body()).await;
-        assert_eq!(data, error::internal("boom!"));
+/// A [`Spawner`] that `Options` can derive `Debug` with.
+#[derive(Clone)]
+struct HandlerSpawner(Arc<dyn Spawner>);
+
+impl Debug for HandlerSpawner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("HandlerSpawner")
+    }
+}
+
+/// Hooks around the calls of a service's methods, for [`Options::interceptor`].
+///
+/// Unlike axum middleware, interceptors get the method called, and the error sent in response
+/// rather than its encoding. `before` runs once a request reaches the service, before the checks
+/// of the other options and before its body is read; `after` runs with the response about to be
+/// sent. Requests for methods the service doesn't have aren't intercepted.
+///
+/// ```
+/// use axum::body::Body;
+/// use twirp::server::{CallOutcome, Interceptor};
+/// use twirp::{MethodPath, TwirpErrorResponse};
+///
+/// #[derive(Clone)]
+/// struct User(String);
+///
+/// struct Auth;
+///
+/// #[twirp::async_trait::async_trait]
+/// impl Interceptor for Auth {
+///     async fn before(
+///         &self,
+///         _method: Option<&MethodPath>,
+///         req: &mut http::Request<Body>,
+///     ) -> Result<(), TwirpErrorResponse> {
+///         let user = match req.headers().get("x-user").map(|v| v.to_str()) {
+///             Some(Ok(user)) => user.to_string(),
+///             _ => return Err(twirp::unauthenticated("missing x-user header")),
+///         };
+///         // Handlers get it with `ctx.get::<User>()`.
+///         req.extensions_mut().insert(User(user));
+///         Ok(())
+///     }
+///
+///     async fn after(&self, call: &CallOutcome, _resp: &mut http::Response<Body>) {
+///         let method = call.method.as_ref().map(MethodPath::to_string);
+///         let code = call.error.as_ref().map_or("ok", |err| err.code.twirp_code());
+///         tracing::info!(?method, code, elapsed = ?call.elapsed, "twirp call");
+///     }
+/// }
+///
+/// let options = twirp::server::Options::new().interceptor(Auth);
+/// ```
+#[async_trait::async_trait]
+pub trait Interceptor: Send + Sync + 'static {
+    /// Called before the call is handled, with the method called (if the request's path names
+    /// one) and the request, whose body hasn't been read yet. Returning an error rejects the
+    /// request with it, without running the handler (or the `before` hooks of the interceptors
+    /// added after this one). Extensions inserted into the request are available to the handler
+    /// through its [`Context`].
+    async fn before(
+        &self,
+        _method: Option<&MethodPath>,
+        _req: &mut Request<Body>,
+    ) -> Result<(), TwirpErrorResponse> {
+        Ok(())
+    }
+
+    /// Called once the call is done, with the response about to be sent, which can still be
+    /// changed (e.g. to add headers). Only interceptors whose `before` hook ran get this call,
+    /// including one that rejected the request.
+    async fn after(&self, _call: &CallOutcome, _resp: &mut Response<Body>) {}
+}
+
+/// A call, once done, as [`Interceptor::after`] sees it.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct CallOutcome {
+    /// The method called, if the request's path names one.
+    pub method: Option<MethodPath>,
+    /// The error sent in response, if the call failed. With
+    /// [`Options::hide_internal_errors`], this is the error with the hidden message.
+    pub error: Option<TwirpErrorResponse>,
+    /// The time from the request reaching the service to the response being ready, the `after`
+    /// hooks aside.
+    pub elapsed: Duration,
+}
+
+/// An [`Interceptor`] that `Options` can derive `Debug` with.
+#[derive(Clone)]
+struct HandlerInterceptor(Arc<dyn Interceptor>);
+
+impl Debug for HandlerInterceptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("HandlerInterceptor")
+    }
+}
+
+impl<S> Layer<S> for Options {
+    type Service = AddExtension<S, Arc<Options>>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Extension(Arc::new(self.clone())).layer(inner)
+    }
+}
+
+/// Where a layer added to a [`Pipeline`] runs, relative to the others. Earlier stages run first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Stage {
+    /// Authentication and authorization, which should see requests before anything else.
+    Auth,
+    /// Request logging and metrics, for requests that got past `Auth`.
+    Logging,
+    /// Everything else, which runs right before the handler.
+    Other,
+}
+
+type ApplyLayer = Box<dyn FnOnce(axum::Router) -> axum::Router + Send>;
+
+/// Server middleware that runs in a well-defined order, whatever the order it was added in.
+///
+/// With `Router::layer`, the layer added *last* runs first, which makes it easy to (say) log
+/// requests before checking that they are authenticated by accident. A pipeline instead runs its
+/// layers by [`Stage`], and in the order they were added within a stage:
+///
+/// ```
+/// use axum::extract::Request;
+/// use axum::middleware::{from_fn, Next};
+/// use twirp::server::{Pipeline, Stage};
+///
+/// # async fn log(req: Request, next: Next) -> axum::response::Response { next.run(req).await }
+/// # async fn auth(req: Request, next: Next) -> axum::response::Response { next.run(req).await }
+/// # fn build_app(twirp_routes: axum::Router) -> axum::Router {
+/// let pipeline = Pipeline::new()
+///     .layer(Stage::Logging, "log", from_fn(log))
+///     .layer(Stage::Auth, "auth", from_fn(auth));
+/// assert_eq!(pipeline.order(), ["auth", "log"]);
+/// let app = pipeline.apply(twirp_routes);
+/// # app }
+/// ```
+#[derive(Default)]
+pub struct Pipeline {
+    layers: Vec<(Stage, &'static str, ApplyLayer)>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `layer` to `stage`, under `name` (which is only used by [`order`](Self::order)).
+    pub fn layer<L>(mut self, stage: Stage, name: &'static str, layer: L) -> Self
+    where
+        L: Layer<axum::routing::Route> + Clone + Send + 'static,
+        L::Service: tower::Service<Request<Body>> + Clone + Send + 'static,
+        <L::Service as tower::Service<Request<Body>>>::Response: IntoResponse + 'static,
+        <L::Service as tower::Service<Request<Body>>>::Error:
+            Into<std::convert::Infallible> + 'static,
+        <L::Service as tower::Service<Request<Body>>>::Future: Send + 'static,
+    {
+        self.layers
+            .push((stage, name, Box::new(move |router| router.layer(layer))));
+        self
+    }
+
+    /// The names of the layers, in the order they run for each request.
+    pub fn order(&self) -> Vec<&'static str> {
+        let mut layers: Vec<_> = self
+            .layers
+            .iter()
+            .map(|(stage, name, _)| (*stage, *name))
+            .collect();
+        layers.sort_by_key(|(stage, _)| *stage);
+        layers.into_iter().map(|(_, name)| name).collect()
+    }
+
+    /// Wrap `router` in the layers.
+    pub fn apply(mut self, router: axum::Router) -> axum::Router {
+        // Stable, so layers keep the order they were added in within each stage.
+        self.layers.sort_by_key(|(stage, _, _)| *stage);
+        // The last layer applied is the first to run.
+        self.layers
+            .into_iter()
+            .rev()
+            .fold(router, |router, (_, _, apply)| apply(router))
+    }
+}
+
+impl Debug for Pipeline {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pipeline")
+            .field("order", &self.order())
+            .finish()
+    }
+}
+
+/// A limit on the number of handlers registered with `TwirpRouterBuilder::route_blocking` that run
+/// at once. Clones share the limit.
+#[derive(Clone, Debug)]
+pub struct BlockingPool {
+    size: usize,
+    permits: Arc<tokio::sync::Semaphore>,
+}
+
+impl BlockingPool {
+    /// A pool running at most `size` handlers at once.
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            permits: Arc::new(tokio::sync::Semaphore::new(size)),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub(crate) async fn run<F, T>(&self, f: F) -> Result<T, TwirpErrorResponse>
+    where
+        F: FnOnce() -> Result<T, TwirpErrorResponse> + Send + 'static,
+        T: Send + 'static,
+    {
+        let Ok(permit) = self.permits.clone().try_acquire_owned() else {
+            return Err(error::resource_exhausted("too many concurrent requests"));
+        };
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            f()
+        })
+        .await
+        .unwrap_or_else(|_| Err(error::internal("handler panicked")))
+    }
+}
+
+/// A table of methods that can change while it serves requests, for servers that load services at
+/// runtime (e.g. from plugins, or to hot-reload an implementation).
+///
+/// Methods are registered by their path relative to the twirp prefix (the `*_METHOD` constants
+/// generated by `twirp-build`), with the service router that serves them:
+///
+/// ```
+/// # fn build_app(haberdasher_routes: twirp::Router) -> twirp::Router {
+/// let methods = twirp::server::DynamicRouter::new();
+/// methods.add_method("example.Haberdasher/MakeHat", haberdasher_routes);
+/// let app = twirp::Router::new().nest("/twirp", methods.router());
+/// // ... and later, from any thread:
+/// methods.remove_method("example.Haberdasher/MakeHat");
+/// # app }
+/// ```
+///
+/// Clones share the table. Requests look their method up without taking a lock: changes copy the
+/// table and swap the copy in atomically, so dispatch never waits for them (or they for dispatch),
+/// at the cost of changes taking time proportional to the number of methods. Each request runs on
+/// the router its method had when it arrived: changes apply to the requests that arrive
+/// afterwards, and requests already running finish undisturbed. Requests for methods that aren't
+/// in the table fail with `bad_route`.
+#[derive(Clone, Debug, Default)]
+pub struct DynamicRouter {
+    methods: Arc<ArcSwap<BTreeMap<String, axum::Router>>>,
+}
+
+impl DynamicRouter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serve the method at `path` (e.g. `example.Haberdasher/MakeHat`) with `router`, a service
+    /// router like the ones generated `router()` functions return. Replaces the method's current
+    /// router, if any.
+    ///
+    /// # Panics
+    ///
+    /// If `path` doesn't look like `package.Service/Method`.
+    pub fn add_method(&self, path: &str, router: axum::Router) {
+        let path: MethodPath = path.parse().unwrap_or_else(|err| panic!("{err}"));
+        let router = axum::Router::new().nest(&format!("/{}", path.service_fqn()), router);
+        let path = path.to_string();
+        self.methods.rcu(|methods| {
+            let mut methods = BTreeMap::clone(methods);
+            methods.insert(path.clone(), router.clone());
+            methods
+        });
+    }
+
+    /// Stop serving the method at `path`. Returns whether it was being served.
+    pub fn remove_method(&self, path: &str) -> bool {
+        let Ok(path) = path.parse::<MethodPath>() else {
+            return false;
+        };
+        let path = path.to_string();
+        let previous = self.methods.rcu(|methods| {
+            let mut methods = BTreeMap::clone(methods);
+            methods.remove(&path);
+            methods
+        });
+        previous.contains_key(&path)
+    }
+
+    /// The paths of the methods being served, in order.
+    pub fn methods(&self) -> Vec<String> {
+        self.methods.load().keys().cloned().collect()
+    }
+
+    /// A router dispatching requests to the methods in the table. Nest it under the twirp prefix.
+    pub fn router(&self) -> axum::Router {
+        let this = self.clone();
+        axum::Router::new().fallback(move |req: Request<Body>| {
+            let this = this.clone();
+            async move { this.dispatch(req).await }
+        })
+    }
+
+    async fn dispatch(&self, req: Request<Body>) -> Response<Body> {
+        let path = req.uri().path().trim_start_matches('/');
+        let router = self.methods.load().get(path).cloned();
+        match router {
+            Some(router) => match tower::ServiceExt::oneshot(router, req).await {
+                Ok(resp) => resp,
+                Err(infallible) => match infallible {},
+            },
+            None => not_found_handler().await,
+        }
+    }
+}
+
+/// Connection settings for [`serve`].
+#[derive(Clone, Debug, Default)]
+pub struct ServeConfig {
+    http2_keep_alive: Option<(Duration, Duration)>,
+    http1_keep_alive: Option<bool>,
+    tcp_nodelay: Option<bool>,
+    max_headers: Option<usize>,
+    max_header_size: Option<usize>,
+}
+
+/// The default for [`ServeConfig::max_headers`], which is also `hyper`'s.
+pub const DEFAULT_MAX_HEADERS: usize = 100;
+
+/// The default for [`ServeConfig::max_header_size`].
+pub const DEFAULT_MAX_HEADER_SIZE: usize = 64 * 1024;
+
+impl ServeConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send an HTTP/2 PING frame on each connection every `interval`, and close the connection if
+    /// the client doesn't acknowledge one within `timeout`. This keeps proxies and load balancers
+    /// with idle timeouts from dropping connections while a slow handler runs, and notices clients
+    /// that went away without closing their connection. Off by default.
+    ///
+    /// Pings only keep the connection open: a request still fails when its own deadline (e.g. a
+    /// `tower::timeout` layer, or the client's timeout) passes, however long the connection lives.
+    pub fn http2_keep_alive(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.http2_keep_alive = Some((interval, timeout));
+        self
+    }
+
+    /// Whether HTTP/1.1 connections stay open for further requests once a response is sent. On by
+    /// default: requests on a connection, including pipelined ones (sent before the previous
+    /// response arrived), are answered one at a time and in order. Turn it off for proxies that
+    /// mishandle reused or pipelined connections, so every response is sent with `Connection: close`
+    /// and the connection is closed after it.
+    pub fn http1_keep_alive(mut self, enabled: bool) -> Self {
+        self.http1_keep_alive = Some(enabled);
+        self
+    }
+
+    /// Whether to disable Nagle's algorithm (set `TCP_NODELAY`) on accepted connections, so
+    /// responses are sent as soon as they are written rather than held back to coalesce with more
+    /// data. On by default, which suits twirp's small request/response exchanges; turning it off
+    /// trades latency for fewer, fuller packets.
+    pub fn tcp_nodelay(mut self, enabled: bool) -> Self {
+        self.tcp_nodelay = Some(enabled);
+        self
+    }
+
+    /// Reject requests with more than `count` headers with a `malformed` error. Defaults to
+    /// [`DEFAULT_MAX_HEADERS`]. Over HTTP/1.1, `hyper` enforces the limit while parsing the
+    /// request, and answers requests over it with `431 Request Header Fields Too Large`.
+    ///
+    /// These limits apply to [`serve`]: `axum::serve` uses `hyper`'s defaults.
+    pub fn max_headers(mut self, count: usize) -> Self {
+        self.max_headers = Some(count);
+        self
+    }
+
+    /// Reject requests whose headers add up to more than `bytes` (counting the names and values)
+    /// with a `malformed` error. Defaults to [`DEFAULT_MAX_HEADER_SIZE`]. Over HTTP/1.1, the
+    /// headers also have to fit in `hyper`'s read buffer of about 400 KB. Over HTTP/2, `hyper`
+    /// enforces the limit itself, counting 32 more bytes for each header (pseudo-headers like
+    /// `:path` included), and answers requests over it with `431 Request Header Fields Too Large`.
+    pub fn max_header_size(mut self, bytes: usize) -> Self {
+        self.max_header_size = Some(bytes);
+        self
+    }
+
+    /// `app`, rejecting requests over the header limits before they reach it.
+    fn limit_headers(&self, app: axum::Router) -> axum::Router {
+        let max_headers = self.max_headers.unwrap_or(DEFAULT_MAX_HEADERS);
+        let max_size = self.max_header_size.unwrap_or(DEFAULT_MAX_HEADER_SIZE);
+        app.layer(axum::middleware::from_fn(
+            move |req: Request<Body>, next: axum::middleware::Next| async move {
+                let headers = req.headers();
+                if headers.len() > max_headers {
+                    return error::malformed(format!("more than {max_headers} headers"))
+                        .into_response();
+                }
+                let size: usize = headers
+                    .iter()
+                    .map(|(name, value)| name.as_str().len() + value.len())
+                    .sum();
+                if size > max_size {
+                    return error::malformed(format!("headers larger than {max_size} bytes"))
+                        .into_response();
+                }
+                next.run(req).await
+            },
+        ))
+    }
+
+    fn configure_stream(&self, stream: &tokio::net::TcpStream) {
+        if let Err(err) = stream.set_nodelay(self.tcp_nodelay.unwrap_or(true)) {
+            tracing::debug!(error = %err, "failed to set TCP_NODELAY");
+        }
+    }
+
+    fn builder(&self) -> auto::Builder<TokioExecutor> {
+        let mut builder = auto::Builder::new(TokioExecutor::new());
+        let max_headers = self.max_headers.unwrap_or(DEFAULT_MAX_HEADERS);
+        let max_header_size = self.max_header_size.unwrap_or(DEFAULT_MAX_HEADER_SIZE);
+        builder.http1().max_headers(max_headers);
+        builder
+            .http2()
+            .max_header_list_size(u32::try_from(max_header_size).unwrap_or(u32::MAX));
+        if let Some(enabled) = self.http1_keep_alive {
+            builder.http1().keep_alive(enabled);
+        }
+        if let Some((interval, timeout)) = self.http2_keep_alive {
+            builder
+                .http2()
+                .timer(TokioTimer::new())
+                .keep_alive_interval(interval)
+                .keep_alive_timeout(timeout);
+        }
+        builder
+    }
+}
+
+/// Serve `app` on the connections accepted by `listener`, over HTTP/1.1 or HTTP/2 (which clients
+/// choose), with the connection settings in `config`. Like `axum::serve`, but with access to the
+/// settings `axum::serve` doesn't expose. Runs until accepting a connection fails.
+///
+/// HTTP/1.1 clients sending `Expect: 100-continue` (to hold back a large body until the server
+/// wants it) get a `100 Continue` once the handler starts reading the body. Requests that are
+/// rejected before that, like those with an unexpected `Content-Type` or arriving while the
+/// server is overloaded, are answered right away and their body is never sent.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use twirp::server::ServeConfig;
+///
+/// # async fn run(app: axum::Router) -> std::io::Result<()> {
+/// let listener = tokio::net::TcpListener::bind("localhost:3000").await?;
+/// let config =
+///     ServeConfig::new().http2_keep_alive(Duration::from_secs(20), Duration::from_secs(5));
+/// twirp::server::serve(listener, app, config).await?;
+/// # Ok(()) }
+/// ```
+pub async fn serve(
+    listener: tokio::net::TcpListener,
+    app: axum::Router,
+    config: ServeConfig,
+) -> std::io::Result<()> {
+    let app = config.limit_headers(app);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        config.configure_stream(&stream);
+        spawn_connection(stream, &app, &config);
+    }
+}
+
+/// Like [`serve`], but serves `app` on the Windows named pipe `pipe_name` (e.g.
+/// `\\.\pipe\my-service`), for local IPC such as between a desktop app and a service it
+/// embeds. Fails if another server already has a pipe with that name.
+///
+/// Clients connect with [`ClientBuilder::named_pipe`](crate::ClientBuilder::named_pipe).
+#[cfg(windows)]
+pub async fn serve_named_pipe(
+    pipe_name: &str,
+    app: axum::Router,
+    config: ServeConfig,
+) -> std::io::Result<()> {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    let app = config.limit_headers(app);
+    let mut pipe = ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(pipe_name)?;
+    loop {
+        pipe.connect().await?;
+        // Create the next instance before handing this one off, so clients always find one.
+        let connected = std::mem::replace(&mut pipe, ServerOptions::new().create(pipe_name)?);
+        spawn_connection(connected, &app, &config);
+    }
+}
+
+/// Like [`serve`], but over TLS: serves `app` on the connections accepted by `listener` once
+/// `acceptor` completes their handshake. Handlers find what the handshake negotiated with
+/// [`Context::tls_info`]. Connections whose handshake fails are closed without affecting the
+/// others.
+///
+/// Clients negotiate HTTP/2 with ALPN only if the acceptor's `ServerConfig` offers `h2` in its
+/// `alpn_protocols`; the server speaks whichever protocol the client then uses.
+///
+/// ```
+/// # async fn run(app: axum::Router, tls: std::sync::Arc<tokio_rustls::rustls::ServerConfig>)
+/// # -> std::io::Result<()> {
+/// let listener = tokio::net::TcpListener::bind("localhost:3443").await?;
+/// let acceptor = tokio_rustls::TlsAcceptor::from(tls);
+/// twirp::server::serve_tls(listener, acceptor, app, twirp::server::ServeConfig::new()).await?;
+/// # Ok(()) }
+/// ```
+#[cfg(feature = "tls")]
+pub async fn serve_tls(
+    listener: tokio::net::TcpListener,
+    acceptor: tokio_rustls::TlsAcceptor,
+    app: axum::Router,
+    config: ServeConfig,
+) -> std::io::Result<()> {
+    let app = config.limit_headers(app);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        config.configure_stream(&stream);
+        let (acceptor, app, config) = (acceptor.clone(), app.clone(), config.clone());
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    tracing::debug!(error = %err, "TLS handshake failed");
+                    return;
+                }
+            };
+            let (_, conn) = stream.get_ref();
+            let info = TlsInfo {
+                server_name: conn.server_name().map(str::to_string),
+                alpn_protocol: conn.alpn_protocol().map(<[u8]>::to_vec),
+            };
+            spawn_connection(stream, &app.layer(axum::Extension(info)), &config);
+        });
+    }
+}
+
+/// Serve `app` on a connection in a new task.
+fn spawn_connection<I>(io: I, app: &axum::Router, config: &ServeConfig)
+where
+    I: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let service = TowerToHyperService::new(app.clone());
+    let builder = config.builder();
+    tokio::spawn(async move {
+        if let Err(err) = builder
+            .serve_connection_with_upgrades(TokioIo::new(io), service)
+            .await
+        {
+            tracing::debug!(error = %err, "error serving connection");
+        }
+    });
+}
+
+/// Creates a signal for graceful shutdown: a handle, and a future that completes when
+/// [`ShutdownHandle::shutdown`] is called or every clone of the handle has been dropped. Pass the
+/// future to `axum::serve(...).with_graceful_shutdown()` to stop accepting connections and let
+/// in-flight requests finish once it completes.
+///
+/// ```
+/// # async fn run(app: axum::Router) -> std::io::Result<()> {
+/// let listener = tokio::net::TcpListener::bind("localhost:0").await?;
+/// let (shutdown, signal) = twirp::server::shutdown_signal();
+/// let server = tokio::spawn(async move {
+///     axum::serve(listener, app)
+///         .with_graceful_shutdown(signal)
+///         .await
+/// });
+///
+/// // ... later
+/// shutdown.shutdown();
+/// server.await??;
+/// # Ok(()) }
+/// ```
+pub fn shutdown_signal() -> (ShutdownHandle, impl Future<Output = ()> + Send + 'static) {
+    let token = CancellationToken::new();
+    let handle = ShutdownHandle {
+        inner: Arc::new(ShutdownOnDrop(token.clone())),
+    };
+    (handle, async move { token.cancelled().await })
+}
+
+/// Triggers the signal returned with it by [`shutdown_signal`].
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    inner: Arc<ShutdownOnDrop>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.inner.0.cancel();
+    }
+
+    /// Whether the signal has been triggered.
+    pub fn is_shutdown(&self) -> bool {
+        self.inner.0.is_cancelled()
+    }
+}
+
+// Dropping the last handle triggers the signal, so a server can't be left without a way to stop.
+#[derive(Debug)]
+struct ShutdownOnDrop(CancellationToken);
+
+impl Drop for ShutdownOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+/// What the TLS handshake of a request's connection negotiated, for handlers to read with
+/// [`Context::tls_info`].
+///
+/// [`serve_tls`] (with the `tls` feature) records it for every connection. Servers terminating TLS
+/// otherwise have to insert it into the extensions of each request themselves, e.g. by layering a
+/// clone of the app for every connection:
+///
+/// ```
+/// use twirp::server::TlsInfo;
+///
+/// # fn serve_connection(app: axum::Router, sni: Option<&str>, alpn: Option<&[u8]>) {
+/// // `sni` and `alpn` come from the TLS library, e.g. `rustls::ServerConnection::server_name()`
+/// // and `alpn_protocol()`.
+/// let info = TlsInfo {
+///     server_name: sni.map(str::to_string),
+///     alpn_protocol: alpn.map(<[u8]>::to_vec),
+/// };
+/// let app = app.layer(axum::Extension(info));
+/// // ... serve the connection with `app` ...
+/// # }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// The hostname the client asked for with Server Name Indication.
+    pub server_name: Option<String>,
+    /// The protocol negotiated with ALPN, e.g. `h2`.
+    pub alpn_protocol: Option<Vec<u8>>,
+}
+
+/// The client implementation a request came from, as sent in the
+/// [`CLIENT_LANGUAGE_HEADER`](crate::headers::CLIENT_LANGUAGE_HEADER) header (e.g. `rust/0.7.0`).
+///
+/// Handlers read it with [`Context::client_language`], and middleware (e.g. for metrics) finds it
+/// in the extensions of the response, like [`Timings`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientLanguage(pub String);
+
+impl ClientLanguage {
+    fn from_headers(headers: &header::HeaderMap) -> Option<Self> {
+        let value = headers.get(CLIENT_LANGUAGE_HEADER)?.to_str().ok()?.trim();
+        (!value.is_empty()).then(|| Self(value.to_string()))
+    }
+}
+
+/// Entry point used in code generated by `twirp-build`. Every response gets the
+/// [`TWIRP_VERSION_HEADER`] header.
+pub(crate) async fn handle_request<S, F, Fut, Req, Resp, R>(
+    service: S,
+    req: Request<Body>,
+    run: R,
+    f: F,
+) -> Response<Body>
+where
+    F: FnOnce(S, Context, Req) -> Fut + Clone + Sync + Send + 'static,
+    Fut: Future<Output = Result<Resp, TwirpErrorResponse>> + Send,
+    Req: FromRequestBody,
+    Resp: IntoResponseBody,
+    R: RunHandler<Instrumented<Fut>>,
+{
+    let interceptors = req
+        .extensions()
+        .get::<Arc<Options>>()
+        .map(|options| options.interceptors.clone())
+        .unwrap_or_default();
+    let mut resp = if interceptors.is_empty() {
+        respond(service, req, run, f).await
+    } else {
+        intercept(&interceptors, service, req, run, f).await
+    };
+    resp.headers_mut().insert(
+        TWIRP_VERSION_HEADER,
+        header::HeaderValue::from_static(TWIRP_VERSION),
+    );
+    resp
+}
+
+/// [`respond`], with the hooks of `interceptors` around it.
+async fn intercept<S, F, Fut, Req, Resp, R>(
+    interceptors: &[HandlerInterceptor],
+    service: S,
+    mut req: Request<Body>,
+    run: R,
+    f: F,
+) -> Response<Body>
+where
+    F: FnOnce(S, Context, Req) -> Fut + Clone + Sync + Send + 'static,
+    Fut: Future<Output = Result<Resp, TwirpErrorResponse>> + Send,
+    Req: FromRequestBody,
+    Resp: IntoResponseBody,
+    R: RunHandler<Instrumented<Fut>>,
+{
+    let start = Instant::now();
+    let method = method_path(&req);
+    let mut ran = 0;
+    let mut rejection = None;
+    for HandlerInterceptor(interceptor) in interceptors {
+        ran += 1;
+        if let Err(err) = interceptor.before(method.as_ref(), &mut req).await {
+            rejection = Some(err);
+            break;
+        }
+    }
+    let mut resp = match rejection {
+        None => respond(service, req, run, f).await,
+        Some(err) => {
+            let options = req
+                .extensions()
+                .get::<Arc<Options>>()
+                .cloned()
+                .unwrap_or_default();
+            // The request's ID is only generated once it gets past the interceptors.
+            let request_id = req
+                .headers()
+                .get(REQUEST_ID_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default();
+            let method = method.as_ref().map(MethodPath::to_string);
+            let err = hide_internal_error(&options, method.as_deref(), request_id, err);
+            // Errors are JSON whatever the request's encoding, gRPC-Web aside.
+            let format = req
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(BodyFormat::from_content_type_str)
+                .unwrap_or_default();
+            error_response(err, format)
+        }
+    };
+    let call = CallOutcome {
+        method,
+        error: resp.extensions().get::<TwirpErrorResponse>().cloned(),
+        elapsed: start.elapsed(),
+    };
+    for HandlerInterceptor(interceptor) in interceptors[..ran].iter().rev() {
+        interceptor.after(&call, &mut resp).await;
+    }
+    resp
+}
+
+async fn respond<S, F, Fut, Req, Resp, R>(
+    service: S,
+    mut req: Request<Body>,
+    run: R,
+    f: F,
+) -> Response<Body>
+where
+    F: FnOnce(S, Context, Req) -> Fut + Clone + Sync + Send + 'static,
+    Fut: Future<Output = Result<Resp, TwirpErrorResponse>> + Send,
+    Req: FromRequestBody,
+    Resp: IntoResponseBody,
+    R: RunHandler<Instrumented<Fut>>,
+{
+    let mut timings = req
+        .extensions()
+        .get::<Timings>()
+        .copied()
+        .unwrap_or_else(|| Timings::new(Instant::now()));
+    let options = req
+        .extensions()
+        .get::<Arc<Options>>()
+        .cloned()
+        .unwrap_or_default();
+
+    let req_fmt = match req.headers().get(header::CONTENT_TYPE) {
+        Some(content_type) => {
+            let content_type = content_type.to_str().unwrap_or_default();
+            let Some(format) = BodyFormat::from_content_type_str(content_type) else {
+                return error::bad_route(format!("unexpected Content-Type: {content_type:?}"))
+                    .into_response();
+            };
+            format
+        }
+        None => match options.default_content_type {
+            Some(Encoding::Protobuf) => BodyFormat::Pb,
+            #[cfg(feature = "json")]
+            Some(Encoding::Json) => BodyFormat::JsonPb,
+            None => return error::malformed("missing Content-Type header").into_response(),
+        },
+    };
+    let resp_fmt = BodyFormat::from_accept(&req, req_fmt);
+
+    #[cfg(not(feature = "json"))]
+    if let BodyFormat::JsonPb = req_fmt {
+        // Without the JSON codec, anything but protobuf (or gRPC-Web) is an unsupported route.
+        return error_response(
+            error::bad_route("JSON requests are not supported"),
+            BodyFormat::Pb,
+        );
+    }
+
+    if !options.is_ready() {
+        return error_response(error::unavailable("service is not ready"), resp_fmt);
+    }
+    for required in &options.required_headers {
+        if let Err(err) = required.check(req.headers()) {
+            return error_response(err, resp_fmt);
+        }
+    }
+    let in_flight = match options.in_flight.clone().map(|s| s.try_acquire_owned()) {
+        Some(Err(_)) => {
+            let mut resp = error_response(error::unavailable("too many requests"), resp_fmt);
+            resp.headers_mut()
+                .insert(header::RETRY_AFTER, header::HeaderValue::from_static("1"));
+            return resp;
+        }
+        permit => InFlightPermit(Arc::new(Mutex::new(permit.and_then(Result::ok)))),
+    };
+    req.extensions_mut().insert(in_flight.clone());
+    let if_none_match = req.headers().get(header::IF_NONE_MATCH).cloned();
+    let http_version = req.version();
+    #[cfg(feature = "gzip")]
+    let accepts_gzip = accepts_gzip(req.headers());
+    let request_id = request_id(req.headers(), &options);
+    req.extensions_mut().insert(request_id.clone());
+    let client_language = ClientLanguage::from_headers(req.headers());
+    if let Some(language) = &client_language {
+        req.extensions_mut().insert(language.clone());
+    }
+    let method_path = method_path(&req);
+    if let Some(path) = &method_path {
+        req.extensions_mut().insert(path.clone());
+    }
+    let method = method_path.as_ref().map(MethodPath::to_string);
+    let deadline = options
+        .timeout(
+            method.as_deref().unwrap_or_default(),
+            request_timeout(req.headers()),
+        )
+        .map(|timeout| timings.start + timeout);
+    let slow_request_log = options
+        .slow_request_threshold
+        .map(|threshold| (threshold, SlowRequestInfo::new(&req)));
+
+    let dry_run = options.allow_dry_run && is_dry_run(req.headers());
+
+    let mut sizes = BodySizes::default();
+    let (req, exts) =
+        match Req::from_request(req, req_fmt, &options, &mut timings, &mut sizes).await {
+            Ok(pair) => pair,
+            Err(err) => return error_response(err, resp_fmt),
+        };
+    if dry_run {
+        return dry_run_response(resp_fmt);
+    }
+
+    let resp_exts = Arc::new(Mutex::new(Extensions::new()));
+    let cancellation = CancelOnDrop(Some(CancellationToken::new()));
+    let deadline_timer = deadline.map(|deadline| {
+        let token = cancellation.token();
+        AbortOnDrop(tokio::spawn(async move {
+            tokio::time::sleep_until(deadline).await;
+            token.cancel();
+        }))
+    });
+    let ctx = Context::new(exts, resp_exts.clone())
+        .with_cancellation(cancellation.token())
+        .with_deadline(deadline.map(Instant::into_std));
+    let span = tracing::info_span!(
+        "twirp_request",
+        method = method.as_deref().unwrap_or_default(),
+        request_id = request_id.0,
+        code = tracing::field::Empty,
+        elapsed_ms = tracing::field::Empty,
+        fields = tracing::field::Empty,
+    );
+    let handler = f(service, ctx, req).instrument(span.clone());
+    let res = match run.spawn(&options, handler) {
+        Ok(rx) => rx
+            .await
+            .unwrap_or_else(|_| Err(error::internal("handler dropped before completing"))),
+        Err(handler) => handler.await,
+    };
+    drop(deadline_timer);
+    cancellation.disarm();
+    timings.set_response_handled();
+    if let Some((threshold, info)) = slow_request_log {
+        let elapsed = timings.response_handled().unwrap_or_default();
+        if elapsed > threshold {
+            tracing::warn!(
+                method = %info.method,
+                elapsed_ms = elapsed.as_millis() as u64,
+                request_id = info.request_id,
+                "slow twirp request"
+            );
+        }
+    }
+
+    let res =
+        res.map_err(|err| hide_internal_error(&options, method.as_deref(), &request_id.0, err));
+
+    span.record(
+        "code",
+        match &res {
+            Ok(_) => "ok",
+            Err(err) => err.code.twirp_code(),
+        },
+    );
+
+    let cacheable = resp_exts
+        .lock()
+        .expect("mutex poisoned")
+        .get::<Cacheable>()
+        .is_some();
+    let if_none_match = if cacheable { if_none_match } else { None };
+    let mut resp = match write_response(res, resp_fmt, &options, cacheable, if_none_match.as_ref())
+    {
+        Ok(resp) => resp,
+        Err(err) => {
+            // The handler succeeded but its response can't be encoded, e.g. a JSON response with
+            // an `Any` of an unknown type. The error may be about the response's contents, so it
+            // is logged rather than sent.
+            tracing::error!(
+                method = method.as_deref().unwrap_or_default(),
+                request_id = request_id.0,
+                error = %err,
+                "failed to encode response"
+            );
+            span.record("code", crate::TwirpErrorCode::Internal.twirp_code());
+            error_response(error::internal(ENCODE_ERROR_MSG), resp_fmt)
+        }
+    };
+    sizes.response = body_size(&resp);
+    #[cfg(feature = "gzip")]
+    if let Some(min_size) = options.gzip_min_size {
+        resp = gzip_response(resp, min_size, accepts_gzip).await;
+        if resp.headers().contains_key(header::CONTENT_ENCODING) {
+            sizes.response_compressed = body_size(&resp);
+        }
+    }
+    timings.set_response_written();
+    if options.server_timing {
+        if let Some(handled) = timings.response_handled() {
+            let value = format!("handler;dur={:.3}", handled.as_secs_f64() * 1000.0);
+            let value = header::HeaderValue::try_from(value).expect("always a valid header value");
+            resp.headers_mut().insert("server-timing", value);
+        }
+    }
+
+    let mut resp_exts = resp_exts.lock().expect("mutex poisoned").clone();
+    let log_fields = resp_exts.get::<LogFields>().cloned().unwrap_or_default();
+    span.record("elapsed_ms", timings.start.elapsed().as_millis() as u64);
+    if !log_fields.0.is_empty() {
+        span.record("fields", tracing::field::display(&log_fields));
+    }
+    if options.access_log {
+        tracing::info!(
+            method = method.as_deref().unwrap_or_default(),
+            request_id = request_id.0,
+            status = resp.status().as_u16(),
+            elapsed_ms = timings.start.elapsed().as_millis() as u64,
+            fields = %log_fields,
+            "twirp request"
+        );
+    }
+    if let Some(ResponseMeta(meta)) = resp_exts.remove::<ResponseMeta>() {
+        for (key, value) in meta {
+            let name = header::HeaderName::try_from(format!("{META_HEADER_PREFIX}{key}"));
+            if let (Ok(name), Ok(value)) = (name, header::HeaderValue::try_from(value)) {
+                resp.headers_mut().insert(name, value);
+            }
+        }
+    }
+    if let Some(ResponseTrailers(trailers)) = resp_exts.remove::<ResponseTrailers>() {
+        if http_version == http::Version::HTTP_2 {
+            resp = with_trailers(resp, trailers);
+        }
+    }
+    resp.extensions_mut().extend(resp_exts);
+    resp.extensions_mut().insert(timings);
+    resp.extensions_mut().insert(sizes);
+    resp.extensions_mut().insert(request_id);
+    if let Some(language) = client_language {
+        resp.extensions_mut().insert(language);
+    }
+    if let Some(path) = method_path {
+        resp.extensions_mut().insert(path);
+    }
+    resp
+}
+
+/// `err`, or the error with [`HIDDEN_INTERNAL_ERROR_MSG`] if it's an `internal` error and the
+/// options hide those (see [`Options::hide_internal_errors`]), logging the original.
+fn hide_internal_error(
+    options: &Options,
+    method: Option<&str>,
+    request_id: &str,
+    err: TwirpErrorResponse,
+) -> TwirpErrorResponse {
+    if !options.hide_internal_errors || err.code != crate::TwirpErrorCode::Internal {
+        return err;
+    }
+    tracing::error!(
+        method = method.unwrap_or_default(),
+        request_id,
+        error = %err.msg,
+        meta = ?err.meta,
+        "internal error"
+    );
+    error::internal(HIDDEN_INTERNAL_ERROR_MSG)
+}
+
+/// Send `trailers` after the body of `resp`, leaving out invalid ones.
+fn with_trailers(resp: Response<Body>, trailers: HashMap<String, String>) -> Response<Body> {
+    let trailers: header::HeaderMap = trailers
+        .into_iter()
+        .filter_map(|(key, value)| {
+            let name = header::HeaderName::try_from(key).ok()?;
+            Some((name, header::HeaderValue::try_from(value).ok()?))
+        })
+        .collect();
+    if trailers.is_empty() {
+        return resp;
+    }
+    let (parts, body) = resp.into_parts();
+    let body = body.with_trailers(async move { Some(Ok(trailers)) });
+    Response::from_parts(parts, Body::new(body))
+}
+
+/// What [`Options::slow_request_log`] logs about a request.
+struct SlowRequestInfo {
+    method: String,
+    request_id: String,
+}
+
+impl SlowRequestInfo {
+    fn new(req: &Request<Body>) -> Self {
+        // Nesting strips the service prefix from the URI.
+        let method = match req.extensions().get::<axum::extract::OriginalUri>() {
+            Some(uri) => uri.path().to_string(),
+            None => req.uri().path().to_string(),
+        };
+        let request_id = req
+            .extensions()
+            .get::<crate::request_id::RequestId>()
+            .map(|id| id.0.clone())
+            .unwrap_or_default();
+        Self { method, request_id }
+    }
+}
+
+/// The ID the client sent for a request, or a new one.
+fn request_id(headers: &header::HeaderMap, options: &Options) -> crate::request_id::RequestId {
+    let sent = headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| !id.is_empty());
+    crate::request_id::RequestId(match (sent, &options.request_id_generator) {
+        (Some(id), _) => id.to_string(),
+        (None, Some(GenerateRequestId(generate))) => generate(),
+        (None, None) => crate::request_id::uuid_v4(),
+    })
+}
+
+/// The method a request is for: the last two segments of its path, wherever the service is
+/// nested.
+fn method_path(req: &Request<Body>) -> Option<MethodPath> {
+    let path = match req.extensions().get::<axum::extract::OriginalUri>() {
+        Some(uri) => uri.path(),
+        None => req.uri().path(),
+    };
+    MethodPath::from_uri_path(path).ok()
+}
+
+/// The timeout the client sent in the `Request-Timeout` header.
+fn request_timeout(headers: &header::HeaderMap) -> Option<Duration> {
+    let ms = headers
+        .get(REQUEST_TIMEOUT_HEADER)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()?;
+    Some(Duration::from_millis(ms))
+}
+
+/// Aborts a task when dropped.
+struct AbortOnDrop(tokio::task::JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Cancels the request's token if dropped before being disarmed, which is what happens when hyper
+/// drops the handler future because the client went away.
+struct CancelOnDrop(Option<CancellationToken>);
+
+impl CancelOnDrop {
+    fn token(&self) -> CancellationToken {
+        self.0.clone().unwrap_or_default()
+    }
+
+    fn disarm(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if let Some(token) = self.0.take() {
+            token.cancel();
+        }
+    }
+}
+
+/// How the request passed to a handler is read from the HTTP request.
+pub(crate) trait FromRequestBody: Sized + Send {
+    fn from_request<'a>(
+        req: Request<Body>,
+        format: BodyFormat,
+        options: &'a Options,
+        timings: &'a mut Timings,
+        sizes: &'a mut BodySizes,
+    ) -> BoxFuture<'a, Result<(Self, Extensions), TwirpErrorResponse>>
+    where
+        Self: 'a;
+}
+
+impl<T> FromRequestBody for T
+where
+    T: prost::Message + Default + JsonDeserialize,
+{
+    fn from_request<'a>(
+        req: Request<Body>,
+        format: BodyFormat,
+        options: &'a Options,
+        timings: &'a mut Timings,
+        sizes: &'a mut BodySizes,
+    ) -> BoxFuture<'a, Result<(Self, Extensions), TwirpErrorResponse>>
+    where
+        Self: 'a,
+    {
+        Box::pin(parse_request(req, format, options, timings, sizes))
+    }
+}
+
+/// The body of a request to a method registered with `TwirpRouterBuilder::route_streaming`: the
+/// protobuf encoding of the request message, in chunks as they arrive, rather than the decoded
+/// message.
+///
+/// This is an escape hatch from the unary model, where the whole request is read and decoded
+/// before the handler runs, for methods taking uploads too large to buffer. For a request message
+/// like `message UploadRequest { bytes data = 1; }`, the stream is the field's tag and length
+/// followed by the uploaded bytes.
+pub struct RequestStream {
+    body: axum::body::BodyDataStream,
+}
+
+impl Debug for RequestStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RequestStream").finish_non_exhaustive()
+    }
+}
+
+impl futures::Stream for RequestStream {
+    type Item = Result<bytes::Bytes, TwirpErrorResponse>;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        std::pin::Pin::new(&mut self.body)
+            .poll_next(cx)
+            .map(|chunk| chunk.map(|chunk| chunk.map_err(|e| malformed(e.into()))))
+    }
+}
+
+impl FromRequestBody for RequestStream {
+    #[cfg_attr(not(feature = "hmac"), allow(unused_variables))]
+    fn from_request<'a>(
+        req: Request<Body>,
+        format: BodyFormat,
+        options: &'a Options,
+        timings: &'a mut Timings,
+        _sizes: &'a mut BodySizes,
+    ) -> BoxFuture<'a, Result<(Self, Extensions), TwirpErrorResponse>>
+    where
+        Self: 'a,
+    {
+        Box::pin(async move {
+            if !matches!(format, BodyFormat::Pb) {
+                return Err(error::bad_route(
+                    "streaming methods only accept protobuf requests",
+                ));
+            }
+            // Signatures cover the whole body, which isn't read before the handler runs.
+            #[cfg(feature = "hmac")]
+            if options.hmac_verifier.is_some() {
+                return Err(error::unimplemented(
+                    "streaming methods don't support signed requests",
+                ));
+            }
+            let (parts, body) = req.into_parts();
+            timings.set_received();
+            timings.set_parsed();
+            let stream = RequestStream {
+                body: body.into_data_stream(),
+            };
+            Ok((stream, parts.extensions))
+        })
+    }
+}
+
+/// An encoded request or response, for methods registered with
+/// `TwirpRouterBuilder::route_raw`: handlers get the body of the request as it was sent, and the
+/// response they return is sent as is, skipping the decoding and encoding of messages. This lets
+/// proxies forward calls without knowing their message types.
+///
+/// Requests are protobuf or JSON, as told by their `content_type`; handlers returning a body in a
+/// format the client didn't send or ask for are responsible for the client understanding it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RawBody {
+    pub content_type: header::HeaderValue,
+    pub body: bytes::Bytes,
+}
+
+impl RawBody {
+    pub fn new(content_type: header::HeaderValue, body: impl Into<bytes::Bytes>) -> Self {
+        Self {
+            content_type,
+            body: body.into(),
+        }
+    }
+
+    /// A protobuf-encoded body.
+    pub fn protobuf(body: impl Into<bytes::Bytes>) -> Self {
+        Self::new(
+            header::HeaderValue::from_static("application/protobuf"),
+            body,
+        )
+    }
+
+    /// A JSON-encoded body.
+    pub fn json(body: impl Into<bytes::Bytes>) -> Self {
+        Self::new(header::HeaderValue::from_static("application/json"), body)
+    }
+}
+
+impl FromRequestBody for RawBody {
+    fn from_request<'a>(
+        req: Request<Body>,
+        format: BodyFormat,
+        options: &'a Options,
+        timings: &'a mut Timings,
+        sizes: &'a mut BodySizes,
+    ) -> BoxFuture<'a, Result<(Self, Extensions), TwirpErrorResponse>>
+    where
+        Self: 'a,
+    {
+        Box::pin(async move {
+            let content_type = match format {
+                BodyFormat::Pb => Self::protobuf(bytes::Bytes::new()).content_type,
+                BodyFormat::JsonPb => Self::json(bytes::Bytes::new()).content_type,
+                #[cfg(feature = "grpc-web")]
+                BodyFormat::GrpcWeb => {
+                    return Err(error::bad_route(
+                        "raw methods only accept protobuf and JSON requests",
+                    ))
+                }
+            };
+            let (body, parts) = read_request(req, options, timings, sizes).await?;
+            timings.set_parsed();
+            Ok((RawBody { content_type, body }, parts.extensions))
+        })
+    }
+}
+
+/// How the response returned by a handler is written to the HTTP response.
+pub(crate) trait IntoResponseBody: Send {
+    fn into_response_body(
+        self,
+        format: BodyFormat,
+        options: &Options,
+    ) -> Result<ResponseBody, GenericError>;
+}
+
+/// An encoded response: its content type and body, or (for gRPC-Web) the whole response.
+pub(crate) enum ResponseBody {
+    Encoded(header::HeaderValue, bytes::Bytes),
+    #[cfg(feature = "grpc-web")]
+    Response(Response<Body>),
+}
+
+impl<T> IntoResponseBody for T
+where
+    T: prost::Message + JsonSerialize,
+{
+    #[cfg_attr(not(feature = "json"), allow(unused_variables))]
+    fn into_response_body(
+        self,
+        format: BodyFormat,
+        options: &Options,
+    ) -> Result<ResponseBody, GenericError> {
+        let (content_type, data) = match format {
+            BodyFormat::Pb => (CONTENT_TYPE_PROTOBUF, serialize_proto_message(self)),
+            #[cfg(feature = "json")]
+            BodyFormat::JsonPb => {
+                let mut data = serde_json::to_vec(&self)?;
+                if options.empty_json_body && data == b"{}" {
+                    data.clear();
+                }
+                (CONTENT_TYPE_JSON, data)
+            }
+            #[cfg(not(feature = "json"))]
+            BodyFormat::JsonPb => return Err("JSON responses are not supported".into()),
+            #[cfg(feature = "grpc-web")]
+            BodyFormat::GrpcWeb => {
+                return grpc_web::response(&serialize_proto_message(self))
+                    .map(ResponseBody::Response)
+            }
+        };
+        let content_type = header::HeaderValue::from_static(
+            std::str::from_utf8(content_type).expect("content types are ASCII"),
+        );
+        Ok(ResponseBody::Encoded(content_type, data.into()))
+    }
+}
+
+impl IntoResponseBody for RawBody {
+    fn into_response_body(self, _: BodyFormat, _: &Options) -> Result<ResponseBody, GenericError> {
+        Ok(ResponseBody::Encoded(self.content_type, self.body))
+    }
+}
+
+async fn parse_request<T>(
+    req: Request<Body>,
+    format: BodyFormat,
+    options: &Options,
+    timings: &mut Timings,
+    sizes: &mut BodySizes,
+) -> Result<(T, Extensions), TwirpErrorResponse>
+where
+    T: prost::Message + Default + JsonDeserialize,
+{
+    let (bytes, parts) = read_request(req, options, timings, sizes).await?;
+    let request = decode_body(&bytes, format, options).map_err(malformed)?;
+    timings.set_parsed();
+    Ok((request, parts.extensions))
+}
+
+/// Read the whole body of a request, copying it to the audit sink and checking its signature if
+/// the options call for them.
+async fn read_request(
+    req: Request<Body>,
+    options: &Options,
+    timings: &mut Timings,
+    sizes: &mut BodySizes,
+) -> Result<(bytes::Bytes, http::request::Parts), TwirpErrorResponse> {
+    let (parts, body) = req.into_parts();
+    let bytes = body
+        .collect()
+        .await
+        .map_err(|e| malformed(e.into()))?
+        .to_bytes();
+    timings.set_received();
+    sizes.request = Some(bytes.len());
+    if let Some(auditor) = &options.audit {
+        let record = AuditRecord {
+            method: parts.extensions.get::<MethodPath>().cloned(),
+            request_id: parts
+                .extensions
+                .get::<crate::request_id::RequestId>()
+                .map(|id| id.0.clone())
+                .unwrap_or_default(),
+            headers: crate::audit::redact(&parts.headers),
+            body: bytes.clone(),
+            received: std::time::SystemTime::now(),
+        };
+        auditor.send(record).await;
+    }
+    #[cfg(feature = "hmac")]
+    if let Some(verifier) = &options.hmac_verifier {
+        // Nesting strips the service prefix from the URI, but clients sign the full path.
+        let path = match parts.extensions.get::<axum::extract::OriginalUri>() {
+            Some(uri) => uri.path(),
+            None => parts.uri.path(),
+        };
+        verifier.verify(&parts.headers, path, &bytes)?;
+    }
+    Ok((bytes, parts))
+}
+
+/// Decode a request body with the given `Content-Type` as the server does, with the default
+/// [`Options`]: this is the step between reading the body and calling the handler, without the
+/// HTTP around it, e.g. for fuzzing (see the `fuzz` directory of this crate).
+///
+/// Content types the server doesn't accept fail with `bad_route`, and bodies that don't decode
+/// with `malformed`, as they would in a response.
+pub fn decode_request<T>(content_type: &str, bytes: &[u8]) -> Result<T, TwirpErrorResponse>
+where
+    T: prost::Message + Default + JsonDeserialize,
+{
+    let format = BodyFormat::from_content_type_str(content_type)
+        .ok_or_else(|| error::bad_route(format!("unexpected Content-Type: {content_type:?}")))?;
+    decode_body(bytes, format, &Options::default()).map_err(malformed)
+}
+
+#[cfg_attr(not(feature = "json"), allow(unused_variables))]
+fn decode_body<T>(bytes: &[u8], format: BodyFormat, options: &Options) -> Result<T, GenericError>
+where
+    T: prost::Message + Default + JsonDeserialize,
+{
+    Ok(match format {
+        BodyFormat::Pb => T::decode(bytes)?,
+        #[cfg(feature = "json")]
+        BodyFormat::JsonPb => {
+            // Some clients start JSON with a UTF-8 byte order mark, which isn't valid JSON.
+            // Whitespace around the document is, and `serde_json` skips it.
+            let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+            let max_depth = options.max_json_depth.unwrap_or(DEFAULT_MAX_JSON_DEPTH);
+            if json_depth(bytes) > max_depth {
+                return Err(format!("JSON is nested deeper than {max_depth} levels").into());
+            }
+            serde_json::from_slice(bytes)?
+        }
+        #[cfg(not(feature = "json"))]
+        BodyFormat::JsonPb => return Err("JSON requests are not supported".into()),
+        #[cfg(feature = "grpc-web")]
+        BodyFormat::GrpcWeb => T::decode(grpc_web::decode_request(bytes)?)?,
+    })
+}
+
+/// The error for a request body that can't be read or decoded.
+fn malformed(err: GenericError) -> TwirpErrorResponse {
+    // TODO: Capture original error in the response extensions. E.g.:
+    // resp_exts
+    //     .lock()
+    //     .expect("mutex poisoned")
+    //     .insert(RequestError(err));
+    let mut twirp_err = error::malformed("bad request");
+    twirp_err.insert_meta("error".to_string(), err.to_string());
+    if let Some(err) = err.downcast_ref::<serde_json::Error>() {
+        // Where in the body the JSON went wrong.
+        twirp_err.insert_meta("line".to_string(), err.line().to_string());
+        twirp_err.insert_meta("column".to_string(), err.column().to_string());
+    }
+    twirp_err
+}
+
+/// The deepest nesting of arrays and objects in a JSON document, found without recursing (or
+/// validating the document, which is left to the deserializer).
+#[cfg(feature = "json")]
+fn json_depth(json: &[u8]) -> usize {
+    let (mut depth, mut max) = (0usize, 0);
+    let (mut in_string, mut escaped) = (false, false);
+    for &b in json {
+        if in_string {
+            match b {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match b {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+                max = max.max(depth);
+            }
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    max
+}
+
+/// Whether the request asks for a dry run. See [`Options::allow_dry_run`].
+fn is_dry_run(headers: &header::HeaderMap) -> bool {
+    headers
+        .get(DRY_RUN_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| v.trim().eq_ignore_ascii_case("true"))
+}
+
+/// The response to a dry run: an empty message. See [`Options::allow_dry_run`].
+fn dry_run_response(format: BodyFormat) -> Response<Body> {
+    let (content_type, body) = match format {
+        BodyFormat::Pb => (CONTENT_TYPE_PROTOBUF, &b""[..]),
+        BodyFormat::JsonPb => (CONTENT_TYPE_JSON, &b"{}"[..]),
+        #[cfg(feature = "grpc-web")]
+        BodyFormat::GrpcWeb => {
+            return match grpc_web::response(&[]) {
+                Ok(mut resp) => {
+                    resp.headers_mut()
+                        .insert(DRY_RUN_HEADER, header::HeaderValue::from_static("true"));
+                    resp
+                }
+                Err(err) => error_response(error::internal(err.to_string()), format),
+            };
+        }
+    };
+    let mut resp = Response::new(Body::from(body));
+    resp.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_bytes(content_type).expect("content types are ASCII"),
+    );
+    resp.headers_mut()
+        .insert(DRY_RUN_HEADER, header::HeaderValue::from_static("true"));
+    resp
+}
+
+/// The response for `err`. Twirp errors are always JSON (`{"code", "msg", "meta"}` with an
+/// `application/json` content type), whatever the encoding of the request or the response asked
+/// for; only gRPC-Web, which has no error body, reports errors its own way.
+fn error_response(err: TwirpErrorResponse, format: BodyFormat) -> Response<Body> {
+    match format {
+        #[cfg(feature = "grpc-web")]
+        BodyFormat::GrpcWeb => grpc_web::error_response(err),
+        _ => err.into_response(),
+    }
+}
+
+/// Writes the response. Successful `cacheable` responses get an `ETag`, and become `304 Not
+/// Modified` if it matches `if_none_match`.
+fn write_response<T>(
+    response: Result<T, TwirpErrorResponse>,
+    response_format: BodyFormat,
+    options: &Options,
+    cacheable: bool,
+    if_none_match: Option<&header::HeaderValue>,
+) -> Result<Response<Body>, GenericError>
+where
+    T: IntoResponseBody,
+{
+    let (content_type, data) = match response {
+        Ok(response) => match response.into_response_body(response_format, options)? {
+            ResponseBody::Encoded(content_type, data) => (content_type, data),
+            #[cfg(feature = "grpc-web")]
+            ResponseBody::Response(resp) => return Ok(resp),
+        },
+        Err(err) => return Ok(error_response(err, response_format)),
+    };
+    let mut res = Response::builder().header(header::CONTENT_TYPE, &content_type);
+    if cacheable {
+        // The JSON and protobuf encodings of a response are different entities.
+        let etag = format!("\"{:016x}\"", fnv1a(content_type.as_bytes(), &data));
+        let not_modified = if_none_match
+            .and_then(|v| v.to_str().ok())
+            .map_or(false, |v| etag_matches(v, &etag));
+        res = res.header(header::ETAG, etag);
+        if not_modified {
+            return Ok(res.status(StatusCode::NOT_MODIFIED).body(Body::empty())?);
+        }
+    }
+    Ok(res.body(Body::from(data))?)
+}
+
+/// 64-bit FNV-1a hash of a response's content type and body. Unlike the standard library's
+/// hashers it is stable across builds and platforms, so replicas of a service agree on ETags.
+fn fnv1a(content_type: &[u8], data: &[u8]) -> u64 {
+    content_type
+        .iter()
+        .chain([0].iter())
+        .chain(data)
+        .fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+            (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+/// Whether an `If-None-Match` header value matches `etag`, using the weak comparison required for
+/// `If-None-Match`.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.trim() == "*"
+        || if_none_match
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == etag)
+}
+
+/// Whether the client accepts gzip-compressed responses, by the `Accept-Encoding` header.
+#[cfg(feature = "gzip")]
+fn accepts_gzip(headers: &header::HeaderMap) -> bool {
+    let mut wildcard = false;
+    for value in headers.get_all(header::ACCEPT_ENCODING) {
+        for item in value.to_str().unwrap_or_default().split(',') {
+            let mut params = item.split(';');
+            let coding = params.next().unwrap_or_default().trim();
+            let q = params
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if coding.eq_ignore_ascii_case("gzip") {
+                return q > 0.0;
+            }
+            if coding == "*" {
+                wildcard = q > 0.0;
+            }
+        }
+    }
+    wildcard
+}
+
+/// Compresses the body of a successful response with gzip if `accepts_gzip` and it is at least
+/// `min_size` bytes, and adds `Vary: Accept-Encoding` either way. See [`Options::gzip_responses`].
+#[cfg(feature = "gzip")]
+async fn gzip_response(
+    resp: Response<Body>,
+    min_size: usize,
+    accepts_gzip: bool,
+) -> Response<Body> {
+    use std::io::Write;
+
+    use axum::body::HttpBody as _;
+
+    let (mut parts, body) = resp.into_parts();
+    parts.headers.append(
+        header::VARY,
+        header::HeaderValue::from_static("accept-encoding"),
+    );
+    let size = body.size_hint().exact().unwrap_or_default();
+    if !accepts_gzip || !parts.status.is_success() || size < min_size as u64 {
+        return Response::from_parts(parts, body);
+    }
+    // Bodies written by `write_response` are in memory, so this doesn't wait.
+    let Ok(data) = body.collect().await.map(|b| b.to_bytes()) else {
+        return error::internal("failed to read response").into_response();
+    };
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    let Ok(compressed) = encoder.write_all(&data).and_then(|()| encoder.finish()) else {
+        return Response::from_parts(parts, Body::from(data));
+    };
+    parts.headers.insert(
+        header::CONTENT_ENCODING,
+        header::HeaderValue::from_static("gzip"),
+    );
+    parts.headers.remove(header::CONTENT_LENGTH);
+    if let Some(etag) = parts
+        .headers
+        .get(header::ETAG)
+        .and_then(|v| v.to_str().ok())
+    {
+        if !etag.starts_with("W/") {
+            if let Ok(weak) = header::HeaderValue::try_from(format!("W/{etag}")) {
+                parts.headers.insert(header::ETAG, weak);
+            }
+        }
+    }
+    Response::from_parts(parts, Body::from(compressed))
+}
+
+/// The size of a response body that is in memory, as those written by `write_response` are.
+fn body_size(resp: &Response<Body>) -> Option<usize> {
+    use axum::body::HttpBody as _;
+
+    let size = resp.body().size_hint().exact()?;
+    usize::try_from(size).ok()
+}
+
+/// Axum handler function that returns 404 Not Found with a Twirp JSON payload.
+///
+/// `axum::Router`'s default fallback handler returns a 404 Not Found with no body content.
+/// Use this fallback instead for full Twirp compliance.
+///
+/// # Usage
+///
+/// ```
+/// use axum::Router;
+///
+/// # fn build_app(twirp_routes: Router) -> Router {
+/// let app = Router::new()
+///     .nest("/twirp", twirp_routes)
+///     .fallback(twirp::server::not_found_handler);
+/// # app }
+/// ```
+pub async fn not_found_handler() -> Response<Body> {
+    error::bad_route("not found").into_response()
+}
+
+/// The response to requests for a method with an HTTP method other than `POST`.
+pub(crate) async fn method_not_allowed_handler(method: http::Method) -> Response<Body> {
+    error::bad_route(format!(
+        "unsupported method {method} (only POST is allowed)"
+    ))
+    .into_response()
+}
+
+/// Build the router for a Twirp service from a list of `"Method" => rust_method` pairs.
+///
+/// Each method is served at `/<Method>` and calls `api.rust_method(ctx, req)`, where `api` is a
+/// clone of the first argument (typically an `Arc` of the type implementing the service). This is
+/// what the `router()` function generated by `twirp-build` uses, and it works just as well for
+/// hand-written services. Prefix a method with `#[validate]` to check the request with its
+/// generated `validate()` method before calling the handler.
+///
+/// ```
+/// use std::sync::Arc;
+///
+/// #[derive(Clone, PartialEq, prost::Message, serde::Serialize, serde::Deserialize)]
+/// pub struct PingRequest {
+///     #[prost(string, tag = "1")]
+///     pub name: String,
+/// }
+///
+/// struct PingService;
+///
+/// impl PingService {
+///     async fn ping(
+///         &self,
+///         _ctx: twirp::Context,
+///         req: PingRequest,
+///     ) -> Result<PingRequest, twirp::TwirpErrorResponse> {
+///         Ok(req)
+///     }
+/// }
+///
+/// let router: twirp::Router = twirp::routes!(Arc::new(PingService), {
+///     "Ping" => ping,
+/// });
+/// let app = twirp::Router::new().nest("/twirp/example.PingService", router);
+/// # let _: twirp::Router = app;
+/// ```
+///
+/// Headers to add to every response of the service, e.g. one naming its version, go before the
+/// methods: `twirp::routes!(api, headers = headers, { ... })`. Headers the responses already have
+/// are kept.
+///
+/// Routes needing more control can be registered with `twirp::details::TwirpRouterBuilder`.
+#[macro_export]
+macro_rules! routes {
+    ($api:expr, { $($(#[$check:ident])? $method:literal => $handler:ident),* $(,)? }) => {
+        $crate::routes!($api, headers = $crate::axum::http::HeaderMap::new(), {
+            $($(#[$check])? $method => $handler),*
+        })
+    };
+    ($api:expr, headers = $headers:expr, { $($(#[$check:ident])? $method:literal => $handler:ident),* $(,)? }) => {
+        $crate::details::TwirpRouterBuilder::new($api)
+            $(.route_spawned(
+                concat!("/", $method),
+                $crate::__route_handler!($(#[$check])? $handler),
+            ))*
+            .default_headers($headers)
+            .build()
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __route_handler {
+    (#[validate] $handler:ident) => {
+        |api, ctx: $crate::Context, req| async move {
+            $crate::details::Validate::validate(&req)?;
+            api.$handler(ctx, req).await
+        }
+    };
+    ($handler:ident) => {
+        |api, ctx: $crate::Context, req| async move { api.$handler(ctx, req).await }
+    };
+}
+
+/// The sizes of the bodies of a request and its response, in bytes, which the server puts in the
+/// extensions of responses next to the [`Timings`], e.g. for middleware recording bandwidth or
+/// spotting bloated messages. The sizes are those of the encoded messages, in whichever encoding
+/// the request and response used.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BodySizes {
+    request: Option<usize>,
+    response: Option<usize>,
+    response_compressed: Option<usize>,
+}
+
+impl BodySizes {
+    /// The size of the request body, or `None` for streaming methods, whose handlers read it.
+    pub fn request(&self) -> Option<usize> {
+        self.request
+    }
+
+    /// The size of the response body before compression, or `None` if it wasn't in memory.
+    pub fn response(&self) -> Option<usize> {
+        self.response
+    }
+
+    /// The size of the response body as sent, if it was compressed (see
+    /// `Options::gzip_responses`).
+    pub fn response_compressed(&self) -> Option<usize> {
+        self.response_compressed
+    }
+}
+
+/// Contains timing information associated with a request.
+/// To access the timings in a given request, use the [extensions](Request::extensions)
+/// method and specialize to `Timings` appropriately.
+#[derive(Debug, Clone, Copy)]
+pub struct Timings {
+    // When the request started.
+    start: Instant,
+    // When the request was received (headers and body).
+    request_received: Option<Instant>,
+    // When the request body was parsed.
+    request_parsed: Option<Instant>,
+    // When the response handler returned.
+    response_handled: Option<Instant>,
+    // When the response was written.
+    response_written: Option<Instant>,
+}
+
+impl Timings {
+    #[allow(clippy::new_without_default)]
+    pub fn new(start: Instant) -> Self {
+        Self {
+            start,
+            request_received: None,
+            request_parsed: None,
+            response_handled: None,
+            response_written: None,
+        }
+    }
+
+    fn set_received(&mut self) {
+        self.request_received = Some(Instant::now());
+    }
+
+    fn set_parsed(&mut self) {
+        self.request_parsed = Some(Instant::now());
+    }
+
+    fn set_response_handled(&mut self) {
+        self.response_handled = Some(Instant::now());
+    }
+
+    fn set_response_written(&mut self) {
+        self.response_written = Some(Instant::now());
+    }
+
+    pub fn received(&self) -> Option<Duration> {
+        self.request_received.map(|x| x - self.start)
+    }
+
+    pub fn parsed(&self) -> Option<Duration> {
+        match (self.request_parsed, self.request_received) {
+            (Some(parsed), Some(received)) => Some(parsed - received),
+            _ => None,
+        }
+    }
+
+    pub fn response_handled(&self) -> Option<Duration> {
+        match (self.response_handled, self.request_parsed) {
+            (Some(handled), Some(parsed)) => Some(handled - parsed),
+            _ => None,
+        }
+    }
+
+    pub fn response_written(&self) -> Option<Duration> {
+        match (self.response_written, self.response_handled) {
+            (Some(written), Some(handled)) => Some(written - handled),
+            (Some(written), None) => {
+                if let Some(parsed) = self.request_parsed {
+                    Some(written - parsed)
+                } else {
+                    self.request_received.map(|received| written - received)
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// The total duration since the request started.
+    pub fn total_duration(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::details::TwirpRouterBuilder;
+    use crate::test::*;
+
+    #[cfg(feature = "json")]
+    use axum::middleware::{self, Next};
+    use tower::{Service, ServiceExt};
+
+    fn timings() -> Timings {
+        Timings::new(Instant::now())
+    }
+
+    #[tokio::test]
+    async fn test_bad_route() {
+        let mut router = test_api_router();
+        let req = Request::get("/nothing")
+            .extension(timings())
+            .body(Body::empty())
+            .unwrap();
+
+        let resp = router.call(req).await.unwrap();
+        let data = read_err_body(resp.into_body()).await;
+        assert_eq!(data, error::bad_route("not found"));
+    }
+
+    #[tokio::test]
+    async fn test_fallback() {
+        let unknown_method =
+            |uri: hyper::Uri| async move { error::bad_route(format!("no method at {uri}")) };
+        let mut router = TwirpRouterBuilder::new(())
+            .route("/Ping", |_, _: Context, req: PingRequest| async move {
+                Ok(PingResponse { name: req.name })
+            })
+            .fallback(unknown_method)
+            .build();
+        let req = Request::post("/Pong")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::empty())
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        let data = read_err_body(resp.into_body()).await;
+        assert_eq!(data, error::bad_route("no method at /Pong"));
+
+        // the generated routers' fallback can be replaced too
+        let api = Arc::new(TestApiServer);
+        let mut router = axum::Router::new().nest(
+            "/twirp/test.TestAPI",
+            crate::routes!(api, { "Ping" => ping }).fallback(unknown_method),
+        );
+        let req = Request::post("/twirp/test.TestAPI/Pong")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::empty())
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        let data = read_err_body(resp.into_body()).await;
+        assert_eq!(data, error::bad_route("no method at /Pong"));
+    }
+
+    #[tokio::test]
+    async fn test_errors_are_json() {
+        let with_meta = TwirpRouterBuilder::new(())
+            .route("/Meta", |_, _: Context, _: PingRequest| async move {
+                let mut err = error::not_found("no such hat");
+                err.insert_meta("hat".to_string(), "fedora".to_string());
+                Err::<PingResponse, _>(err)
+            })
+            .build();
+        let app = test_api_router().nest("/twirp/test.MetaAPI", with_meta);
+        let ping = PingRequest {
+            name: "hi".to_string(),
+        };
+        let ping = prost::Message::encode_to_vec(&ping);
+
+        for (method, path, body, expected) in [
+            (
+                http::Method::POST,
+                "/twirp/test.TestAPI/Boom",
+                ping.clone(),
+                serde_json::json!({"code": "internal", "msg": "boom!"}),
+            ),
+            (
+                http::Method::POST,
+                "/twirp/test.MetaAPI/Meta",
+                ping.clone(),
+                serde_json::json!({"code": "not_found", "msg": "no such hat", "meta": {"hat": "fedora"}}),
+            ),
+            (
+                http::Method::POST,
+                "/twirp/test.TestAPI/Pong",
+                ping.clone(),
+                serde_json::json!({"code": "bad_route", "msg": "not found"}),
+            ),
+            (
+                http::Method::GET,
+                "/twirp/test.TestAPI/Ping",
+                vec![],
+                serde_json::json!({
+                    "code": "bad_route",
+                    "msg": "unsupported method GET (only POST is allowed)",
+                }),
+            ),
+        ] {
+            // Protobuf requests get JSON errors, even when asking for protobuf responses.
+            let req = Request::builder()
+                .method(method)
+                .uri(path)
+                .header(header::CONTENT_TYPE, "application/protobuf")
+                .header(header::ACCEPT, "application/protobuf")
+                .body(Body::from(body))
+                .unwrap();
+            let resp = app.clone().oneshot(req).await.unwrap();
+            assert_ne!(resp.status(), StatusCode::OK, "{path}");
+            assert_eq!(
+                resp.headers()[header::CONTENT_TYPE],
+                "application/json",
+                "{path}"
+            );
+            let body: serde_json::Value = read_json_body(resp.into_body()).await;
+            assert_eq!(body, expected, "{path}");
+        }
+
+        // The same goes for requests that fail to decode.
+        let req = Request::post("/twirp/test.TestAPI/Ping")
+            .header(header::CONTENT_TYPE, "application/protobuf")
+            .body(Body::from(&b"\xff\xff\xff"[..]))
+            .unwrap();
+        let resp = app.oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+        let body: serde_json::Value = read_json_body(resp.into_body()).await;
+        assert_eq!(body["code"], "malformed");
+        assert!(body["msg"].is_string());
+        let meta = body["meta"].as_object().unwrap();
+        assert!(meta.values().all(|v| v.is_string()), "{meta:?}");
+        let mut keys: Vec<_> = body.as_object().unwrap().keys().collect();
+        keys.sort();
+        assert_eq!(keys, ["code", "meta", "msg"]);
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_interceptors() {
+        type Log = Arc<Mutex<Vec<String>>>;
+
+        struct Recorder {
+            name: &'static str,
+            log: Log,
+            // Reject requests without this header.
+            require: Option<&'static str>,
+        }
+
+        #[async_trait::async_trait]
+        impl Interceptor for Recorder {
+            async fn before(
+                &self,
+                method: Option<&MethodPath>,
+                req: &mut Request<Body>,
+            ) -> Result<(), TwirpErrorResponse> {
+                let method = method.map(MethodPath::to_string).unwrap_or_default();
+                self.log
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} before {method}", self.name));
+                if let Some(name) = self.require {
+                    if !req.headers().contains_key(name) {
+                        return Err(error::unauthenticated(format!("missing {name}")));
+                    }
+                    req.extensions_mut()
+                        .insert(RequestId("intercepted".to_string()));
+                }
+                Ok(())
+            }
+
+            async fn after(&self, call: &CallOutcome, resp: &mut Response<Body>) {
+                let code = call
+                    .error
+                    .as_ref()
+                    .map_or("ok", |err| err.code.twirp_code());
+                self.log
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} after {code}", self.name));
+                resp.headers_mut()
+                    .insert("x-intercepted", header::HeaderValue::from_static(self.name));
+            }
+        }
+
+        let log = Log::default();
+        let options = Options::new()
+            .interceptor(Recorder {
+                name: "outer",
+                log: log.clone(),
+                require: None,
+            })
+            .interceptor(Recorder {
+                name: "auth",
+                log: log.clone(),
+                require: Some("x-user"),
+            });
+        let app = test_api_router().layer(options);
+        let call = |method: &str, user: bool| {
+            let mut req = Request::post(format!("/twirp/test.TestAPI/{method}"))
+                .header(header::CONTENT_TYPE, "application/json");
+            if user {
+                req = req.header("x-user", "ann");
+            }
+            let req = req.body(Body::from(r#"{"name":"hi"}"#)).unwrap();
+            let app = app.clone();
+            async move { app.oneshot(req).await.unwrap() }
+        };
+
+        // Extensions inserted by `before` reach the handler.
+        let resp = call("Ping", true).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["x-intercepted"], "outer");
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(data.name, "hi-intercepted");
+        assert_eq!(
+            log.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            [
+                "outer before test.TestAPI/Ping",
+                "auth before test.TestAPI/Ping",
+                "auth after ok",
+                "outer after ok",
+            ]
+        );
+
+        // `after` sees the errors of handlers
+        let resp = call("Boom", true).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            log.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            [
+                "outer before test.TestAPI/Boom",
+                "auth before test.TestAPI/Boom",
+                "auth after internal",
+                "outer after internal",
+            ]
+        );
+
+        // and of `before` hooks, which skip the handler
+        let resp = call("Ping", false).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(resp.headers()[TWIRP_VERSION_HEADER], TWIRP_VERSION);
+        let data = read_err_body(resp.into_body()).await;
+        assert_eq!(data, error::unauthenticated("missing x-user"));
+        assert_eq!(
+            log.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            [
+                "outer before test.TestAPI/Ping",
+                "auth before test.TestAPI/Ping",
+                "auth after unauthenticated",
+                "outer after unauthenticated",
+            ]
+        );
+
+        // Requests for unknown methods aren't intercepted.
+        let resp = call("Pong", true).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert!(log.lock().unwrap().is_empty());
+
+        // `internal` errors of `before` hooks are hidden like those of handlers
+        struct Unavailable;
+
+        #[async_trait::async_trait]
+        impl Interceptor for Unavailable {
+            async fn before(
+                &self,
+                _: Option<&MethodPath>,
+                _: &mut Request<Body>,
+            ) -> Result<(), TwirpErrorResponse> {
+                Err(TwirpErrorResponse::builder(error::TwirpErrorCode::Internal)
+                    .msg("user store is down")
+                    .meta("host", "db-1")
+                    .build())
+            }
+        }
+
+        let options = Options::new()
+            .hide_internal_errors(true)
+            .interceptor(Recorder {
+                name: "outer",
+                log: log.clone(),
+                require: None,
+            })
+            .interceptor(Unavailable);
+        let resp = test_api_router()
+            .layer(options)
+            .oneshot(gen_ping_request("hi"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let data = read_err_body(resp.into_body()).await;
+        assert_eq!(data, error::internal(HIDDEN_INTERNAL_ERROR_MSG));
+        assert_eq!(
+            log.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            ["outer before test.TestAPI/Ping", "outer after internal"]
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_default_headers() {
+        let mut headers = header::HeaderMap::new();
+        headers.insert("x-service-version", header::HeaderValue::from_static("v2"));
+        headers.insert(
+            header::CACHE_CONTROL,
+            header::HeaderValue::from_static("no-store"),
+        );
+        headers.append(
+            header::CACHE_CONTROL,
+            header::HeaderValue::from_static("no-transform"),
+        );
+        let api = Arc::new(TestApiServer);
+        let service = crate::routes!(api.clone(), headers = headers, {
+            "Ping" => ping,
+            "Boom" => boom,
+        });
+        let app = axum::Router::new()
+            .nest("/twirp/test.TestAPI", service)
+            .nest(
+                "/twirp/test.OtherAPI",
+                crate::routes!(api, { "Ping" => ping }),
+            );
+
+        for (method, status) in [
+            ("Ping", StatusCode::OK),
+            ("Boom", StatusCode::INTERNAL_SERVER_ERROR),
+            ("Missing", StatusCode::NOT_FOUND),
+        ] {
+            let req = Request::post(format!("/twirp/test.TestAPI/{method}"))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(r#"{"name":"hi"}"#))
+                .unwrap();
+            let resp = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(resp.status(), status, "{method}");
+            assert_eq!(resp.headers()["x-service-version"], "v2", "{method}");
+            let cache_control: Vec<_> = resp
+                .headers()
+                .get_all(header::CACHE_CONTROL)
+                .iter()
+                .collect();
+            assert_eq!(cache_control, ["no-store", "no-transform"], "{method}");
+        }
+
+        // other services don't get them
+        let req = Request::post("/twirp/test.OtherAPI/Ping")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"name":"hi"}"#))
+            .unwrap();
+        let resp = app.oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!resp.headers().contains_key("x-service-version"));
+    }
+
+    #[tokio::test]
+    async fn test_default_headers_keep_response_headers() {
+        let mut headers = header::HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            header::HeaderValue::from_static("text/plain"),
+        );
+        headers.append(
+            header::CONTENT_TYPE,
+            header::HeaderValue::from_static("text/html"),
+        );
+        let router = TwirpRouterBuilder::new(())
+            .route("/Ping", |_, _: Context, req: PingRequest| async move {
+                Ok(PingResponse { name: req.name })
+            })
+            .default_headers(headers)
+            .build();
+        let req = Request::post("/Ping")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"name":"hi"}"#))
+            .unwrap();
+        let resp = router.oneshot(req).await.unwrap();
+        let content_type: Vec<_> = resp
+            .headers()
+            .get_all(header::CONTENT_TYPE)
+            .iter()
+            .collect();
+        assert_eq!(content_type, ["application/json"]);
+    }
+
+    fn versioned_router(version: &'static str) -> axum::Router {
+        TwirpRouterBuilder::new(())
+            .route("/Ping", move |_, _: Context, req: PingRequest| async move {
+                Ok(PingResponse {
+                    name: format!("{version}: {}", req.name),
+                })
+            })
+            .build()
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_nest() {
+        let twirp_routes = axum::Router::new()
+            .nest("/v1/test.TestAPI", versioned_router("v1"))
+            .nest("/v2/test.TestAPI", versioned_router("v2"));
+        let app = axum::Router::new()
+            .nest("/twirp", twirp_routes)
+            .fallback(not_found_handler);
+        for (path, expected) in [
+            ("/twirp/v1/test.TestAPI/Ping", Some("v1: hi")),
+            ("/twirp/v2/test.TestAPI/Ping", Some("v2: hi")),
+            ("/twirp/v3/test.TestAPI/Ping", None),
+            ("/twirp/test.TestAPI/Ping", None),
+        ] {
+            let req = Request::post(path)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(r#"{"name":"hi"}"#))
+                .unwrap();
+            let resp = app.clone().oneshot(req).await.unwrap();
+            match expected {
+                Some(name) => {
+                    let data: PingResponse = read_json_body(resp.into_body()).await;
+                    assert_eq!(data.name, name, "{path}");
+                }
+                None => {
+                    let data = read_err_body(resp.into_body()).await;
+                    assert_eq!(data, error::bad_route("not found"), "{path}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_nest_collision() {
+        let _ = axum::Router::<()>::new()
+            .nest("/v1/test.TestAPI", versioned_router("v1"))
+            .nest("/v1/test.TestAPI", versioned_router("v2"));
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_ping_success() {
+        let mut router = test_api_router();
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(&data.name, "hi");
+    }
+
+    #[tokio::test]
+    async fn test_wrong_method() {
+        let router = test_api_router();
+        let req = Request::get("/twirp/test.TestAPI/Ping")
+            .body(Body::empty())
+            .unwrap();
+        let resp = router.oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+        let data = read_err_body(resp.into_body()).await;
+        assert_eq!(
+            data,
+            error::bad_route("unsupported method GET (only POST is allowed)")
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_unsupported_content_type() {
+        let router = test_api_router();
+        let req = Request::post("/twirp/test.TestAPI/Ping")
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(Body::from("hi"))
+            .unwrap();
+        let resp = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let data = read_err_body(resp.into_body()).await;
+        assert_eq!(
+            data,
+            error::bad_route(r#"unexpected Content-Type: "text/plain""#)
+        );
+
+        // Parameters are ignored.
+        let req = Request::post("/twirp/test.TestAPI/Ping")
+            .header(header::CONTENT_TYPE, "application/json; charset=utf-8")
+            .body(Body::from(r#"{"name":"hi"}"#))
+            .unwrap();
+        let resp = router.oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_missing_content_type() {
+        let ping = PingRequest {
+            name: "hi".to_string(),
+        };
+        let req = |body: Vec<u8>| {
+            Request::post("/twirp/test.TestAPI/Ping")
+                .body(Body::from(body))
+                .unwrap()
+        };
+        let json = || serde_json::to_vec(&ping).unwrap();
+
+        let resp = test_api_router().oneshot(req(json())).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let data = read_err_body(resp.into_body()).await;
+        assert_eq!(data, error::malformed("missing Content-Type header"));
+
+        let router = test_api_router().layer(Options::new().default_content_type(Encoding::Json));
+        let resp = router.oneshot(req(json())).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(data.name, "hi");
+
+        let router =
+            test_api_router().layer(Options::new().default_content_type(Encoding::Protobuf));
+        let resp = router
+            .clone()
+            .oneshot(req(serialize_proto_message(ping.clone())))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/protobuf");
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(
+            <PingResponse as prost::Message>::decode(body).unwrap().name,
+            "hi"
+        );
+
+        // A content type that is there is still checked.
+        let req = Request::post("/twirp/test.TestAPI/Ping")
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(Body::from("hi"))
+            .unwrap();
+        let resp = router.oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_body_sizes() {
+        let ping = PingRequest {
+            name: "hi".to_string(),
+        };
+        let json = serde_json::to_vec(&ping).unwrap();
+        let pb = serialize_proto_message(ping.clone());
+        for (content_type, body) in [("application/json", json), ("application/protobuf", pb)] {
+            let req = Request::post("/twirp/test.TestAPI/Ping")
+                .header(header::CONTENT_TYPE, content_type)
+                .header(REQUEST_ID_HEADER, "abc")
+                .body(Body::from(body.clone()))
+                .unwrap();
+            let resp = test_api_router().oneshot(req).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            let sizes = *resp.extensions().get::<BodySizes>().unwrap();
+            let data = resp.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(sizes.request(), Some(body.len()), "{content_type}");
+            assert_eq!(sizes.response(), Some(data.len()), "{content_type}");
+            assert_eq!(sizes.response_compressed(), None);
+        }
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_ping_invalid_request() {
+        let mut router = test_api_router();
+        let req = Request::post("/twirp/test.TestAPI/Ping")
+            .header(header::CONTENT_TYPE, "application/json")
+            .extension(timings())
+            .body(Body::empty()) // not a valid request
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        assert!(resp.status().is_client_error(), "{:?}", resp);
+        let data = read_err_body(resp.into_body()).await;
+
+        // TODO: I think malformed should return some info about what was wrong
+        // with the request, but we don't want to leak server errors that have
+        // other details.
+        let mut expected = error::malformed("bad request");
+        expected.insert_meta(
+            "error".to_string(),
+            "EOF while parsing a value at line 1 column 0".to_string(),
+        );
+        expected.insert_meta("line".to_string(), "1".to_string());
+        expected.insert_meta("column".to_string(), "0".to_string());
+        assert_eq!(data, expected);
+
+        let req = Request::post("/twirp/test.TestAPI/Ping")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from("{\n  \"name\": 42\n}"))
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        let data = read_err_body(resp.into_body()).await;
+        assert_eq!(data.code, error::TwirpErrorCode::Malformed);
+        assert_eq!(data.meta["line"], "2");
+        assert_eq!(data.meta["column"], "12");
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_invalid_utf8() {
+        let router = test_api_router();
+        for body in [&b"{\"name\":\"\xff\xfe\"}"[..], b"\xc3\x28"] {
+            let req = Request::post("/twirp/test.TestAPI/Ping")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body))
+                .unwrap();
+            let resp = router.clone().oneshot(req).await.unwrap();
+            assert!(resp.status().is_client_error(), "{:?}", resp);
+            let data = read_err_body(resp.into_body()).await;
+            assert_eq!(data.code, error::TwirpErrorCode::Malformed);
+            assert!(data.meta.contains_key("error"), "{data:?}");
+        }
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_unknown_fields() {
+        // A newer version of `PingRequest`.
+        #[derive(Clone, PartialEq, prost::Message)]
+        struct PingRequestV2 {
+            #[prost(string, tag = "2")]
+            name: String,
+            #[prost(int64, tag = "3")]
+            added_later: i64,
+        }
+
+        let router = test_api_router();
+        let json = Request::post("/twirp/test.TestAPI/Ping")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"name":"hi","added_later":{"x":[1]}}"#))
+            .unwrap();
+        let pb = Request::post("/twirp/test.TestAPI/Ping")
+            .header(header::CONTENT_TYPE, "application/protobuf")
+            .header(header::ACCEPT, "application/json")
+            .body(Body::from(serialize_proto_message(PingRequestV2 {
+                name: "hi".to_string(),
+                added_later: 7,
+            })))
+            .unwrap();
+        for req in [json, pb] {
+            let resp = router.clone().oneshot(req).await.unwrap();
+            assert!(resp.status().is_success(), "{:?}", resp);
+            let data: PingResponse = read_json_body(resp.into_body()).await;
+            assert_eq!(data.name, "hi");
+        }
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_route_streaming() {
+        use futures::StreamExt;
+
+        let router = TwirpRouterBuilder::new(())
+            .route_streaming(
+                "/Upload",
+                |_, _: Context, mut body: RequestStream| async move {
+                    let (mut chunks, mut bytes) = (0, Vec::new());
+                    while let Some(chunk) = body.next().await {
+                        chunks += 1;
+                        bytes.extend_from_slice(&chunk?);
+                    }
+                    let req = <PingRequest as prost::Message>::decode(bytes.as_slice())
+                        .map_err(|e| error::malformed(e.to_string()))?;
+                    Ok(PingResponse {
+                        name: format!("{} bytes in {chunks} chunks", req.name.len()),
+                    })
+                },
+            )
+            .build();
+
+        let encoded = serialize_proto_message(PingRequest {
+            name: "x".repeat(100),
+        });
+        let (head, tail) = encoded.split_at(10);
+        let chunks = [head.to_vec(), tail.to_vec()].map(Ok::<_, std::io::Error>);
+        let req = Request::post("/Upload")
+            .header(header::CONTENT_TYPE, "application/protobuf")
+            .header(header::ACCEPT, "application/json")
+            .body(Body::from_stream(futures::stream::iter(chunks)))
+            .unwrap();
+        let resp = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(data.name, "100 bytes in 2 chunks");
+
+        let req = Request::post("/Upload")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"name":"hi"}"#))
+            .unwrap();
+        let resp = router.oneshot(req).await.unwrap();
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, crate::TwirpErrorCode::BadRoute);
+        assert_eq!(err.msg, "streaming methods only accept protobuf requests");
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_decode_request() {
+        let encoded = serialize_proto_message(PingRequest {
+            name: "hi".to_string(),
+        });
+        let req: PingRequest = decode_request("application/protobuf", &encoded).unwrap();
+        assert_eq!(req.name, "hi");
+        let req: PingRequest =
+            decode_request("application/json; charset=utf-8", br#"{"name":"hi"}"#).unwrap();
+        assert_eq!(req.name, "hi");
+
+        let err = decode_request::<PingRequest>("application/json", b"{").unwrap_err();
+        assert_eq!(err.code, crate::TwirpErrorCode::Malformed);
+        let err = decode_request::<PingRequest>("application/protobuf", b"\xff").unwrap_err();
+        assert_eq!(err.code, crate::TwirpErrorCode::Malformed);
+        let err = decode_request::<PingRequest>("text/plain", b"hi").unwrap_err();
+        assert_eq!(err.code, crate::TwirpErrorCode::BadRoute);
+        assert_eq!(err.msg, "unexpected Content-Type: \"text/plain\"");
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_json_bom_and_whitespace() {
+        let router = test_api_router();
+        for body in [
+            &b"\xEF\xBB\xBF{\"name\":\"hi\"}"[..],
+            b"\xEF\xBB\xBF \r\n\t{\"name\":\"hi\"}\n",
+            b"  {\"name\":\"hi\"}  ",
+        ] {
+            let req = Request::post("/twirp/test.TestAPI/Ping")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body))
+                .unwrap();
+            let resp = router.clone().oneshot(req).await.unwrap();
+            assert!(resp.status().is_success(), "{:?}", resp);
+            let data: PingResponse = read_json_body(resp.into_body()).await;
+            assert_eq!(data.name, "hi");
+        }
+
+        // only a leading byte order mark is skipped
+        let req = Request::post("/twirp/test.TestAPI/Ping")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(&b"{\"name\":\"hi\"}\xEF\xBB\xBF"[..]))
+            .unwrap();
+        let resp = router.oneshot(req).await.unwrap();
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, crate::TwirpErrorCode::Malformed);
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_route_raw() {
+        // echoes requests, as a proxy would forward them and their responses
+        let router = TwirpRouterBuilder::new(())
+            .route_raw("/Echo", |_, _: Context, req: RawBody| async move {
+                if req.body.is_empty() {
+                    return Err(error::invalid_argument("empty body"));
+                }
+                Ok(req)
+            })
+            .build();
+
+        let encoded = serialize_proto_message(PingRequest {
+            name: "hi".to_string(),
+        });
+        let req = Request::post("/Echo")
+            .header(header::CONTENT_TYPE, "application/protobuf")
+            .body(Body::from(encoded.clone()))
+            .unwrap();
+        let resp = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/protobuf");
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, encoded);
+
+        let req = Request::post("/Echo")
+            .header(header::CONTENT_TYPE, "application/json; charset=utf-8")
+            .body(Body::from(r#"{"name":"hi"}"#))
+            .unwrap();
+        let resp = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(data.name, "hi");
+
+        let req = Request::post("/Echo")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::empty())
+            .unwrap();
+        let resp = router.oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, crate::TwirpErrorCode::InvalidArgument);
+        assert_eq!(err.msg, "empty body");
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_boom() {
+        let mut router = test_api_router();
+        let req = serde_json::to_string(&PingRequest {
+            name: "hi".to_string(),
+        })
+        .unwrap();
+        let req = Request::post("/twirp/test.TestAPI/Boom")
+            .header(header::CONTENT_TYPE, "application/json")
+            .extension(timings())
+            .body(Body::from(req))
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        assert!(resp.status().is_server_error(), "{:?}", resp);
+        let data = read_err_body(resp.into_body()).await;
+        assert_eq!(data, error::internal("boom!"));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_accept_negotiation() {
+        let json = BodyFormat::JsonPb;
+        let pb = BodyFormat::Pb;
+        let negotiated = |accept| match negotiate(accept, json) {
+            BodyFormat::Pb => "pb",
+            _ => "json",
+        };
+        assert_eq!(negotiated("application/protobuf"), "pb");
+        assert_eq!(
+            negotiated("application/protobuf, application/json;q=0.9"),
+            "pb"
+        );
+        assert_eq!(
+            negotiated("application/protobuf;q=0.5, application/json;q=0.9"),
+            "json"
+        );
+        assert_eq!(
+            negotiated("application/json;q=0.1, application/protobuf"),
+            "pb"
+        );
+        assert_eq!(negotiated("*/*;q=0.8, application/protobuf;q=0.8"), "pb");
+        assert_eq!(negotiated("application/protobuf;q=0"), "json");
+        assert_eq!(negotiated("text/html"), "json");
+        assert!(matches!(negotiate("*/*", pb), BodyFormat::Pb));
+        assert!(matches!(negotiate("text/html", pb), BodyFormat::Pb));
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_accept_header() {
+        let mut router = test_api_router();
+        let mut req = gen_ping_request("hi");
+        req.headers_mut().insert(
+            header::ACCEPT,
+            "application/json;q=0.5, application/protobuf"
+                .parse()
+                .unwrap(),
+        );
+        let resp = router.call(req).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap().as_bytes(),
+            CONTENT_TYPE_PROTOBUF
+        );
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let data = <PingResponse as prost::Message>::decode(body).unwrap();
+        assert_eq!(&data.name, "hi");
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_cancellation() {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let tx = Arc::new(Mutex::new(Some(tx)));
+        let router = TwirpRouterBuilder::new(())
+            .route("/Ping", move |_, ctx: Context, _: PingRequest| {
+                let tx = tx.clone();
+                async move {
+                    if let Some(tx) = tx.lock().unwrap().take() {
+                        let _ = tx.send(ctx.cancellation_token());
+                    }
+                    futures::future::pending::<Result<PingResponse, TwirpErrorResponse>>().await
+                }
+            })
+            .build();
+        let req = Request::post("/Ping")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"name":"hi"}"#))
+            .unwrap();
+
+        let call = tokio::spawn(router.oneshot(req));
+        let token = rx.await.unwrap();
+        assert!(!token.is_cancelled());
+
+        // hyper drops the handler future when the connection closes
+        call.abort();
+        let _ = call.await;
+        assert!(token.is_cancelled());
+        token.cancelled().await;
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_etag() {
+        let router = TwirpRouterBuilder::new(())
+            .route("/Ping", |_, ctx: Context, req: PingRequest| async move {
+                if req.name != "fresh" {
+                    ctx.set_cacheable();
+                }
+                Ok(PingResponse { name: req.name })
+            })
+            .build();
+        let call = |name: &str, content_type: &str, if_none_match: Option<&str>| {
+            let mut req = Request::post("/Ping").header(header::CONTENT_TYPE, content_type);
+            if let Some(etag) = if_none_match {
+                req = req.header(header::IF_NONE_MATCH, etag);
+            }
+            let body = match content_type {
+                "application/json" => Body::from(format!(r#"{{"name":"{name}"}}"#)),
+                _ => Body::from(serialize_proto_message(PingRequest {
+                    name: name.to_string(),
+                })),
+            };
+            router.clone().oneshot(req.body(body).unwrap())
+        };
+        let etag = |resp: &Response<Body>| {
+            resp.headers()
+                .get(header::ETAG)
+                .map(|v| v.to_str().unwrap().to_string())
+        };
+
+        let resp = call("hi", "application/json", None).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let json_etag = etag(&resp).unwrap();
+        let resp = call("hi", "application/protobuf", None).await.unwrap();
+        let pb_etag = etag(&resp).unwrap();
+        assert_ne!(json_etag, pb_etag);
+
+        let resp = call("hi", "application/json", Some(&json_etag))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(etag(&resp).as_ref(), Some(&json_etag));
+        assert_eq!(read_string_body(resp.into_body()).await.unwrap(), "");
+
+        let resp = call("hi", "application/protobuf", Some(&format!("W/{pb_etag}")))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+
+        // the protobuf etag doesn't match the JSON encoding, nor another response
+        let resp = call("hi", "application/json", Some(&pb_etag))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = call("bye", "application/json", Some(&json_etag))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // responses that aren't marked cacheable don't get an etag
+        let resp = call("fresh", "application/json", Some("*")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(etag(&resp), None);
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_response_meta() {
+        let router = TwirpRouterBuilder::new(())
+            .route("/Ping", |_, ctx: Context, req: PingRequest| async move {
+                ctx.set_meta("Served-By", "replica-1");
+                ctx.set_meta("not a header name", "dropped");
+                if req.name.is_empty() {
+                    return Err(error::invalid_argument("name is required"));
+                }
+                Ok(PingResponse { name: req.name })
+            })
+            .build();
+        for body in [r#"{"name":"hi"}"#, "{}"] {
+            let req = Request::post("/Ping")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body))
+                .unwrap();
+            let resp = router.clone().oneshot(req).await.unwrap();
+            assert_eq!(resp.headers()["twirp-meta-served-by"], "replica-1");
+            let meta: Vec<_> = resp
+                .headers()
+                .keys()
+                .filter(|k| k.as_str().starts_with(META_HEADER_PREFIX))
+                .collect();
+            assert_eq!(meta.len(), 1);
+        }
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json_depth() {
+        assert_eq!(json_depth(b"1"), 0);
+        assert_eq!(json_depth(br#"{"a":[1,{"b":[]}],"c":{}}"#), 4);
+        assert_eq!(json_depth(br#"{"a":"[[[{{\"]]"}"#), 1);
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_max_json_depth() {
+        let nested = |depth: usize| {
+            let req = format!(
+                r#"{{"name":"hi","extra":{}1{}}}"#,
+                "[".repeat(depth),
+                "]".repeat(depth)
+            );
+            Request::post("/twirp/test.TestAPI/Ping")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(req))
+                .unwrap()
+        };
+
+        // unknown fields are skipped, but still have to be parsed
+        let router = test_api_router();
+        let resp = router.clone().oneshot(nested(10)).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        let resp = router.oneshot(nested(100_000)).await.unwrap();
+        let data = read_err_body(resp.into_body()).await;
+        assert_eq!(data.code, error::TwirpErrorCode::Malformed);
+        assert_eq!(data.meta["error"], "JSON is nested deeper than 128 levels");
+
+        let router = test_api_router().layer(Options::new().max_json_depth(5));
+        let resp = router.oneshot(nested(5)).await.unwrap();
+        let data = read_err_body(resp.into_body()).await;
+        assert_eq!(data.meta["error"], "JSON is nested deeper than 5 levels");
+    }
+
+    /// Records the fields of every event as `name=value` strings.
+    #[derive(Clone, Default)]
+    struct RecordEvents(Arc<Mutex<Vec<String>>>);
+
+    impl tracing::Subscriber for RecordEvents {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, _: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            tracing::span::Id::from_u64(1)
+        }
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+        fn event(&self, event: &tracing::Event<'_>) {
+            struct Fields(Vec<String>);
+            impl tracing::field::Visit for Fields {
+                fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn Debug) {
+                    self.0.push(format!("{}={value:?}", field.name()));
+                }
+            }
+            let mut fields = Fields(vec![]);
+            event.record(&mut fields);
+            self.0.lock().unwrap().push(fields.0.join(" "));
+        }
+        fn enter(&self, _: &tracing::span::Id) {}
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_slow_request_log() {
+        let events = RecordEvents::default();
+        let _guard = tracing::subscriber::set_default(events.clone());
+        let router = TwirpRouterBuilder::new(())
+            .route("/Ping", |_, _: Context, req: PingRequest| async move {
+                if req.name == "slow" {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+                Ok(PingResponse { name: req.name })
+            })
+            .build();
+        let router = axum::Router::new()
+            .nest("/twirp/test.TestAPI", router)
+            .layer(Options::new().slow_request_log(Duration::from_millis(20)));
+        for name in ["fast", "slow"] {
+            let req = Request::post("/twirp/test.TestAPI/Ping")
+                .header(header::CONTENT_TYPE, "application/json")
+                .header("x-request-id", name)
+                .body(Body::from(format!(r#"{{"name":"{name}"}}"#)))
+                .unwrap();
+            let resp = router.clone().oneshot(req).await.unwrap();
+            assert!(resp.status().is_success(), "{:?}", resp);
+        }
+
+        let events = events.0.lock().unwrap();
+        assert_eq!(events.len(), 1, "{events:?}");
+        assert!(
+            events[0].starts_with("message=slow twirp request method=/twirp/test.TestAPI/Ping"),
+            "{}",
+            events[0]
+        );
+        assert!(events[0].ends_with("request_id=\"slow\""), "{}", events[0]);
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_access_log() {
+        let router = TwirpRouterBuilder::new(())
+            .route("/Ping", |_, ctx: Context, req: PingRequest| async move {
+                ctx.record("user", &req.name);
+                ctx.record("tenant", "acme");
+                ctx.record("user", format!("{}!", req.name));
+                Ok(PingResponse { name: req.name })
+            })
+            .build();
+        let router = axum::Router::new()
+            .nest("/twirp/test.TestAPI", router)
+            .layer(Options::new().access_log(true));
+        let req = || {
+            Request::post("/twirp/test.TestAPI/Ping")
+                .header(header::CONTENT_TYPE, "application/json")
+                .header("x-request-id", "abc")
+                .body(Body::from(r#"{"name":"alice"}"#))
+                .unwrap()
+        };
+
+        // without a subscriber, nothing is recorded
+        let resp = router.clone().oneshot(req()).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        assert!(resp.extensions().get::<LogFields>().is_none());
+
+        let events = RecordEvents::default();
+        let _guard = tracing::subscriber::set_default(events.clone());
+        let resp = router.oneshot(req()).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+
+        let events = events.0.lock().unwrap();
+        assert_eq!(events.len(), 1, "{events:?}");
+        assert!(
+            events[0].starts_with(
+                "message=twirp request method=\"test.TestAPI/Ping\" request_id=\"abc\" status=200"
+            ),
+            "{}",
+            events[0]
+        );
+        assert!(
+            events[0].ends_with("fields=user=alice! tenant=acme"),
+            "{}",
+            events[0]
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_spawner() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let handle = runtime.handle().clone();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let thread = std::thread::Builder::new()
+            .name("twirp-handlers".to_string())
+            .spawn(move || runtime.block_on(stopped))
+            .unwrap();
+
+        let ping = |_, _: Context, _: PingRequest| async move {
+            let name = std::thread::current()
+                .name()
+                .unwrap_or_default()
+                .to_string();
+            Ok(PingResponse { name })
+        };
+        let router = TwirpRouterBuilder::new(())
+            .route_spawned("/Ping", ping)
+            .build();
+        let router = axum::Router::new().nest("/twirp/test.TestAPI", router);
+        let resp = router
+            .clone()
+            .layer(Options::new().spawner(handle))
+            .oneshot(gen_ping_request("hi"))
+            .await
+            .unwrap();
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(data.name, "twirp-handlers");
+        stop.send(()).unwrap();
+        thread.join().unwrap().unwrap();
+
+        // handlers the spawner drops fail
+        struct DropAll;
+        impl Spawner for DropAll {
+            fn spawn(&self, _: BoxFuture<'static, ()>) {}
+        }
+        let resp = router
+            .layer(Options::new().spawner(DropAll))
+            .oneshot(gen_ping_request("hi"))
+            .await
+            .unwrap();
+        let data = read_err_body(resp.into_body()).await;
+        assert_eq!(data.code, error::TwirpErrorCode::Internal);
+        assert_eq!(data.msg, "handler dropped before completing");
+
+        // handlers added with `route` run inline
+        let router = TwirpRouterBuilder::new(()).route("/Ping", ping).build();
+        let resp = axum::Router::new()
+            .nest("/twirp/test.TestAPI", router)
+            .layer(Options::new().spawner(DropAll))
+            .oneshot(gen_ping_request("hi"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_server_timing() {
+        let router = TwirpRouterBuilder::new(())
+            .route("/Ping", |_, _: Context, req: PingRequest| async move {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                Ok(PingResponse { name: req.name })
+            })
+            .build();
+        let router = axum::Router::new().nest("/twirp/test.TestAPI", router);
+
+        let resp = router
+            .clone()
+            .oneshot(gen_ping_request("hi"))
+            .await
+            .unwrap();
+        assert!(resp.headers().get("server-timing").is_none());
+
+        let router = router.layer(Options::new().server_timing(true));
+        let resp = router.oneshot(gen_ping_request("hi")).await.unwrap();
+        let value = resp.headers()["server-timing"].to_str().unwrap();
+        let ms: f64 = value
+            .strip_prefix("handler;dur=")
+            .expect(value)
+            .parse()
+            .unwrap();
+        assert!((20.0..10_000.0).contains(&ms), "{value}");
+        assert_eq!(value.split_once('.').unwrap().1.len(), 3, "{value}");
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_require_header() {
+        let options = Options::new()
+            .require_header("x-tenant", |_| true)
+            .require_header_with_code(
+                "x-api-version",
+                crate::TwirpErrorCode::FailedPrecondition,
+                |v| v == "2",
+            );
+        let router = test_api_router().layer(options);
+        let call = |headers: &[(&'static str, &'static str)]| {
+            let mut req = gen_ping_request("hi");
+            for (name, value) in headers {
+                req.headers_mut()
+                    .insert(*name, header::HeaderValue::from_static(value));
+            }
+            router.clone().oneshot(req)
+        };
+
+        let resp = call(&[("x-tenant", "acme"), ("x-api-version", "2")])
+            .await
+            .unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+
+        let resp = call(&[("x-api-version", "2")]).await.unwrap();
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, crate::TwirpErrorCode::InvalidArgument);
+        assert_eq!(err.msg, "missing required header x-tenant");
+
+        let resp = call(&[("x-tenant", "acme"), ("x-api-version", "1")])
+            .await
+            .unwrap();
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, crate::TwirpErrorCode::FailedPrecondition);
+        assert_eq!(err.msg, "invalid x-api-version header");
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_tls_info() {
+        let router = TwirpRouterBuilder::new(())
+            .route("/Ping", |_, ctx: Context, _: PingRequest| async move {
+                let name = match ctx.tls_info() {
+                    Some(TlsInfo {
+                        server_name: Some(name),
+                        ..
+                    }) => name.clone(),
+                    _ => "plaintext".to_string(),
+                };
+                Ok(PingResponse { name })
+            })
+            .build();
+        let tls = TlsInfo {
+            server_name: Some("tenant.example.com".to_string()),
+            alpn_protocol: Some(b"h2".to_vec()),
+        };
+        for (router, expected) in [
+            (router.clone(), "plaintext"),
+            (router.layer(Extension(tls)), "tenant.example.com"),
+        ] {
+            let req = Request::post("/Ping")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from("{}"))
+                .unwrap();
+            let resp = router.oneshot(req).await.unwrap();
+            let data: PingResponse = read_json_body(resp.into_body()).await;
+            assert_eq!(data.name, expected);
+        }
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_client_language() {
+        let router = TwirpRouterBuilder::new(())
+            .route("/Ping", |_, ctx: Context, _: PingRequest| async move {
+                let name = ctx.client_language().unwrap_or("unknown").to_string();
+                Ok(PingResponse { name })
+            })
+            .build();
+        for (header, expected) in [(Some("go/8.1.3"), "go/8.1.3"), (None, "unknown")] {
+            let mut req = Request::post("/Ping").header(header::CONTENT_TYPE, "application/json");
+            if let Some(header) = header {
+                req = req.header(CLIENT_LANGUAGE_HEADER, header);
+            }
+            let resp = router
+                .clone()
+                .oneshot(req.body(Body::from("{}")).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(
+                resp.extensions().get::<ClientLanguage>(),
+                header.map(|h| ClientLanguage(h.to_string())).as_ref()
+            );
+            let data: PingResponse = read_json_body(resp.into_body()).await;
+            assert_eq!(data.name, expected);
+        }
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_request_id() {
+        let router = TwirpRouterBuilder::new(())
+            .route("/Ping", |_, ctx: Context, _: PingRequest| async move {
+                let name = ctx.request_id().unwrap().to_string();
+                Ok(PingResponse { name })
+            })
+            .build();
+        let call = |router: axum::Router, id: Option<&str>| {
+            let mut req = Request::post("/Ping").header(header::CONTENT_TYPE, "application/json");
+            if let Some(id) = id {
+                req = req.header(REQUEST_ID_HEADER, id);
+            }
+            let req = req.body(Body::from("{}")).unwrap();
+            async move {
+                let resp = router.oneshot(req).await.unwrap();
+                let id = resp
+                    .extensions()
+                    .get::<crate::request_id::RequestId>()
+                    .cloned();
+                let data: PingResponse = read_json_body(resp.into_body()).await;
+                assert_eq!(id.unwrap().0, data.name);
+                data.name
+            }
+        };
+
+        assert_eq!(call(router.clone(), Some("abcd")).await, "abcd");
+        assert_eq!(call(router.clone(), None).await.len(), 36);
+        let router = router.layer(Options::new().request_id_generator(|| "generated".to_string()));
+        assert_eq!(call(router.clone(), None).await, "generated");
+        assert_eq!(call(router, Some("abcd")).await, "abcd");
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_signal() {
+        let (handle, signal) = shutdown_signal();
+        let clone = handle.clone();
+        let signal = tokio::spawn(signal);
+        drop(clone);
+        tokio::task::yield_now().await;
+        assert!(!signal.is_finished());
+        assert!(!handle.is_shutdown());
+        handle.shutdown();
+        assert!(handle.is_shutdown());
+        signal.await.unwrap();
+
+        // dropping every handle shuts down too
+        let (handle, signal) = shutdown_signal();
+        drop(handle);
+        signal.await;
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_empty_json_body() {
+        #[derive(Clone, PartialEq, prost::Message, serde::Serialize)]
+        struct Empty {}
+
+        let router = || {
+            TwirpRouterBuilder::new(())
+                .route("/Empty", |_, _: Context, _: PingRequest| async move {
+                    Ok(Empty {})
+                })
+                .route("/Ping", |_, _: Context, req: PingRequest| async move {
+                    Ok(PingResponse { name: req.name })
+                })
+                .build()
+        };
+        let call = |router: axum::Router, method: &str| {
+            let req = Request::post(format!("/{method}"))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from("{}"))
+                .unwrap();
+            async move {
+                let resp = router.oneshot(req).await.unwrap();
+                assert!(resp.status().is_success(), "{:?}", resp);
+                read_string_body(resp.into_body()).await.unwrap()
+            }
+        };
+
+        assert_eq!(call(router(), "Empty").await, "{}");
+        let router = router().layer(Options::new().empty_json_body(true));
+        assert_eq!(call(router.clone(), "Empty").await, "");
+        assert_eq!(call(router, "Ping").await, r#"{"name":""}"#);
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_max_in_flight() {
+        let (entered_tx, mut entered_rx) = tokio::sync::mpsc::channel(1);
+        let release = Arc::new(tokio::sync::Notify::new());
+        let r = release.clone();
+        let router = TwirpRouterBuilder::new(())
+            .route("/Ping", move |_, _: Context, req: PingRequest| {
+                let (entered_tx, release) = (entered_tx.clone(), r.clone());
+                async move {
+                    if req.name == "wait" {
+                        entered_tx.send(()).await.unwrap();
+                        release.notified().await;
+                    }
+                    Ok(PingResponse { name: req.name })
+                }
+            })
+            .build()
+            .layer(Options::new().max_in_flight(1));
+        let ping = |name: &str| {
+            Request::post("/Ping")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(format!(r#"{{"name":"{name}"}}"#)))
+                .unwrap()
+        };
+
+        let waiting = tokio::spawn(router.clone().oneshot(ping("wait")));
+        entered_rx.recv().await.unwrap();
+        let resp = router.clone().oneshot(ping("hi")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers()[header::RETRY_AFTER], "1");
+        let data = read_err_body(resp.into_body()).await;
+        assert_eq!(data, error::unavailable("too many requests"));
+
+        release.notify_one();
+        assert!(waiting.await.unwrap().unwrap().status().is_success());
+        let resp = router.oneshot(ping("hi")).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_deadline() {
+        let router = TwirpRouterBuilder::new(())
+            .route("/Ping", |_, ctx: Context, req: PingRequest| async move {
+                let Some(remaining) = ctx.remaining() else {
+                    return Ok(PingResponse { name: req.name });
+                };
+                assert!(remaining <= Duration::from_millis(50), "{remaining:?}");
+                ctx.cancelled().await;
+                assert_eq!(ctx.remaining(), Some(Duration::ZERO));
+                Err(error::deadline_exceeded("out of time"))
+            })
+            .build();
+        let ping = |timeout: Option<&str>| {
+            let mut req = Request::post("/Ping").header(header::CONTENT_TYPE, "application/json");
+            if let Some(timeout) = timeout {
+                req = req.header(REQUEST_TIMEOUT_HEADER, timeout);
+            }
+            req.body(Body::from(r#"{"name":"hi"}"#)).unwrap()
+        };
+
+        let resp = router.clone().oneshot(ping(Some("50"))).await.unwrap();
+        let data = read_err_body(resp.into_body()).await;
+        assert_eq!(data, error::deadline_exceeded("out of time"));
+
+        for timeout in [None, Some("soon")] {
+            let resp = router.clone().oneshot(ping(timeout)).await.unwrap();
+            assert!(resp.status().is_success(), "{:?}", resp);
+        }
+    }
+
+    #[test]
+    fn test_timeout_precedence() {
+        let secs = Duration::from_secs;
+        let options = Options::new()
+            .default_timeout(secs(10))
+            .method_timeout("test.TestAPI/Boom", secs(2));
+        // server only
+        assert_eq!(options.timeout("test.TestAPI/Ping", None), Some(secs(10)));
+        assert_eq!(options.timeout("test.TestAPI/Boom", None), Some(secs(2)));
+        // both: the tightest wins
+        assert_eq!(
+            options.timeout("test.TestAPI/Ping", Some(secs(5))),
+            Some(secs(5))
+        );
+        assert_eq!(
+            options.timeout("test.TestAPI/Boom", Some(secs(5))),
+            Some(secs(2))
+        );
+        // client only
+        assert_eq!(Options::new().timeout("test.TestAPI/Ping", None), None);
+        assert_eq!(
+            Options::new().timeout("test.TestAPI/Ping", Some(secs(5))),
+            Some(secs(5))
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_server_timeout() {
+        let router = TwirpRouterBuilder::new(())
+            .route("/Ping", |_, ctx: Context, _: PingRequest| async move {
+                let name = match ctx.remaining() {
+                    Some(remaining) => format!("{}", remaining.as_secs_f64().ceil()),
+                    None => "none".to_string(),
+                };
+                Ok(PingResponse { name })
+            })
+            .build();
+        let router = axum::Router::new()
+            .nest("/twirp/test.TestAPI", router)
+            .layer(Options::new().method_timeout("test.TestAPI/Ping", Duration::from_secs(10)));
+        for (timeout, expected) in [(None, "10"), (Some("5000"), "5"), (Some("60000"), "10")] {
+            let mut req = Request::post("/twirp/test.TestAPI/Ping")
+                .header(header::CONTENT_TYPE, "application/json");
+            if let Some(timeout) = timeout {
+                req = req.header(REQUEST_TIMEOUT_HEADER, timeout);
+            }
+            let req = req.body(Body::from("{}")).unwrap();
+            let resp = router.clone().oneshot(req).await.unwrap();
+            let data: PingResponse = read_json_body(resp.into_body()).await;
+            assert_eq!(data.name, expected, "{timeout:?}");
+        }
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_pipeline() {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let record = |name: &'static str| {
+            let seen = seen.clone();
+            middleware::from_fn(move |req: Request<Body>, next: Next| {
+                seen.lock().unwrap().push(name);
+                next.run(req)
+            })
+        };
+        let pipeline = Pipeline::new()
+            .layer(Stage::Other, "other", record("other"))
+            .layer(Stage::Logging, "log", record("log"))
+            .layer(Stage::Auth, "authn", record("authn"))
+            .layer(Stage::Logging, "metrics", record("metrics"))
+            .layer(Stage::Auth, "authz", record("authz"));
+        let expected = ["authn", "authz", "log", "metrics", "other"];
+        assert_eq!(pipeline.order(), expected);
+
+        let router = pipeline.apply(test_api_router());
+        let resp = router.oneshot(gen_ping_request("hi")).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        assert_eq!(*seen.lock().unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config =
+            ServeConfig::new().http2_keep_alive(Duration::from_millis(10), Duration::from_secs(1));
+        let server = tokio::spawn(serve(listener, test_api_router(), config));
+
+        let base_url = url::Url::parse(&format!("http://{addr}/twirp/")).unwrap();
+        let client = crate::Client::from_base_url(base_url).unwrap();
+        let resp = client
+            .ping(PingRequest {
+                name: "hi".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(resp.name, "hi");
+
+        server.abort();
+    }
+
+    #[cfg(all(feature = "tls", feature = "json"))]
+    #[tokio::test]
+    async fn test_serve_tls() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio_rustls::rustls::pki_types::pem::PemObject;
+        use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+        use tokio_rustls::rustls::{crypto, ClientConfig, RootCertStore, ServerConfig};
+
+        // A self-signed certificate for tenant.example.com.
+        let cert = CertificateDer::from_pem_slice(include_bytes!("../testdata/cert.pem")).unwrap();
+        let key = PrivateKeyDer::from_pem_slice(include_bytes!("../testdata/key.pem")).unwrap();
+        let provider = Arc::new(crypto::ring::default_provider());
+        let mut server_config = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.clone()], key)
+            .unwrap();
+        server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let mut client_config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        let router = TwirpRouterBuilder::new(())
+            .route("/Ping", |_, ctx: Context, _: PingRequest| async move {
+                let info = ctx.tls_info().cloned().unwrap_or_default();
+                let alpn = String::from_utf8(info.alpn_protocol.unwrap_or_default()).unwrap();
+                let name = format!("{} {alpn}", info.server_name.unwrap_or_default());
+                Ok(PingResponse { name })
+            })
+            .build();
+        let app = axum::Router::new().nest("/twirp/test.TestAPI", router);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+        let server = tokio::spawn(serve_tls(listener, acceptor, app, ServeConfig::new()));
+
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let server_name = ServerName::try_from("tenant.example.com").unwrap();
+        let mut stream = connector.connect(server_name, stream).await.unwrap();
+        stream
+            .write_all(http1_ping("hi", true).as_bytes())
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        // The server closes the connection without a TLS close_notify, which rustls reports as an
+        // error once the response has been read.
+        let _ = stream.read_to_string(&mut resp).await;
+        assert!(resp.starts_with("HTTP/1.1 200 OK"), "{resp}");
+        assert!(
+            resp.ends_with(r#"{"name":"tenant.example.com http/1.1"}"#),
+            "{resp}"
+        );
+
+        server.abort();
+    }
+
+    /// Writes `requests` (raw HTTP/1.1) to a new connection to `addr` all at once, and returns
+    /// everything the server sends back until it closes the connection.
+    async fn http1_exchange(addr: std::net::SocketAddr, requests: &[String]) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(requests.concat().as_bytes())
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_string(&mut resp))
+            .await
+            .expect("the server didn't close the connection")
+            .unwrap();
+        resp
+    }
+
+    fn http1_ping(name: &str, close: bool) -> String {
+        let body = format!(r#"{{"name":"{name}"}}"#);
+        let connection = if close { "close" } else { "keep-alive" };
+        format!(
+            "POST /twirp/test.TestAPI/Ping HTTP/1.1\r\nhost: localhost\r\n\
+             content-type: application/json\r\ncontent-length: {}\r\n\
+             connection: {connection}\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_serve_http1_keep_alive() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener, test_api_router(), ServeConfig::new()));
+
+        // sequential requests on one connection
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        for name in ["one", "two"] {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            stream
+                .write_all(http1_ping(name, false).as_bytes())
+                .await
+                .unwrap();
+            let expected = format!(r#"{{"name":"{name}"}}"#);
+            let mut resp = Vec::new();
+            while !String::from_utf8_lossy(&resp).ends_with(&expected) {
+                let mut buf = [0; 1024];
+                let n = stream.read(&mut buf).await.unwrap();
+                assert!(
+                    n > 0,
+                    "connection closed: {}",
+                    String::from_utf8_lossy(&resp)
+                );
+                resp.extend_from_slice(&buf[..n]);
+            }
+            assert!(String::from_utf8_lossy(&resp).starts_with("HTTP/1.1 200 OK"));
+        }
+
+        // pipelined requests are answered in order
+        let resp = http1_exchange(addr, &[http1_ping("one", false), http1_ping("two", true)]).await;
+        assert_eq!(resp.matches("HTTP/1.1 200 OK").count(), 2, "{resp}");
+        let one = resp.find(r#"{"name":"one"}"#).expect(&resp);
+        let two = resp.find(r#"{"name":"two"}"#).expect(&resp);
+        assert!(one < two, "{resp}");
+
+        server.abort();
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_serve_expect_continue() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener, test_api_router(), ServeConfig::new()));
+
+        // the body is sent once the server asks for it
+        let ping = http1_ping("hi", true);
+        let (head, body) = ping.split_once("\r\n\r\n").unwrap();
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("{head}\r\nexpect: 100-continue\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut buf = [0; 1024];
+        let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+            .await
+            .expect("the server didn't ask for the body")
+            .unwrap();
+        let interim = String::from_utf8_lossy(&buf[..n]).to_string();
+        assert_eq!(interim, "HTTP/1.1 100 Continue\r\n\r\n");
+        stream.write_all(body.as_bytes()).await.unwrap();
+        let mut resp = String::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_string(&mut resp))
+            .await
+            .expect("the server didn't respond")
+            .unwrap();
+        assert!(resp.starts_with("HTTP/1.1 200 OK"), "{resp}");
+        assert!(resp.ends_with(r#"{"name":"hi"}"#), "{resp}");
+
+        // requests rejected without reading the body are answered without asking for it
+        let ping = http1_ping("hi", true).replace("application/json", "text/plain");
+        let (head, _) = ping.split_once("\r\n\r\n").unwrap();
+        let resp = http1_exchange(addr, &[format!("{head}\r\nexpect: 100-continue\r\n\r\n")]).await;
+        assert!(resp.starts_with("HTTP/1.1 404 Bad Route"), "{resp}");
+        assert!(!resp.contains("100 Continue"), "{resp}");
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_serve_tcp_nodelay() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        for (config, expected) in [
+            (ServeConfig::new(), true),
+            (ServeConfig::new().tcp_nodelay(true), true),
+            (ServeConfig::new().tcp_nodelay(false), false),
+        ] {
+            let _client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            stream.set_nodelay(!expected).unwrap();
+            config.configure_stream(&stream);
+            assert_eq!(stream.nodelay().unwrap(), expected, "{config:?}");
+        }
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_serve_header_limits() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ServeConfig::new().max_headers(8).max_header_size(200);
+        let server = tokio::spawn(serve(listener, test_api_router(), config));
+        let url = format!("http://{addr}/twirp/test.TestAPI/Ping");
+        let ping = |headers: Vec<(String, String)>| {
+            let mut req = reqwest::Client::new()
+                .post(&url)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(r#"{"name":"hi"}"#);
+            for (name, value) in headers {
+                req = req.header(name, value);
+            }
+            req.send()
+        };
+
+        let resp = ping(vec![]).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = ping(vec![("x-big".to_string(), "x".repeat(200))])
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let err: TwirpErrorResponse = serde_json::from_slice(&resp.bytes().await.unwrap()).unwrap();
+        assert_eq!(err, error::malformed("headers larger than 200 bytes"));
+
+        // hyper counts the headers of HTTP/1.1 requests itself.
+        let many = (0..10)
+            .map(|i| (format!("x-{i}"), "1".to_string()))
+            .collect();
+        let resp = ping(many).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+
+        server.abort();
+
+        // Over HTTP/2, the headers are counted once they are parsed.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ServeConfig::new().max_headers(8);
+        let server = tokio::spawn(serve(listener, test_api_router(), config));
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (mut sender, conn) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                .await
+                .unwrap();
+        tokio::spawn(conn);
+        let mut req = Request::post(format!("http://{addr}/twirp/test.TestAPI/Ping"))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"name":"hi"}"#))
+            .unwrap();
+        for i in 0..10 {
+            let name = header::HeaderName::try_from(format!("x-{i}")).unwrap();
+            req.headers_mut()
+                .insert(name, header::HeaderValue::from_static("1"));
+        }
+        let resp = sender.send_request(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let data = read_err_body(Body::new(resp.into_body())).await;
+        assert_eq!(data, error::malformed("more than 8 headers"));
+
+        server.abort();
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_serve_trailers() {
+        let router = TwirpRouterBuilder::new(())
+            .route("/Ping", |_, ctx: Context, req: PingRequest| async move {
+                ctx.set_trailer("x-checksum", "abc");
+                ctx.set_trailer("invalid name", "left out");
+                Ok(PingResponse { name: req.name })
+            })
+            .build();
+        let app = axum::Router::new().nest("/twirp/test.TestAPI", router);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener, app, ServeConfig::new()));
+
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (mut sender, conn) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                .await
+                .unwrap();
+        tokio::spawn(conn);
+        let req = Request::post(format!("http://{addr}/twirp/test.TestAPI/Ping"))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"name":"hi"}"#))
+            .unwrap();
+        let resp = sender.send_request(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.into_body().collect().await.unwrap();
+        let trailers = body.trailers().cloned().expect("no trailers");
+        assert_eq!(trailers.len(), 1, "{trailers:?}");
+        assert_eq!(trailers["x-checksum"], "abc");
+        assert_eq!(body.to_bytes(), r#"{"name":"hi"}"#);
+
+        // HTTP/1.1 responses go without them
+        let resp = http1_exchange(addr, &[http1_ping("hi", true)]).await;
+        assert!(resp.starts_with("HTTP/1.1 200 OK"), "{resp}");
+        assert!(!resp.contains("x-checksum"), "{resp}");
+        assert!(resp.ends_with(r#"{"name":"hi"}"#), "{resp}");
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_serve_reason_phrase() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener, test_api_router(), ServeConfig::new()));
+
+        let missing = http1_ping("hi", true).replace("/Ping", "/Missing");
+        let resp = http1_exchange(addr, &[missing]).await;
+        assert!(resp.starts_with("HTTP/1.1 404 Bad Route\r\n"), "{resp}");
+
+        server.abort();
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_serve_http1_keep_alive_disabled() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ServeConfig::new().http1_keep_alive(false);
+        let server = tokio::spawn(serve(listener, test_api_router(), config));
+
+        // the connection is closed after the first response
+        let resp =
+            http1_exchange(addr, &[http1_ping("one", false), http1_ping("two", false)]).await;
+        assert_eq!(resp.matches("HTTP/1.1 200 OK").count(), 1, "{resp}");
+        assert!(resp.contains("connection: close"), "{resp}");
+        assert!(resp.ends_with(r#"{"name":"one"}"#), "{resp}");
+
+        server.abort();
+    }
+
+    #[cfg(feature = "gzip")]
+    #[tokio::test]
+    async fn test_gzip_responses() {
+        use std::io::Read;
+
+        let app = test_api_router().layer(Options::new().gzip_responses(100));
+        let call = |name: String, accept_encoding: Option<&'static str>| {
+            let mut req = gen_ping_request(&name);
+            if let Some(accept_encoding) = accept_encoding {
+                req.headers_mut().insert(
+                    header::ACCEPT_ENCODING,
+                    header::HeaderValue::from_static(accept_encoding),
+                );
+            }
+            app.clone().oneshot(req)
+        };
+        let vary = |resp: &Response<Body>| resp.headers().get(header::VARY).cloned();
+        let encoding =
+            |resp: &Response<Body>| resp.headers().get(header::CONTENT_ENCODING).cloned();
+
+        // large enough, and accepted
+        let long = "x".repeat(200);
+        let resp = call(long.clone(), Some("deflate, gzip;q=0.8"))
+            .await
+            .unwrap();
+        assert_eq!(vary(&resp).unwrap(), "accept-encoding");
+        assert_eq!(encoding(&resp).unwrap(), "gzip");
+        let sizes = *resp.extensions().get::<BodySizes>().unwrap();
+        let compressed = resp.into_body().collect().await.unwrap().to_bytes();
+        let mut json = String::new();
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_string(&mut json)
+            .unwrap();
+        assert_eq!(json, format!(r#"{{"name":"{long}"}}"#));
+        assert_eq!(sizes.request(), Some(json.len()));
+        assert_eq!(sizes.response(), Some(json.len()));
+        assert_eq!(sizes.response_compressed(), Some(compressed.len()));
+
+        // not accepted, or too small
+        for (name, accept_encoding) in [
+            (long.clone(), None),
+            (long.clone(), Some("gzip;q=0, *")),
+            (long.clone(), Some("br")),
+            ("hi".to_string(), Some("gzip")),
+        ] {
+            let resp = call(name, accept_encoding).await.unwrap();
+            assert_eq!(vary(&resp).unwrap(), "accept-encoding");
+            assert_eq!(encoding(&resp), None, "{accept_encoding:?}");
+            let sizes = resp.extensions().get::<BodySizes>().unwrap();
+            assert_eq!(sizes.response_compressed(), None);
+        }
+        let resp = call(long, Some("*")).await.unwrap();
+        assert_eq!(encoding(&resp).unwrap(), "gzip");
+
+        // without the option
+        let resp = test_api_router()
+            .oneshot(gen_ping_request(&"x".repeat(200)))
+            .await
+            .unwrap();
+        assert_eq!(vary(&resp), None);
+        assert_eq!(encoding(&resp), None);
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_hide_internal_errors() {
+        let call = |options: Options, path: &'static str| async move {
+            let app = test_api_router().layer(options);
+            let req = gen_ping_request("hi");
+            let (mut parts, body) = req.into_parts();
+            parts.uri = path.parse().unwrap();
+            let resp = app.oneshot(Request::from_parts(parts, body)).await.unwrap();
+            read_err_body(resp.into_body()).await
+        };
+
+        let err = call(Options::new(), "/twirp/test.TestAPI/Boom").await;
+        assert_eq!(err.msg, "boom!");
+        let err = call(
+            Options::new().hide_internal_errors(true),
+            "/twirp/test.TestAPI/Boom",
+        )
+        .await;
+        assert_eq!(err.code, crate::TwirpErrorCode::Internal);
+        assert_eq!(err.msg, HIDDEN_INTERNAL_ERROR_MSG);
+
+        // other errors are sent as they are
+        let err = call(
+            Options::new().hide_internal_errors(true),
+            "/twirp/test.TestAPI/Missing",
+        )
+        .await;
+        assert_eq!(err.code, crate::TwirpErrorCode::BadRoute);
+    }
+
+    /// A `google.protobuf.Any` whose JSON encoding, like `pbjson`'s, needs the message type to be
+    /// known.
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct Any {
+        #[prost(string, tag = "1")]
+        type_url: String,
+        #[prost(bytes = "vec", tag = "2")]
+        value: Vec<u8>,
+    }
+
+    impl serde::Serialize for Any {
+        fn serialize<S: serde::Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom(format!(
+                "unknown message type {}",
+                self.type_url
+            )))
+        }
+    }
+
+    #[derive(Clone, PartialEq, prost::Message, serde::Serialize)]
+    struct DetailResponse {
+        #[prost(message, optional, tag = "1")]
+        detail: Option<Any>,
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_encode_error() {
+        let router = TwirpRouterBuilder::new(())
+            .route("/Detail", |_, _: Context, _: PingRequest| async move {
+                Ok(DetailResponse {
+                    detail: Some(Any {
+                        type_url: "type.googleapis.com/secret.Detail".to_string(),
+                        value: vec![1, 2, 3],
+                    }),
+                })
+            })
+            .build();
+        let app = axum::Router::new().nest("/twirp/test.TestAPI", router);
+
+        let req = Request::post("/twirp/test.TestAPI/Detail")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"name":"hi"}"#))
+            .unwrap();
+        let resp = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err, error::internal(ENCODE_ERROR_MSG));
+
+        // The protobuf encoding doesn't care about the type.
+        let req = Request::post("/twirp/test.TestAPI/Detail")
+            .header(header::CONTENT_TYPE, "application/protobuf")
+            .body(Body::empty())
+            .unwrap();
+        let resp = app.oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_dry_run() {
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let router = {
+            let calls = calls.clone();
+            TwirpRouterBuilder::new(())
+                .route("/Ping", move |_, _: Context, req: PingRequest| {
+                    let calls = calls.clone();
+                    async move {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        Ok(PingResponse { name: req.name })
+                    }
+                })
+                .build()
+        };
+        let call = |options: Options, content_type: &'static str, body: &'static str| {
+            let app = router.clone().layer(options);
+            async move {
+                let req = Request::post("/Ping")
+                    .header(header::CONTENT_TYPE, content_type)
+                    .header(DRY_RUN_HEADER, "true")
+                    .body(Body::from(body))
+                    .unwrap();
+                app.oneshot(req).await.unwrap()
+            }
+        };
+
+        let resp = call(
+            Options::new().allow_dry_run(true),
+            "application/json",
+            r#"{"name":"hi"}"#,
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[DRY_RUN_HEADER], "true");
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "{}");
+
+        let resp = call(
+            Options::new().allow_dry_run(true),
+            "application/protobuf",
+            "",
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/protobuf");
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert!(body.is_empty());
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        // requests that don't decode fail as usual
+        let resp = call(
+            Options::new().allow_dry_run(true),
+            "application/json",
+            "not json",
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!(!resp.headers().contains_key(DRY_RUN_HEADER));
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        // without the option, the header is ignored
+        let resp = call(Options::new(), "application/json", r#"{"name":"hi"}"#).await;
+        assert!(!resp.headers().contains_key(DRY_RUN_HEADER));
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(data.name, "hi");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_method_path() {
+        let router = TwirpRouterBuilder::new(())
+            .route("/Ping", |_, ctx: Context, _: PingRequest| async move {
+                Ok(PingResponse {
+                    name: ctx.method_path().unwrap().service_fqn(),
+                })
+            })
+            .build();
+        let app = axum::Router::new().nest("/twirp/test.TestAPI", router);
+        let req = Request::post("/twirp/test.TestAPI/Ping")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from("{}"))
+            .unwrap();
+        let resp = app.oneshot(req).await.unwrap();
+        assert_eq!(
+            resp.extensions().get::<MethodPath>(),
+            Some(&MethodPath::new("test", "TestAPI", "Ping"))
+        );
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(data.name, "test.TestAPI");
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_dynamic_router() {
+        let methods = DynamicRouter::new();
+        let app = axum::Router::new().nest("/twirp", methods.router());
+        let call = |path: &str| {
+            let req = Request::post(format!("/twirp/{path}"))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(r#"{"name":"hi"}"#))
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let resp = app.oneshot(req).await.unwrap();
+                let status = resp.status();
+                (status, read_string_body(resp.into_body()).await.unwrap())
+            }
+        };
+
+        let (status, _) = call("test.TestAPI/Ping").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let api = Arc::new(TestApiServer);
+        let test_routes = crate::routes!(api, { "Ping" => ping, "Boom" => boom });
+        methods.add_method("test.TestAPI/Ping", test_routes.clone());
+        methods.add_method("/test.TestAPI/Boom", test_routes);
+        assert_eq!(
+            methods.methods(),
+            ["test.TestAPI/Boom", "test.TestAPI/Ping"]
+        );
+        assert_eq!(
+            call("test.TestAPI/Ping").await,
+            (StatusCode::OK, r#"{"name":"hi"}"#.to_string())
+        );
+
+        // replaced methods serve new requests with the new router
+        let versioned = versioned_router("v2");
+        methods.add_method("test.TestAPI/Ping", versioned);
+        let (status, body) = call("test.TestAPI/Ping").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("v2"), "{body}");
+
+        assert!(methods.remove_method("test.TestAPI/Ping"));
+        assert!(!methods.remove_method("test.TestAPI/Ping"));
+        assert_eq!(methods.methods(), ["test.TestAPI/Boom"]);
+        let (status, body) = call("test.TestAPI/Ping").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body.contains("bad_route"), "{body}");
+        let (status, _) = call("test.TestAPI/Boom").await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_dynamic_router_concurrent_changes() {
+        let methods = DynamicRouter::new();
+        let app = axum::Router::new().nest("/twirp", methods.router());
+        methods.add_method("test.TestAPI/Ping", versioned_router("v1"));
+
+        // requests for a method that is always there never fail, whatever else changes
+        let callers: Vec<_> = (0..4)
+            .map(|_| {
+                let app = app.clone();
+                tokio::spawn(async move {
+                    for _ in 0..200 {
+                        let req = Request::post("/twirp/test.TestAPI/Ping")
+                            .header(header::CONTENT_TYPE, "application/json")
+                            .body(Body::from(r#"{"name":"hi"}"#))
+                            .unwrap();
+                        let resp = app.clone().oneshot(req).await.unwrap();
+                        assert_eq!(resp.status(), StatusCode::OK);
+                        let body = read_string_body(resp.into_body()).await.unwrap();
+                        assert!(body.contains("v1: hi") || body.contains("v2: hi"), "{body}");
+                    }
+                })
+            })
+            .collect();
+
+        // changes are made from other threads while the requests are dispatched
+        let changers: Vec<_> = (0..4)
+            .map(|i| {
+                let methods = methods.clone();
+                std::thread::spawn(move || {
+                    let path = format!("test.TestAPI/Method{i}");
+                    for n in 0..200 {
+                        let version = if n % 2 == 0 { "v1" } else { "v2" };
+                        methods.add_method("test.TestAPI/Ping", versioned_router(version));
+                        methods.add_method(&path, versioned_router(version));
+                        assert!(methods.remove_method(&path));
+                    }
+                })
+            })
+            .collect();
+        for caller in callers {
+            caller.await.unwrap();
+        }
+        for changer in changers {
+            changer.join().unwrap();
+        }
+
+        // no change was lost to a concurrent one
+        assert_eq!(methods.methods(), ["test.TestAPI/Ping"]);
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_route_blocking() {
+        let (entered_tx, entered_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let release_rx = Arc::new(Mutex::new(release_rx));
+        let router = TwirpRouterBuilder::new(())
+            .route_blocking(
+                "/Ping",
+                BlockingPool::new(1),
+                move |_, _: Context, req: PingRequest| {
+                    if req.name == "wait" {
+                        entered_tx.send(()).unwrap();
+                        release_rx.lock().unwrap().recv().unwrap();
+                    }
+                    Ok(PingResponse { name: req.name })
+                },
+            )
+            .build();
+        let call = |name: &str| {
+            let req = Request::post("/Ping")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(format!(r#"{{"name":"{name}"}}"#)))
+                .unwrap();
+            router.clone().oneshot(req)
+        };
+
+        let waiting = tokio::spawn(call("wait"));
+        tokio::task::spawn_blocking(move || entered_rx.recv().unwrap())
+            .await
+            .unwrap();
+        let resp = call("hi").await.unwrap();
+        let data = read_err_body(resp.into_body()).await;
+        assert_eq!(
+            data,
+            error::resource_exhausted("too many concurrent requests")
+        );
+
+        release_tx.send(()).unwrap();
+        let resp = waiting.await.unwrap().unwrap();
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(data.name, "wait");
+        let resp = call("hi").await.unwrap();
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(data.name, "hi");
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_readiness() {
+        let ready = Arc::new(AtomicBool::new(false));
+        let mut router = test_api_router().layer(Options::new().readiness(ready.clone()));
+
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        let data = read_err_body(resp.into_body()).await;
+        assert_eq!(data, error::unavailable("service is not ready"));
+
+        ready.store(true, Ordering::Release);
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
     }
 
+    #[cfg(feature = "json")]
     #[tokio::test]
     async fn test_middleware() {
         let mut router = test_api_router().layer(middleware::from_fn(request_id_middleware));
@@ -327,6 +4807,7 @@ mod tests {
 
         // now pass a header with x-request-id
         let req = Request::post("/twirp/test.TestAPI/Ping")
+            .header(header::CONTENT_TYPE, "application/json")
             .header("x-request-id", "abcd")
             .body(Body::from(
                 serde_json::to_string(&PingRequest {
@@ -341,6 +4822,7 @@ mod tests {
         assert_eq!(&data.name, "hello-abcd");
     }
 
+    #[cfg(feature = "json")]
     async fn request_id_middleware(
         mut request: http::Request<Body>,
         next: Next,
@@ -0,0 +1,368 @@
+//! HMAC-SHA256 request signing, enabled with the `hmac` feature.
+//!
+//! Clients sign requests with [`ClientBuilder::hmac_signer`](crate::ClientBuilder::hmac_signer)
+//! (or an [`HmacSigner`] added as middleware), which sets a header of the form `v1=<hex>`, the
+//! HMAC of the request body under a shared key. Signers made with [`HmacSigner::with_timestamp`]
+//! also sign the request path and the time the request was sent, as
+//! `t=<unix seconds>,v1=<hex>` with the HMAC taken over `<t>.<path>.<body>`, so that a signature
+//! can't be replayed against another method or long after it was made.
+//!
+//! Servers check the signatures with an [`HmacVerifier`] in their
+//! [`Options`](crate::server::Options):
+//!
+//! ```
+//! use std::time::Duration;
+//!
+//! use axum::Router;
+//! use twirp::reqwest::header::HeaderName;
+//! use twirp::signing::HmacVerifier;
+//!
+//! # fn build_app(api_routes: Router) -> Router {
+//! let verifier = HmacVerifier::new("secret", HeaderName::from_static("x-signature"))
+//!     .max_age(Duration::from_secs(300));
+//! let twirp_routes = Router::new()
+//!     .nest("/my.Service", api_routes)
+//!     .layer(twirp::server::Options::new().verify_hmac(verifier));
+//! # twirp_routes }
+//! ```
+
+use std::fmt::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use sha2::Sha256;
+
+use crate::{error, Middleware, Next, Result, TwirpErrorResponse};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Client middleware that signs each request with HMAC-SHA256. See the [module docs](self) for the
+/// format of the header.
+pub struct HmacSigner {
+    key: Vec<u8>,
+    header_name: HeaderName,
+    timestamp: bool,
+}
+
+impl HmacSigner {
+    /// Sign request bodies with `key`, sending the signature in the `header_name` header.
+    pub fn new(key: impl Into<Vec<u8>>, header_name: HeaderName) -> Self {
+        Self {
+            key: key.into(),
+            header_name,
+            timestamp: false,
+        }
+    }
+
+    /// Also sign the request path and the current time.
+    pub fn with_timestamp(self) -> Self {
+        Self {
+            timestamp: true,
+            ..self
+        }
+    }
+}
+
+impl std::fmt::Debug for HmacSigner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HmacSigner")
+            .field("header_name", &self.header_name)
+            .field("timestamp", &self.timestamp)
+            .finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl Middleware for HmacSigner {
+    async fn handle(&self, mut req: reqwest::Request, next: Next<'_>) -> Result<reqwest::Response> {
+        let timestamp = self.timestamp.then(unix_now);
+        let body = req.body().and_then(|b| b.as_bytes()).unwrap_or_default();
+        let mut value = HeaderValue::try_from(sign(&self.key, timestamp, req.url().path(), body))?;
+        value.set_sensitive(true);
+        req.headers_mut().insert(self.header_name.clone(), value);
+        next.run(req).await
+    }
+}
+
+/// Checks the signatures made by an [`HmacSigner`] with the same key and header name, for
+/// [`Options::verify_hmac`](crate::server::Options::verify_hmac).
+#[derive(Clone)]
+pub struct HmacVerifier {
+    key: Vec<u8>,
+    header_name: HeaderName,
+    max_age: Option<Duration>,
+}
+
+impl HmacVerifier {
+    pub fn new(key: impl Into<Vec<u8>>, header_name: HeaderName) -> Self {
+        Self {
+            key: key.into(),
+            header_name,
+            max_age: None,
+        }
+    }
+
+    /// Only accept timestamped signatures (see [`HmacSigner::with_timestamp`]) made less than
+    /// `max_age` ago, or as far ahead to allow for clock skew.
+    pub fn max_age(self, max_age: Duration) -> Self {
+        Self {
+            max_age: Some(max_age),
+            ..self
+        }
+    }
+
+    /// Check the signature of a request to `path` (the full path, before any nesting stripped a
+    /// prefix). The HMACs are compared in constant time.
+    pub(crate) fn verify(
+        &self,
+        headers: &HeaderMap,
+        path: &str,
+        body: &[u8],
+    ) -> Result<(), TwirpErrorResponse> {
+        let Some(value) = headers.get(&self.header_name) else {
+            return Err(error::unauthenticated("missing request signature"));
+        };
+        let Some((timestamp, tag)) = value.to_str().ok().and_then(parse_signature) else {
+            return Err(error::unauthenticated("malformed request signature"));
+        };
+        if let Some(max_age) = self.max_age {
+            let Some(timestamp) = timestamp else {
+                return Err(error::unauthenticated("request signature has no timestamp"));
+            };
+            if unix_now().abs_diff(timestamp) > max_age.as_secs() {
+                return Err(error::unauthenticated("request signature has expired"));
+            }
+        }
+        mac(&self.key, timestamp, path, body)
+            .verify_slice(&tag)
+            .map_err(|_| error::unauthenticated("invalid request signature"))
+    }
+}
+
+impl std::fmt::Debug for HmacVerifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HmacVerifier")
+            .field("header_name", &self.header_name)
+            .field("max_age", &self.max_age)
+            .finish_non_exhaustive()
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// The timestamp and HMAC in a signature header value.
+fn parse_signature(value: &str) -> Option<(Option<u64>, Vec<u8>)> {
+    let (timestamp, hex) = match value.split_once(',') {
+        Some((t, rest)) => (Some(t.strip_prefix("t=")?.parse().ok()?), rest),
+        None => (None, value),
+    };
+    let hex = hex.strip_prefix("v1=")?;
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    let tag = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<_>>()?;
+    Some((timestamp, tag))
+}
+
+/// The signature header value for a request.
+fn sign(key: &[u8], timestamp: Option<u64>, path: &str, body: &[u8]) -> String {
+    let tag = mac(key, timestamp, path, body).finalize().into_bytes();
+    let hex = tag.iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{b:02x}");
+        hex
+    });
+    match timestamp {
+        Some(t) => format!("t={t},v1={hex}"),
+        None => format!("v1={hex}"),
+    }
+}
+
+fn mac(key: &[u8], timestamp: Option<u64>, path: &str, body: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    if let Some(t) = timestamp {
+        mac.update(format!("{t}.{path}.").as_bytes());
+    }
+    mac.update(body);
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::http::HeaderMap;
+    use url::Url;
+
+    use super::*;
+    use crate::test::*;
+    use crate::ClientBuilder;
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231, test case 2.
+        assert_eq!(
+            sign(b"Jefe", None, "/ignored", b"what do ya want for nothing?"),
+            "v1=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        let body = b"what do ya want for nothing?";
+        let signed = sign(b"Jefe", Some(1700000000), "/twirp/a.B/C", body);
+        assert!(signed.starts_with("t=1700000000,v1="), "{signed}");
+        assert_ne!(
+            signed,
+            sign(b"Jefe", Some(1700000001), "/twirp/a.B/C", body)
+        );
+        assert_ne!(
+            signed,
+            sign(b"Jefe", Some(1700000000), "/twirp/a.B/D", body)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hmac_signer() {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let s = seen.clone();
+        let app = axum::Router::new().route(
+            "/twirp/test.TestAPI/Ping",
+            axum::routing::post(
+                move |headers: HeaderMap, body: axum::body::Bytes| async move {
+                    let signature = headers["x-signature"].to_str().unwrap().to_string();
+                    s.lock().unwrap().push((signature, body.to_vec()));
+                    axum::http::StatusCode::IM_A_TEAPOT
+                },
+            ),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move { axum::serve(listener, app).await });
+        let base_url = Url::parse(&format!("http://{addr}/twirp/")).unwrap();
+        let header_name = HeaderName::from_static("x-signature");
+        let ping = || PingRequest {
+            name: "hi".to_string(),
+        };
+
+        let client = ClientBuilder::new(base_url.clone(), reqwest::Client::new())
+            .hmac_signer("secret", header_name.clone())
+            .build()
+            .unwrap();
+        assert!(client.ping(ping()).await.is_err());
+        let client = ClientBuilder::new(base_url, reqwest::Client::new())
+            .with(HmacSigner::new("secret", header_name).with_timestamp())
+            .build()
+            .unwrap();
+        assert!(client.ping(ping()).await.is_err());
+
+        let seen = seen.lock().unwrap();
+        let (signature, body) = &seen[0];
+        assert_eq!(signature, &sign(b"secret", None, "/", body));
+        let (signature, body) = &seen[1];
+        let t = signature
+            .strip_prefix("t=")
+            .and_then(|s| s.split(',').next())
+            .unwrap();
+        assert_eq!(
+            signature,
+            &sign(
+                b"secret",
+                Some(t.parse().unwrap()),
+                "/twirp/test.TestAPI/Ping",
+                body
+            )
+        );
+
+        server.abort();
+    }
+
+    #[test]
+    fn test_verify() {
+        let header_name = HeaderName::from_static("x-signature");
+        let verifier = HmacVerifier::new("secret", header_name.clone());
+        let path = "/twirp/test.TestAPI/Ping";
+        let headers = |value: String| {
+            HeaderMap::from_iter([(header_name.clone(), HeaderValue::try_from(value).unwrap())])
+        };
+        let msg = |res: Result<(), TwirpErrorResponse>| {
+            let err = res.unwrap_err();
+            assert_eq!(err.code, crate::TwirpErrorCode::Unauthenticated);
+            err.msg
+        };
+
+        let signed = headers(sign(b"secret", None, path, b"hi"));
+        assert!(verifier.verify(&signed, path, b"hi").is_ok());
+        assert_eq!(
+            msg(verifier.verify(&signed, path, b"bye")),
+            "invalid request signature"
+        );
+        assert_eq!(
+            msg(verifier.verify(&HeaderMap::new(), path, b"hi")),
+            "missing request signature"
+        );
+        assert_eq!(
+            msg(verifier.verify(&headers("v1=zz".to_string()), path, b"hi")),
+            "malformed request signature"
+        );
+        let wrong_key = headers(sign(b"guess", None, path, b"hi"));
+        assert_eq!(
+            msg(verifier.verify(&wrong_key, path, b"hi")),
+            "invalid request signature"
+        );
+
+        let verifier = verifier.max_age(Duration::from_secs(60));
+        assert_eq!(
+            msg(verifier.verify(&signed, path, b"hi")),
+            "request signature has no timestamp"
+        );
+        let fresh = headers(sign(b"secret", Some(unix_now()), path, b"hi"));
+        assert!(verifier.verify(&fresh, path, b"hi").is_ok());
+        assert_eq!(
+            msg(verifier.verify(&fresh, "/twirp/test.TestAPI/Boom", b"hi")),
+            "invalid request signature"
+        );
+        let stale = headers(sign(b"secret", Some(unix_now() - 61), path, b"hi"));
+        assert_eq!(
+            msg(verifier.verify(&stale, path, b"hi")),
+            "request signature has expired"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_verify_hmac() {
+        let header_name = HeaderName::from_static("x-signature");
+        let verifier =
+            HmacVerifier::new("secret", header_name.clone()).max_age(Duration::from_secs(60));
+        let app = test_api_router().layer(crate::server::Options::new().verify_hmac(verifier));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move { axum::serve(listener, app).await });
+        let base_url = Url::parse(&format!("http://{addr}/twirp/")).unwrap();
+        let ping = || PingRequest {
+            name: "hi".to_string(),
+        };
+
+        let client = ClientBuilder::new(base_url.clone(), reqwest::Client::new())
+            .with(HmacSigner::new("secret", header_name.clone()).with_timestamp())
+            .build()
+            .unwrap();
+        assert_eq!(client.ping(ping()).await.unwrap().name, "hi");
+
+        let client = ClientBuilder::new(base_url, reqwest::Client::new())
+            .with(HmacSigner::new("guess", header_name).with_timestamp())
+            .build()
+            .unwrap();
+        crate::assert_twirp_error!(
+            client.ping(ping()).await,
+            Unauthenticated,
+            contains = "invalid"
+        );
+
+        server.abort();
+    }
+}
@@ -1,4 +1,5 @@
 //! Test helpers and mini twirp api server implementation.
+use std::fmt::Debug;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -11,9 +12,8 @@ use serde::de::DeserializeOwned;
 use tokio::task::JoinHandle;
 use tokio::time::Instant;
 
-use crate::details::TwirpRouterBuilder;
 use crate::server::Timings;
-use crate::{error, Client, Context, Result, TwirpErrorResponse};
+use crate::{error, Client, ClientError, Context, Result, TwirpErrorCode, TwirpErrorResponse};
 
 pub async fn run_test_server(port: u16) -> JoinHandle<Result<(), std::io::Error>> {
     let router = test_api_router();
@@ -31,20 +31,10 @@ pub fn test_api_router() -> Router {
     let api = Arc::new(TestApiServer {});
 
     // NB: This part would be generated
-    let test_router = TwirpRouterBuilder::new(api)
-        .route(
-            "/Ping",
-            |api: Arc<TestApiServer>, ctx: Context, req: PingRequest| async move {
-                api.ping(ctx, req).await
-            },
-        )
-        .route(
-            "/Boom",
-            |api: Arc<TestApiServer>, ctx: Context, req: PingRequest| async move {
-                api.boom(ctx, req).await
-            },
-        )
-        .build();
+    let test_router = crate::routes!(api, {
+        "Ping" => ping,
+        "Boom" => boom,
+    });
 
     axum::Router::new()
         .nest("/twirp/test.TestAPI", test_router)
@@ -57,14 +47,16 @@ pub fn gen_ping_request(name: &str) -> Request<Body> {
     })
     .expect("will always be valid json");
     Request::post("/twirp/test.TestAPI/Ping")
+        .header(http::header::CONTENT_TYPE, "application/json")
         .extension(Timings::new(Instant::now()))
         .body(Body::from(req))
         .expect("always a valid twirp request")
 }
 
-pub async fn read_string_body(body: Body) -> String {
+/// Read a body as text, failing if it isn't UTF-8.
+pub async fn read_string_body(body: Body) -> Result<String, std::string::FromUtf8Error> {
     let data = Vec::<u8>::from(body.collect().await.expect("invalid body").to_bytes());
-    String::from_utf8(data).expect("non-utf8 body")
+    String::from_utf8(data)
 }
 
 pub async fn read_json_body<T>(body: Body) -> T
@@ -79,6 +71,73 @@ pub async fn read_err_body(body: Body) -> TwirpErrorResponse {
     read_json_body(body).await
 }
 
+/// Assert that a result is a twirp error with the given code, and optionally that its message
+/// contains a substring. Works with the results of both handlers (`TwirpErrorResponse`) and
+/// clients (`ClientError`), and prints the whole result on failure.
+///
+/// ```
+/// # use twirp::assert_twirp_error;
+/// let res: Result<(), _> = Err(twirp::invalid_argument("inches must be at least 1"));
+/// assert_twirp_error!(res, InvalidArgument);
+/// assert_twirp_error!(res, InvalidArgument, contains = "inches");
+/// ```
+#[macro_export]
+macro_rules! assert_twirp_error {
+    ($result:expr, $code:ident $(,)?) => {
+        $crate::test::assert_twirp_error(&$result, $crate::TwirpErrorCode::$code, None)
+    };
+    ($result:expr, $code:ident, contains = $needle:expr $(,)?) => {
+        $crate::test::assert_twirp_error(&$result, $crate::TwirpErrorCode::$code, Some($needle))
+    };
+}
+
+/// Error types that can carry a twirp error, for [`assert_twirp_error!`].
+pub trait AsTwirpError: Debug {
+    fn as_twirp_error(&self) -> Option<&TwirpErrorResponse>;
+}
+
+impl AsTwirpError for TwirpErrorResponse {
+    fn as_twirp_error(&self) -> Option<&TwirpErrorResponse> {
+        Some(self)
+    }
+}
+
+impl AsTwirpError for ClientError {
+    fn as_twirp_error(&self) -> Option<&TwirpErrorResponse> {
+        match self {
+            ClientError::TwirpError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// The implementation of [`assert_twirp_error!`].
+#[track_caller]
+pub fn assert_twirp_error<T, E>(
+    result: &std::result::Result<T, E>,
+    code: TwirpErrorCode,
+    contains: Option<&str>,
+) where
+    T: Debug,
+    E: AsTwirpError,
+{
+    let err = match result {
+        Err(e) => e.as_twirp_error(),
+        Ok(_) => None,
+    };
+    let Some(err) = err else {
+        panic!("expected a twirp {code:?} error, got {result:?}");
+    };
+    if err.code != code {
+        panic!("expected a twirp {code:?} error, got {err:?}");
+    }
+    if let Some(needle) = contains {
+        if !err.msg.contains(needle) {
+            panic!("expected a twirp error with a message containing {needle:?}, got {err:?}");
+        }
+    }
+}
+
 // Hand written sample test server and client
 
 pub struct TestApiServer;
@@ -160,3 +219,47 @@ pub struct PingResponse {
     #[prost(string, tag = "2")]
     pub name: ::prost::alloc::string::String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_string_body() {
+        assert_eq!(read_string_body(Body::from("hi")).await.unwrap(), "hi");
+        assert!(read_string_body(Body::from(&b"\xc3\x28"[..]))
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_assert_twirp_error() {
+        let res: Result<(), _> = Err(error::invalid_argument("inches must be at least 1"));
+        assert_twirp_error!(res, InvalidArgument);
+        assert_twirp_error!(res, InvalidArgument, contains = "inches");
+
+        let res: Result<(), _> = Err(ClientError::TwirpError(error::internal("boom!")));
+        assert_twirp_error!(res, Internal, contains = "boom");
+    }
+
+    #[test]
+    #[should_panic(expected = "got Ok(1)")]
+    fn test_assert_twirp_error_ok() {
+        let res: Result<i32, TwirpErrorResponse> = Ok(1);
+        assert_twirp_error!(res, Internal);
+    }
+
+    #[test]
+    #[should_panic(expected = "message containing \"hats\"")]
+    fn test_assert_twirp_error_message() {
+        let res: Result<(), _> = Err(error::internal("boom!"));
+        assert_twirp_error!(res, Internal, contains = "hats");
+    }
+
+    #[test]
+    #[should_panic(expected = "expected a twirp NotFound error")]
+    fn test_assert_twirp_error_code() {
+        let res: Result<(), _> = Err(error::internal("boom!"));
+        assert_twirp_error!(res, NotFound);
+    }
+}
@@ -0,0 +1,121 @@
+//! Helpers for tests that talk to a twirp server over the network.
+
+use std::net::SocketAddr;
+
+use tokio::task::JoinHandle;
+use url::Url;
+
+use crate::server::{shutdown_signal, ShutdownHandle};
+use crate::Client;
+
+/// A server running an app on an arbitrary port on localhost, chosen by the OS.
+///
+/// ```
+/// # async fn run(app: twirp::Router) {
+/// let server = twirp::testing::TestServer::start(app).await;
+/// let client = server.client();
+/// // ... make requests with `client` ...
+/// server.shutdown().await;
+/// # }
+/// ```
+///
+/// Dropping the server stops it right away, without waiting for requests in progress.
+#[derive(Debug)]
+pub struct TestServer {
+    addr: SocketAddr,
+    shutdown: ShutdownHandle,
+    task: Option<JoinHandle<std::io::Result<()>>>,
+}
+
+impl TestServer {
+    /// Start serving `app`.
+    ///
+    /// # Panics
+    ///
+    /// If binding to a port fails.
+    pub async fn start(app: axum::Router) -> Self {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind to a local port");
+        let addr = listener
+            .local_addr()
+            .expect("failed to get the local address");
+        let (shutdown, signal) = shutdown_signal();
+        let task = tokio::spawn(async move {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(signal)
+                .await
+        });
+        TestServer {
+            addr,
+            shutdown,
+            task: Some(task),
+        }
+    }
+
+    /// The address the server listens on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The URL of the server with the conventional `/twirp/` prefix, e.g.
+    /// `http://127.0.0.1:52123/twirp/`.
+    pub fn base_url(&self) -> Url {
+        Url::parse(&format!("http://{}/twirp/", self.addr)).expect("always a valid URL")
+    }
+
+    /// A client for the server, with the [`base_url`](Self::base_url).
+    pub fn client(&self) -> Client {
+        Client::from_base_url(self.base_url()).expect("always a valid base URL")
+    }
+
+    /// Stop the server, letting requests in progress finish first.
+    ///
+    /// # Panics
+    ///
+    /// If the server failed or panicked.
+    pub async fn shutdown(mut self) {
+        self.shutdown.shutdown();
+        let task = self.task.take().expect("only taken here");
+        task.await
+            .expect("the server panicked")
+            .expect("the server failed");
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::*;
+
+    #[tokio::test]
+    async fn test_test_server() {
+        let server = TestServer::start(test_api_router()).await;
+        assert_eq!(server.addr().ip(), std::net::Ipv4Addr::LOCALHOST);
+        assert_eq!(
+            server.base_url().as_str(),
+            format!("http://{}/twirp/", server.addr())
+        );
+
+        let resp = server
+            .client()
+            .ping(PingRequest {
+                name: "hi".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(resp.name, "hi");
+
+        let addr = server.addr();
+        server.shutdown().await;
+        assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+    }
+}
@@ -13,12 +13,33 @@ fn main() {
         println!("cargo:rerun-if-changed={}", entry.display());
     }
 
+    let fds = prost_build
+        .file_descriptor_set_path(&descriptor_file)
+        .load_fds(&proto_source_files, &["./proto"])
+        .expect("error loading protos");
+
+    twirp_build::protobuf_json(&mut prost_build, &fds);
+
+    // Requests are also usable as map keys.
+    twirp_build::derive(
+        &mut prost_build,
+        &fds,
+        ".service.haberdash.v1.MakeHatRequest",
+        &["Eq", "Hash"],
+    );
+
+    let service_generator = twirp_build::ServiceGenerator::new()
+        .file_descriptor_set(fds.clone())
+        .validate_requests("service.haberdash.v1.HaberdasherAPI")
+        .error_meta_constants(true)
+        .client_structs(true)
+        .openapi(out.join("openapi"));
+
     prost_build
-        .service_generator(twirp_build::service_generator())
+        .service_generator(Box::new(service_generator))
         .type_attribute(".", "#[derive(serde::Serialize,serde::Deserialize)]")
         .extern_path(".google.protobuf.Timestamp", "::prost_wkt_types::Timestamp")
-        .file_descriptor_set_path(&descriptor_file)
-        .compile_protos(&proto_source_files, &["./proto"])
+        .compile_fds(fds)
         .expect("error compiling protos");
 
     let descriptor_bytes =
@@ -1,4 +1,6 @@
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::UNIX_EPOCH;
 
 use twirp::async_trait::async_trait;
@@ -6,7 +8,7 @@ use twirp::axum::body::Body;
 use twirp::axum::http;
 use twirp::axum::middleware::{self, Next};
 use twirp::axum::routing::get;
-use twirp::{invalid_argument, Context, Router, TwirpErrorResponse};
+use twirp::{Context, Router, TwirpErrorResponse};
 
 pub mod service {
     pub mod haberdash {
@@ -17,23 +19,40 @@ pub mod service {
 }
 use service::haberdash::v1::{self as haberdash, MakeHatRequest, MakeHatResponse};
 
-async fn ping() -> &'static str {
-    "Pong\n"
+async fn ping(ready: Arc<AtomicBool>) -> (http::StatusCode, &'static str) {
+    if ready.load(Ordering::Acquire) {
+        (http::StatusCode::OK, "Pong\n")
+    } else {
+        (http::StatusCode::SERVICE_UNAVAILABLE, "Starting\n")
+    }
 }
 
 #[tokio::main]
 pub async fn main() {
+    twirp::logging::init();
     let api_impl = HaberdasherApiServer {};
+    let ready = Arc::new(AtomicBool::new(false));
     let middleware = twirp::tower::builder::ServiceBuilder::new()
-        .layer(middleware::from_fn(request_id_middleware));
+        .layer(middleware::from_fn(request_id_middleware))
+        .layer(twirp::server::Options::new().readiness(ready.clone()));
     let twirp_routes = Router::new()
         .nest(haberdash::SERVICE_FQN, haberdash::router(api_impl))
         .layer(middleware);
     let app = Router::new()
         .nest("/twirp", twirp_routes)
-        .route("/_ping", get(ping))
+        .route(
+            "/_ping",
+            get({
+                let ready = ready.clone();
+                move || ping(ready)
+            }),
+        )
         .fallback(twirp::server::not_found_handler);
 
+    // Any slow startup work (connecting to databases, warming caches) would happen here. Until
+    // the flag is set, RPCs get `unavailable` and the health check fails.
+    ready.store(true, Ordering::Release);
+
     let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
     let tcp_listener = tokio::net::TcpListener::bind(addr)
         .await
@@ -55,10 +74,6 @@ impl haberdash::HaberdasherApi for HaberdasherApiServer {
         ctx: Context,
         req: MakeHatRequest,
     ) -> Result<MakeHatResponse, TwirpErrorResponse> {
-        if req.inches == 0 {
-            return Err(invalid_argument("inches"));
-        }
-
         if let Some(id) = ctx.get::<RequestId>() {
             println!("{id:?}");
         };
@@ -116,9 +131,8 @@ async fn request_id_middleware(
 #[cfg(test)]
 mod test {
     use service::haberdash::v1::HaberdasherApiClient;
-    use twirp::client::Client;
-    use twirp::url::Url;
-    use twirp::TwirpErrorCode;
+    use twirp::assert_twirp_error;
+    use twirp::testing::TestServer;
 
     use crate::service::haberdash::v1::HaberdasherApi;
 
@@ -136,74 +150,95 @@ mod test {
 
     #[tokio::test]
     async fn invalid_request() {
-        let api = HaberdasherApiServer {};
-        let ctx = twirp::Context::default();
-        let res = api.make_hat(ctx, MakeHatRequest { inches: 0 }).await;
-        assert!(res.is_err());
+        let res = MakeHatRequest { inches: 0 }.validate();
+        assert_twirp_error!(res, InvalidArgument, contains = "inches must be at least 1");
         let err = res.unwrap_err();
-        assert_eq!(err.code, TwirpErrorCode::InvalidArgument);
+        assert_eq!(
+            err.meta
+                .get(haberdash::ERR_META_ARGUMENT)
+                .map(String::as_str),
+            Some("inches")
+        );
     }
 
-    /// A running network server task, bound to an arbitrary port on localhost, chosen by the OS
-    struct NetServer {
-        port: u16,
-        server_task: tokio::task::JoinHandle<()>,
-        shutdown_sender: tokio::sync::oneshot::Sender<()>,
+    /// Serves `api_impl` like `main` does.
+    async fn start_server(api_impl: HaberdasherApiServer) -> TestServer {
+        let twirp_routes = Router::new().nest(haberdash::SERVICE_FQN, haberdash::router(api_impl));
+        let ready = Arc::new(AtomicBool::new(true));
+        let app = Router::new()
+            .nest("/twirp", twirp_routes)
+            .route("/_ping", get(move || ping(ready)))
+            .fallback(twirp::server::not_found_handler);
+        TestServer::start(app).await
     }
 
-    impl NetServer {
-        async fn start(api_impl: HaberdasherApiServer) -> Self {
-            let twirp_routes =
-                Router::new().nest(haberdash::SERVICE_FQN, haberdash::router(api_impl));
-            let app = Router::new()
-                .nest("/twirp", twirp_routes)
-                .route("/_ping", get(ping))
-                .fallback(twirp::server::not_found_handler);
-
-            let tcp_listener = tokio::net::TcpListener::bind("localhost:0")
-                .await
-                .expect("failed to bind");
-            let addr = tcp_listener.local_addr().unwrap();
-            println!("Listening on {addr}");
-            let port = addr.port();
-
-            let (shutdown_sender, shutdown_receiver) = tokio::sync::oneshot::channel::<()>();
-            let server_task = tokio::spawn(async move {
-                let shutdown_receiver = async move {
-                    shutdown_receiver.await.unwrap();
-                };
-                if let Err(e) = twirp::axum::serve(tcp_listener, app)
-                    .with_graceful_shutdown(shutdown_receiver)
-                    .await
-                {
-                    eprintln!("server error: {}", e);
-                }
-            });
-
-            NetServer {
-                port,
-                server_task,
-                shutdown_sender,
-            }
+    #[derive(Debug)]
+    struct MockHaberdasherApiClient;
+
+    #[async_trait]
+    impl HaberdasherApiClient for MockHaberdasherApiClient {
+        async fn make_hat(
+            &self,
+            req: MakeHatRequest,
+        ) -> Result<MakeHatResponse, twirp::ClientError> {
+            Ok(MakeHatResponse {
+                size: req.inches,
+                ..Default::default()
+            })
         }
+    }
+
+    async fn hat_size(client: impl HaberdasherApiClient) -> i32 {
+        client
+            .make_hat(MakeHatRequest { inches: 3 })
+            .await
+            .unwrap()
+            .size
+    }
 
-        async fn shutdown(self) {
-            self.shutdown_sender.send(()).unwrap();
-            self.server_task.await.unwrap();
+    #[tokio::test]
+    async fn test_client_trait_object() {
+        let server = start_server(HaberdasherApiServer {}).await;
+
+        let clients: Vec<Box<dyn HaberdasherApiClient>> = vec![
+            Box::new(server.client()),
+            Box::new(MockHaberdasherApiClient),
+        ];
+        for client in clients {
+            assert_eq!(hat_size(client).await, 3);
         }
+        let shared: Arc<dyn HaberdasherApiClient> = Arc::new(MockHaberdasherApiClient);
+        assert_eq!(hat_size(shared).await, 3);
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_client_struct() {
+        let server = start_server(HaberdasherApiServer {}).await;
+
+        let client = haberdash::HaberdasherApiHttpClient::new(server.client());
+        let resp = client.make_hat(MakeHatRequest { inches: 2 }).await;
+        assert_eq!(resp.unwrap().size, 2);
+        assert_eq!(hat_size(client).await, 3);
+
+        server.shutdown().await;
     }
 
     #[tokio::test]
     async fn test_net() {
         let api_impl = HaberdasherApiServer {};
-        let server = NetServer::start(api_impl).await;
+        let server = start_server(api_impl).await;
 
-        let url = Url::parse(&format!("http://localhost:{}/twirp/", server.port)).unwrap();
-        let client = Client::from_base_url(url).unwrap();
+        let client = server.client();
         let resp = client.make_hat(MakeHatRequest { inches: 1 }).await;
         println!("{:?}", resp);
         assert_eq!(resp.unwrap().size, 1);
 
+        // the router validates requests before they reach the handler
+        let resp = client.make_hat(MakeHatRequest { inches: 0 }).await;
+        assert_twirp_error!(resp, InvalidArgument);
+
         server.shutdown().await;
     }
 }